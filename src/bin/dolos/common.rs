@@ -1,6 +1,6 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
-use dolos::wal::redb::WalStore;
+use dolos::wal::{redb::WalStore, tee::Tee};
 use miette::{Context as _, IntoDiagnostic};
 use pallas::ledger::configs::alonzo::GenesisFile as AlonzoFile;
 use pallas::ledger::configs::byron::GenesisFile as ByronFile;
@@ -10,9 +10,14 @@ use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 use tracing_subscriber::{filter::Targets, prelude::*};
 
-use dolos::{ledger::store::LedgerStore, prelude::*};
+use dolos::{
+    ledger::store::LedgerStore,
+    prelude::*,
+    querydb::store::Store as Archive,
+    submit::{InputCheck, MinFeeFilter},
+};
 
-use crate::{GenesisConfig, LoggingConfig};
+use crate::{GenesisConfig, LoggingConfig, RuntimeConfig};
 
 pub type Stores = (WalStore, LedgerStore);
 
@@ -21,12 +26,63 @@ pub fn open_data_stores(config: &crate::Config) -> Result<Stores, Error> {
 
     std::fs::create_dir_all(root).map_err(Error::storage)?;
 
-    let wal = WalStore::open(root.join("wal")).map_err(Error::storage)?;
+    let mut wal = WalStore::open(
+        root.join("wal"),
+        config.storage.wal_cache.map(|x| x * 1024 * 1024),
+        config.storage.wal_durability.unwrap_or_default(),
+    )
+    .map_err(Error::storage)?;
+
+    if let Some(tee) = &config.storage.wal_tee {
+        let tee = Tee::from_config(tee).map_err(Error::storage)?;
+        wal.set_tee(tee, None).map_err(Error::storage)?;
+    }
+
+    if config.storage.wal_checksums.unwrap_or_default() {
+        wal.enable_checksums();
+    }
+
+    if config.storage.wal_continuity_check.unwrap_or_default() {
+        wal.enable_continuity_check();
+    }
+
+    if let Some(limits) = config.storage.wal_write_batch {
+        wal.set_write_batch_limits(limits);
+    }
+
+    if let Some(bits_per_key) = config.storage.wal_bloom {
+        wal.enable_bloom(bits_per_key).map_err(Error::storage)?;
+    }
+
     let ledger = LedgerStore::open(root.join("ledger")).map_err(Error::storage)?;
 
     Ok((wal, ledger))
 }
 
+/// Prefetches recent WAL blocks in the background, if configured
+///
+/// Only meant for the long-running commands. The warmup thread keeps a handle
+/// on the db, which would get in the way of doctor commands like compaction.
+pub fn spawn_wal_warmup(config: &crate::Config, wal: &WalStore) {
+    if let Some(blocks) = config.storage.wal_warmup {
+        wal.spawn_warmup(blocks);
+    }
+}
+
+/// Opens the archive of trimmed blocks, if one is configured
+///
+/// The same handle is shared by sync and serve, the db can only be opened once
+/// per process. A missing archive is created empty, WAL compaction fills it.
+pub fn open_archive(config: &crate::Config) -> Result<Option<Arc<Archive>>, Error> {
+    let Some(path) = &config.storage.archive_path else {
+        return Ok(None);
+    };
+
+    let archive = Archive::open(path).map_err(Error::storage)?;
+
+    Ok(Some(Arc::new(archive)))
+}
+
 pub fn data_stores_exist(config: &crate::Config) -> bool {
     let root = &config.storage.path;
 
@@ -47,6 +103,21 @@ pub fn destroy_data_stores(config: &crate::Config) -> Result<(), Error> {
     Ok(())
 }
 
+pub fn build_runtime(config: &RuntimeConfig) -> Result<tokio::runtime::Runtime, Error> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+
+    if let Some(threads) = config.worker_threads {
+        builder.worker_threads(threads);
+    }
+
+    if let Some(threads) = config.max_blocking_threads {
+        builder.max_blocking_threads(threads);
+    }
+
+    builder.build().map_err(Error::config)
+}
+
 pub fn setup_tracing(config: &LoggingConfig) -> miette::Result<()> {
     let level = config.max_level;
 
@@ -89,6 +160,29 @@ pub fn open_genesis_files(config: &GenesisConfig) -> miette::Result<GenesisFiles
     Ok((byron_genesis, shelley_genesis, alonzo_genesis))
 }
 
+/// Builds the min-fee filter for submitted txs, if enabled in the config
+pub fn build_fee_filter(
+    config: &crate::Config,
+    ledger: &LedgerStore,
+) -> miette::Result<Option<Arc<MinFeeFilter>>> {
+    if !config.submit.min_fee_filter {
+        return Ok(None);
+    }
+
+    let (byron, shelley, alonzo) = open_genesis_files(&config.genesis)?;
+    let filter = MinFeeFilter::new(ledger.clone(), byron, shelley, alonzo);
+
+    Ok(Some(Arc::new(filter)))
+}
+
+pub fn build_input_check(config: &crate::Config, ledger: &LedgerStore) -> Option<Arc<InputCheck>> {
+    if !config.submit.input_check {
+        return None;
+    }
+
+    Some(Arc::new(InputCheck::new(ledger.clone())))
+}
+
 #[inline]
 #[cfg(unix)]
 async fn wait_for_exit_signal() {
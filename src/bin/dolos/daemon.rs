@@ -5,14 +5,29 @@ use tracing::warn;
 #[derive(Debug, clap::Args)]
 pub struct Args {}
 
-#[tokio::main]
-pub async fn run(config: super::Config, _args: &Args) -> miette::Result<()> {
+pub fn run(config: super::Config, args: &Args) -> miette::Result<()> {
     crate::common::setup_tracing(&config.logging)?;
 
+    crate::common::build_runtime(&config.runtime)
+        .context("building async runtime")?
+        .block_on(run_async(config, args))
+}
+
+async fn run_async(config: super::Config, _args: &Args) -> miette::Result<()> {
+    config.serve.validate().context("validating serve config")?;
+    config
+        .submit
+        .validate()
+        .context("validating submit config")?;
+
     let (wal, ledger) = crate::common::open_data_stores(&config)?;
+    crate::common::spawn_wal_warmup(&config, &wal);
+    let archive = crate::common::open_archive(&config)?;
     let (byron, shelley, _) = crate::common::open_genesis_files(&config.genesis)?;
-    let (txs_out, _) = gasket::messaging::tokio::mpsc_channel(64);
+    let (txs_out, txs_in) = gasket::messaging::tokio::mpsc_channel(64);
     let mempool = Arc::new(dolos::submit::MempoolState::default());
+    let fee_filter = crate::common::build_fee_filter(&config, &ledger)?;
+    let input_check = crate::common::build_input_check(&config, &ledger);
     let exit = crate::common::hook_exit_token();
 
     let sync = dolos::sync::pipeline(
@@ -22,6 +37,7 @@ pub async fn run(config: super::Config, _args: &Args) -> miette::Result<()> {
         ledger.clone(),
         byron,
         shelley,
+        archive.clone(),
         &config.retries,
     )
     .into_diagnostic()
@@ -29,21 +45,35 @@ pub async fn run(config: super::Config, _args: &Args) -> miette::Result<()> {
 
     let sync = crate::common::spawn_pipeline(gasket::daemon::Daemon::new(sync), exit.clone());
 
-    // TODO: spawn submit pipeline. Skipping for now since it's giving more trouble
-    // that benefits
-
-    let serve = tokio::spawn(dolos::serve::serve(
-        config.serve,
+    let submit = dolos::submit::pipeline(
+        &config.submit,
+        &config.upstream,
         wal.clone(),
-        ledger.clone(),
         mempool.clone(),
+        txs_in,
+        &config.retries,
+    )
+    .into_diagnostic()
+    .context("bootstrapping submit pipeline")?;
+
+    let submit = crate::common::spawn_pipeline(gasket::daemon::Daemon::new(submit), exit.clone());
+
+    let context = dolos::serve::ServeContext {
+        wal: wal.clone(),
+        ledger: ledger.clone(),
+        mempool: mempool.clone(),
         txs_out,
-        exit.clone(),
-    ));
+        fee_filter,
+        input_check,
+        fairness: config.submit.fairness.clone(),
+        archive,
+    };
+
+    let serve = tokio::spawn(dolos::serve::serve(config.serve, context, exit.clone()));
 
     let relay = tokio::spawn(dolos::relay::serve(config.relay, wal.clone(), exit.clone()));
 
-    let (_, serve, relay) = tokio::try_join!(sync, serve, relay)
+    let (_, _, serve, relay) = tokio::try_join!(sync, submit, serve, relay)
         .into_diagnostic()
         .context("joining threads")?;
 
@@ -0,0 +1,49 @@
+use dolos::ledger::{self, replay};
+use miette::{Context, IntoDiagnostic};
+use std::path::PathBuf;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// copy of a ledger db file, taken while dolos was stopped
+    #[arg(long)]
+    snapshot: PathBuf,
+}
+
+pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
+    let (byron, shelley, _) = crate::common::open_genesis_files(&config.genesis)?;
+
+    let (wal, ledger) = crate::common::open_data_stores(config).context("opening data stores")?;
+
+    if !ledger.is_empty() {
+        miette::bail!("ledger already has data, remove it before restoring a snapshot");
+    }
+
+    // the restored snapshot takes the place of the empty ledger
+    drop(ledger);
+
+    let path = config.storage.path.join("ledger");
+
+    let (mut ledger, seq) = replay::restore_snapshot(&args.snapshot, &path, &wal)
+        .into_diagnostic()
+        .context("restoring ledger snapshot")?;
+
+    println!("snapshot restored at wal sequence {seq}, replaying the rest of the wal");
+
+    let last = replay::replay(&wal, &mut ledger, &byron, &shelley, None)
+        .into_diagnostic()
+        .context("replaying wal tail")?;
+
+    let cursor = ledger
+        .cursor()
+        .into_diagnostic()
+        .context("finding ledger cursor")?;
+
+    if let Some(ledger::ChainPoint(slot, hash)) = cursor {
+        println!(
+            "replayed {} wal entries, ledger is at slot {slot} ({hash})",
+            last.unwrap_or(seq) - seq
+        );
+    }
+
+    Ok(())
+}
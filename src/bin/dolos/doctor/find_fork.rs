@@ -0,0 +1,70 @@
+use dolos::wal::{ChainDensity, ChainPoint, WalReader as _};
+use miette::{Context, IntoDiagnostic};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// reference points to compare against, formatted as `slot:hash`
+    #[arg(required = true)]
+    points: Vec<String>,
+}
+
+fn parse_point(raw: &str) -> miette::Result<ChainPoint> {
+    let (slot, hash) = raw
+        .split_once(':')
+        .ok_or(miette::miette!("invalid point {raw}, expected slot:hash"))?;
+
+    let slot = slot.parse().into_diagnostic().context("parsing slot")?;
+    let hash = hash.parse().into_diagnostic().context("parsing hash")?;
+
+    Ok(ChainPoint::Specific(slot, hash))
+}
+
+fn format_point(point: &Option<ChainPoint>) -> String {
+    match point {
+        Some(ChainPoint::Specific(slot, hash)) => format!("{slot}:{hash}"),
+        Some(ChainPoint::Origin) => "origin".into(),
+        None => "none".into(),
+    }
+}
+
+fn format_density(density: &ChainDensity) -> String {
+    format!(
+        "{} blocks over {} slots ({:.4} blocks/slot)",
+        density.blocks,
+        density.slots,
+        density.density()
+    )
+}
+
+pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
+    let (wal, _) = crate::common::open_data_stores(config).context("opening data stores")?;
+
+    let reference: Vec<_> = args
+        .points
+        .iter()
+        .map(|x| parse_point(x))
+        .collect::<miette::Result<_>>()?;
+
+    let weight = wal
+        .fork_weight(&reference)
+        .into_diagnostic()
+        .context("comparing chains")?;
+
+    let report = weight.fork;
+
+    println!("agreement point: {}", format_point(&report.agreement));
+
+    match report.reference {
+        Some(_) => {
+            println!("divergence found");
+            println!("  reference: {}", format_point(&report.reference));
+            println!("  local: {}", format_point(&report.local));
+            println!("density after the agreement point");
+            println!("  reference: {}", format_density(&weight.reference));
+            println!("  local: {}", format_density(&weight.local));
+        }
+        None => println!("no divergence found"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use dolos::wal::redb::WalStore;
+use miette::{Context, IntoDiagnostic};
+use std::path::PathBuf;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// file with the WAL entries to import, as written by a WAL export
+    #[arg(long)]
+    input: PathBuf,
+
+    /// decode and check every block before importing anything (slower)
+    #[arg(long, action)]
+    validate: bool,
+}
+
+fn open_input(args: &Args) -> miette::Result<std::io::BufReader<std::fs::File>> {
+    let file = std::fs::File::open(&args.input)
+        .into_diagnostic()
+        .context("opening input file")?;
+
+    Ok(std::io::BufReader::new(file))
+}
+
+pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
+    if args.validate {
+        let check = WalStore::check_dump(open_input(args)?)
+            .into_diagnostic()
+            .context("validating blocks")?;
+
+        println!(
+            "{} blocks passed, {} failed",
+            check.passed,
+            check.failed.len()
+        );
+
+        for (seq, slot, err) in check.failed.iter() {
+            println!("entry {seq} (slot {slot}): {err}");
+        }
+
+        if !check.failed.is_empty() {
+            miette::bail!("input has malformed blocks, nothing was imported");
+        }
+    }
+
+    let (mut wal, _) = crate::common::open_data_stores(config).context("opening data stores")?;
+
+    let count = wal
+        .import(open_input(args)?)
+        .into_diagnostic()
+        .context("importing wal entries")?;
+
+    println!("{count} wal entries imported");
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use dolos::wal::{BlockHash, BlockSlot, BlockSummary, RawBlock, ReadUtils as _, WalReader as _};
+use miette::{Context, IntoDiagnostic};
+
+#[derive(Debug, clap::Args)]
+#[group(required = true, multiple = false)]
+pub struct Args {
+    /// slot of the block to inspect
+    #[arg(long)]
+    slot: Option<BlockSlot>,
+
+    /// hash of the block to inspect
+    #[arg(long)]
+    hash: Option<BlockHash>,
+}
+
+fn find_by_slot(
+    wal: &dolos::wal::redb::WalStore,
+    slot: BlockSlot,
+) -> miette::Result<(RawBlock, BlockSummary)> {
+    // the latest apply wins, a slot can hold a block that was rolled back
+    let raw = wal
+        .crawl_from(None)
+        .into_diagnostic()
+        .context("crawling wal")?
+        .rev()
+        .filter_apply()
+        .into_blocks()
+        .flatten()
+        .find(|x| x.slot == slot)
+        .ok_or(miette::miette!("no block found at slot {slot}"))?;
+
+    let block = raw.decode().into_diagnostic().context("decoding block")?;
+    let summary = BlockSummary::from(&block);
+
+    Ok((raw, summary))
+}
+
+fn find_by_hash(
+    wal: &dolos::wal::redb::WalStore,
+    hash: &BlockHash,
+) -> miette::Result<(RawBlock, BlockSummary)> {
+    wal.read_decoded_block(hash, |x| x.into())
+        .into_diagnostic()
+        .context("reading block")?
+        .ok_or(miette::miette!("block {hash} not found in the chain"))
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
+    let (wal, _) = crate::common::open_data_stores(config).context("opening data stores")?;
+
+    let (raw, summary) = match (&args.slot, &args.hash) {
+        (Some(slot), _) => find_by_slot(&wal, *slot)?,
+        (_, Some(hash)) => find_by_hash(&wal, hash)?,
+        _ => unreachable!("clap requires one of slot or hash"),
+    };
+
+    let in_chain = wal
+        .contains_blocks(&[raw.hash])
+        .into_diagnostic()
+        .context("checking chain membership")?[0];
+
+    let prev_hash = summary
+        .prev_hash
+        .map(|x| x.to_string())
+        .unwrap_or("none".into());
+
+    println!("era: {:?}", summary.era);
+    println!("hash: {}", summary.hash);
+    println!("prev hash: {prev_hash}");
+    println!("slot: {}", summary.slot);
+    println!("number: {}", summary.number);
+    println!("body size: {} bytes", raw.body.len());
+    println!("in chain: {}", yes_no(in_chain));
+    println!(
+        "txs: {} ({} invalid)",
+        summary.tx_count, summary.invalid_tx_count
+    );
+    println!("output value: {} lovelace", summary.output_lovelace);
+    println!(
+        "scripts: {} ({} txs)",
+        yes_no(summary.script_tx_count > 0),
+        summary.script_tx_count
+    );
+    println!(
+        "metadata: {} ({} txs)",
+        yes_no(summary.metadata_tx_count > 0),
+        summary.metadata_tx_count
+    );
+
+    Ok(())
+}
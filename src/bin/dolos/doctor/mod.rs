@@ -1,7 +1,16 @@
 use clap::{Parser, Subcommand};
 
+mod bootstrap;
+mod find_fork;
+mod import_wal;
+mod inspect_block;
+mod rebuild_index;
 mod rebuild_ledger;
+mod reconcile;
+mod rollbacks;
+mod stats;
 mod trim_wal;
+mod verify_ledger;
 mod wal_integrity;
 
 #[derive(Debug, Subcommand)]
@@ -10,8 +19,27 @@ pub enum Command {
     RebuildLedger(rebuild_ledger::Args),
     /// checks the integrity of the WAL records
     WalIntegrity(wal_integrity::Args),
-    /// remove parts of the WAL
+    /// remove parts of the WAL, optionally shrinking the db file afterwards
     TrimWal(trim_wal::Args),
+    /// finds where the local chain diverges from a set of reference points and
+    /// compares the density of both sides
+    FindFork(find_fork::Args),
+    /// drops and re-populates a single secondary index
+    RebuildIndex(rebuild_index::Args),
+    /// replays the WAL into a scratch ledger and diffs its utxos against the live one
+    VerifyLedger(verify_ledger::Args),
+    /// restores a ledger snapshot and replays only the WAL entries after it
+    Bootstrap(bootstrap::Args),
+    /// shows how much space each table of the WAL takes
+    Stats(stats::Args),
+    /// prints a summary of a single block, looked up by slot or hash
+    InspectBlock(inspect_block::Args),
+    /// lists the latest rollbacks recorded in the WAL and how deep they went
+    Rollbacks(rollbacks::Args),
+    /// appends exported WAL entries to the WAL, optionally validating their blocks first
+    ImportWal(import_wal::Args),
+    /// compares the ledger cursor against the WAL and brings them back in line
+    Reconcile(reconcile::Args),
 }
 
 #[derive(Debug, Parser)]
@@ -25,6 +53,15 @@ pub fn run(config: &super::Config, args: &Args) -> miette::Result<()> {
         Command::RebuildLedger(x) => rebuild_ledger::run(config, x)?,
         Command::WalIntegrity(x) => wal_integrity::run(config, x)?,
         Command::TrimWal(x) => trim_wal::run(config, x)?,
+        Command::FindFork(x) => find_fork::run(config, x)?,
+        Command::RebuildIndex(x) => rebuild_index::run(config, x)?,
+        Command::VerifyLedger(x) => verify_ledger::run(config, x)?,
+        Command::Bootstrap(x) => bootstrap::run(config, x)?,
+        Command::Stats(x) => stats::run(config, x)?,
+        Command::InspectBlock(x) => inspect_block::run(config, x)?,
+        Command::Rollbacks(x) => rollbacks::run(config, x)?,
+        Command::ImportWal(x) => import_wal::run(config, x)?,
+        Command::Reconcile(x) => reconcile::run(config, x)?,
     }
 
     Ok(())
@@ -0,0 +1,64 @@
+use miette::{Context, IntoDiagnostic};
+
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum IndexName {
+    /// WAL index from chain point to sequence
+    WalPosition,
+    /// WAL index from sequence to block height
+    WalHeight,
+    /// WAL index from block hash to sequence
+    WalHash,
+    /// WAL index from tx hash to sequence
+    WalTx,
+    /// ledger index from address to utxos (the address history isn't rebuilt)
+    UtxoByAddress,
+}
+
+/// Store that holds an index, along with the kind of index within it
+enum IndexTarget {
+    Wal(dolos::wal::IndexKind),
+    Ledger(dolos::ledger::IndexKind),
+}
+
+impl IndexName {
+    fn target(&self) -> IndexTarget {
+        match self {
+            IndexName::WalPosition => IndexTarget::Wal(dolos::wal::IndexKind::Position),
+            IndexName::WalHeight => IndexTarget::Wal(dolos::wal::IndexKind::Height),
+            IndexName::WalHash => IndexTarget::Wal(dolos::wal::IndexKind::Hash),
+            IndexName::WalTx => IndexTarget::Wal(dolos::wal::IndexKind::Tx),
+            IndexName::UtxoByAddress => {
+                IndexTarget::Ledger(dolos::ledger::IndexKind::UtxoByAddress)
+            }
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// name of the index to rebuild
+    #[arg(value_enum)]
+    name: IndexName,
+}
+
+pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
+    crate::common::setup_tracing(&config.logging)?;
+
+    let (mut wal, mut ledger) =
+        crate::common::open_data_stores(config).context("opening data stores")?;
+
+    match args.name.target() {
+        IndexTarget::Wal(kind) => wal
+            .rebuild_index(kind)
+            .into_diagnostic()
+            .with_context(|| format!("rebuilding WAL {kind:?} index"))?,
+        IndexTarget::Ledger(kind) => ledger
+            .rebuild_index(kind)
+            .into_diagnostic()
+            .with_context(|| format!("rebuilding ledger {kind:?} index"))?,
+    }
+
+    println!("index rebuilt");
+
+    Ok(())
+}
@@ -73,7 +73,7 @@ pub fn run(config: &crate::Config, _args: &Args) -> miette::Result<()> {
         .cursor()
         .into_diagnostic()
         .context("finding ledger cursor")?
-        .map(|ledger::ChainPoint(s, h)| wal.assert_point(&wal::ChainPoint::Specific(s, h)))
+        .map(|ledger::ChainPoint(s, h)| wal.assert_cursor(&wal::ChainPoint::Specific(s, h)))
         .transpose()
         .into_diagnostic()
         .context("locating wal sequence")?;
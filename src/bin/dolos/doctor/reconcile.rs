@@ -0,0 +1,159 @@
+use dolos::{
+    ledger::{
+        self,
+        replay::{self, Drift, Fix},
+    },
+    wal::{self, ReadUtils as _, WalReader as _},
+};
+use miette::{Context, IntoDiagnostic};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// carry out the fix, otherwise it's only reported
+    #[arg(long, action)]
+    apply: bool,
+}
+
+fn format_point(point: &Option<wal::ChainPoint>) -> String {
+    match point {
+        Some(wal::ChainPoint::Specific(slot, hash)) => format!("{slot}:{hash}"),
+        Some(wal::ChainPoint::Origin) => "origin".into(),
+        None => "none".into(),
+    }
+}
+
+fn describe(drift: &Drift) -> String {
+    match drift {
+        Drift::Aligned => "aligned, the ledger has applied every wal entry".into(),
+        Drift::LedgerBehind { pending, .. } => {
+            format!("ledger behind, {pending} wal entries left to apply")
+        }
+        Drift::LedgerAhead => "ledger ahead, its cursor is past the tip of the wal".into(),
+        Drift::Gap => "gap, the ledger cursor is before the start of the wal".into(),
+        Drift::Diverged => "diverged, the ledger cursor is on a fork the wal doesn't have".into(),
+    }
+}
+
+pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
+    let (wal, mut ledger) =
+        crate::common::open_data_stores(config).context("opening data stores")?;
+
+    let cursor = ledger
+        .cursor()
+        .into_diagnostic()
+        .context("finding ledger cursor")?
+        .map(|ledger::ChainPoint(slot, hash)| wal::ChainPoint::Specific(slot, hash));
+
+    let oldest = wal
+        .crawl_from(None)
+        .into_diagnostic()
+        .context("crawling wal")?
+        .filter_forward()
+        .map(|(_, log)| wal::ChainPoint::from(&log))
+        .next();
+
+    let tip = wal
+        .find_tip()
+        .into_diagnostic()
+        .context("finding wal tip")?
+        .map(|(_, x)| x);
+
+    let archive = crate::common::open_archive(config).context("opening archive")?;
+
+    let drift = replay::reconcile(&wal, &ledger)
+        .into_diagnostic()
+        .context("reconciling ledger with wal")?;
+
+    println!("ledger cursor: {}", format_point(&cursor));
+    println!(
+        "wal range: {} to {}",
+        format_point(&oldest),
+        format_point(&tip)
+    );
+    println!("status: {}", describe(&drift));
+
+    match drift.fix(archive.is_some()) {
+        Fix::Nothing => {
+            println!("nothing to do");
+            Ok(())
+        }
+        Fix::RollForward => {
+            println!(
+                "fix: roll the ledger forward from its cursor to the wal tip at {}",
+                format_point(&tip)
+            );
+
+            if !args.apply {
+                println!("run again with --apply to do it");
+                return Ok(());
+            }
+
+            let (byron, shelley, _) = crate::common::open_genesis_files(&config.genesis)?;
+
+            let last = replay::replay(&wal, &mut ledger, &byron, &shelley, None)
+                .into_diagnostic()
+                .context("replaying wal")?;
+
+            if let Some(seq) = last {
+                println!("ledger rolled forward up to wal sequence {seq}");
+            }
+
+            Ok(())
+        }
+        Fix::BridgeGap => {
+            println!(
+                "fix: apply the archived blocks up to the start of the wal at {}, then roll the \
+                 ledger forward to the wal tip",
+                format_point(&oldest)
+            );
+
+            if !args.apply {
+                println!("run again with --apply to do it");
+                return Ok(());
+            }
+
+            let (byron, shelley, _) = crate::common::open_genesis_files(&config.genesis)?;
+
+            let Some(archive) = archive else {
+                miette::bail!("there's no storage archive to bridge the gap with");
+            };
+
+            let seq = replay::bridge_gap(&archive, &wal, &mut ledger, &byron, &shelley)
+                .into_diagnostic()
+                .context("bridging gap with archive")?;
+
+            println!("ledger bridged up to wal sequence {seq}");
+
+            let last = replay::replay(&wal, &mut ledger, &byron, &shelley, None)
+                .into_diagnostic()
+                .context("replaying wal")?;
+
+            if let Some(seq) = last {
+                println!("ledger rolled forward up to wal sequence {seq}");
+            }
+
+            Ok(())
+        }
+        Fix::Rebuild => {
+            println!("fix: the wal can't bring the ledger back in line, it has to be rebuilt");
+
+            if matches!(oldest, Some(wal::ChainPoint::Origin)) {
+                println!("the wal starts at origin, run `doctor rebuild-ledger`");
+            } else {
+                println!(
+                    "the wal doesn't start at origin, restore a ledger snapshot within the wal \
+                     range with `doctor bootstrap`"
+                );
+            }
+
+            if matches!(drift, Drift::Gap) {
+                println!(
+                    "alternatively, configure a storage archive that holds the trimmed blocks \
+                     and run this command again to bridge the gap"
+                );
+            }
+
+            miette::bail!("ledger needs to be rebuilt")
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use dolos::ledger::time::{NetworkConfig, SlotConverter};
+use miette::{Context, IntoDiagnostic};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// max number of rollbacks to list, the latest first
+    #[arg(long, default_value_t = 20)]
+    limit: usize,
+}
+
+pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
+    let (wal, _) = crate::common::open_data_stores(config).context("opening data stores")?;
+
+    let rollbacks = wal
+        .recent_rollbacks(args.limit)
+        .into_diagnostic()
+        .context("reading rollbacks")?;
+
+    if rollbacks.is_empty() {
+        println!("no rollbacks in the wal");
+        return Ok(());
+    }
+
+    // chain time is only known for the public networks
+    let slots =
+        NetworkConfig::from_magic(config.upstream.network_magic).map(|x| SlotConverter::new(&x));
+
+    println!(
+        "{:>12} {:>6} {:>24} {:>12} {:>14}",
+        "seq", "depth", "undone slots", "back to", "tip time"
+    );
+
+    for rollback in rollbacks {
+        let back_to = match rollback.point {
+            dolos::wal::ChainPoint::Origin => "origin".to_string(),
+            dolos::wal::ChainPoint::Specific(slot, _) => slot.to_string(),
+        };
+
+        let time = slots
+            .as_ref()
+            .and_then(|x| x.slot_to_time(*rollback.undone.end()))
+            .map(|x| x.to_string())
+            .unwrap_or("-".into());
+
+        println!(
+            "{:>12} {:>6} {:>24} {:>12} {:>14}",
+            rollback.seq,
+            rollback.depth,
+            format!("{}-{}", rollback.undone.start(), rollback.undone.end()),
+            back_to,
+            time
+        );
+    }
+
+    Ok(())
+}
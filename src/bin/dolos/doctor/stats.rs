@@ -0,0 +1,35 @@
+use miette::{Context, IntoDiagnostic};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {}
+
+pub fn run(config: &crate::Config, _args: &Args) -> miette::Result<()> {
+    let (wal, _) = crate::common::open_data_stores(config).context("opening data stores")?;
+
+    let file = std::fs::metadata(config.storage.path.join("wal"))
+        .into_diagnostic()
+        .context("reading wal file metadata")?;
+
+    println!("wal file: {} bytes", file.len());
+
+    let mut sizes = wal
+        .table_sizes()
+        .into_diagnostic()
+        .context("reading table sizes")?;
+
+    sizes.sort_by_key(|x| std::cmp::Reverse(x.total_bytes()));
+
+    println!(
+        "{:<10} {:>12} {:>14} {:>14} {:>14}",
+        "table", "entries", "stored", "metadata", "fragmented"
+    );
+
+    for size in sizes {
+        println!(
+            "{:<10} {:>12} {:>14} {:>14} {:>14}",
+            size.name, size.entries, size.stored_bytes, size.metadata_bytes, size.fragmented_bytes
+        );
+    }
+
+    Ok(())
+}
@@ -10,6 +10,11 @@ pub struct Args {
     /// wal sequence where to stop trimming (inclusive)
     #[arg(long)]
     to: Option<LogSeq>,
+
+    /// shrink the db file after trimming, so the disk space is given back (the
+    /// node has to be stopped, a running one never shrinks the file)
+    #[arg(long, action)]
+    compact: bool,
 }
 
 pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
@@ -17,11 +22,31 @@ pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
 
     let (mut wal, _) = crate::common::open_data_stores(config).context("opening data stores")?;
 
+    // trimmed blocks go to the archive, same as with compaction
+    let archive = crate::common::open_archive(config).context("opening archive")?;
+
+    if let Some(archive) = archive {
+        wal.archive_range(&archive, args.from, args.to)
+            .into_diagnostic()
+            .context("archiving trimmed blocks")?;
+    }
+
     wal.remove_range(args.from, args.to)
         .into_diagnostic()
         .context("removing range from WAL")?;
 
     println!("wal segment trimmed");
 
+    if args.compact {
+        let compacted = wal
+            .compact_storage()
+            .into_diagnostic()
+            .context("compacting WAL storage")?;
+
+        if compacted {
+            println!("wal storage compacted");
+        }
+    }
+
     Ok(())
 }
@@ -0,0 +1,118 @@
+use dolos::{
+    ledger::{self, replay, store::LedgerStore, UtxoDiff},
+    wal::{self, LogValue, WalReader as _},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use miette::{Context, IntoDiagnostic};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// max number of differing utxos to print
+    #[arg(long, default_value_t = 20)]
+    sample: usize,
+}
+
+fn format_diff(diff: &UtxoDiff) -> String {
+    let (kind, ledger::TxoRef(hash, idx)) = match diff {
+        UtxoDiff::Missing(x) => ("missing", x),
+        UtxoDiff::Unexpected(x) => ("unexpected", x),
+        UtxoDiff::Mismatch(x) => ("mismatch", x),
+    };
+
+    format!("{kind}: {hash}#{idx}")
+}
+
+pub fn run(config: &crate::Config, args: &Args) -> miette::Result<()> {
+    let (byron, shelley, _) = crate::common::open_genesis_files(&config.genesis)?;
+
+    let (wal, live) = crate::common::open_data_stores(config).context("opening data stores")?;
+
+    let ledger::ChainPoint(slot, hash) = live
+        .cursor()
+        .into_diagnostic()
+        .context("finding ledger cursor")?
+        .ok_or(miette::miette!("ledger is empty, nothing to verify"))?;
+
+    let until = wal
+        .assert_cursor(&wal::ChainPoint::Specific(slot, hash))
+        .into_diagnostic()
+        .context("locating ledger cursor in wal")?;
+
+    // the replay needs the whole history, a compacted wal can't provide it
+    let first = wal
+        .crawl_from(None)
+        .into_diagnostic()
+        .context("crawling wal")?
+        .next();
+
+    if !matches!(first, Some((_, LogValue::Mark(wal::ChainPoint::Origin)))) {
+        miette::bail!("wal doesn't start at origin, the ledger can't be replayed");
+    }
+
+    let path = config.storage.path.join("ledger-verify");
+
+    if path.exists() {
+        std::fs::remove_file(&path)
+            .into_diagnostic()
+            .context("removing leftover verification ledger")?;
+    }
+
+    let mut rebuilt = LedgerStore::open(&path)
+        .into_diagnostic()
+        .context("opening verification ledger")?;
+
+    let pb = ProgressBar::new(slot);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta}) {msg}",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+
+    let entries = wal
+        .crawl_from(None)
+        .into_diagnostic()
+        .context("crawling wal")?
+        .take_while(|(seq, _)| *seq <= until);
+
+    for (_, log) in entries {
+        if let LogValue::Apply(block) = &log {
+            pb.set_position(block.slot);
+        }
+
+        replay::apply_entry(&mut rebuilt, &log, &byron, &shelley)
+            .into_diagnostic()
+            .context("replaying wal entry")?;
+    }
+
+    pb.finish_and_clear();
+
+    let report = live
+        .diff_utxos(&rebuilt, args.sample)
+        .into_diagnostic()
+        .context("comparing utxo sets")?;
+
+    drop(rebuilt);
+
+    std::fs::remove_file(&path)
+        .into_diagnostic()
+        .context("removing verification ledger")?;
+
+    if report.count == 0 {
+        println!("ledger matches the replayed wal up to slot {slot}");
+        return Ok(());
+    }
+
+    println!("{} differing utxos found", report.count);
+
+    for diff in report.sample.iter() {
+        println!("  {}", format_diff(diff));
+    }
+
+    if report.count > report.sample.len() {
+        println!("  ... and {} more", report.count - report.sample.len());
+    }
+
+    miette::bail!("ledger doesn't match the replayed wal")
+}
@@ -59,6 +59,7 @@ pub fn run(config: &crate::Config, _args: &Args) -> miette::Result<()> {
         .flatten();
 
     let mut last_hash = None;
+    let mut invalid = 0;
 
     for block in remaining {
         let RawBlock {
@@ -69,6 +70,17 @@ pub fn run(config: &crate::Config, _args: &Args) -> miette::Result<()> {
             .global_pb
             .set_message(format!("checking block {hash}"));
 
+        if body.len() < wal::MIN_BLOCK_BODY_SIZE {
+            feedback.global_pb.println(format!(
+                "block {hash} at slot {slot} has an invalid body of {} bytes",
+                body.len()
+            ));
+
+            invalid += 1;
+            last_hash = Some(hash);
+            continue;
+        }
+
         let blockd = MultiEraBlock::decode(&body)
             .into_diagnostic()
             .context("decoding blocks")?;
@@ -84,6 +96,10 @@ pub fn run(config: &crate::Config, _args: &Args) -> miette::Result<()> {
         feedback.global_pb.set_position(slot);
     }
 
+    if invalid > 0 {
+        miette::bail!("{invalid} blocks with invalid bodies found in wal");
+    }
+
     println!("no integrity issues found in wal");
 
     Ok(())
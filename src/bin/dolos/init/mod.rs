@@ -139,6 +139,7 @@ impl Default for ConfigEditor {
                 serve: Default::default(),
                 relay: Default::default(),
                 retries: Default::default(),
+                runtime: Default::default(),
                 logging: Default::default(),
             },
             None,
@@ -169,11 +170,7 @@ impl ConfigEditor {
     fn apply_serve_grpc(mut self, value: Option<bool>) -> Self {
         if let Some(value) = value {
             if value {
-                self.0.serve.grpc = dolos::serve::grpc::Config {
-                    listen_address: "[::]:50051".into(),
-                    tls_client_ca_root: None,
-                }
-                .into();
+                self.0.serve.grpc = dolos::serve::grpc::Config::default().into();
             } else {
                 self.0.serve.grpc = None;
             }
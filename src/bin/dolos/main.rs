@@ -67,6 +67,33 @@ pub struct StorageConfig {
     path: std::path::PathBuf,
     #[allow(dead_code)]
     wal_size: Option<u64>,
+
+    /// Size (in MB) of the memory cache used by the WAL db
+    wal_cache: Option<usize>,
+
+    /// Durability of WAL commits, eventual trades crash-safety for speed
+    wal_durability: Option<dolos::wal::redb::Durability>,
+
+    /// Number of recent blocks to prefetch from the WAL after opening it
+    wal_warmup: Option<usize>,
+
+    /// Optional sink that receives every committed WAL entry
+    wal_tee: Option<dolos::wal::tee::Config>,
+
+    /// Bits per key of the in-memory bloom filter over WAL block hashes
+    wal_bloom: Option<usize>,
+
+    /// Store a checksum of each block body and verify it on block reads
+    wal_checksums: Option<bool>,
+
+    /// Reject blocks that don't build on top of the WAL tip
+    wal_continuity_check: Option<bool>,
+
+    /// Caps on the size of each write when appending blocks in bulk
+    wal_write_batch: Option<dolos::wal::redb::WriteBatchLimits>,
+
+    /// Query db with the blocks already trimmed from the WAL
+    archive_path: Option<PathBuf>,
 }
 
 impl Default for StorageConfig {
@@ -74,6 +101,15 @@ impl Default for StorageConfig {
         Self {
             path: PathBuf::from("data"),
             wal_size: None,
+            wal_cache: None,
+            wal_durability: None,
+            wal_warmup: None,
+            wal_tee: None,
+            wal_bloom: None,
+            wal_checksums: None,
+            wal_continuity_check: None,
+            wal_write_batch: None,
+            archive_path: None,
         }
     }
 }
@@ -126,6 +162,15 @@ impl Default for LoggingConfig {
     }
 }
 
+#[derive(Serialize, Deserialize, Default)]
+pub struct RuntimeConfig {
+    /// Number of worker threads, defaults to the number of cpu cores
+    worker_threads: Option<usize>,
+
+    /// Max number of threads in the blocking pool, used for storage reads
+    max_blocking_threads: Option<usize>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub upstream: dolos::model::UpstreamConfig,
@@ -138,6 +183,9 @@ pub struct Config {
     pub retries: Option<gasket::retries::Policy>,
     pub mithril: Option<MithrilConfig>,
 
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+
     #[serde(default)]
     pub logging: LoggingConfig,
 }
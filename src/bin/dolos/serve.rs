@@ -5,16 +5,42 @@ use std::sync::Arc;
 #[derive(Debug, clap::Args)]
 pub struct Args {}
 
-#[tokio::main]
-pub async fn run(config: super::Config, _args: &Args) -> miette::Result<()> {
+pub fn run(config: super::Config, args: &Args) -> miette::Result<()> {
     crate::common::setup_tracing(&config.logging)?;
 
+    crate::common::build_runtime(&config.runtime)
+        .context("building async runtime")?
+        .block_on(run_async(config, args))
+}
+
+async fn run_async(config: super::Config, _args: &Args) -> miette::Result<()> {
+    config.serve.validate().context("validating serve config")?;
+    config
+        .submit
+        .validate()
+        .context("validating submit config")?;
+
     let (wal, ledger) = crate::common::open_data_stores(&config)?;
+    crate::common::spawn_wal_warmup(&config, &wal);
+    let archive = crate::common::open_archive(&config)?;
     let (txs_out, _txs_in) = gasket::messaging::tokio::mpsc_channel(64);
     let mempool = Arc::new(dolos::submit::MempoolState::default());
+    let fee_filter = crate::common::build_fee_filter(&config, &ledger)?;
+    let input_check = crate::common::build_input_check(&config, &ledger);
     let exit = crate::common::hook_exit_token();
 
-    dolos::serve::serve(config.serve, wal, ledger, mempool, txs_out, exit)
+    let context = dolos::serve::ServeContext {
+        wal,
+        ledger,
+        mempool,
+        txs_out,
+        fee_filter,
+        input_check,
+        fairness: config.submit.fairness.clone(),
+        archive,
+    };
+
+    dolos::serve::serve(config.serve, context, exit)
         .await
         .context("serving clients")?;
 
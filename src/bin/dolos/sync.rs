@@ -7,6 +7,8 @@ pub fn run(config: &super::Config, _args: &Args) -> miette::Result<()> {
     crate::common::setup_tracing(&config.logging)?;
 
     let (wal, ledger) = crate::common::open_data_stores(config)?;
+    crate::common::spawn_wal_warmup(config, &wal);
+    let archive = crate::common::open_archive(config)?;
 
     let (byron, shelley, _) = crate::common::open_genesis_files(&config.genesis)?;
 
@@ -17,6 +19,7 @@ pub fn run(config: &super::Config, _args: &Args) -> miette::Result<()> {
         ledger,
         byron,
         shelley,
+        archive,
         &config.retries,
     )
     .into_diagnostic()
@@ -1,12 +1,15 @@
 use itertools::Itertools;
 use pallas::ledger::configs::{byron, shelley};
+use pallas::ledger::primitives::{alonzo, conway};
 use pallas::ledger::traverse::{Era, MultiEraBlock, MultiEraTx};
 use pallas::{crypto::hash::Hash, ledger::traverse::MultiEraOutput};
 use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 
 pub mod pparams;
+pub mod replay;
 pub mod store;
+pub mod time;
 //pub mod validate;
 
 pub type TxHash = Hash<32>;
@@ -14,6 +17,18 @@ pub type TxoIdx = u32;
 pub type BlockSlot = u64;
 pub type BlockHash = Hash<32>;
 pub type TxOrder = usize;
+pub type PoolId = Hash<28>;
+pub type Lovelace = u64;
+
+/// A stake credential as raw bytes, a tag (0 = key, 1 = script) and the hash
+pub type StakeKey = Vec<u8>;
+
+pub fn stake_key(is_script: bool, hash: &Hash<28>) -> StakeKey {
+    let mut out = Vec::with_capacity(29);
+    out.push(u8::from(is_script));
+    out.extend_from_slice(hash.as_ref());
+    out
+}
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct EraCbor(pub Era, pub Vec<u8>);
@@ -67,8 +82,96 @@ pub struct ChainPoint(pub BlockSlot, pub BlockHash);
 #[derive(Debug)]
 pub struct PParamsBody(pub Era, pub Vec<u8>);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TxDirection {
+    Received,
+    Spent,
+}
+
+impl From<TxDirection> for u8 {
+    fn from(value: TxDirection) -> Self {
+        match value {
+            TxDirection::Received => 0,
+            TxDirection::Spent => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for TxDirection {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TxDirection::Received),
+            1 => Ok(TxDirection::Spent),
+            x => Err(x),
+        }
+    }
+}
+
+/// A tx that touched a particular address, as seen by the history index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressTx {
+    pub slot: BlockSlot,
+    pub tx: TxHash,
+    pub direction: TxDirection,
+}
+
+impl AddressTx {
+    /// Encodes the entry as an opaque token to resume paginated queries
+    pub fn to_token(&self) -> String {
+        let mut raw = Vec::with_capacity(41);
+        raw.extend_from_slice(&self.slot.to_be_bytes());
+        raw.extend_from_slice(self.tx.as_ref());
+        raw.push(self.direction.into());
+
+        hex::encode(raw)
+    }
+
+    pub fn from_token(token: &str) -> Option<Self> {
+        let raw = hex::decode(token).ok()?;
+
+        if raw.len() != 41 {
+            return None;
+        }
+
+        let slot = u64::from_be_bytes(raw[0..8].try_into().ok()?);
+        let tx = TxHash::new(raw[8..40].try_into().ok()?);
+        let direction = TxDirection::try_from(raw[40]).ok()?;
+
+        Some(Self {
+            slot,
+            tx,
+            direction,
+        })
+    }
+}
+
 pub type UtxoMap = HashMap<TxoRef, EraCbor>;
 
+/// A UTxO that differs between two ledgers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UtxoDiff {
+    /// Present in the expected ledger only
+    Missing(TxoRef),
+
+    /// Present in the checked ledger only
+    Unexpected(TxoRef),
+
+    /// Present in both ledgers, with a different body
+    Mismatch(TxoRef),
+}
+
+/// Outcome of comparing the UTxO sets of two ledgers
+#[derive(Debug, Default)]
+pub struct UtxoDiffReport {
+    /// Total number of differing UTxOs
+    pub count: usize,
+
+    /// The first differences found, in key order
+    pub sample: Vec<UtxoDiff>,
+}
+
 #[derive(Debug, Error)]
 pub enum BrokenInvariant {
     #[error("missing utxo {0:?}")]
@@ -84,6 +187,35 @@ pub enum LedgerError {
     StorageError(#[source] redb::Error),
 }
 
+/// Secondary indexes of the ledger that can be rebuilt from the UTxO set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Address to the unspent outputs locked by it, the address history isn't
+    /// part of it
+    UtxoByAddress,
+}
+
+#[derive(Debug, Error)]
+pub enum ValidationError {
+    #[error("block slot {block} is not after the ledger tip slot {tip}")]
+    SlotNotAfterTip { block: BlockSlot, tip: BlockSlot },
+
+    #[error("block doesn't extend the ledger tip {tip}, previous hash is {previous:?}")]
+    NotExtendingTip {
+        tip: BlockHash,
+        previous: Option<BlockHash>,
+    },
+
+    #[error("input {0:?} is consumed more than once")]
+    DoubleSpend(TxoRef),
+
+    #[error("input {0:?} is not available in the ledger")]
+    MissingInput(TxoRef),
+
+    #[error("ledger error")]
+    Ledger(#[source] LedgerError),
+}
+
 /// A persistent store for ledger state
 pub trait LedgerStore {
     fn get_utxos(&self, refs: Vec<TxoRef>) -> Result<UtxoMap, LedgerError>;
@@ -150,6 +282,16 @@ where
     Ok(LedgerSlice { resolved_inputs })
 }
 
+/// A change of the pool that a stake credential delegates to
+///
+/// `None` means that the credential was deregistered, so its stake doesn't
+/// count for any pool anymore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DelegationChange {
+    pub credential: StakeKey,
+    pub pool: Option<PoolId>,
+}
+
 #[derive(Default, Debug)]
 pub struct LedgerDelta {
     pub new_position: Option<ChainPoint>,
@@ -159,6 +301,15 @@ pub struct LedgerDelta {
     pub recovered_stxi: HashMap<TxoRef, EraCbor>,
     pub undone_utxo: HashMap<TxoRef, EraCbor>,
     pub new_pparams: Vec<PParamsBody>,
+    pub undone_pparams: Vec<PParamsBody>,
+    /// Delegation certs of the block, in the order they appear
+    pub new_delegations: Vec<DelegationChange>,
+    pub undone_delegations: Vec<DelegationChange>,
+    /// The tx that spends each of the consumed (or recovered) utxos
+    pub consumed_by: HashMap<TxoRef, TxHash>,
+    /// Epoch of the new position, if known, used to snapshot the stake
+    /// distribution when an epoch ends
+    pub new_epoch: Option<u64>,
 }
 
 /// Computes the ledger delta of applying a particular block.
@@ -201,24 +352,81 @@ pub fn compute_delta(
                 .remove(&stxi_ref)
                 .ok_or_else(|| BrokenInvariant::MissingUtxo(stxi_ref.clone()))?;
 
+            delta.consumed_by.insert(stxi_ref.clone(), *tx_hash);
             delta.consumed_utxo.insert(stxi_ref, stxi_body);
         }
+    }
+
+    delta.new_pparams = block_pparams_updates(block);
+    delta.new_delegations = block_delegation_changes(block);
 
+    Ok(delta)
+}
+
+/// Protocol param updates included in a block, either in its txs or in the
+/// block itself
+pub fn block_pparams_updates(block: &MultiEraBlock) -> Vec<PParamsBody> {
+    let mut updates = vec![];
+
+    for tx in block.txs() {
         if let Some(update) = tx.update() {
-            delta
-                .new_pparams
-                .push(PParamsBody(tx.era(), update.encode()));
+            updates.push(PParamsBody(tx.era(), update.encode()));
         }
     }
 
     // check block-level updates (because of f#!@#@ byron)
     if let Some(update) = block.update() {
-        delta
-            .new_pparams
-            .push(PParamsBody(block.era(), update.encode()));
+        updates.push(PParamsBody(block.era(), update.encode()));
     }
 
-    Ok(delta)
+    updates
+}
+
+fn credential_key(credential: &alonzo::StakeCredential) -> StakeKey {
+    match credential {
+        alonzo::StakeCredential::AddrKeyhash(x) => stake_key(false, x),
+        alonzo::StakeCredential::Scripthash(x) => stake_key(true, x),
+    }
+}
+
+/// Delegation changes made by the certs of the valid txs of a block
+pub fn block_delegation_changes(block: &MultiEraBlock) -> Vec<DelegationChange> {
+    let mut changes = vec![];
+
+    for tx in block.txs().iter().filter(|x| x.is_valid()) {
+        for cert in tx.certs() {
+            let change = if let Some(cert) = cert.as_alonzo() {
+                match cert {
+                    alonzo::Certificate::StakeDelegation(cred, pool) => Some((cred, Some(*pool))),
+                    alonzo::Certificate::StakeDeregistration(cred) => Some((cred, None)),
+                    _ => None,
+                }
+            } else if let Some(cert) = cert.as_conway() {
+                match cert {
+                    conway::Certificate::StakeDelegation(cred, pool)
+                    | conway::Certificate::StakeVoteDeleg(cred, pool, ..)
+                    | conway::Certificate::StakeRegDeleg(cred, pool, ..)
+                    | conway::Certificate::StakeVoteRegDeleg(cred, pool, ..) => {
+                        Some((cred, Some(*pool)))
+                    }
+                    conway::Certificate::StakeDeregistration(cred)
+                    | conway::Certificate::UnReg(cred, ..) => Some((cred, None)),
+                    _ => None,
+                }
+            } else {
+                None
+            };
+
+            if let Some((cred, pool)) = change {
+                changes.push(DelegationChange {
+                    credential: credential_key(cred),
+                    pool,
+                });
+            }
+        }
+    }
+
+    changes
 }
 
 pub fn compute_undo_delta(
@@ -239,7 +447,7 @@ pub fn compute_undo_delta(
         }
     }
 
-    for (_, tx) in txs.iter() {
+    for (tx_hash, tx) in txs.iter() {
         for consumed in tx.consumes() {
             let stxi_ref = TxoRef(*consumed.hash(), consumed.index() as u32);
 
@@ -248,10 +456,14 @@ pub fn compute_undo_delta(
                 .remove(&stxi_ref)
                 .ok_or_else(|| BrokenInvariant::MissingUtxo(stxi_ref.clone()))?;
 
+            delta.consumed_by.insert(stxi_ref.clone(), *tx_hash);
             delta.recovered_stxi.insert(stxi_ref, stxi_body);
         }
     }
 
+    delta.undone_pparams = block_pparams_updates(block);
+    delta.undone_delegations = block_delegation_changes(block);
+
     Ok(delta)
 }
 
@@ -304,7 +516,8 @@ pub fn import_block_batch<S: LedgerStore>(
 
     for block in blocks {
         let context = load_slice_for_block(block, store, &deltas)?;
-        let delta = compute_delta(block, context).map_err(LedgerError::BrokenInvariant)?;
+        let mut delta = compute_delta(block, context).map_err(LedgerError::BrokenInvariant)?;
+        delta.new_epoch = pparams::epoch_at_slot(shelley, block.slot());
 
         deltas.push(delta);
     }
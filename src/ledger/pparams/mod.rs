@@ -11,12 +11,33 @@ use pallas::{
 };
 use tracing::{trace, warn};
 
+use super::time::{NetworkConfig, SlotConverter};
+
 pub struct Genesis<'a> {
     pub byron: &'a byron::GenesisFile,
     pub shelley: &'a shelley::GenesisFile,
     pub alonzo: &'a alonzo::GenesisFile,
 }
 
+/// Epoch of a slot in the network of the Shelley genesis
+///
+/// Public networks (by their magic) use their built-in eras, so Byron epochs
+/// are counted with their own length. Other networks are assumed to start
+/// right in Shelley and use the epoch length of the genesis. Returns `None`
+/// when there's no built-in config and the genesis doesn't define the epoch
+/// length.
+pub fn epoch_at_slot(shelley: &shelley::GenesisFile, slot: u64) -> Option<u64> {
+    let network = shelley
+        .network_magic
+        .and_then(|x| NetworkConfig::from_magic(x as u64));
+
+    if let Some(network) = network {
+        return SlotConverter::new(&network).slot_to_epoch(slot);
+    }
+
+    slot.checked_div(shelley.epoch_length? as u64)
+}
+
 fn bootstrap_byron_pparams(byron: &byron::GenesisFile) -> ByronProtParams {
     ByronProtParams {
         block_version: (0, 0, 0),
@@ -366,4 +387,24 @@ mod tests {
     fn test_mainnet_fold() {
         test_env_fold("mainnet")
     }
+
+    #[test]
+    fn test_epoch_at_slot() {
+        let shelley: shelley::GenesisFile =
+            load_json("src/ledger/pparams/test_data/mainnet/genesis/shelley_genesis.json");
+
+        // byron epochs are shorter, shelley starts at epoch 208
+        assert_eq!(epoch_at_slot(&shelley, 21600), Some(1));
+        assert_eq!(epoch_at_slot(&shelley, 4492799), Some(207));
+        assert_eq!(epoch_at_slot(&shelley, 4492800), Some(208));
+        assert_eq!(epoch_at_slot(&shelley, 39916800), Some(290));
+
+        // without a built-in config, the network starts in shelley
+        let custom = shelley::GenesisFile {
+            network_magic: Some(42),
+            ..shelley
+        };
+
+        assert_eq!(epoch_at_slot(&custom, 432000), Some(1));
+    }
 }
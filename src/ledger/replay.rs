@@ -0,0 +1,649 @@
+//! Replay of WAL entries into the ledger
+//!
+//! The ledger is derived data: replaying the WAL from origin rebuilds it from
+//! scratch. When a snapshot of the ledger is available (a copy of the ledger
+//! db file taken while the node was stopped) only the WAL entries after the
+//! snapshot cursor need to be replayed, which is much faster than a full
+//! replay as long as the WAL still holds the snapshot point. A ledger that
+//! fell behind the start of the WAL can be bridged with archived blocks, as
+//! long as the archive holds the ones that were trimmed. `reconcile` tells
+//! which of these (if any) brings a ledger back in line with the WAL.
+
+use pallas::ledger::configs::{byron, shelley};
+use std::path::Path;
+use thiserror::Error;
+
+use super::store::LedgerStore;
+use super::{BrokenInvariant, LedgerError};
+use crate::querydb::store::{self as archive, Store as Archive};
+use crate::wal::{self, DecodeError, LogSeq, LogValue, ReadUtils as _, WalError, WalReader};
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("snapshot has no cursor, it doesn't hold any ledger state")]
+    EmptySnapshot,
+
+    #[error("ledger cursor {0:?} doesn't match any point in the wal")]
+    Misaligned(wal::ChainPoint),
+
+    #[error("archive doesn't hold the chain from the ledger cursor {0:?} to the start of the wal {1:?}, the ledger needs to be rebuilt")]
+    Unbridgeable(wal::ChainPoint, wal::ChainPoint),
+
+    #[error("archive error")]
+    ArchiveError(#[source] archive::Error),
+
+    #[error("wal error")]
+    WalError(#[source] WalError),
+
+    #[error("ledger error")]
+    LedgerError(#[source] LedgerError),
+
+    #[error("invalid block in wal")]
+    DecodeError(#[source] DecodeError),
+
+    #[error("IO error")]
+    IO(#[source] std::io::Error),
+}
+
+impl From<redb::Error> for ReplayError {
+    fn from(value: redb::Error) -> Self {
+        ReplayError::LedgerError(LedgerError::StorageError(value))
+    }
+}
+
+impl From<BrokenInvariant> for ReplayError {
+    fn from(value: BrokenInvariant) -> Self {
+        ReplayError::LedgerError(LedgerError::BrokenInvariant(value))
+    }
+}
+
+/// Applies a single WAL entry to the ledger
+pub fn apply_entry(
+    store: &mut LedgerStore,
+    log: &LogValue,
+    byron: &byron::GenesisFile,
+    shelley: &shelley::GenesisFile,
+) -> Result<(), ReplayError> {
+    match log {
+        LogValue::Mark(wal::ChainPoint::Origin) => {
+            let delta = super::compute_origin_delta(byron);
+            store.apply(&[delta])?;
+        }
+        LogValue::Apply(block) => {
+            let block = wal::decode_block(&block.body).map_err(ReplayError::DecodeError)?;
+
+            super::import_block_batch(&[block], store, byron, shelley)
+                .map_err(ReplayError::LedgerError)?;
+        }
+        LogValue::Undo(block) => {
+            let block = wal::decode_block(&block.body).map_err(ReplayError::DecodeError)?;
+
+            let context = super::load_slice_for_block(&block, &*store, &[])
+                .map_err(ReplayError::LedgerError)?;
+
+            let delta = super::compute_undo_delta(&block, context)?;
+            store.apply(&[delta])?;
+        }
+        LogValue::Mark(..) => (),
+    }
+
+    Ok(())
+}
+
+/// Finds the WAL sequence that matches the ledger cursor
+///
+/// `None` means that the ledger is empty and the replay has to start from the
+/// beginning of the WAL.
+fn locate_cursor<W>(wal: &W, store: &LedgerStore) -> Result<Option<LogSeq>, ReplayError>
+where
+    W: WalReader,
+{
+    let Some(super::ChainPoint(slot, hash)) = store.cursor()? else {
+        return Ok(None);
+    };
+
+    let seq = wal
+        .assert_cursor(&wal::ChainPoint::Specific(slot, hash))
+        .map_err(|err| match err {
+            WalError::PointNotFound(x) => ReplayError::Misaligned(x),
+            x => ReplayError::WalError(x),
+        })?;
+
+    Ok(Some(seq))
+}
+
+/// Tells if the entry at the ledger cursor is already part of the ledger
+///
+/// The cursor resolves to the latest entry of its slot. When the block was
+/// rolled back in the WAL but not in the ledger yet, that's its undo, which
+/// the ledger still has to apply.
+fn applied_at_cursor(log: &LogValue) -> bool {
+    !matches!(log, LogValue::Undo(_))
+}
+
+/// Replays the WAL entries after the ledger cursor, up to `until` (inclusive)
+///
+/// Without `until` the replay goes all the way to the tip of the WAL. Returns
+/// the sequence of the last entry that the ledger has applied.
+pub fn replay<W>(
+    wal: &W,
+    store: &mut LedgerStore,
+    byron: &byron::GenesisFile,
+    shelley: &shelley::GenesisFile,
+    until: Option<LogSeq>,
+) -> Result<Option<LogSeq>, ReplayError>
+where
+    W: WalReader,
+{
+    let cursor = locate_cursor(wal, store)?;
+    let mut last = cursor;
+
+    let entries = wal
+        .crawl_from(cursor)
+        .map_err(ReplayError::WalError)?
+        .skip_while(|(seq, log)| Some(*seq) == cursor && applied_at_cursor(log))
+        .take_while(|(seq, _)| !until.is_some_and(|x| *seq > x));
+
+    for (seq, log) in entries {
+        apply_entry(store, &log, byron, shelley)?;
+        last = Some(seq);
+    }
+
+    Ok(last)
+}
+
+/// Applies archived blocks to a ledger that fell behind the start of the WAL
+///
+/// When the WAL was trimmed past the ledger cursor (eg: compaction outran the
+/// ledger) the entries that the ledger still needs are gone, but the archive
+/// might hold their blocks. Those are applied up to the oldest point of the
+/// WAL, followed by the block at that point, from where the ledger can carry
+/// on as usual. The archived blocks have to chain from the cursor to the
+/// oldest block of the WAL, otherwise nothing is applied. Returns the WAL
+/// sequence of the new cursor.
+pub fn bridge_gap<W>(
+    archive: &Archive,
+    wal: &W,
+    store: &mut LedgerStore,
+    byron: &byron::GenesisFile,
+    shelley: &shelley::GenesisFile,
+) -> Result<LogSeq, ReplayError>
+where
+    W: WalReader,
+{
+    let cursor = match store.cursor()? {
+        Some(super::ChainPoint(slot, hash)) => wal::ChainPoint::Specific(slot, hash),
+        None => wal::ChainPoint::Origin,
+    };
+
+    let oldest = wal
+        .crawl_from(None)
+        .map_err(ReplayError::WalError)?
+        .filter_forward()
+        .next();
+
+    let (wal::ChainPoint::Specific(from, mut linked), Some((seq, oldest))) =
+        (cursor.clone(), oldest)
+    else {
+        return Err(ReplayError::Misaligned(cursor));
+    };
+
+    let target = wal::ChainPoint::from(&oldest);
+
+    let (LogValue::Apply(first), wal::ChainPoint::Specific(until, _)) = (&oldest, &target) else {
+        return Err(ReplayError::Misaligned(cursor));
+    };
+
+    let until = *until;
+
+    if until <= from {
+        return Err(ReplayError::Misaligned(cursor));
+    }
+
+    // compaction only archives the trimmed blocks, the gap ends right before
+    // the oldest block of the wal
+    let first = wal::decode_block(&first.body).map_err(ReplayError::DecodeError)?;
+    let until_hash = first.header().previous_hash();
+
+    let in_gap = |entry: &Result<(u64, Vec<u8>), archive::Error>| {
+        !entry.as_ref().is_ok_and(|(slot, _)| *slot >= until)
+    };
+
+    // checked in full before touching the ledger, so a broken chain leaves it
+    // as it was
+    for entry in archive.crawl_chain(Some(from)).take_while(in_gap) {
+        let (_, body) = entry.map_err(ReplayError::ArchiveError)?;
+        let block = wal::decode_block(&body).map_err(ReplayError::DecodeError)?;
+
+        if block.header().previous_hash() != Some(linked) {
+            return Err(ReplayError::Unbridgeable(cursor, target));
+        }
+
+        linked = block.hash();
+    }
+
+    if Some(linked) != until_hash {
+        return Err(ReplayError::Unbridgeable(cursor, target));
+    }
+
+    for entry in archive.crawl_chain(Some(from)).take_while(in_gap) {
+        let (_, body) = entry.map_err(ReplayError::ArchiveError)?;
+        let block = wal::decode_block(&body).map_err(ReplayError::DecodeError)?;
+
+        super::import_block_batch(&[block], store, byron, shelley)
+            .map_err(ReplayError::LedgerError)?;
+    }
+
+    apply_entry(store, &oldest, byron, shelley)?;
+
+    Ok(seq)
+}
+
+/// How the ledger cursor relates to the WAL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Drift {
+    /// The ledger has applied every entry of the WAL
+    Aligned,
+
+    /// The ledger cursor is in the WAL (`None` for an empty ledger, which
+    /// starts from origin) with `pending` entries after it left to apply
+    LedgerBehind {
+        cursor: Option<LogSeq>,
+        pending: usize,
+    },
+
+    /// The ledger cursor is past the tip of the WAL (eg: the WAL lost its last
+    /// entries in a crash)
+    LedgerAhead,
+
+    /// The ledger cursor is before the start of the WAL (eg: compaction
+    /// outran the ledger)
+    Gap,
+
+    /// The ledger cursor is within the range of the WAL, but on a fork that
+    /// the WAL doesn't have
+    Diverged,
+}
+
+/// What brings the ledger back in line with the WAL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fix {
+    /// Nothing, they're already in line
+    Nothing,
+
+    /// Replaying the WAL from the ledger cursor, see `replay`
+    RollForward,
+
+    /// Applying the archived blocks up to the start of the WAL, then
+    /// replaying it, see `bridge_gap`
+    BridgeGap,
+
+    /// The WAL doesn't hold what the ledger needs, it has to be rebuilt from
+    /// scratch or restored from a snapshot
+    Rebuild,
+}
+
+impl Drift {
+    /// What to do about the drift, `archive` tells if there's an archive of
+    /// the trimmed blocks to bridge a gap with
+    pub fn fix(&self, archive: bool) -> Fix {
+        match self {
+            Drift::Aligned => Fix::Nothing,
+            Drift::LedgerBehind { .. } => Fix::RollForward,
+            Drift::Gap if archive => Fix::BridgeGap,
+            Drift::LedgerAhead | Drift::Gap | Drift::Diverged => Fix::Rebuild,
+        }
+    }
+}
+
+// entries that change the ledger when replayed, see `apply_entry`
+fn changes_ledger(log: &LogValue) -> bool {
+    !matches!(log, LogValue::Mark(x) if *x != wal::ChainPoint::Origin)
+}
+
+/// Inspects the ledger cursor against the WAL, without changing either
+pub fn reconcile<W>(wal: &W, store: &LedgerStore) -> Result<Drift, ReplayError>
+where
+    W: WalReader,
+{
+    let Some(super::ChainPoint(slot, hash)) = store.cursor()? else {
+        let mut entries = wal
+            .crawl_from(None)
+            .map_err(ReplayError::WalError)?
+            .peekable();
+
+        // an empty ledger can only be replayed from the very start of the chain
+        return match entries.peek() {
+            None => Ok(Drift::Aligned),
+            Some((_, LogValue::Mark(wal::ChainPoint::Origin))) => Ok(Drift::LedgerBehind {
+                cursor: None,
+                pending: entries.filter(|(_, x)| changes_ledger(x)).count(),
+            }),
+            Some(_) => Ok(Drift::Gap),
+        };
+    };
+
+    let seq = match wal.assert_cursor(&wal::ChainPoint::Specific(slot, hash)) {
+        Ok(x) => x,
+        Err(WalError::CursorAheadOfTip(..)) => return Ok(Drift::LedgerAhead),
+        Err(WalError::CursorBehindWal(..)) => return Ok(Drift::Gap),
+        Err(WalError::PointNotFound(..)) => return Ok(Drift::Diverged),
+        Err(x) => return Err(ReplayError::WalError(x)),
+    };
+
+    let pending = wal
+        .crawl_from(Some(seq))
+        .map_err(ReplayError::WalError)?
+        .skip_while(|(x, log)| *x == seq && applied_at_cursor(log))
+        .filter(|(_, x)| changes_ledger(x))
+        .count();
+
+    match pending {
+        0 => Ok(Drift::Aligned),
+        pending => Ok(Drift::LedgerBehind {
+            cursor: Some(seq),
+            pending,
+        }),
+    }
+}
+
+/// Restores a ledger snapshot to the given path
+///
+/// The snapshot is only useful if the WAL still holds the point of its cursor,
+/// otherwise there's no way to know which entries to replay on top of it.
+/// Nothing is left at `target` when the snapshot doesn't line up with the WAL.
+/// Returns the restored ledger and the WAL sequence of its cursor.
+pub fn restore_snapshot<W>(
+    snapshot: &Path,
+    target: &Path,
+    wal: &W,
+) -> Result<(LedgerStore, LogSeq), ReplayError>
+where
+    W: WalReader,
+{
+    std::fs::copy(snapshot, target).map_err(ReplayError::IO)?;
+
+    let checked = LedgerStore::open(target)
+        .map_err(ReplayError::from)
+        .and_then(|store| match locate_cursor(wal, &store)? {
+            Some(seq) => Ok((store, seq)),
+            None => Err(ReplayError::EmptySnapshot),
+        });
+
+    if checked.is_err() {
+        std::fs::remove_file(target).map_err(ReplayError::IO)?;
+    }
+
+    checked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::{testing, WalWriter as _};
+
+    fn load_genesis() -> (byron::GenesisFile, shelley::GenesisFile) {
+        let path = std::path::PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join("examples")
+            .join("sync-preview");
+
+        let byron = byron::from_file(&path.join("byron.json")).unwrap();
+        let shelley = shelley::from_file(&path.join("shelley.json")).unwrap();
+
+        (byron, shelley)
+    }
+
+    #[test]
+    fn test_snapshot_and_tail_match_full_replay() {
+        let (byron, shelley) = load_genesis();
+        let dir = tempfile::tempdir().unwrap();
+
+        let main = testing::TestChainBuilder::new().extend(0..20);
+        let fork = main.fork_at(15).extend([17, 19, 21, 23]);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(main.blocks().iter().cloned()).unwrap();
+
+        // snapshot taken halfway through the chain, slot 10 sits at seq 11
+        let snapshot = dir.path().join("snapshot");
+        let mut store = LedgerStore::open(&snapshot).unwrap();
+        let seq = replay(&wal, &mut store, &byron, &shelley, Some(11)).unwrap();
+        assert_eq!(seq, Some(11));
+        drop(store);
+
+        // the tail includes a rollback, so it has undo entries
+        wal.roll_back(&main.point(15)).unwrap();
+        wal.roll_forward(fork.blocks_after(15).into_iter()).unwrap();
+
+        let mut full = LedgerStore::open(dir.path().join("full")).unwrap();
+        let full_seq = replay(&wal, &mut full, &byron, &shelley, None).unwrap();
+
+        let (mut restored, seq) =
+            restore_snapshot(&snapshot, &dir.path().join("restored"), &wal).unwrap();
+        assert_eq!(seq, 11);
+
+        let restored_seq = replay(&wal, &mut restored, &byron, &shelley, None).unwrap();
+        assert_eq!(restored_seq, full_seq);
+
+        let report = full.diff_utxos(&restored, 10).unwrap();
+        assert_eq!(report.count, 0);
+
+        let wal::ChainPoint::Specific(slot, hash) = fork.tip() else {
+            unreachable!()
+        };
+
+        let tip = crate::ledger::ChainPoint(slot, hash);
+        assert_eq!(full.cursor().unwrap().as_ref(), Some(&tip));
+        assert_eq!(restored.cursor().unwrap().as_ref(), Some(&tip));
+    }
+
+    #[test]
+    fn test_archive_bridges_trimmed_wal() {
+        let (byron, shelley) = load_genesis();
+        let dir = tempfile::tempdir().unwrap();
+
+        let chain = testing::TestChainBuilder::new().extend(0..30);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        let mut full = LedgerStore::open(dir.path().join("full")).unwrap();
+        replay(&wal, &mut full, &byron, &shelley, None).unwrap();
+
+        // the ledger stopped at slot 10, while compaction trimmed up to slot 19
+        let mut behind = LedgerStore::open(dir.path().join("behind")).unwrap();
+        replay(&wal, &mut behind, &byron, &shelley, Some(11)).unwrap();
+
+        // compaction hands the trimmed blocks over to the archive
+        let policy = wal::redb::CompactionPolicy {
+            high_water: 0,
+            low_water: 0,
+            undo_body_retention: None,
+        };
+
+        let archive = Archive::open(dir.path().join("archive")).unwrap();
+        let trimmed = wal.compact_into(&policy, 19, u64::MAX, Some(&archive));
+        assert_eq!(trimmed.unwrap(), Some(20));
+
+        let cursor = chain.point(10);
+        assert!(matches!(
+            wal.assert_cursor(&cursor),
+            Err(WalError::CursorBehindWal(x, y)) if x == cursor && y == chain.point(20)
+        ));
+
+        // an archive missing the trimmed blocks can't help
+        let partial = Archive::open(dir.path().join("partial")).unwrap();
+
+        for block in chain.blocks().iter().filter(|x| x.slot != 15) {
+            partial.apply_block(&block.body).unwrap();
+        }
+
+        let result = bridge_gap(&partial, &wal, &mut behind, &byron, &shelley);
+        assert!(matches!(result, Err(ReplayError::Unbridgeable(..))));
+        assert_eq!(
+            behind.cursor().unwrap(),
+            Some(crate::ledger::ChainPoint(10, chain.blocks()[10].hash))
+        );
+
+        let seq = bridge_gap(&archive, &wal, &mut behind, &byron, &shelley).unwrap();
+        assert_eq!(seq, 21);
+
+        replay(&wal, &mut behind, &byron, &shelley, None).unwrap();
+
+        let report = full.diff_utxos(&behind, 10).unwrap();
+        assert_eq!(report.count, 0);
+        assert_eq!(behind.cursor().unwrap(), full.cursor().unwrap());
+    }
+
+    #[test]
+    fn test_reconcile_recommends_fix_for_each_drift() {
+        let (byron, shelley) = load_genesis();
+        let dir = tempfile::tempdir().unwrap();
+
+        let chain = testing::TestChainBuilder::new().extend(0..20);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        // an empty ledger replays the whole wal, origin mark included
+        let mut store = LedgerStore::open(dir.path().join("empty")).unwrap();
+        let drift = reconcile(&wal, &store).unwrap();
+        assert_eq!(
+            drift,
+            Drift::LedgerBehind {
+                cursor: None,
+                pending: 21
+            }
+        );
+        assert_eq!(drift.fix(false), Fix::RollForward);
+
+        replay(&wal, &mut store, &byron, &shelley, None).unwrap();
+        let drift = reconcile(&wal, &store).unwrap();
+        assert_eq!(drift, Drift::Aligned);
+        assert_eq!(drift.fix(false), Fix::Nothing);
+
+        // slot 10 sits at seq 11, with slots 11 to 19 after it
+        let mut behind = LedgerStore::open(dir.path().join("behind")).unwrap();
+        replay(&wal, &mut behind, &byron, &shelley, Some(11)).unwrap();
+        let drift = reconcile(&wal, &behind).unwrap();
+        assert_eq!(
+            drift,
+            Drift::LedgerBehind {
+                cursor: Some(11),
+                pending: 9
+            }
+        );
+        assert_eq!(drift.fix(false), Fix::RollForward);
+
+        // the wal lost the blocks after slot 9, but the ledger applied them
+        let mut short = testing::empty_db();
+        short
+            .roll_forward(chain.blocks()[..10].iter().cloned())
+            .unwrap();
+        let drift = reconcile(&short, &store).unwrap();
+        assert_eq!(drift, Drift::LedgerAhead);
+        assert_eq!(drift.fix(false), Fix::Rebuild);
+
+        // a ledger on a fork that the wal doesn't have
+        let fork = chain.fork_at(5).extend(6..12);
+        let mut other = testing::empty_db();
+        other.roll_forward(fork.blocks().iter().cloned()).unwrap();
+
+        let mut forked = LedgerStore::open(dir.path().join("forked")).unwrap();
+        replay(&other, &mut forked, &byron, &shelley, None).unwrap();
+        let drift = reconcile(&wal, &forked).unwrap();
+        assert_eq!(drift, Drift::Diverged);
+        assert_eq!(drift.fix(false), Fix::Rebuild);
+
+        // compaction trimmed past the cursor of the lagging ledger
+        wal.remove_range(None, Some(15)).unwrap();
+        let drift = reconcile(&wal, &behind).unwrap();
+        assert_eq!(drift, Drift::Gap);
+        assert_eq!(drift.fix(false), Fix::Rebuild);
+
+        // unless there's an archive to bridge it with
+        assert_eq!(drift.fix(true), Fix::BridgeGap);
+
+        // same for an empty ledger, origin is gone from the wal
+        let empty = LedgerStore::open(dir.path().join("empty-again")).unwrap();
+        assert_eq!(reconcile(&wal, &empty).unwrap(), Drift::Gap);
+
+        // while the synced one is still in line
+        assert_eq!(reconcile(&wal, &store).unwrap(), Drift::Aligned);
+    }
+
+    #[test]
+    fn test_undo_at_cursor_is_applied_first() {
+        let (byron, shelley) = load_genesis();
+        let dir = tempfile::tempdir().unwrap();
+
+        let chain = testing::TestChainBuilder::new().extend(0..20);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        let mut store = LedgerStore::open(dir.path().join("store")).unwrap();
+        replay(&wal, &mut store, &byron, &shelley, None).unwrap();
+
+        // slot 15 sits at seq 16
+        let mut expected = LedgerStore::open(dir.path().join("expected")).unwrap();
+        replay(&wal, &mut expected, &byron, &shelley, Some(16)).unwrap();
+
+        // the wal rolls back before the ledger catches up with it, so the
+        // cursor (slot 19, seq 20) resolves to its undo at seq 21
+        wal.roll_back(&chain.point(15)).unwrap();
+        assert_eq!(wal.assert_cursor(&chain.point(19)).unwrap(), 21);
+
+        // the undos of slots 19 to 16, the mark doesn't change the ledger
+        let drift = reconcile(&wal, &store).unwrap();
+        assert_eq!(
+            drift,
+            Drift::LedgerBehind {
+                cursor: Some(21),
+                pending: 4
+            }
+        );
+        assert_eq!(drift.fix(false), Fix::RollForward);
+
+        let seq = replay(&wal, &mut store, &byron, &shelley, None).unwrap();
+        assert_eq!(seq, Some(25));
+
+        let report = expected.diff_utxos(&store, 10).unwrap();
+        assert_eq!(report.count, 0);
+        assert_eq!(store.cursor().unwrap(), expected.cursor().unwrap());
+        assert_eq!(reconcile(&wal, &store).unwrap(), Drift::Aligned);
+    }
+
+    #[test]
+    fn test_snapshot_from_other_chain_is_rejected() {
+        let (byron, shelley) = load_genesis();
+        let dir = tempfile::tempdir().unwrap();
+
+        let main = testing::TestChainBuilder::new().extend(0..20);
+        let fork = main.fork_at(5).extend(6..12);
+
+        let mut other = testing::empty_db();
+        other.roll_forward(fork.blocks().iter().cloned()).unwrap();
+
+        let snapshot = dir.path().join("snapshot");
+        let mut store = LedgerStore::open(&snapshot).unwrap();
+        replay(&other, &mut store, &byron, &shelley, None).unwrap();
+        drop(store);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(main.blocks().iter().cloned()).unwrap();
+
+        let target = dir.path().join("restored");
+        let result = restore_snapshot(&snapshot, &target, &wal);
+
+        assert!(matches!(result, Err(ReplayError::Misaligned(_))));
+        assert!(!target.exists());
+
+        // an empty ledger is not a snapshot
+        let empty = dir.path().join("empty");
+        drop(LedgerStore::open(&empty).unwrap());
+
+        let result = restore_snapshot(&empty, &target, &wal);
+        assert!(matches!(result, Err(ReplayError::EmptySnapshot)));
+    }
+}
@@ -1,9 +1,12 @@
+use pallas::applying::utils::MultiEraProtocolParameters;
 use pallas::interop::utxorpc as interop;
+use pallas::ledger::addresses::{Address, ShelleyDelegationPart};
+use pallas::ledger::traverse::MultiEraUpdate;
 use redb::{
     MultimapTableDefinition, ReadableMultimapTable, ReadableTable, TableDefinition, TableError,
-    WriteTransaction,
+    TableHandle, WriteTransaction,
 };
-use std::{collections::HashSet, path::Path, sync::Arc};
+use std::{cmp::Ordering, collections::HashSet, path::Path, sync::Arc};
 use tracing::warn;
 
 use super::*;
@@ -133,6 +136,81 @@ impl LedgerTable for PParamsTable {
     }
 }
 
+/// Epoch in which a pparams update takes effect, `None` if it can't be decoded
+///
+/// Updates are proposed for an epoch and become active at the start of the
+/// following one.
+fn update_effective_epoch(era: Era, cbor: &[u8]) -> Option<u64> {
+    MultiEraUpdate::decode_for_era(era, cbor)
+        .map_err(|err| warn!(%err, "skipping undecodable pparams update"))
+        .ok()
+        .map(|x| x.epoch() + 1)
+}
+
+type PParamsHistoryKey<'a> = (u64, BlockSlot, &'a [u8]);
+
+const PPARAMS_HISTORY: TableDefinition<PParamsHistoryKey, u16> =
+    TableDefinition::new("pparams_history");
+struct PParamsHistoryTable;
+
+impl LedgerTable for PParamsHistoryTable {
+    fn create(wx: &WriteTransaction) -> Result<(), redb::Error> {
+        let mut table = wx.open_table(PPARAMS_HISTORY)?;
+
+        // ledgers created before the history was tracked still have the updates
+        // keyed by slot, which are enough to backfill it
+        if table.first()?.is_none() {
+            let pparams = wx.open_table(PPARAMS)?;
+
+            for item in pparams.iter()? {
+                let (slot, body) = item?;
+                let (era, cbor) = body.value();
+
+                let Ok(era) = Era::try_from(era) else {
+                    continue;
+                };
+
+                if let Some(epoch) = update_effective_epoch(era, cbor) {
+                    table.insert((epoch, slot.value(), cbor), u16::from(era))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(wx: &WriteTransaction, delta: &LedgerDelta) -> Result<(), redb::Error> {
+        let mut table = wx.open_table(PPARAMS_HISTORY)?;
+
+        if let Some(ChainPoint(slot, _)) = delta.new_position {
+            for PParamsBody(era, body) in delta.new_pparams.iter() {
+                if let Some(epoch) = update_effective_epoch(*era, body) {
+                    table.insert((epoch, slot, body.as_slice()), u16::from(*era))?;
+                }
+            }
+        }
+
+        if let Some(ChainPoint(slot, _)) = delta.undone_position {
+            for PParamsBody(era, body) in delta.undone_pparams.iter() {
+                if let Some(epoch) = update_effective_epoch(*era, body) {
+                    table.remove((epoch, slot, body.as_slice()))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compact(
+        _wx: &WriteTransaction,
+        _slot: BlockSlot,
+        _tombstone: &[TxoRef],
+    ) -> Result<(), redb::Error> {
+        // history is kept forever, it's tiny
+        Ok(())
+    }
+}
+
 pub const TOMBSTONES: MultimapTableDefinition<BlockSlot, (&[u8; 32], TxoIdx)> =
     MultimapTableDefinition::new("tombstones");
 struct TombstonesTable;
@@ -175,47 +253,161 @@ impl LedgerTable for TombstonesTable {
 
 pub const BY_ADDRESS_INDEX: MultimapTableDefinition<&[u8], UtxosKey> =
     MultimapTableDefinition::new("byaddress");
+
+type AddressHistoryKey<'a> = (&'a [u8], BlockSlot, &'a [u8; 32], u8);
+
+/// Txs that touched each address, kept even after their utxos are spent
+const ADDRESS_HISTORY: TableDefinition<AddressHistoryKey, ()> =
+    TableDefinition::new("address_history");
+
+fn output_address(body: &EraCbor) -> Option<Vec<u8>> {
+    let body = MultiEraOutput::try_from(body).ok()?;
+    body.address().ok().map(|x| x.to_vec())
+}
+
+/// Fills the address side of the index from the unspent utxos
+///
+/// Utxos that are spent but not finalized yet are left out, same as the index
+/// does when it follows the deltas.
+fn fill_address_utxos(wx: &WriteTransaction) -> Result<(), redb::Error> {
+    let spent = spent_utxos(&wx.open_multimap_table(TOMBSTONES)?)?;
+    let utxos = wx.open_table(UTXOS)?;
+    let mut index = wx.open_multimap_table(BY_ADDRESS_INDEX)?;
+
+    for entry in utxos.iter()? {
+        let (k, v) = entry?;
+        let (hash, idx) = k.value();
+
+        if spent.contains(&(*hash, idx)) {
+            continue;
+        }
+
+        let (era, cbor) = v.value();
+
+        let Ok(era) = Era::try_from(era) else {
+            continue;
+        };
+
+        let body = EraCbor(era, cbor.to_vec());
+
+        if let Some(address) = output_address(&body) {
+            index.insert(address.as_slice(), (hash, idx))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Indexes the ledger by address
+///
+/// Keeps the unspent utxos of each address and the history of the txs that
+/// touched it (an output received or spent). Both follow the same deltas, so
+/// undone blocks remove their history entries along with their utxos.
+///
+/// Ledgers from before the index existed get the utxo side filled when
+/// opened. The history can't be derived from the utxo set, it only covers the
+/// blocks applied from then on, unless the ledger is rebuilt from the WAL
+/// (`dolos doctor rebuild-ledger` on an empty ledger).
 struct ByAddressIndex;
 
 impl LedgerTable for ByAddressIndex {
     fn create(wx: &WriteTransaction) -> Result<(), redb::Error> {
+        let has_utxos = wx
+            .list_multimap_tables()?
+            .any(|x| x.name() == BY_ADDRESS_INDEX.name());
+
+        let has_history = wx
+            .list_tables()?
+            .any(|x| x.name() == ADDRESS_HISTORY.name());
+
         wx.open_multimap_table(BY_ADDRESS_INDEX)?;
+        wx.open_table(ADDRESS_HISTORY)?;
+
+        if !has_utxos {
+            fill_address_utxos(wx)?;
+        }
+
+        let has_blocks = wx.open_table(BLOCKS)?.last()?.is_some();
+
+        if !has_history && has_blocks {
+            warn!("address history starts at the ledger tip, rebuild the ledger to fill it");
+        }
+
         Ok(())
     }
 
     fn apply(wx: &WriteTransaction, delta: &LedgerDelta) -> Result<(), redb::Error> {
-        let mut table = wx.open_multimap_table(BY_ADDRESS_INDEX)?;
+        let mut utxos = wx.open_multimap_table(BY_ADDRESS_INDEX)?;
+        let mut history = wx.open_table(ADDRESS_HISTORY)?;
 
-        for (utxo, body) in delta.produced_utxo.iter() {
-            // TODO: decoding here is very inefficient
-            let body = MultiEraOutput::try_from(body).unwrap();
+        let applied = delta.new_position.as_ref().map(|ChainPoint(x, _)| *x);
+        let undone = delta.undone_position.as_ref().map(|ChainPoint(x, _)| *x);
 
-            if let Ok(address) = body.address() {
-                let k = address.to_vec();
-                let v: (&[u8; 32], u32) = (&utxo.0, utxo.1);
-                table.insert(k.as_slice(), v)?;
+        for (utxo, body) in delta.produced_utxo.iter() {
+            let Some(address) = output_address(body) else {
+                continue;
+            };
+
+            let v: (&[u8; 32], u32) = (&utxo.0, utxo.1);
+            utxos.insert(address.as_slice(), v)?;
+
+            if let Some(slot) = applied {
+                let k: AddressHistoryKey = (
+                    address.as_slice(),
+                    slot,
+                    &utxo.0,
+                    TxDirection::Received.into(),
+                );
+                history.insert(k, ())?;
             }
         }
 
         for (stxi, body) in delta.consumed_utxo.iter() {
-            // TODO: decoding here is very inefficient
-            let body = MultiEraOutput::try_from(body).unwrap();
+            let Some(address) = output_address(body) else {
+                continue;
+            };
 
-            if let Ok(address) = body.address() {
-                let k = address.to_vec();
-                let v: (&[u8; 32], u32) = (&stxi.0, stxi.1);
-                table.remove(k.as_slice(), v)?;
+            let v: (&[u8; 32], u32) = (&stxi.0, stxi.1);
+            utxos.remove(address.as_slice(), v)?;
+
+            if let (Some(slot), Some(tx)) = (applied, delta.consumed_by.get(stxi)) {
+                let k: AddressHistoryKey =
+                    (address.as_slice(), slot, tx, TxDirection::Spent.into());
+                history.insert(k, ())?;
             }
         }
 
         for (stxi, body) in delta.undone_utxo.iter() {
-            // TODO: decoding here is very inefficient
-            let body = MultiEraOutput::try_from(body).unwrap();
+            let Some(address) = output_address(body) else {
+                continue;
+            };
+
+            let v: (&[u8; 32], u32) = (&stxi.0, stxi.1);
+            utxos.remove(address.as_slice(), v)?;
+
+            if let Some(slot) = undone {
+                let k: AddressHistoryKey = (
+                    address.as_slice(),
+                    slot,
+                    &stxi.0,
+                    TxDirection::Received.into(),
+                );
+                history.remove(k)?;
+            }
+        }
+
+        for (stxi, body) in delta.recovered_stxi.iter() {
+            let Some(address) = output_address(body) else {
+                continue;
+            };
 
-            if let Ok(address) = body.address() {
-                let k = address.to_vec();
-                let v: (&[u8; 32], u32) = (&stxi.0, stxi.1);
-                table.remove(k.as_slice(), v)?;
+            let v: (&[u8; 32], u32) = (&stxi.0, stxi.1);
+            utxos.insert(address.as_slice(), v)?;
+
+            if let (Some(slot), Some(tx)) = (undone, delta.consumed_by.get(stxi)) {
+                let k: AddressHistoryKey =
+                    (address.as_slice(), slot, tx, TxDirection::Spent.into());
+                history.remove(k)?;
             }
         }
 
@@ -227,11 +419,311 @@ impl LedgerTable for ByAddressIndex {
         _slot: BlockSlot,
         _tombstone: &[TxoRef],
     ) -> Result<(), redb::Error> {
-        // do nothing
+        // spent utxos are removed as they're consumed, history is kept
+        Ok(())
+    }
+}
+
+type DelegationsKey<'a> = (&'a [u8], BlockSlot, u32);
+
+/// History of delegation changes, an empty value is a deregistration
+///
+/// Keys are sorted by credential first, so the last entry of each credential
+/// is its current delegation. Rolling back a block just drops its entries.
+const DELEGATIONS: TableDefinition<DelegationsKey, &[u8]> = TableDefinition::new("delegations");
+struct DelegationsTable;
+
+impl LedgerTable for DelegationsTable {
+    fn create(wx: &WriteTransaction) -> Result<(), redb::Error> {
+        wx.open_table(DELEGATIONS)?;
+        Ok(())
+    }
+
+    fn apply(wx: &WriteTransaction, delta: &LedgerDelta) -> Result<(), redb::Error> {
+        let mut table = wx.open_table(DELEGATIONS)?;
+
+        if let Some(ChainPoint(slot, _)) = delta.new_position {
+            for (idx, change) in delta.new_delegations.iter().enumerate() {
+                let k: DelegationsKey = (&change.credential, slot, idx as u32);
+                let v: &[u8] = match &change.pool {
+                    Some(x) => x.as_ref(),
+                    None => &[],
+                };
+                table.insert(k, v)?;
+            }
+        }
+
+        if let Some(ChainPoint(slot, _)) = delta.undone_position {
+            for (idx, change) in delta.undone_delegations.iter().enumerate() {
+                let k: DelegationsKey = (&change.credential, slot, idx as u32);
+                table.remove(k)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compact(
+        _wx: &WriteTransaction,
+        _slot: BlockSlot,
+        _tombstone: &[TxoRef],
+    ) -> Result<(), redb::Error> {
+        // superseded entries are tiny, the history is kept
+        Ok(())
+    }
+}
+
+/// Lovelace held by the unspent outputs of each stake credential
+///
+/// Kept up to date as outputs are produced and spent, so that taking a
+/// snapshot of the stake distribution doesn't need to go over the utxo set.
+const CREDENTIAL_STAKE: TableDefinition<&[u8], Lovelace> = TableDefinition::new("credential_stake");
+struct CredentialStakeTable;
+
+impl LedgerTable for CredentialStakeTable {
+    fn create(wx: &WriteTransaction) -> Result<(), redb::Error> {
+        let exists = wx
+            .list_tables()?
+            .any(|x| x.name() == CREDENTIAL_STAKE.name());
+
+        let mut table = wx.open_table(CREDENTIAL_STAKE)?;
+
+        // ledgers from before the table existed get it filled once
+        if !exists {
+            let spent = spent_utxos(&wx.open_multimap_table(TOMBSTONES)?)?;
+
+            let mut stake: HashMap<StakeKey, Lovelace> = HashMap::new();
+
+            for item in wx.open_table(UTXOS)?.iter()? {
+                let (key, body) = item?;
+                let (hash, idx) = key.value();
+
+                if spent.contains(&(*hash, idx)) {
+                    continue;
+                }
+
+                let (era, cbor) = body.value();
+
+                let Some((credential, amount)) = Era::try_from(era)
+                    .ok()
+                    .and_then(|era| output_stake(&EraCbor(era, cbor.to_vec())))
+                else {
+                    continue;
+                };
+
+                *stake.entry(credential).or_default() += amount;
+            }
+
+            for (credential, amount) in stake {
+                table.insert(credential.as_slice(), amount)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(wx: &WriteTransaction, delta: &LedgerDelta) -> Result<(), redb::Error> {
+        let mut table = wx.open_table(CREDENTIAL_STAKE)?;
+
+        let added = delta
+            .produced_utxo
+            .values()
+            .chain(delta.recovered_stxi.values());
+        let removed = delta
+            .consumed_utxo
+            .values()
+            .chain(delta.undone_utxo.values());
+
+        let mut changes: HashMap<StakeKey, i128> = HashMap::new();
+
+        for (credential, amount) in added.filter_map(output_stake) {
+            *changes.entry(credential).or_default() += amount as i128;
+        }
+
+        for (credential, amount) in removed.filter_map(output_stake) {
+            *changes.entry(credential).or_default() -= amount as i128;
+        }
+
+        for (credential, change) in changes {
+            let current = table
+                .get(credential.as_slice())?
+                .map(|x| x.value())
+                .unwrap_or_default();
+
+            match (current as i128 + change).max(0) as Lovelace {
+                0 => table.remove(credential.as_slice())?,
+                x => table.insert(credential.as_slice(), x)?,
+            };
+        }
+
+        Ok(())
+    }
+
+    fn compact(
+        _wx: &WriteTransaction,
+        _slot: BlockSlot,
+        _tombstone: &[TxoRef],
+    ) -> Result<(), redb::Error> {
+        // spent outputs are taken out as they're consumed
         Ok(())
     }
 }
 
+type StakeSnapshotsKey<'a> = (u64, &'a [u8]);
+
+/// Stake delegated to each pool at the end of an epoch, keyed by epoch and pool
+const STAKE_SNAPSHOTS: TableDefinition<StakeSnapshotsKey, Lovelace> =
+    TableDefinition::new("stake_snapshots");
+
+/// Slot of the first block of each epoch seen by the ledger
+const EPOCH_BOUNDARIES: TableDefinition<BlockSlot, u64> = TableDefinition::new("epoch_boundaries");
+
+/// Snapshots the stake distribution when a block opens a new epoch
+///
+/// The snapshot of an epoch is taken right before the first block of the next
+/// one is applied, which is the distribution that the protocol uses. Undoing
+/// that block drops the snapshot, the epoch is open again until the boundary
+/// is crossed once more. Deltas without an epoch don't take part.
+struct StakeSnapshotsTable;
+
+impl LedgerTable for StakeSnapshotsTable {
+    fn create(wx: &WriteTransaction) -> Result<(), redb::Error> {
+        wx.open_table(STAKE_SNAPSHOTS)?;
+        wx.open_table(EPOCH_BOUNDARIES)?;
+        Ok(())
+    }
+
+    fn apply(wx: &WriteTransaction, delta: &LedgerDelta) -> Result<(), redb::Error> {
+        let mut boundaries = wx.open_table(EPOCH_BOUNDARIES)?;
+
+        if let (Some(ChainPoint(slot, _)), Some(epoch)) = (&delta.new_position, delta.new_epoch) {
+            let current = boundaries.last()?.map(|(_, x)| x.value());
+
+            if !current.is_some_and(|x| x >= epoch) {
+                // the first epoch seen by the ledger has no start to snapshot from
+                if let Some(ended) = current {
+                    let distribution = compute_stake_distribution(
+                        &wx.open_table(DELEGATIONS)?,
+                        &wx.open_table(CREDENTIAL_STAKE)?,
+                    )?;
+
+                    let mut snapshots = wx.open_table(STAKE_SNAPSHOTS)?;
+
+                    for (pool, stake) in distribution {
+                        snapshots.insert((ended, pool.as_slice()), stake)?;
+                    }
+                }
+
+                boundaries.insert(slot, epoch)?;
+            }
+        }
+
+        if let Some(ChainPoint(slot, _)) = delta.undone_position {
+            let removed = boundaries.remove(slot)?.is_some();
+
+            // the epoch before the undone boundary is open again
+            let reopened = boundaries.last()?.map(|(_, x)| x.value());
+
+            if let (true, Some(epoch)) = (removed, reopened) {
+                let mut snapshots = wx.open_table(STAKE_SNAPSHOTS)?;
+
+                let none: &[u8] = &[];
+
+                let pools: Vec<_> = snapshots
+                    .range((epoch, none)..(epoch + 1, none))?
+                    .map_ok(|(k, _)| k.value().1.to_vec())
+                    .try_collect()?;
+
+                for pool in pools {
+                    snapshots.remove((epoch, pool.as_slice()))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compact(
+        _wx: &WriteTransaction,
+        _slot: BlockSlot,
+        _tombstone: &[TxoRef],
+    ) -> Result<(), redb::Error> {
+        // one snapshot per epoch, kept forever
+        Ok(())
+    }
+}
+
+/// Total stake delegated to each pool by the unspent outputs
+///
+/// The stake of a credential is the value of the unspent outputs with an
+/// address that delegates to it (see `CredentialStakeTable`). Reward balances
+/// and pointer addresses aren't counted.
+fn compute_stake_distribution(
+    delegations: &impl ReadableTable<DelegationsKey<'static>, &'static [u8]>,
+    stake: &impl ReadableTable<&'static [u8], Lovelace>,
+) -> Result<HashMap<PoolId, Lovelace>, redb::Error> {
+    let mut delegated: HashMap<StakeKey, PoolId> = HashMap::new();
+
+    for item in delegations.iter()? {
+        let (key, pool) = item?;
+        let (credential, _, _) = key.value();
+
+        match pool.value() {
+            [] => delegated.remove(credential),
+            x => delegated.insert(credential.to_vec(), PoolId::from(x)),
+        };
+    }
+
+    let mut out = HashMap::new();
+
+    for (credential, pool) in delegated {
+        if let Some(amount) = stake.get(credential.as_slice())? {
+            *out.entry(pool).or_default() += amount.value();
+        }
+    }
+
+    Ok(out)
+}
+
+/// Stake credential of an output and the lovelace it holds, if it's staked
+fn output_stake(output: &EraCbor) -> Option<(StakeKey, Lovelace)> {
+    let EraCbor(era, cbor) = output;
+    let output = MultiEraOutput::decode(*era, cbor).ok()?;
+
+    Some((output_stake_key(&output)?, output.lovelace_amount()))
+}
+
+/// Key of the stake credential that an output address delegates to
+fn output_stake_key(output: &MultiEraOutput) -> Option<StakeKey> {
+    let Ok(Address::Shelley(address)) = output.address() else {
+        return None;
+    };
+
+    match address.delegation() {
+        ShelleyDelegationPart::Key(x) => Some(stake_key(false, x)),
+        ShelleyDelegationPart::Script(x) => Some(stake_key(true, x)),
+        _ => None,
+    }
+}
+
+/// Utxos that are spent but still in the table, until their slot is finalized
+fn spent_utxos(
+    tombstones: &impl ReadableMultimapTable<BlockSlot, (&'static [u8; 32], TxoIdx)>,
+) -> Result<HashSet<([u8; 32], u32)>, redb::Error> {
+    let mut spent = HashSet::new();
+
+    for item in tombstones.iter()? {
+        let (_, values) = item?;
+
+        for value in values {
+            let (hash, idx) = value?.value();
+            spent.insert((*hash, idx));
+        }
+    }
+
+    Ok(spent)
+}
+
 #[derive(Clone)]
 pub struct LedgerStore(Arc<redb::Database>);
 
@@ -247,8 +739,13 @@ impl LedgerStore {
         let wx = inner.begin_write()?;
         UtxosTable::create(&wx)?;
         PParamsTable::create(&wx)?;
+        PParamsHistoryTable::create(&wx)?;
         TombstonesTable::create(&wx)?;
         BlocksTable::create(&wx)?;
+        ByAddressIndex::create(&wx)?;
+        DelegationsTable::create(&wx)?;
+        StakeSnapshotsTable::create(&wx)?;
+        CredentialStakeTable::create(&wx)?;
         wx.commit()?;
 
         Ok(Self(Arc::new(inner)))
@@ -281,13 +778,16 @@ impl LedgerStore {
         wx.set_durability(redb::Durability::Eventual);
 
         for delta in deltas {
+            // the snapshot of an ending epoch can't include the block that opens the next
+            StakeSnapshotsTable::apply(&wx, delta)?;
+            CredentialStakeTable::apply(&wx, delta)?;
             UtxosTable::apply(&wx, delta)?;
             PParamsTable::apply(&wx, delta)?;
+            PParamsHistoryTable::apply(&wx, delta)?;
             TombstonesTable::apply(&wx, delta)?;
             BlocksTable::apply(&wx, delta)?;
-
-            // indexes?
-            //ByAddressIndex::apply(&wx, delta)?;
+            ByAddressIndex::apply(&wx, delta)?;
+            DelegationsTable::apply(&wx, delta)?;
         }
 
         wx.commit()?;
@@ -318,8 +818,12 @@ impl LedgerStore {
             let (slot, txos) = ts?;
             UtxosTable::compact(&wx, slot, &txos)?;
             PParamsTable::compact(&wx, slot, &txos)?;
+            PParamsHistoryTable::compact(&wx, slot, &txos)?;
             BlocksTable::compact(&wx, slot, &txos)?;
             TombstonesTable::compact(&wx, slot, &txos)?;
+            DelegationsTable::compact(&wx, slot, &txos)?;
+            StakeSnapshotsTable::compact(&wx, slot, &txos)?;
+            CredentialStakeTable::compact(&wx, slot, &txos)?;
         }
 
         wx.commit()?;
@@ -352,6 +856,26 @@ impl LedgerStore {
         Ok(out)
     }
 
+    /// Same as `get_utxos`, but leaves out the utxos that are already spent
+    ///
+    /// `get_utxos` also finds spent utxos that aren't finalized yet (eg: to
+    /// undo a block), this is for callers that need the actual unspent set.
+    pub fn get_unspent_utxos(&self, refs: Vec<TxoRef>) -> Result<UtxoMap, redb::Error> {
+        if refs.is_empty() {
+            return Ok(Default::default());
+        }
+
+        let rx = self.0.begin_read()?;
+        let spent = spent_utxos(&rx.open_multimap_table(TOMBSTONES)?)?;
+
+        let unspent = refs
+            .into_iter()
+            .filter(|x| !spent.contains(&(*x.0, x.1)))
+            .collect();
+
+        self.get_utxos(unspent)
+    }
+
     pub fn get_pparams(&self, until: BlockSlot) -> Result<Vec<PParamsBody>, redb::Error> {
         let rx = self.0.begin_read()?;
         let table = rx.open_table(PPARAMS)?;
@@ -368,6 +892,43 @@ impl LedgerStore {
         Ok(out)
     }
 
+    /// Protocol params in effect during the given epoch
+    ///
+    /// Folds the genesis params with every recorded update that took effect
+    /// up to (and including) the epoch. Updates of rolled back blocks are
+    /// removed from the history, so they don't affect the result.
+    pub fn protocol_params_at(
+        &self,
+        genesis: &pparams::Genesis,
+        epoch: u64,
+    ) -> Result<MultiEraProtocolParameters, redb::Error> {
+        let rx = self.0.begin_read()?;
+        let table = rx.open_table(PPARAMS_HISTORY)?;
+
+        let mut bodies = vec![];
+
+        for item in table.iter()? {
+            let (key, era) = item?;
+            let (effective, _, cbor) = key.value();
+
+            if effective > epoch {
+                break;
+            }
+
+            bodies.push((era.value(), cbor.to_vec()));
+        }
+
+        let updates: Vec<_> = bodies
+            .iter()
+            .filter_map(|(era, cbor)| {
+                let era = Era::try_from(*era).ok()?;
+                MultiEraUpdate::decode_for_era(era, cbor).ok()
+            })
+            .collect();
+
+        Ok(pparams::fold_pparams(genesis, &updates, epoch))
+    }
+
     pub fn get_utxo_by_address_set(&self, address: &[u8]) -> Result<HashSet<TxoRef>, redb::Error> {
         let rx = self.0.begin_read()?;
         let table = rx.open_multimap_table(BY_ADDRESS_INDEX)?;
@@ -382,6 +943,251 @@ impl LedgerStore {
 
         Ok(out)
     }
+
+    /// Drops a secondary index and re-populates it from the UTxO set
+    ///
+    /// Meant to recover a corrupted index without rebuilding the whole ledger.
+    /// Utxos that are spent but not finalized yet are left out, same as the
+    /// index does when it follows the deltas. The address history is not
+    /// rebuilt: the UTxO set doesn't know when each utxo was created, nor the
+    /// spent ones, so it's only recovered by replaying the chain.
+    pub fn rebuild_index(&mut self, which: IndexKind) -> Result<(), redb::Error> {
+        let wx = self.0.begin_write()?;
+
+        match which {
+            IndexKind::UtxoByAddress => {
+                wx.delete_multimap_table(BY_ADDRESS_INDEX)?;
+                fill_address_utxos(&wx)?;
+            }
+        }
+
+        wx.commit()?;
+
+        Ok(())
+    }
+
+    /// Checks if a block can be applied on top of the current ledger state
+    ///
+    /// This is the dry-run counterpart of `apply`: it checks that the block
+    /// extends the ledger tip and goes through the same steps that the sync
+    /// pipeline uses to compute the block delta, but nothing is committed.
+    /// Phase-1 / phase-2 tx rules depend on protocol params and genesis
+    /// config, which aren't available to the store, so they're not checked.
+    pub fn validate_block(&self, block: &MultiEraBlock) -> Result<(), ValidationError> {
+        let tip = self
+            .cursor()
+            .map_err(|x| ValidationError::Ledger(LedgerError::StorageError(x)))?;
+
+        if let Some(ChainPoint(slot, hash)) = tip {
+            if block.slot() <= slot {
+                return Err(ValidationError::SlotNotAfterTip {
+                    block: block.slot(),
+                    tip: slot,
+                });
+            }
+
+            let previous = block.header().previous_hash();
+
+            if previous != Some(hash) {
+                return Err(ValidationError::NotExtendingTip {
+                    tip: hash,
+                    previous,
+                });
+            }
+        }
+
+        let mut consumed = HashSet::new();
+        let mut produced = HashSet::new();
+
+        for tx in block.txs() {
+            for input in tx.consumes() {
+                let txo = TxoRef(*input.hash(), input.index() as u32);
+
+                if !consumed.insert(txo.clone()) {
+                    return Err(ValidationError::DoubleSpend(txo));
+                }
+            }
+
+            for (idx, _) in tx.produces() {
+                produced.insert(TxoRef(tx.hash(), idx as u32));
+            }
+        }
+
+        // utxos spent by recent blocks stay in the table until they're
+        // finalized, those inputs are double spends and not available ones
+        let external: Vec<_> = consumed
+            .into_iter()
+            .filter(|x| !produced.contains(x))
+            .collect();
+
+        let storage = |x: redb::Error| ValidationError::Ledger(LedgerError::StorageError(x));
+        let unspent = self.get_unspent_utxos(external.clone()).map_err(storage)?;
+
+        let spent: Vec<_> = external
+            .into_iter()
+            .filter(|x| !unspent.contains_key(x))
+            .collect();
+
+        if let Some(txo) = self.get_utxos(spent).map_err(storage)?.into_keys().next() {
+            return Err(ValidationError::DoubleSpend(txo));
+        }
+
+        let context =
+            super::load_slice_for_block(block, self, &[]).map_err(ValidationError::Ledger)?;
+
+        super::compute_delta(block, context).map_err(|x| match x {
+            BrokenInvariant::MissingUtxo(txo) => ValidationError::MissingInput(txo),
+        })?;
+
+        Ok(())
+    }
+
+    /// Returns a page of the txs that touched an address, newest first
+    ///
+    /// Pagination follows the same approach as the `dump_history` endpoint: we
+    /// fetch one extra item and, if present, return it as the starting point
+    /// of the next page. Undone blocks remove their entries from the index, so
+    /// rolled-back txs won't show up in subsequent queries.
+    pub fn get_address_history(
+        &self,
+        address: &[u8],
+        from: Option<&AddressTx>,
+        max_items: usize,
+    ) -> Result<(Vec<AddressTx>, Option<AddressTx>), redb::Error> {
+        let rx = self.0.begin_read()?;
+        let table = rx.open_table(ADDRESS_HISTORY)?;
+
+        let min_hash = [0u8; 32];
+        let max_hash = [u8::MAX; 32];
+
+        let lower: AddressHistoryKey = (address, 0, &min_hash, 0);
+
+        let upper: AddressHistoryKey = match from {
+            Some(x) => (address, x.slot, &x.tx, x.direction.into()),
+            None => (address, BlockSlot::MAX, &max_hash, u8::MAX),
+        };
+
+        let mut items = Vec::with_capacity(max_items + 1);
+
+        for entry in table.range(lower..=upper)?.rev().take(max_items + 1) {
+            let (key, _) = entry?;
+            let (_, slot, tx, direction) = key.value();
+
+            // unknown directions can't be written by this version of the index
+            let Ok(direction) = TxDirection::try_from(direction) else {
+                continue;
+            };
+
+            items.push(AddressTx {
+                slot,
+                tx: Hash::new(*tx),
+                direction,
+            });
+        }
+
+        let next = if items.len() > max_items {
+            items.pop()
+        } else {
+            None
+        };
+
+        Ok((items, next))
+    }
+
+    /// Total stake delegated to each pool for the current epoch
+    ///
+    /// That's the snapshot taken when the previous epoch ended, see
+    /// `StakeSnapshotsTable`. Empty until the ledger has seen an epoch from
+    /// its start to its end (eg: right after a bootstrap, or on ledgers that
+    /// were created before the snapshots existed).
+    pub fn stake_distribution(&self) -> Result<HashMap<PoolId, Lovelace>, redb::Error> {
+        let rx = self.0.begin_read()?;
+
+        let boundaries = rx.open_table(EPOCH_BOUNDARIES)?;
+
+        let Some(ended) = boundaries.iter()?.rev().nth(1).transpose()? else {
+            return Ok(HashMap::new());
+        };
+
+        let ended = ended.1.value();
+
+        let snapshots = rx.open_table(STAKE_SNAPSHOTS)?;
+        let none: &[u8] = &[];
+
+        let out = snapshots
+            .range((ended, none)..(ended + 1, none))?
+            .map_ok(|(k, v)| (PoolId::from(k.value().1), v.value()))
+            .try_collect()?;
+
+        Ok(out)
+    }
+
+    /// Compares the UTxO set against the one of an expected ledger
+    ///
+    /// Both tables are sorted by key, so they're walked side by side in a
+    /// single pass without loading either set into memory. Only the first
+    /// `max_sample` differences are kept, the rest are just counted.
+    pub fn diff_utxos(
+        &self,
+        expected: &LedgerStore,
+        max_sample: usize,
+    ) -> Result<UtxoDiffReport, redb::Error> {
+        let rx = self.0.begin_read()?;
+        let actual = rx.open_table(UTXOS)?;
+
+        let expected_rx = expected.0.begin_read()?;
+        let expected = expected_rx.open_table(UTXOS)?;
+
+        let mut actual = actual.iter()?;
+        let mut expected = expected.iter()?;
+
+        let mut a = actual.next().transpose()?;
+        let mut e = expected.next().transpose()?;
+
+        let mut report = UtxoDiffReport::default();
+
+        let as_ref = |(hash, idx): (&[u8; 32], u32)| TxoRef(Hash::new(*hash), idx);
+
+        loop {
+            let order = match (&a, &e) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((ak, _)), Some((ek, _))) => ak.value().cmp(&ek.value()),
+            };
+
+            let diff = match (order, &a, &e) {
+                (Ordering::Less, Some((k, _)), _) => {
+                    let diff = UtxoDiff::Unexpected(as_ref(k.value()));
+                    a = actual.next().transpose()?;
+                    Some(diff)
+                }
+                (Ordering::Greater, _, Some((k, _))) => {
+                    let diff = UtxoDiff::Missing(as_ref(k.value()));
+                    e = expected.next().transpose()?;
+                    Some(diff)
+                }
+                (Ordering::Equal, Some((k, av)), Some((_, ev))) => {
+                    let diff =
+                        (av.value() != ev.value()).then(|| UtxoDiff::Mismatch(as_ref(k.value())));
+                    a = actual.next().transpose()?;
+                    e = expected.next().transpose()?;
+                    diff
+                }
+                _ => unreachable!(),
+            };
+
+            if let Some(diff) = diff {
+                report.count += 1;
+
+                if report.sample.len() < max_sample {
+                    report.sample.push(diff);
+                }
+            }
+        }
+
+        Ok(report)
+    }
 }
 
 impl super::LedgerStore for LedgerStore {
@@ -412,3 +1218,610 @@ impl interop::LedgerContext for LedgerStore {
         Some(some)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_test_output() -> EraCbor {
+        let cbor = crate::wal::testing::test_data_cbor();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let tx = block.txs().into_iter().next().unwrap();
+        let (_, output) = tx.produces().into_iter().next().unwrap();
+
+        output.into()
+    }
+
+    fn receive_delta(slot: BlockSlot, body: &EraCbor) -> LedgerDelta {
+        LedgerDelta {
+            new_position: Some(ChainPoint(slot, Hash::new([slot as u8; 32]))),
+            produced_utxo: HashMap::from([(TxoRef(Hash::new([slot as u8; 32]), 0), body.clone())]),
+            ..Default::default()
+        }
+    }
+
+    fn slots(items: &[AddressTx]) -> Vec<BlockSlot> {
+        items.iter().map(|x| x.slot).collect()
+    }
+
+    #[test]
+    fn test_rebuild_corrupted_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let body = load_test_output();
+        let address = output_address(&body).unwrap();
+
+        store
+            .apply(&[receive_delta(1, &body), receive_delta(2, &body)])
+            .unwrap();
+
+        store.rebuild_index(IndexKind::UtxoByAddress).unwrap();
+
+        let expected =
+            HashSet::from([TxoRef(Hash::new([1; 32]), 0), TxoRef(Hash::new([2; 32]), 0)]);
+
+        let found = store.get_utxo_by_address_set(&address).unwrap();
+        assert_eq!(found, expected);
+
+        // corrupt the index, dropping a real utxo and adding a bogus one
+        let wx = store.0.begin_write().unwrap();
+
+        {
+            let mut index = wx.open_multimap_table(BY_ADDRESS_INDEX).unwrap();
+            index.remove(address.as_slice(), (&[1; 32], 0)).unwrap();
+            index.insert(address.as_slice(), (&[9; 32], 0)).unwrap();
+        }
+
+        wx.commit().unwrap();
+
+        let found = store.get_utxo_by_address_set(&address).unwrap();
+        assert_ne!(found, expected);
+
+        store.rebuild_index(IndexKind::UtxoByAddress).unwrap();
+
+        let found = store.get_utxo_by_address_set(&address).unwrap();
+        assert_eq!(found, expected);
+
+        // spent utxos stay in the table until finalized, but not in the index
+        let spend = LedgerDelta {
+            new_position: Some(ChainPoint(3, Hash::new([3; 32]))),
+            consumed_utxo: HashMap::from([(TxoRef(Hash::new([1; 32]), 0), body.clone())]),
+            ..Default::default()
+        };
+
+        store.apply(&[spend]).unwrap();
+        store.rebuild_index(IndexKind::UtxoByAddress).unwrap();
+
+        let found = store.get_utxo_by_address_set(&address).unwrap();
+        assert_eq!(found, HashSet::from([TxoRef(Hash::new([2; 32]), 0)]));
+    }
+
+    #[test]
+    fn test_address_index_backfill() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger");
+        let mut store = LedgerStore::open(&path).unwrap();
+
+        let body = load_test_output();
+        let address = output_address(&body).unwrap();
+
+        store
+            .apply(&[receive_delta(1, &body), receive_delta(2, &body)])
+            .unwrap();
+
+        // a ledger from before the index existed gets its utxos on open
+        let wx = store.0.begin_write().unwrap();
+        wx.delete_multimap_table(BY_ADDRESS_INDEX).unwrap();
+        wx.commit().unwrap();
+        drop(store);
+
+        let store = LedgerStore::open(&path).unwrap();
+
+        let found = store.get_utxo_by_address_set(&address).unwrap();
+        let expected =
+            HashSet::from([TxoRef(Hash::new([1; 32]), 0), TxoRef(Hash::new([2; 32]), 0)]);
+        assert_eq!(found, expected);
+    }
+
+    /// A ledger positioned right before the block, holding all of its inputs
+    ///
+    /// The inputs are produced two blocks before, the block right before
+    /// spends the first `spent` of them. The dir has to outlive the store.
+    fn ledger_for_block(block: &MultiEraBlock, spent: usize) -> (tempfile::TempDir, LedgerStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let inputs: Vec<_> = block
+            .txs()
+            .iter()
+            .flat_map(|tx| tx.consumes())
+            .map(|x| TxoRef(*x.hash(), x.index() as u32))
+            .map(|x| (x, EraCbor(Era::Alonzo, vec![])))
+            .collect();
+
+        let previous = block.header().previous_hash().unwrap();
+
+        let produced = LedgerDelta {
+            new_position: Some(ChainPoint(block.slot() - 2, Hash::new([1; 32]))),
+            produced_utxo: inputs.iter().cloned().collect(),
+            ..Default::default()
+        };
+
+        let consumed = LedgerDelta {
+            new_position: Some(ChainPoint(block.slot() - 1, previous)),
+            consumed_utxo: inputs.into_iter().take(spent).collect(),
+            ..Default::default()
+        };
+
+        store.apply(&[produced, consumed]).unwrap();
+
+        (dir, store)
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let cbor = crate::wal::testing::test_data_cbor();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let (_dir, store) = ledger_for_block(&block, 0);
+        store.validate_block(&block).unwrap();
+
+        // validating is a dry-run, the ledger stays at the same position
+        assert_eq!(store.cursor().unwrap().unwrap().0, block.slot() - 1);
+    }
+
+    #[test]
+    fn test_validate_block_errors() {
+        let cbor = crate::wal::testing::test_data_cbor();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        // a ledger on a different fork
+        let (_dir, mut store) = ledger_for_block(&block, 0);
+
+        let delta = LedgerDelta {
+            new_position: Some(ChainPoint(block.slot() - 1, Hash::new([0; 32]))),
+            ..Default::default()
+        };
+
+        store.apply(&[delta]).unwrap();
+
+        let result = store.validate_block(&block);
+        assert!(matches!(
+            result,
+            Err(ValidationError::NotExtendingTip { .. })
+        ));
+
+        // a ledger where inputs were never produced
+        let dir = tempfile::tempdir().unwrap();
+        let store = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let result = store.validate_block(&block);
+        assert!(matches!(result, Err(ValidationError::MissingInput(_))));
+
+        // an input already spent by the block right before
+        let (_dir, store) = ledger_for_block(&block, 1);
+
+        let first = block
+            .txs()
+            .iter()
+            .flat_map(|tx| tx.consumes())
+            .map(|x| TxoRef(*x.hash(), x.index() as u32))
+            .next()
+            .unwrap();
+
+        let result = store.validate_block(&block);
+        assert!(matches!(result, Err(ValidationError::DoubleSpend(x)) if x == first));
+    }
+
+    #[test]
+    fn test_address_history_pagination() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let body = load_test_output();
+        let address = output_address(&body).unwrap();
+
+        let deltas: Vec<_> = (1..=5).map(|x| receive_delta(x * 10, &body)).collect();
+        store.apply(&deltas).unwrap();
+
+        let (page, next) = store.get_address_history(&address, None, 2).unwrap();
+        assert_eq!(slots(&page), vec![50, 40]);
+        assert_eq!(next.as_ref().unwrap().slot, 30);
+
+        let token = next.unwrap().to_token();
+        let from = AddressTx::from_token(&token).unwrap();
+
+        let (page, next) = store.get_address_history(&address, Some(&from), 2).unwrap();
+        assert_eq!(slots(&page), vec![30, 20]);
+
+        let (page, next) = store
+            .get_address_history(&address, next.as_ref(), 2)
+            .unwrap();
+        assert_eq!(slots(&page), vec![10]);
+        assert!(next.is_none());
+
+        // a page that fits exactly doesn't point to a next one
+        let (page, next) = store.get_address_history(&address, None, 5).unwrap();
+        assert_eq!(page.len(), 5);
+        assert!(next.is_none());
+
+        let (page, _) = store.get_address_history(b"unknown", None, 5).unwrap();
+        assert!(page.is_empty());
+    }
+
+    fn load_json<T: serde::de::DeserializeOwned>(path: &str) -> T {
+        let file = std::fs::File::open(path).unwrap();
+        serde_json::from_reader(file).unwrap()
+    }
+
+    #[test]
+    fn test_protocol_params_history() {
+        let test_data = "src/ledger/pparams/test_data/mainnet";
+
+        let byron = load_json(&format!("{test_data}/genesis/byron_genesis.json"));
+        let shelley = load_json(&format!("{test_data}/genesis/shelley_genesis.json"));
+        let alonzo = load_json(&format!("{test_data}/genesis/alonzo_genesis.json"));
+
+        let genesis = pparams::Genesis {
+            byron: &byron,
+            shelley: &shelley,
+            alonzo: &alonzo,
+        };
+
+        let cbors: Vec<_> = std::fs::read_dir(format!("{test_data}/update_proposal_blocks"))
+            .unwrap()
+            .map(|x| std::fs::read(x.unwrap().path()).unwrap())
+            .collect();
+
+        let blocks: Vec<_> = cbors
+            .iter()
+            .map(|x| MultiEraBlock::decode(x).unwrap())
+            .sorted_by_key(|x| x.slot())
+            .collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let deltas: Vec<_> = blocks
+            .iter()
+            .map(|x| LedgerDelta {
+                new_position: Some(ChainPoint(x.slot(), x.hash())),
+                new_pparams: block_pparams_updates(x),
+                ..Default::default()
+            })
+            .collect();
+
+        store.apply(&deltas).unwrap();
+
+        // the protocol version moves across the byron / shelley boundary
+        let version = |store: &LedgerStore, epoch| {
+            store
+                .protocol_params_at(&genesis, epoch)
+                .unwrap()
+                .protocol_version()
+        };
+
+        assert_eq!(version(&store, 100), 0);
+        assert_eq!(version(&store, 200), 1);
+        assert_eq!(version(&store, 211), 2);
+        assert_eq!(version(&store, 400), 8);
+
+        // rolling back the later half of the updates drops them from the history
+        let (kept, undone) = blocks.split_at(blocks.len() / 2);
+
+        let undos: Vec<_> = undone
+            .iter()
+            .rev()
+            .map(|x| LedgerDelta {
+                undone_position: Some(ChainPoint(x.slot(), x.hash())),
+                undone_pparams: block_pparams_updates(x),
+                ..Default::default()
+            })
+            .collect();
+
+        store.apply(&undos).unwrap();
+
+        let kept_cbor: Vec<_> = kept.iter().flat_map(block_pparams_updates).collect();
+
+        let kept_updates: Vec<_> = kept_cbor
+            .iter()
+            .map(|PParamsBody(era, cbor)| MultiEraUpdate::decode_for_era(*era, cbor).unwrap())
+            .collect();
+
+        let expected = pparams::fold_pparams(&genesis, &kept_updates, 400).protocol_version();
+        assert_eq!(version(&store, 400), expected);
+
+        // with every update rolled back, only the genesis params are left
+        let undos: Vec<_> = kept
+            .iter()
+            .rev()
+            .map(|x| LedgerDelta {
+                undone_position: Some(ChainPoint(x.slot(), x.hash())),
+                undone_pparams: block_pparams_updates(x),
+                ..Default::default()
+            })
+            .collect();
+
+        store.apply(&undos).unwrap();
+
+        assert_eq!(version(&store, 400), 0);
+    }
+
+    #[test]
+    fn test_address_history_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let body = load_test_output();
+        let address = output_address(&body).unwrap();
+
+        let received = TxoRef(Hash::new([10; 32]), 0);
+        let spender = Hash::new([99; 32]);
+
+        let spend = LedgerDelta {
+            new_position: Some(ChainPoint(20, Hash::new([20; 32]))),
+            consumed_utxo: HashMap::from([(received.clone(), body.clone())]),
+            consumed_by: HashMap::from([(received.clone(), spender)]),
+            ..Default::default()
+        };
+
+        store.apply(&[receive_delta(10, &body), spend]).unwrap();
+
+        let (page, _) = store.get_address_history(&address, None, 10).unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].tx, spender);
+        assert_eq!(page[0].direction, TxDirection::Spent);
+        assert_eq!(page[1].direction, TxDirection::Received);
+
+        // the utxos of the address follow the same deltas
+        assert!(store.get_utxo_by_address_set(&address).unwrap().is_empty());
+
+        let undo = LedgerDelta {
+            undone_position: Some(ChainPoint(20, Hash::new([20; 32]))),
+            recovered_stxi: HashMap::from([(received.clone(), body.clone())]),
+            consumed_by: HashMap::from([(received.clone(), spender)]),
+            ..Default::default()
+        };
+
+        store.apply(&[undo]).unwrap();
+
+        let (page, _) = store.get_address_history(&address, None, 10).unwrap();
+        assert_eq!(slots(&page), vec![10]);
+        assert_eq!(page[0].direction, TxDirection::Received);
+
+        let found = store.get_utxo_by_address_set(&address).unwrap();
+        assert_eq!(found, HashSet::from([received]));
+    }
+
+    fn delegated_output(stake: u8, coin: u64) -> EraCbor {
+        use pallas::ledger::addresses::{Network, ShelleyAddress, ShelleyPaymentPart};
+        use pallas::ledger::primitives::alonzo;
+
+        let address = ShelleyAddress::new(
+            Network::Testnet,
+            ShelleyPaymentPart::Key(Hash::new([1; 28])),
+            ShelleyDelegationPart::Key(Hash::new([stake; 28])),
+        );
+
+        let output = alonzo::TransactionOutput {
+            address: Address::Shelley(address).to_vec().into(),
+            amount: alonzo::Value::Coin(coin),
+            datum_hash: None,
+        };
+
+        EraCbor(
+            Era::Alonzo,
+            pallas::codec::minicbor::to_vec(&output).unwrap(),
+        )
+    }
+
+    fn delegation(stake: u8, pool: Option<u8>) -> DelegationChange {
+        DelegationChange {
+            credential: stake_key(false, &Hash::new([stake; 28])),
+            pool: pool.map(|x| Hash::new([x; 28])),
+        }
+    }
+
+    #[test]
+    fn test_stake_distribution_epoch_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let pool_a = Hash::new([0xa; 28]);
+        let pool_b = Hash::new([0xb; 28]);
+
+        let utxo = |x: u8| TxoRef(Hash::new([x; 32]), 0);
+        let point = |x: u8| Some(ChainPoint(x as u64, Hash::new([x; 32])));
+
+        // stake 3 is never delegated, so its output doesn't count
+        let genesis = LedgerDelta {
+            new_position: point(10),
+            new_epoch: Some(0),
+            produced_utxo: HashMap::from([
+                (utxo(1), delegated_output(1, 100)),
+                (utxo(2), delegated_output(2, 50)),
+                (utxo(3), delegated_output(1, 25)),
+                (utxo(4), delegated_output(3, 1000)),
+            ]),
+            new_delegations: vec![delegation(1, Some(0xa)), delegation(2, Some(0xb))],
+            ..Default::default()
+        };
+
+        store.apply(&[genesis]).unwrap();
+
+        // no epoch has ended yet
+        assert!(store.stake_distribution().unwrap().is_empty());
+
+        // stake 2 moves to pool a and one of the outputs of stake 1 is spent
+        let changes = vec![delegation(2, Some(0xb)), delegation(2, Some(0xa))];
+
+        let moved = LedgerDelta {
+            new_position: point(20),
+            new_epoch: Some(0),
+            consumed_utxo: HashMap::from([(utxo(3), delegated_output(1, 25))]),
+            new_delegations: changes.clone(),
+            ..Default::default()
+        };
+
+        store.apply(&[moved]).unwrap();
+
+        // changes within the epoch don't show until it ends
+        assert!(store.stake_distribution().unwrap().is_empty());
+
+        // a block of the next epoch closes it, the snapshot is taken before
+        // its own changes, so stake 2 deregistering here doesn't count yet
+        let boundary = || LedgerDelta {
+            new_position: point(30),
+            new_epoch: Some(1),
+            new_delegations: vec![delegation(2, None)],
+            ..Default::default()
+        };
+
+        store.apply(&[boundary()]).unwrap();
+
+        let moved = HashMap::from([(pool_a, 150)]);
+        assert_eq!(store.stake_distribution().unwrap(), moved);
+
+        // undoing the boundary block opens the epoch again
+        let undo_boundary = LedgerDelta {
+            undone_position: point(30),
+            undone_delegations: vec![delegation(2, None)],
+            ..Default::default()
+        };
+
+        store.apply(&[undo_boundary]).unwrap();
+        assert!(store.stake_distribution().unwrap().is_empty());
+
+        // rolling back the move restores both the delegation and the output,
+        // and crossing the boundary again snapshots the restored state
+        let undo_moved = LedgerDelta {
+            undone_position: point(20),
+            recovered_stxi: HashMap::from([(utxo(3), delegated_output(1, 25))]),
+            undone_delegations: changes,
+            ..Default::default()
+        };
+
+        store.apply(&[undo_moved, boundary()]).unwrap();
+
+        let expected = HashMap::from([(pool_a, 125), (pool_b, 50)]);
+        assert_eq!(store.stake_distribution().unwrap(), expected);
+
+        // later blocks of the same epoch don't touch the snapshot
+        let deregistered = LedgerDelta {
+            new_position: point(40),
+            new_epoch: Some(1),
+            new_delegations: vec![delegation(1, None)],
+            ..Default::default()
+        };
+
+        store.apply(&[deregistered]).unwrap();
+        assert_eq!(store.stake_distribution().unwrap(), expected);
+
+        // once epoch 1 ends, neither deregistered credential counts anymore
+        let next = LedgerDelta {
+            new_position: point(50),
+            new_epoch: Some(2),
+            ..Default::default()
+        };
+
+        store.apply(&[next]).unwrap();
+        assert!(store.stake_distribution().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_credential_stake_backfill() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger");
+        let mut store = LedgerStore::open(&path).unwrap();
+
+        let utxo = |x: u8| TxoRef(Hash::new([x; 32]), 0);
+        let point = |x: u8| Some(ChainPoint(x as u64, Hash::new([x; 32])));
+
+        let produced = LedgerDelta {
+            new_position: point(10),
+            produced_utxo: HashMap::from([
+                (utxo(1), delegated_output(1, 100)),
+                (utxo(2), delegated_output(1, 25)),
+            ]),
+            ..Default::default()
+        };
+
+        let spent = LedgerDelta {
+            new_position: point(20),
+            consumed_utxo: HashMap::from([(utxo(2), delegated_output(1, 25))]),
+            ..Default::default()
+        };
+
+        store.apply(&[produced, spent]).unwrap();
+
+        let stake = |store: &LedgerStore| {
+            let rx = store.0.begin_read().unwrap();
+            let table = rx.open_table(CREDENTIAL_STAKE).unwrap();
+            let key = stake_key(false, &Hash::new([1; 28]));
+            table.get(key.as_slice()).unwrap().map(|x| x.value())
+        };
+
+        assert_eq!(stake(&store), Some(100));
+
+        // a ledger from before the table existed gets it filled on open
+        let wx = store.0.begin_write().unwrap();
+        wx.delete_table(CREDENTIAL_STAKE).unwrap();
+        wx.commit().unwrap();
+        drop(store);
+
+        let store = LedgerStore::open(&path).unwrap();
+        assert_eq!(stake(&store), Some(100));
+    }
+
+    #[test]
+    fn test_diff_detects_corrupted_utxos() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut live = LedgerStore::open(dir.path().join("live")).unwrap();
+        let mut rebuilt = LedgerStore::open(dir.path().join("rebuilt")).unwrap();
+
+        let body = load_test_output();
+        let deltas = || (1..=5).map(|x| receive_delta(x, &body)).collect::<Vec<_>>();
+
+        live.apply(&deltas()).unwrap();
+        rebuilt.apply(&deltas()).unwrap();
+
+        let report = live.diff_utxos(&rebuilt, 10).unwrap();
+        assert_eq!(report.count, 0);
+        assert!(report.sample.is_empty());
+
+        // corrupt the live ledger: drop one utxo, add a bogus one and tamper
+        // with the body of another
+        let wx = live.0.begin_write().unwrap();
+
+        {
+            let mut utxos = wx.open_table(UTXOS).unwrap();
+
+            let valid: UtxosValue = (body.0.into(), &body.1);
+            let tampered: UtxosValue = (body.0.into(), &[0u8; 4]);
+
+            utxos.remove((&[2; 32], 0)).unwrap();
+            utxos.insert((&[9; 32], 0), valid).unwrap();
+            utxos.insert((&[4; 32], 0), tampered).unwrap();
+        }
+
+        wx.commit().unwrap();
+
+        let report = live.diff_utxos(&rebuilt, 10).unwrap();
+        assert_eq!(report.count, 3);
+        assert_eq!(
+            report.sample,
+            vec![
+                UtxoDiff::Missing(TxoRef(Hash::new([2; 32]), 0)),
+                UtxoDiff::Mismatch(TxoRef(Hash::new([4; 32]), 0)),
+                UtxoDiff::Unexpected(TxoRef(Hash::new([9; 32]), 0)),
+            ]
+        );
+
+        // the sample is capped but every difference is still counted
+        let report = live.diff_utxos(&rebuilt, 1).unwrap();
+        assert_eq!(report.count, 3);
+        assert_eq!(report.sample.len(), 1);
+    }
+}
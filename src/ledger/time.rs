@@ -0,0 +1,232 @@
+//! Conversion between slots, epochs and wall-clock time
+//!
+//! Each era of a network can have a different slot and epoch length, so
+//! converting a slot into a timestamp (or an epoch) requires knowing where each
+//! era starts. Public networks have built-in configs, custom networks can
+//! describe their own.
+
+use serde::{Deserialize, Serialize};
+
+use super::BlockSlot;
+
+/// Unix timestamp, in seconds
+pub type Timestamp = u64;
+
+pub const MAINNET_MAGIC: u64 = 764824073;
+pub const PREPROD_MAGIC: u64 = 1;
+pub const PREVIEW_MAGIC: u64 = 2;
+
+/// Boundary where an era with a particular slot length starts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EraStart {
+    /// First slot of the era
+    pub slot: BlockSlot,
+
+    /// Unix timestamp (in seconds) of the first slot of the era
+    pub time: Timestamp,
+
+    /// Duration of each slot, in milliseconds
+    pub slot_length: u64,
+
+    /// Number of slots in each epoch of the era
+    pub epoch_length: u64,
+}
+
+/// Era boundaries of a network
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub eras: Vec<EraStart>,
+}
+
+impl NetworkConfig {
+    pub fn mainnet() -> Self {
+        Self {
+            eras: vec![
+                EraStart {
+                    slot: 0,
+                    time: 1506203091,
+                    slot_length: 20_000,
+                    epoch_length: 21_600,
+                },
+                EraStart {
+                    slot: 4492800,
+                    time: 1596059091,
+                    slot_length: 1_000,
+                    epoch_length: 432_000,
+                },
+            ],
+        }
+    }
+
+    pub fn preprod() -> Self {
+        Self {
+            eras: vec![
+                EraStart {
+                    slot: 0,
+                    time: 1654041600,
+                    slot_length: 20_000,
+                    epoch_length: 21_600,
+                },
+                EraStart {
+                    slot: 86400,
+                    time: 1655769600,
+                    slot_length: 1_000,
+                    epoch_length: 432_000,
+                },
+            ],
+        }
+    }
+
+    pub fn preview() -> Self {
+        Self {
+            eras: vec![EraStart {
+                slot: 0,
+                time: 1666656000,
+                slot_length: 1_000,
+                epoch_length: 86_400,
+            }],
+        }
+    }
+
+    /// Built-in config for a public network, identified by its magic
+    pub fn from_magic(magic: u64) -> Option<Self> {
+        match magic {
+            MAINNET_MAGIC => Some(Self::mainnet()),
+            PREPROD_MAGIC => Some(Self::preprod()),
+            PREVIEW_MAGIC => Some(Self::preview()),
+            _ => None,
+        }
+    }
+}
+
+/// Converts slots into timestamps (and back) using the eras of a network
+#[derive(Debug, Clone)]
+pub struct SlotConverter {
+    eras: Vec<EraStart>,
+}
+
+impl SlotConverter {
+    pub fn new(config: &NetworkConfig) -> Self {
+        let mut eras = config.eras.clone();
+        eras.sort_by_key(|x| x.slot);
+
+        Self { eras }
+    }
+
+    /// Timestamp of the start of the slot, `None` if the slot is before the
+    /// first known era
+    pub fn slot_to_time(&self, slot: BlockSlot) -> Option<Timestamp> {
+        let era = self.eras.iter().rev().find(|x| x.slot <= slot)?;
+
+        let elapsed = (slot - era.slot) * era.slot_length / 1000;
+
+        Some(era.time + elapsed)
+    }
+
+    /// Slot in progress at the given timestamp, `None` if the time is before
+    /// the first known era
+    pub fn time_to_slot(&self, time: Timestamp) -> Option<BlockSlot> {
+        let era = self.eras.iter().rev().find(|x| x.time <= time)?;
+
+        let elapsed = (time - era.time) * 1000 / era.slot_length;
+
+        Some(era.slot + elapsed)
+    }
+
+    /// Epoch of the slot, `None` if the slot is before the first known era
+    ///
+    /// Every era starts at an epoch boundary, the epochs of the eras before
+    /// it are counted with their own length.
+    pub fn slot_to_epoch(&self, slot: BlockSlot) -> Option<u64> {
+        let mut epoch = 0;
+
+        for (era, next) in self.eras.iter().zip(self.eras.iter().skip(1)) {
+            if next.slot > slot {
+                break;
+            }
+
+            epoch += (next.slot - era.slot) / era.epoch_length;
+        }
+
+        let era = self.eras.iter().rev().find(|x| x.slot <= slot)?;
+
+        Some(epoch + (slot - era.slot) / era.epoch_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mainnet_conversion() {
+        let converter = SlotConverter::new(&NetworkConfig::mainnet());
+
+        // byron, 20 secs per slot
+        assert_eq!(converter.slot_to_time(0), Some(1506203091));
+        assert_eq!(converter.slot_to_time(10), Some(1506203291));
+
+        // first shelley block
+        assert_eq!(converter.slot_to_time(4492800), Some(1596059091));
+
+        // first babbage block (2022-09-22 21:44:51 UTC)
+        assert_eq!(converter.slot_to_time(72316800), Some(1663883091));
+        assert_eq!(converter.time_to_slot(1663883091), Some(72316800));
+
+        assert_eq!(converter.time_to_slot(1506203291), Some(10));
+        assert_eq!(converter.time_to_slot(1506203090), None);
+
+        // byron epochs are 21600 slots, shelley ones 432000
+        assert_eq!(converter.slot_to_epoch(21599), Some(0));
+        assert_eq!(converter.slot_to_epoch(21600), Some(1));
+        assert_eq!(converter.slot_to_epoch(4492799), Some(207));
+        assert_eq!(converter.slot_to_epoch(4492800), Some(208));
+
+        // first alonzo and babbage epochs
+        assert_eq!(converter.slot_to_epoch(39916800), Some(290));
+        assert_eq!(converter.slot_to_epoch(72316800), Some(365));
+        assert_eq!(converter.slot_to_epoch(72316799), Some(364));
+    }
+
+    #[test]
+    fn test_testnet_conversion() {
+        let preprod = SlotConverter::new(&NetworkConfig::from_magic(PREPROD_MAGIC).unwrap());
+        assert_eq!(preprod.slot_to_time(86399), Some(1655769580));
+        assert_eq!(preprod.slot_to_time(86400), Some(1655769600));
+        assert_eq!(preprod.slot_to_time(86460), Some(1655769660));
+
+        // preprod went into shelley at epoch 4
+        assert_eq!(preprod.slot_to_epoch(86399), Some(3));
+        assert_eq!(preprod.slot_to_epoch(86400), Some(4));
+        assert_eq!(preprod.slot_to_epoch(86400 + 432000), Some(5));
+
+        let preview = SlotConverter::new(&NetworkConfig::from_magic(PREVIEW_MAGIC).unwrap());
+        assert_eq!(preview.slot_to_time(1000), Some(1666657000));
+        assert_eq!(preview.time_to_slot(1666657000), Some(1000));
+        assert_eq!(preview.slot_to_epoch(86400), Some(1));
+
+        // a custom network with a short slot length, eras given out of order
+        let custom = SlotConverter::new(&NetworkConfig {
+            eras: vec![
+                EraStart {
+                    slot: 100,
+                    time: 1_000_200,
+                    slot_length: 200,
+                    epoch_length: 1_000,
+                },
+                EraStart {
+                    slot: 0,
+                    time: 1_000_000,
+                    slot_length: 2_000,
+                    epoch_length: 10,
+                },
+            ],
+        });
+
+        assert_eq!(custom.slot_to_time(50), Some(1_000_100));
+        assert_eq!(custom.slot_to_time(150), Some(1_000_210));
+        assert_eq!(custom.time_to_slot(1_000_210), Some(150));
+        assert_eq!(custom.slot_to_epoch(99), Some(9));
+        assert_eq!(custom.slot_to_epoch(1_150), Some(11));
+    }
+}
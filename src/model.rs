@@ -16,6 +16,10 @@ pub struct RawBlock {
     pub hash: BlockHash,
     pub era: BlockEra,
     pub body: BlockBody,
+
+    /// Hashes of the txs in the block, in order, so that the WAL can index
+    /// them without decoding the block again
+    pub txs: Vec<TxHash>,
 }
 
 #[derive(Debug, Clone)]
@@ -29,6 +33,21 @@ pub enum RollEvent {
     TipChanged,
 }
 
+/// What tells clients apart, both for the history quota of the gRPC endpoint
+/// and for their share of the mempool
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaKey {
+    /// IP address of the peer
+    #[default]
+    Peer,
+
+    /// Value of the `x-dolos-api-key` header, falling back to the peer address
+    /// for requests without one. The key isn't verified, it only makes sense
+    /// behind a proxy that does.
+    ApiKey,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UpstreamConfig {
     pub peer_address: String,
@@ -128,6 +128,71 @@ impl Store {
         Ok(())
     }
 
+    /// Stores the given blocks and their txs, all in a single write
+    ///
+    /// Unlike `apply_block`, outputs aren't tracked, blocks that are already
+    /// stored are simply overwritten. Blocks in `undone` (by slot and hash)
+    /// are removed first, so the archive doesn't keep blocks that were rolled
+    /// back. This is what the WAL uses to hand over the blocks it trims.
+    pub fn archive_blocks(
+        &self,
+        blocks: &[BlockValueType],
+        undone: &[(BlockKeyType, [u8; 32])],
+    ) -> Result<(), Error> {
+        let write_tx: WriteTransaction = self.inner_store.begin_write().map_err(Error::redb)?;
+
+        {
+            let mut block_table = write_tx.open_table(BLOCK_TABLE).map_err(Error::redb)?;
+            let mut hash_index = write_tx
+                .open_table(BLOCK_BY_HASH_INDEX)
+                .map_err(Error::redb)?;
+            let mut tx_table = write_tx.open_table(TX_TABLE).map_err(Error::redb)?;
+
+            for (slot, hash) in undone {
+                hash_index.remove(hash).map_err(Error::redb)?;
+
+                let Some(body) = block_table
+                    .get(slot)
+                    .map_err(Error::redb)?
+                    .map(|x| Vec::from(x.value()))
+                else {
+                    continue;
+                };
+
+                // the slot might hold the block of the chain that replaced it
+                let block = MultiEraBlock::decode(&body).map_err(Error::BlockDecoding)?;
+
+                if block.hash().deref() != hash {
+                    continue;
+                }
+
+                for tx in block.txs() {
+                    tx_table.remove(tx.hash().deref()).map_err(Error::redb)?;
+                }
+
+                block_table.remove(slot).map_err(Error::redb)?;
+            }
+        }
+
+        {
+            let mut tx_table = write_tx.open_table(TX_TABLE).map_err(Error::redb)?;
+
+            for block_cbor in blocks {
+                let block = self.store_block(&write_tx, block_cbor)?;
+
+                for tx in block.txs() {
+                    tx_table
+                        .insert(tx.hash().deref(), tx.encode().as_slice())
+                        .map_err(Error::redb)?;
+                }
+            }
+        }
+
+        write_tx.commit().map_err(Error::redb)?;
+
+        Ok(())
+    }
+
     fn store_block<'a>(
         &self,
         write_tx: &'a WriteTransaction,
@@ -256,6 +321,105 @@ impl Store {
             .map(|entry| Vec::from(entry.1.value()))
     }
 
+    /// Returns up to `limit` blocks with a slot after the given one, in slot
+    /// order
+    pub fn get_blocks_after(
+        &self,
+        slot: BlockKeyType,
+        limit: usize,
+    ) -> Result<Vec<(u64, BlockResultType)>, Error> {
+        self.get_blocks_from(slot.saturating_add(1), u64::MAX, limit)
+    }
+
+    /// Returns up to `limit` blocks with a slot before the given one, from
+    /// the most recent backwards
+    pub fn get_blocks_before(
+        &self,
+        slot: BlockKeyType,
+        limit: usize,
+    ) -> Result<Vec<(u64, BlockResultType)>, Error> {
+        self.inner_store
+            .begin_read()
+            .map_err(Error::redb)?
+            .open_table(BLOCK_TABLE)
+            .map_err(Error::redb)?
+            .range(..slot)
+            .map_err(Error::redb)?
+            .rev()
+            .take(limit)
+            .map(|entry| {
+                let (key, value) = entry.map_err(Error::redb)?;
+                Ok((key.value(), Vec::from(value.value())))
+            })
+            .collect()
+    }
+
+    fn get_blocks_from(
+        &self,
+        slot: BlockKeyType,
+        until: BlockKeyType,
+        limit: usize,
+    ) -> Result<Vec<(u64, BlockResultType)>, Error> {
+        self.inner_store
+            .begin_read()
+            .map_err(Error::redb)?
+            .open_table(BLOCK_TABLE)
+            .map_err(Error::redb)?
+            .range(slot..=until)
+            .map_err(Error::redb)?
+            .take(limit)
+            .map(|entry| {
+                let (key, value) = entry.map_err(Error::redb)?;
+                Ok((key.value(), Vec::from(value.value())))
+            })
+            .collect()
+    }
+
+    /// Crawls the blocks in slot order, starting after the given slot
+    ///
+    /// Without a slot the crawl starts from the first block. Consumers that
+    /// build derived data from the archive pass the last slot they processed
+    /// to pick up where they left, instead of rescanning everything. Blocks
+    /// are read in pages, each page in its own read transaction.
+    pub fn crawl_chain(&self, after: Option<BlockKeyType>) -> ChainCrawl<'_> {
+        let from = match after {
+            Some(slot) => slot.checked_add(1),
+            None => Some(0),
+        };
+
+        ChainCrawl {
+            store: self,
+            from,
+            until: u64::MAX,
+            page: vec![].into_iter(),
+        }
+    }
+
+    /// Crawls the blocks within a window of slots, both ends included
+    ///
+    /// Same as `crawl_chain`, but it seeks straight to `from` and stops at
+    /// `to` (eg: to serve a page of history). An empty window yields nothing.
+    pub fn crawl_chain_range(&self, from: BlockKeyType, to: BlockKeyType) -> ChainCrawl<'_> {
+        ChainCrawl {
+            store: self,
+            from: Some(from),
+            until: to,
+            page: vec![].into_iter(),
+        }
+    }
+
+    /// Crawls the blocks from the most recent backwards
+    ///
+    /// Same as `crawl_chain`, but for consumers that show the chain newest
+    /// first (eg: an explorer), without reading the whole of it to reverse it.
+    pub fn crawl_chain_rev(&self) -> ChainCrawlRev<'_> {
+        ChainCrawlRev {
+            store: self,
+            before: Some(u64::MAX),
+            page: vec![].into_iter(),
+        }
+    }
+
     pub fn get_protocol_parameters(&self) -> Result<ProtParamsResultType, Error> {
         self.inner_store
             .begin_read()
@@ -345,6 +509,87 @@ impl Store {
     }
 }
 
+const CRAWL_PAGE_SIZE: usize = 100;
+
+/// Iterator over the blocks of the store, see `Store::crawl_chain`
+pub struct ChainCrawl<'a> {
+    store: &'a Store,
+    /// Slot where the next page starts, `None` once the end was reached
+    from: Option<u64>,
+    /// Last slot of the crawl
+    until: u64,
+    page: std::vec::IntoIter<(u64, BlockResultType)>,
+}
+
+impl Iterator for ChainCrawl<'_> {
+    type Item = Result<(u64, BlockResultType), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(block) = self.page.next() {
+            return Some(Ok(block));
+        }
+
+        let from = self.from.filter(|x| *x <= self.until)?;
+
+        let page = match self
+            .store
+            .get_blocks_from(from, self.until, CRAWL_PAGE_SIZE)
+        {
+            Ok(x) => x,
+            Err(err) => {
+                self.from = None;
+                return Some(Err(err));
+            }
+        };
+
+        self.from = match page.last() {
+            Some((slot, _)) if page.len() == CRAWL_PAGE_SIZE => slot.checked_add(1),
+            _ => None,
+        };
+
+        self.page = page.into_iter();
+        self.page.next().map(Ok)
+    }
+}
+
+/// Iterator over the blocks of the store backwards, see
+/// `Store::crawl_chain_rev`
+pub struct ChainCrawlRev<'a> {
+    store: &'a Store,
+    /// Slot where the next page ends (exclusive), `None` once the start was
+    /// reached
+    before: Option<u64>,
+    page: std::vec::IntoIter<(u64, BlockResultType)>,
+}
+
+impl Iterator for ChainCrawlRev<'_> {
+    type Item = Result<(u64, BlockResultType), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(block) = self.page.next() {
+            return Some(Ok(block));
+        }
+
+        let before = self.before?;
+
+        let page = match self.store.get_blocks_before(before, CRAWL_PAGE_SIZE) {
+            Ok(x) => x,
+            Err(err) => {
+                self.before = None;
+                return Some(Err(err));
+            }
+        };
+
+        self.before = match page.last() {
+            Some((slot, _)) if page.len() == CRAWL_PAGE_SIZE => Some(*slot),
+            _ => None,
+        };
+
+        self.page = page.into_iter();
+        self.page.next().map(Ok)
+    }
+}
+
 fn output_policy_ids(output: &MultiEraOutput) -> Vec<[u8; 28]> {
     output
         .non_ada_assets()
@@ -364,3 +609,115 @@ fn decode_output(encoded_output: &[u8]) -> Result<MultiEraOutput, DecodingError>
         .or_else(|_| MultiEraOutput::decode(Era::Alonzo, encoded_output))
         .or_else(|_| MultiEraOutput::decode(Era::Babbage, encoded_output))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::testing;
+
+    /// Derives the number of blocks and the sum of their slots, returns the
+    /// last slot it saw
+    fn derive(
+        crawl: impl Iterator<Item = Result<(u64, BlockResultType), Error>>,
+        acc: &mut (usize, u64),
+    ) -> Option<u64> {
+        let mut last = None;
+
+        for block in crawl {
+            let (slot, _) = block.unwrap();
+            acc.0 += 1;
+            acc.1 += slot;
+            last = Some(slot);
+        }
+
+        last
+    }
+
+    #[test]
+    fn test_incremental_crawl_matches_full_crawl() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("archive")).unwrap();
+
+        // enough blocks to span a few pages
+        let chain = testing::TestChainBuilder::new().extend((0..250).map(|x| x * 2));
+
+        for block in chain.blocks() {
+            store.apply_block(&block.body).unwrap();
+        }
+
+        let mut full = (0, 0);
+        derive(store.crawl_chain(None), &mut full);
+        assert_eq!(full.0, 250);
+
+        // first pass stops halfway through a page, the second resumes after it
+        let mut incremental = (0, 0);
+        let last = derive(store.crawl_chain(None).take(130), &mut incremental).unwrap();
+        assert_eq!(last, 258);
+
+        let last = derive(store.crawl_chain(Some(last)), &mut incremental);
+        assert_eq!(last, Some(498));
+        assert_eq!(incremental, full);
+
+        // nothing left after the last block
+        assert!(store.crawl_chain(last).next().is_none());
+    }
+
+    #[test]
+    fn test_reverse_crawl_goes_newest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("archive")).unwrap();
+
+        assert!(store.crawl_chain_rev().next().is_none());
+
+        // enough blocks to span a few pages
+        let chain = testing::TestChainBuilder::new().extend((0..250).map(|x| x * 2));
+
+        for block in chain.blocks() {
+            store.apply_block(&block.body).unwrap();
+        }
+
+        let slots: Vec<_> = store.crawl_chain_rev().map(|x| x.unwrap().0).collect();
+
+        assert_eq!(slots.len(), 250);
+        assert_eq!(slots.first(), Some(&498));
+        assert_eq!(slots.last(), Some(&0));
+        assert!(slots.windows(2).all(|x| x[0] > x[1]));
+    }
+
+    #[test]
+    fn test_crawl_within_slot_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("archive")).unwrap();
+
+        // a block every 10 slots
+        let chain = testing::TestChainBuilder::new().extend((0..300).map(|x| x * 10));
+
+        for block in chain.blocks() {
+            store.apply_block(&block.body).unwrap();
+        }
+
+        let window = |from, to| -> Vec<_> {
+            store
+                .crawl_chain_range(from, to)
+                .map(|x| x.unwrap().0)
+                .collect()
+        };
+
+        assert_eq!(window(30, 70), vec![30, 40, 50, 60, 70]);
+
+        // bounds between blocks
+        assert_eq!(window(25, 55), vec![30, 40, 50]);
+        assert_eq!(window(41, 49), Vec::<u64>::new());
+
+        // a window wider than a page
+        let wide = window(500, 2490);
+        assert_eq!(wide.len(), 200);
+        assert_eq!(wide.first(), Some(&500));
+        assert_eq!(wide.last(), Some(&2490));
+
+        // empty, inverted or past the tip
+        assert!(store.crawl_chain_range(70, 30).next().is_none());
+        assert!(store.crawl_chain_range(5000, 6000).next().is_none());
+        assert_eq!(window(2990, u64::MAX), vec![2990]);
+    }
+}
@@ -0,0 +1,610 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+
+use crate::querydb::store::{Error as ArchiveError, Store as Archive};
+use crate::wal::{
+    redb::WalStore, BlockHash, BlockSlot, ChainPoint, RawBlock, WalError, WalReader as _,
+};
+
+/// Storage tier that served a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Wal,
+    Archive,
+    Cache,
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchedBlock {
+    pub block: RawBlock,
+
+    /// Where the block was found, for diagnostics
+    pub tier: Tier,
+}
+
+type CacheKey = (BlockSlot, BlockHash);
+
+/// Bounded set of the most recently fetched blocks
+///
+/// Eviction is LRU, with one twist: when the caller provides a slot below
+/// which blocks are considered stale, the least recently used stale block is
+/// evicted first, so old blocks can't push out the recent ones.
+struct BlockCache {
+    capacity: usize,
+    blocks: HashMap<CacheKey, RawBlock>,
+    order: VecDeque<CacheKey>,
+}
+
+impl BlockCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        self.order.retain(|x| x != key);
+        self.order.push_back(*key);
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<RawBlock> {
+        let block = self.blocks.get(key).cloned()?;
+        self.touch(key);
+
+        Some(block)
+    }
+
+    fn put(&mut self, block: &RawBlock, stale_below: Option<BlockSlot>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = (block.slot, block.hash);
+        self.blocks.insert(key, block.clone());
+        self.touch(&key);
+
+        while self.order.len() > self.capacity {
+            let stale = stale_below
+                .and_then(|min| self.order.iter().position(|(slot, _)| *slot < min))
+                .unwrap_or(0);
+
+            if let Some(evicted) = self.order.remove(stale) {
+                self.blocks.remove(&evicted);
+            }
+        }
+    }
+}
+
+/// Reads blocks from whichever storage tier holds them
+///
+/// Tiers are checked in order: the WAL, then the archive (if any) for blocks
+/// that are not in the WAL anymore, and last the cache (if enabled) of
+/// recently fetched blocks, which can still serve a block after it was
+/// trimmed from the WAL.
+#[derive(Clone)]
+pub struct BlockFetcher {
+    wal: WalStore,
+    archive: Option<Arc<Archive>>,
+    cache: Option<Arc<Mutex<BlockCache>>>,
+    cache_ttl: Option<(BlockSlot, tokio::sync::watch::Receiver<ChainPoint>)>,
+}
+
+impl BlockFetcher {
+    pub fn new(wal: WalStore) -> Self {
+        Self {
+            wal,
+            archive: None,
+            cache: None,
+            cache_ttl: None,
+        }
+    }
+
+    pub fn set_archive(&mut self, archive: Arc<Archive>) {
+        self.archive = Some(archive);
+    }
+
+    /// Keeps up to `capacity` of the most recently fetched blocks in memory
+    pub fn enable_cache(&mut self, capacity: usize) {
+        self.cache = Some(Arc::new(Mutex::new(BlockCache::new(capacity))));
+    }
+
+    /// Evicts cached blocks further than `slots` from the tip before others
+    ///
+    /// Serving is mostly about recent blocks, this keeps a burst of reads of
+    /// old blocks (eg: a client backfilling history) from evicting them.
+    pub fn set_cache_ttl(&mut self, slots: BlockSlot) {
+        self.cache_ttl = Some((slots, self.wal.watch_tip()));
+    }
+
+    fn stale_below(&self) -> Option<BlockSlot> {
+        let (ttl, tip) = self.cache_ttl.as_ref()?;
+
+        match *tip.borrow() {
+            ChainPoint::Specific(tip, _) => Some(tip.saturating_sub(*ttl)),
+            ChainPoint::Origin => None,
+        }
+    }
+
+    fn read_archive(&self, slot: BlockSlot, hash: &BlockHash) -> Option<RawBlock> {
+        let body = self.archive.as_ref()?.get_block_from_hash(&slot)?;
+        let block = crate::wal::decode_block(&body).ok()?;
+
+        // the archive is keyed by slot, the block there might be from a fork
+        if block.hash() != *hash {
+            return None;
+        }
+
+        Some(RawBlock {
+            slot,
+            hash: *hash,
+            era: block.era(),
+            body,
+        })
+    }
+
+    /// Whether the point is a block held by the archive
+    pub fn archive_has(&self, point: &ChainPoint) -> bool {
+        match point {
+            ChainPoint::Specific(slot, hash) => self.read_archive(*slot, hash).is_some(),
+            ChainPoint::Origin => false,
+        }
+    }
+
+    /// Reads up to `limit` archived blocks with a slot after the given one
+    ///
+    /// Blocks are returned in slot order, an archive that can't be read (or
+    /// no archive at all) yields an empty page.
+    pub fn read_archive_page(&self, after: BlockSlot, limit: usize) -> Vec<RawBlock> {
+        let Some(archive) = &self.archive else {
+            return vec![];
+        };
+
+        let page = match archive.get_blocks_after(after, limit) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(%err, "can't read archived blocks");
+                return vec![];
+            }
+        };
+
+        page.into_iter()
+            .filter_map(|(slot, body)| {
+                let block = crate::wal::decode_block(&body).ok()?;
+
+                Some(RawBlock {
+                    slot,
+                    hash: block.hash(),
+                    era: block.era(),
+                    body,
+                })
+            })
+            .collect()
+    }
+
+    /// Finds the block of the live chain at a slot, `None` if there's none
+    ///
+    /// The archive is only checked for slots before the start of the WAL.
+    /// Within the WAL an empty slot really has no block, even if the archive
+    /// holds one from a fork.
+    pub fn resolve_slot(&self, slot: BlockSlot) -> Result<Option<ChainPoint>, WalError> {
+        if let Some(point) = self.wal.point_at_slot(slot)? {
+            return Ok(Some(point));
+        }
+
+        let trimmed = match self.wal.oldest_point()? {
+            Some(ChainPoint::Specific(oldest, _)) => slot < oldest,
+            Some(ChainPoint::Origin) => false,
+            None => true,
+        };
+
+        if !trimmed {
+            return Ok(None);
+        }
+
+        let point = self
+            .archive
+            .as_ref()
+            .and_then(|x| x.get_block_from_hash(&slot))
+            .and_then(|body| crate::wal::decode_block(&body).ok())
+            .map(|block| ChainPoint::Specific(slot, block.hash()));
+
+        Ok(point)
+    }
+
+    fn archive_tip(&self) -> Option<ChainPoint> {
+        let archive = self.archive.as_ref()?;
+
+        let body = match archive.get_blockchain_tip() {
+            Ok(x) => x,
+            Err(ArchiveError::KeyNotFound) => return None,
+            Err(err) => {
+                warn!(%err, "can't read archive tip");
+                return None;
+            }
+        };
+
+        let block = crate::wal::decode_block(&body).ok()?;
+
+        Some(ChainPoint::Specific(block.slot(), block.hash()))
+    }
+
+    /// Finds the tip of the chain across the WAL and the archive
+    ///
+    /// The WAL holds the tip whenever it has entries, even if the archive has
+    /// higher slots: after a rollback, the archive can still hold blocks that
+    /// aren't part of the chain anymore. The archive is only used once the
+    /// WAL is empty (eg: trimmed with `doctor trim-wal`), by then the trim has
+    /// removed the rolled back blocks from it. `None` if both are empty.
+    pub fn find_tip(&self) -> Result<Option<ChainPoint>, WalError> {
+        match self.wal.find_tip()? {
+            Some((_, x)) => Ok(Some(x)),
+            None => Ok(self.archive_tip()),
+        }
+    }
+
+    /// Reads up to `n` archived blocks that chain back from the given one
+    ///
+    /// The archive is keyed by slot and might hold blocks of forks, so each
+    /// block has to be the parent of the one after it. The walk stops at the
+    /// first one that isn't. Blocks come newest-first.
+    fn read_archive_before(&self, child: &RawBlock, n: usize) -> Vec<RawBlock> {
+        let Some(archive) = &self.archive else {
+            return vec![];
+        };
+
+        let mut parent = match child.decode() {
+            Ok(block) => block.header().previous_hash(),
+            Err(_) => return vec![],
+        };
+
+        let page = match archive.get_blocks_before(child.slot, n) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(%err, "can't read archived blocks");
+                return vec![];
+            }
+        };
+
+        page.into_iter()
+            .map_while(|(slot, body)| {
+                let block = crate::wal::decode_block(&body).ok()?;
+                let hash = block.hash();
+
+                if parent != Some(hash) {
+                    return None;
+                }
+
+                parent = block.header().previous_hash();
+
+                Some(RawBlock {
+                    slot,
+                    hash,
+                    era: block.era(),
+                    body,
+                })
+            })
+            .collect()
+    }
+
+    /// Whether the archive holds the parent of the given block
+    ///
+    /// Tells if archived blocks can be streamed right up to the start of the
+    /// WAL, without a hole in between.
+    pub fn archive_has_parent(&self, child: &RawBlock) -> bool {
+        !self.read_archive_before(child, 1).is_empty()
+    }
+
+    /// Reads the last `n` blocks of the live chain, oldest-first
+    ///
+    /// Blocks are taken from the tip of the WAL and, once the walk reaches
+    /// the start of the WAL (eg: after compaction), from the archive, so
+    /// callers get the same blocks no matter which tier holds them. Fewer
+    /// blocks are returned if neither tier holds that many.
+    pub fn recent_blocks(&self, n: u64) -> Result<Vec<RawBlock>, WalError> {
+        let n = n as usize;
+
+        let mut blocks = self.wal.recent_live_blocks(n)?;
+
+        let older = match blocks.last() {
+            Some(oldest) if blocks.len() < n => self.read_archive_before(oldest, n - blocks.len()),
+            _ => vec![],
+        };
+
+        blocks.extend(older);
+
+        blocks.reverse();
+
+        Ok(blocks)
+    }
+
+    /// Reads the block of the live chain at a slot, `None` if there's none
+    ///
+    /// Same as `resolve_slot` followed by `fetch`: blocks still in the WAL are
+    /// read from there, trimmed ones from the archive. A slot that was rolled
+    /// back within the WAL has no block, even if the archive holds one.
+    pub fn fetch_slot(&self, slot: BlockSlot) -> Result<Option<FetchedBlock>, WalError> {
+        let Some(point) = self.resolve_slot(slot)? else {
+            return Ok(None);
+        };
+
+        match self.fetch(&point) {
+            Ok(x) => Ok(Some(x)),
+            Err(WalError::PointNotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn served(&self, block: RawBlock, tier: Tier) -> FetchedBlock {
+        debug!(slot = block.slot, ?tier, "block fetched");
+
+        if let (Some(cache), Tier::Wal | Tier::Archive) = (&self.cache, tier) {
+            let stale_below = self.stale_below();
+            cache.lock().unwrap().put(&block, stale_below);
+        }
+
+        FetchedBlock { block, tier }
+    }
+
+    pub fn fetch(&self, point: &ChainPoint) -> Result<FetchedBlock, WalError> {
+        let ChainPoint::Specific(slot, hash) = point else {
+            return Err(WalError::PointNotFound(point.clone()));
+        };
+
+        match self.wal.read_block(point) {
+            Ok(block) => return Ok(self.served(block, Tier::Wal)),
+            Err(WalError::PointNotFound(_)) => (),
+            Err(err) => return Err(err),
+        }
+
+        if let Some(block) = self.read_archive(*slot, hash) {
+            return Ok(self.served(block, Tier::Archive));
+        }
+
+        if let Some(cache) = &self.cache {
+            if let Some(block) = cache.lock().unwrap().get(&(*slot, *hash)) {
+                return Ok(self.served(block, Tier::Cache));
+            }
+        }
+
+        Err(WalError::PointNotFound(point.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::{testing, WalWriter as _};
+
+    fn point_of(block: &RawBlock) -> ChainPoint {
+        ChainPoint::Specific(block.slot, block.hash)
+    }
+
+    #[test]
+    fn test_fetch_from_wal() {
+        let wal = testing::db_with_dummy_blocks(10);
+        let fetcher = BlockFetcher::new(wal);
+
+        let point = ChainPoint::Specific(5, testing::slot_to_hash(5));
+        let fetched = fetcher.fetch(&point).unwrap();
+
+        assert_eq!(fetched.tier, Tier::Wal);
+        assert_eq!(point_of(&fetched.block), point);
+
+        let missing = ChainPoint::Specific(50, testing::slot_to_hash(50));
+        assert!(matches!(
+            fetcher.fetch(&missing),
+            Err(WalError::PointNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_fetch_from_archive() {
+        let block = testing::test_data_block(0);
+        let decoded = block.decode().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive = Archive::open(dir.path().join("archive")).unwrap();
+        archive.apply_block(&block.body).unwrap();
+
+        let mut fetcher = BlockFetcher::new(testing::empty_db());
+        fetcher.set_archive(Arc::new(archive));
+
+        let point = ChainPoint::Specific(decoded.slot(), decoded.hash());
+        let fetched = fetcher.fetch(&point).unwrap();
+
+        assert_eq!(fetched.tier, Tier::Archive);
+        assert_eq!(fetched.block.body, block.body);
+
+        // same slot, different hash
+        let forked = ChainPoint::Specific(decoded.slot(), testing::slot_to_hash(0));
+        assert!(fetcher.fetch(&forked).is_err());
+    }
+
+    #[test]
+    fn test_fetch_from_cache() {
+        let mut wal = testing::db_with_dummy_blocks(10);
+
+        let mut fetcher = BlockFetcher::new(wal.clone());
+        fetcher.enable_cache(2);
+
+        let points: Vec<_> = (3..6)
+            .map(|x| ChainPoint::Specific(x, testing::slot_to_hash(x)))
+            .collect();
+
+        for point in points.iter() {
+            assert_eq!(fetcher.fetch(point).unwrap().tier, Tier::Wal);
+        }
+
+        // trim everything up to slot 5 (seq 6) from the wal
+        wal.remove_range(None, Some(6)).unwrap();
+
+        assert_eq!(fetcher.fetch(&points[1]).unwrap().tier, Tier::Cache);
+        assert_eq!(fetcher.fetch(&points[2]).unwrap().tier, Tier::Cache);
+
+        // the oldest block was evicted to stay within capacity
+        assert!(fetcher.fetch(&points[0]).is_err());
+
+        // blocks still in the wal keep being served from there
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(10)))
+            .unwrap();
+        let point = ChainPoint::Specific(10, testing::slot_to_hash(10));
+        assert_eq!(fetcher.fetch(&point).unwrap().tier, Tier::Wal);
+    }
+
+    #[test]
+    fn test_fetch_slot_from_wal_or_archive() {
+        let chain = testing::TestChainBuilder::new().extend(0..30);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive = Archive::open(dir.path().join("archive")).unwrap();
+
+        for block in chain.blocks() {
+            archive.apply_block(&block.body).unwrap();
+        }
+
+        // slots 28 and 29 are rolled back, the archive still holds them
+        wal.roll_back(&chain.point(27)).unwrap();
+
+        // compaction trimmed everything before slot 20 (seq 21)
+        wal.remove_range(None, Some(20)).unwrap();
+
+        let mut fetcher = BlockFetcher::new(wal);
+        fetcher.set_archive(Arc::new(archive));
+
+        let fetched = fetcher.fetch_slot(25).unwrap().unwrap();
+        assert_eq!(fetched.tier, Tier::Wal);
+        assert_eq!(fetched.block, chain.blocks()[25]);
+
+        let fetched = fetcher.fetch_slot(10).unwrap().unwrap();
+        assert_eq!(fetched.tier, Tier::Archive);
+        assert_eq!(fetched.block, chain.blocks()[10]);
+
+        // the live chain is the one of the wal
+        assert!(fetcher.fetch_slot(28).unwrap().is_none());
+        assert!(fetcher.fetch_slot(50).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_find_tip_across_wal_and_archive() {
+        let chain = testing::TestChainBuilder::new().extend(0..30);
+        let dir = tempfile::tempdir().unwrap();
+
+        // both empty
+        let archive = Arc::new(Archive::open(dir.path().join("archive")).unwrap());
+        let mut fetcher = BlockFetcher::new(testing::empty_db());
+        fetcher.set_archive(archive.clone());
+        assert_eq!(fetcher.find_tip().unwrap(), None);
+
+        // freshly synced, nothing archived yet
+        let mut wal = testing::empty_db();
+        wal.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        let mut fetcher = BlockFetcher::new(wal.clone());
+        fetcher.set_archive(archive.clone());
+        assert_eq!(fetcher.find_tip().unwrap(), Some(chain.tip()));
+
+        // archive behind the wal
+        for block in &chain.blocks()[..20] {
+            archive.apply_block(&block.body).unwrap();
+        }
+
+        assert_eq!(fetcher.find_tip().unwrap(), Some(chain.tip()));
+
+        // the wal rolled back past blocks that the archive still holds
+        for block in &chain.blocks()[20..] {
+            archive.apply_block(&block.body).unwrap();
+        }
+
+        wal.roll_back(&chain.point(25)).unwrap();
+        assert_eq!(fetcher.find_tip().unwrap(), Some(chain.point(25)));
+
+        // fully trimmed wal, only the archive has blocks. Trimming hands the
+        // rollback over to the archive, so the tip is still on the live chain
+        wal.archive_range(&archive, None, None).unwrap();
+        wal.remove_range(None, None).unwrap();
+        assert_eq!(fetcher.find_tip().unwrap(), Some(chain.point(25)));
+
+        // without the archive there's no tip to be found
+        let fetcher = BlockFetcher::new(wal);
+        assert_eq!(fetcher.find_tip().unwrap(), None);
+    }
+
+    #[test]
+    fn test_recent_blocks_span_wal_and_archive() {
+        let chain = testing::TestChainBuilder::new().extend(0..30);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive = Archive::open(dir.path().join("archive")).unwrap();
+
+        for block in chain.blocks() {
+            archive.apply_block(&block.body).unwrap();
+        }
+
+        // compaction trimmed everything before slot 20 (seq 21)
+        wal.remove_range(None, Some(20)).unwrap();
+
+        let slots = |blocks: Vec<RawBlock>| blocks.iter().map(|x| x.slot).collect::<Vec<_>>();
+
+        // without an archive, the walk stops at the start of the wal
+        let mut fetcher = BlockFetcher::new(wal.clone());
+        assert_eq!(
+            slots(fetcher.recent_blocks(15).unwrap()),
+            (20..30).collect::<Vec<_>>()
+        );
+
+        fetcher.set_archive(Arc::new(archive));
+
+        // within the wal, the archive isn't needed
+        assert_eq!(
+            slots(fetcher.recent_blocks(5).unwrap()),
+            (25..30).collect::<Vec<_>>()
+        );
+
+        // straddling the boundary, oldest-first across both tiers
+        let recent = fetcher.recent_blocks(15).unwrap();
+        assert_eq!(slots(recent.clone()), (15..30).collect::<Vec<_>>());
+        assert_eq!(recent, chain.blocks()[15..].to_vec());
+
+        // more than both tiers hold
+        assert_eq!(fetcher.recent_blocks(100).unwrap(), chain.blocks().to_vec());
+    }
+
+    #[test]
+    fn test_cache_ttl_keeps_recent_blocks() {
+        let mut wal = testing::db_with_dummy_blocks(50);
+
+        let mut fetcher = BlockFetcher::new(wal.clone());
+        fetcher.enable_cache(3);
+        fetcher.set_cache_ttl(10);
+
+        let recent: Vec<_> = (47..50)
+            .map(|x| ChainPoint::Specific(x, testing::slot_to_hash(x)))
+            .collect();
+
+        for point in recent.iter() {
+            fetcher.fetch(point).unwrap();
+        }
+
+        // a burst of old blocks, each one would have evicted a recent block
+        // with plain LRU
+        for slot in 0..20 {
+            let point = ChainPoint::Specific(slot, testing::slot_to_hash(slot));
+            assert_eq!(fetcher.fetch(&point).unwrap().tier, Tier::Wal);
+        }
+
+        wal.remove_range(None, None).unwrap();
+
+        for point in recent.iter() {
+            assert_eq!(fetcher.fetch(point).unwrap().tier, Tier::Cache);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Upper bounds (in ms) of the histogram buckets, the last bucket is unbounded
+const BUCKET_BOUNDS_MS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+/// Distribution of durations over fixed buckets
+///
+/// Samples are counted with atomics so that recording from concurrent
+/// requests doesn't need any locking.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKET_BOUNDS_MS.len() + 1],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    pub fn record(&self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+
+        let idx = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+
+        if count == 0 {
+            return None;
+        }
+
+        let sum = self.sum_us.load(Ordering::Relaxed);
+
+        Some(Duration::from_micros(sum / count))
+    }
+
+    /// Upper bound of the bucket holding the given quantile
+    ///
+    /// Returns `None` if there are no samples or if the quantile falls in the
+    /// unbounded bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+
+        if count == 0 {
+            return None;
+        }
+
+        let target = ((count as f64) * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+
+        for (idx, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+
+            if seen >= target {
+                return BUCKET_BOUNDS_MS
+                    .get(idx)
+                    .copied()
+                    .map(Duration::from_millis);
+            }
+        }
+
+        None
+    }
+}
+
+/// Records the elapsed time into a histogram when dropped
+pub struct Timer {
+    histogram: Arc<Histogram>,
+    start: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.histogram.record(self.start.elapsed());
+    }
+}
+
+/// Set of named latency histograms
+#[derive(Default)]
+pub struct Latency {
+    histograms: Mutex<BTreeMap<&'static str, Arc<Histogram>>>,
+}
+
+impl Latency {
+    pub fn histogram(&self, name: &'static str) -> Arc<Histogram> {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms.entry(name).or_default().clone()
+    }
+
+    pub fn timer(&self, name: &'static str) -> Timer {
+        Timer {
+            histogram: self.histogram(name),
+            start: Instant::now(),
+        }
+    }
+
+    fn report(&self) {
+        let histograms = self.histograms.lock().unwrap();
+
+        for (name, histogram) in histograms.iter() {
+            let as_ms = |x: Option<Duration>| x.map(|x| x.as_millis() as u64);
+
+            info!(
+                name,
+                count = histogram.count(),
+                mean_ms = as_ms(histogram.mean()),
+                p50_ms = as_ms(histogram.quantile(0.5)),
+                p99_ms = as_ms(histogram.quantile(0.99)),
+                "serve latency"
+            );
+        }
+    }
+}
+
+/// Logs a summary of each histogram at a fixed interval until cancelled
+pub async fn report_loop(latency: Arc<Latency>, interval: Duration, exit: CancellationToken) {
+    let mut interval = tokio::time::interval(interval);
+
+    // the first tick completes right away, there's nothing to report yet
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => latency.report(),
+            _ = exit.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_quantiles() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_millis(3));
+        }
+
+        for _ in 0..10 {
+            histogram.record(Duration::from_millis(300));
+        }
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_millis(500)));
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(32_700)));
+
+        // samples past the last bound can't be bounded
+        histogram.record(Duration::from_secs(60));
+        assert_eq!(histogram.quantile(1.0), None);
+    }
+}
@@ -1,49 +1,328 @@
+use itertools::Itertools;
 use pallas::interop::utxorpc::spec as u5c;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 use tokio_util::sync::CancellationToken;
+use tonic::codec::CompressionEncoding;
 use tonic::transport::{Certificate, Server, ServerTlsConfig};
 use tracing::info;
 
-use crate::ledger::store::LedgerStore;
-use crate::wal::redb::WalStore;
-use crate::{prelude::*, submit::Transaction};
+use crate::prelude::*;
 
+mod latency;
 mod query;
+mod quota;
 mod submit;
 mod sync;
 mod watch;
 
-#[derive(Serialize, Deserialize, Clone)]
+pub use quota::QuotaPolicy;
+pub use sync::{BatchLimits, ChainSyncServiceImpl, MappingOptions};
+
+impl From<crate::wal::DecodeError> for tonic::Status {
+    fn from(value: crate::wal::DecodeError) -> Self {
+        match value {
+            crate::wal::DecodeError::UnsupportedEra(_) => {
+                tonic::Status::failed_precondition(value.to_string())
+            }
+            crate::wal::DecodeError::InvalidCbor(_) => tonic::Status::data_loss(value.to_string()),
+        }
+    }
+}
+
+/// Runs blocking storage reads outside of the async executor
+///
+/// Reads from the WAL and ledger stores hit the disk synchronously, running
+/// them in the blocking pool keeps the executor responsive for the rest of the
+/// connections while a heavy request is being served.
+pub(crate) async fn run_blocking<T, F>(f: F) -> Result<T, tonic::Status>
+where
+    F: FnOnce() -> Result<T, tonic::Status> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| tonic::Status::internal("storage read task failed"))?
+}
+
+/// Default for the max number of intersect points accepted per request
+///
+/// Chainsync clients usually send a few dozen points sampled exponentially
+/// from their tip, so this is generous for any legitimate use.
+pub const DEFAULT_MAX_INTERSECT_POINTS: usize = 100;
+
+/// Default for the max size of a block body served to clients
+///
+/// Way above the max block body size of the protocol (90KB on mainnet at the
+/// time of writing), so it only trips on corrupt blocks.
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default for the delay that `FollowTip` clients are told to wait before
+/// reconnecting, once their stream ends in error
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_millis(1000);
+
+pub const DEFAULT_LISTEN_ADDRESS: &str = "[::]:50051";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
 pub struct Config {
     pub listen_address: String,
     pub tls_client_ca_root: Option<PathBuf>,
+
+    /// Max number of intersect points accepted in a single request
+    pub max_intersect_points: Option<usize>,
+
+    /// Codecs enabled for compressing messages, in order of preference
+    pub compression: Option<Vec<Compression>>,
+
+    /// Rollbacks deeper than this are sent to `FollowTip` clients as a single
+    /// reset instead of one undo per block
+    pub max_reorg_depth: Option<usize>,
+
+    /// Seconds without new events after which caught-up `FollowTip` streams
+    /// get a heartbeat (a response without action), to keep the stream alive
+    pub keepalive_interval: Option<u64>,
+
+    /// Seconds between reports of the request latency histograms, latencies
+    /// aren't tracked at all when not set
+    pub latency_report_interval: Option<u64>,
+
+    /// Max number of concurrent ledger reads used to resolve the inputs of a
+    /// history page
+    pub resolve_parallelism: Option<usize>,
+
+    /// Max number of threads used to decode and map the blocks of a history
+    /// page
+    pub decode_parallelism: Option<usize>,
+
+    /// Max size (in bytes) of a block body served to clients, bigger blocks
+    /// are reported as an error
+    pub max_block_size: Option<usize>,
+
+    /// Cap on the blocks that each client can get through `DumpHistory` over a
+    /// window of time, unlimited when not set
+    pub history_quota: Option<QuotaPolicy>,
+
+    /// Milliseconds that `FollowTip` clients are told to wait before
+    /// reconnecting, sent along with the status that ends their stream
+    pub reconnect_delay_ms: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_address: DEFAULT_LISTEN_ADDRESS.into(),
+            tls_client_ca_root: None,
+            max_intersect_points: None,
+            compression: None,
+            max_reorg_depth: None,
+            keepalive_interval: None,
+            latency_report_interval: None,
+            resolve_parallelism: None,
+            decode_parallelism: None,
+            max_block_size: None,
+            history_quota: None,
+            reconnect_delay_ms: None,
+        }
+    }
+}
+
+impl Config {
+    /// Checks the settings upfront, so that mistakes are reported at startup
+    /// instead of once a client hits the affected code path
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.listen_address.parse::<SocketAddr>().is_err() {
+            return Err(Error::config(format!(
+                "invalid gRPC listen address '{}', expected IP:PORT",
+                self.listen_address
+            )));
+        }
+
+        if let Some(path) = &self.tls_client_ca_root {
+            if !path.is_file() {
+                return Err(Error::config(format!(
+                    "gRPC TLS client CA root '{}' doesn't exist",
+                    path.display()
+                )));
+            }
+        }
+
+        if self.max_intersect_points == Some(0) {
+            return Err(Error::config(
+                "gRPC max_intersect_points must be greater than zero",
+            ));
+        }
+
+        if self.keepalive_interval == Some(0) {
+            return Err(Error::config(
+                "gRPC keepalive_interval must be greater than zero",
+            ));
+        }
+
+        if self.resolve_parallelism == Some(0) {
+            return Err(Error::config(
+                "gRPC resolve_parallelism must be greater than zero",
+            ));
+        }
+
+        if self.decode_parallelism == Some(0) {
+            return Err(Error::config(
+                "gRPC decode_parallelism must be greater than zero",
+            ));
+        }
+
+        if self.max_block_size == Some(0) {
+            return Err(Error::config(
+                "gRPC max_block_size must be greater than zero",
+            ));
+        }
+
+        if let Some(quota) = &self.history_quota {
+            if quota.max_blocks == 0 || quota.window_secs == 0 {
+                return Err(Error::config(
+                    "gRPC history_quota limits must be greater than zero",
+                ));
+            }
+        }
+
+        if self.latency_report_interval == Some(0) {
+            return Err(Error::config(
+                "gRPC latency_report_interval must be greater than zero",
+            ));
+        }
+
+        if let Some(codecs) = &self.compression {
+            if codecs.iter().duplicates().next().is_some() {
+                return Err(Error::config("gRPC compression codecs are repeated"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Message compression codecs supported by the gRPC endpoint
+///
+/// Requests are accepted compressed with any of the enabled codecs and
+/// responses are compressed with the first enabled codec that the client
+/// lists in its `grpc-accept-encoding` header, uncompressed otherwise.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl From<Compression> for CompressionEncoding {
+    fn from(value: Compression) -> Self {
+        match value {
+            Compression::Gzip => CompressionEncoding::Gzip,
+            Compression::Zstd => CompressionEncoding::Zstd,
+        }
+    }
 }
 
 pub async fn serve(
     config: Config,
-    wal: WalStore,
-    ledger: LedgerStore,
-    mempool: Arc<crate::submit::MempoolState>,
-    txs_out: gasket::messaging::tokio::ChannelSendAdapter<Vec<Transaction>>,
+    context: super::ServeContext,
     exit: CancellationToken,
 ) -> Result<(), Error> {
-    let addr = config.listen_address.parse().unwrap();
+    let super::ServeContext {
+        wal,
+        ledger,
+        mempool,
+        txs_out,
+        fee_filter,
+        input_check,
+        fairness,
+        archive,
+    } = context;
+
+    let addr = config.listen_address.parse().map_err(Error::config)?;
+
+    let mut sync_service = sync::ChainSyncServiceImpl::new(
+        wal.clone(),
+        ledger.clone(),
+        config
+            .max_intersect_points
+            .unwrap_or(DEFAULT_MAX_INTERSECT_POINTS),
+        config.max_reorg_depth,
+    );
+
+    if let Some(archive) = archive {
+        sync_service.set_archive(archive);
+    }
+
+    if let Some(secs) = config.keepalive_interval {
+        sync_service.set_keepalive(Duration::from_secs(secs));
+    }
+
+    if let Some(parallelism) = config.resolve_parallelism {
+        sync_service.set_resolve_parallelism(parallelism);
+    }
+
+    if let Some(parallelism) = config.decode_parallelism {
+        sync_service.set_decode_parallelism(parallelism);
+    }
+
+    if let Some(max_size) = config.max_block_size {
+        sync_service.set_max_block_size(max_size);
+    }
+
+    if let Some(policy) = &config.history_quota {
+        sync_service.set_history_quota(Arc::new(quota::Quota::new(policy.clone())));
+    }
+
+    if let Some(ms) = config.reconnect_delay_ms {
+        sync_service.set_reconnect_delay(Duration::from_millis(ms));
+    }
+
+    sync_service.set_shutdown(exit.clone());
+
+    if let Some(secs) = config.latency_report_interval {
+        let latency = Arc::new(latency::Latency::default());
+        sync_service.set_latency(latency.clone());
 
-    let sync_service = sync::ChainSyncServiceImpl::new(wal.clone(), ledger.clone());
-    let sync_service =
+        tokio::spawn(latency::report_loop(
+            latency,
+            Duration::from_secs(secs),
+            exit.clone(),
+        ));
+    }
+
+    let mut sync_service =
         u5c::sync::chain_sync_service_server::ChainSyncServiceServer::new(sync_service);
 
     let query_service = query::QueryServiceImpl::new(ledger.clone());
-    let query_service = u5c::query::query_service_server::QueryServiceServer::new(query_service);
+    let mut query_service =
+        u5c::query::query_service_server::QueryServiceServer::new(query_service);
 
     let watch_service = watch::WatchServiceImpl::new(wal.clone(), ledger.clone());
-    let watch_service = u5c::watch::watch_service_server::WatchServiceServer::new(watch_service);
+    let mut watch_service =
+        u5c::watch::watch_service_server::WatchServiceServer::new(watch_service);
 
-    let submit_service = submit::SubmitServiceImpl::new(txs_out, mempool);
     let submit_service =
+        submit::SubmitServiceImpl::new(txs_out, mempool, fee_filter, input_check, fairness);
+    let mut submit_service =
         u5c::submit::submit_service_server::SubmitServiceServer::new(submit_service);
 
+    for codec in config.compression.iter().flatten() {
+        let encoding = CompressionEncoding::from(*codec);
+
+        sync_service = sync_service
+            .accept_compressed(encoding)
+            .send_compressed(encoding);
+        query_service = query_service
+            .accept_compressed(encoding)
+            .send_compressed(encoding);
+        watch_service = watch_service
+            .accept_compressed(encoding)
+            .send_compressed(encoding);
+        submit_service = submit_service
+            .accept_compressed(encoding)
+            .send_compressed(encoding);
+    }
+
     let reflection = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(u5c::cardano::FILE_DESCRIPTOR_SET)
         .register_encoded_file_descriptor_set(u5c::sync::FILE_DESCRIPTOR_SET)
@@ -81,3 +360,326 @@ pub async fn serve(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use u5c::sync::{
+        chain_sync_service_client::ChainSyncServiceClient,
+        chain_sync_service_server::ChainSyncServiceServer,
+    };
+
+    use super::*;
+    use crate::ledger::store::LedgerStore;
+    use crate::wal::testing;
+
+    fn encoding_of<T>(response: &tonic::Response<T>) -> Option<&str> {
+        response
+            .metadata()
+            .get("grpc-encoding")
+            .map(|x| x.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_config_defaults_round_trip() {
+        let config: Config = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.listen_address, DEFAULT_LISTEN_ADDRESS);
+        assert!(config.validate().is_ok());
+
+        let json = serde_json::to_string(&config).unwrap();
+        let parsed: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, config);
+    }
+
+    #[test]
+    fn test_config_rejects_invalid_settings() {
+        let invalid = [
+            Config {
+                listen_address: "localhost".into(),
+                ..Default::default()
+            },
+            Config {
+                tls_client_ca_root: Some("./missing-ca.pem".into()),
+                ..Default::default()
+            },
+            Config {
+                max_intersect_points: Some(0),
+                ..Default::default()
+            },
+            Config {
+                keepalive_interval: Some(0),
+                ..Default::default()
+            },
+            Config {
+                latency_report_interval: Some(0),
+                ..Default::default()
+            },
+            Config {
+                decode_parallelism: Some(0),
+                ..Default::default()
+            },
+            Config {
+                compression: Some(vec![Compression::Gzip, Compression::Gzip]),
+                ..Default::default()
+            },
+        ];
+
+        for config in invalid {
+            assert!(
+                matches!(config.validate(), Err(Error::ConfigError(_))),
+                "{config:?} should be rejected"
+            );
+        }
+
+        let valid = Config {
+            listen_address: "127.0.0.1:50051".into(),
+            max_intersect_points: Some(10),
+            compression: Some(vec![Compression::Zstd, Compression::Gzip]),
+            ..Default::default()
+        };
+
+        assert!(valid.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_responses() {
+        let wal = testing::db_with_dummy_blocks(50);
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let service =
+            sync::ChainSyncServiceImpl::new(wal, ledger, DEFAULT_MAX_INTERSECT_POINTS, None);
+
+        let service = ChainSyncServiceServer::new(service)
+            .accept_compressed(CompressionEncoding::Gzip)
+            .send_compressed(CompressionEncoding::Gzip);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+
+        let incoming = async_stream::stream! {
+            loop {
+                yield listener.accept().await.map(|(x, _)| x);
+            }
+        };
+
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+
+        let request = u5c::sync::DumpHistoryRequest {
+            max_items: 20,
+            ..Default::default()
+        };
+
+        let mut client = ChainSyncServiceClient::connect(addr.clone()).await.unwrap();
+        let plain = client.dump_history(request.clone()).await.unwrap();
+        assert_eq!(encoding_of(&plain), None);
+        assert_eq!(plain.get_ref().block.len(), 20);
+
+        // the client advertises gzip, so the page goes through the codec
+        let mut client = ChainSyncServiceClient::connect(addr.clone())
+            .await
+            .unwrap()
+            .accept_compressed(CompressionEncoding::Gzip)
+            .send_compressed(CompressionEncoding::Gzip);
+
+        let compressed = client.dump_history(request.clone()).await.unwrap();
+        assert_eq!(encoding_of(&compressed), Some("gzip"));
+        assert_eq!(compressed.get_ref(), plain.get_ref());
+
+        // codecs not enabled on the server fall back to plain messages
+        let mut client = ChainSyncServiceClient::connect(addr)
+            .await
+            .unwrap()
+            .accept_compressed(CompressionEncoding::Zstd);
+
+        let fallback = client.dump_history(request).await.unwrap();
+        assert_eq!(encoding_of(&fallback), None);
+        assert_eq!(fallback.get_ref(), plain.get_ref());
+    }
+
+    /// Relays connections to `target`, reporting the bytes sent back to the
+    /// client once each connection closes
+    async fn counting_proxy(
+        target: std::net::SocketAddr,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<u64>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let (report, reports) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((mut inbound, _)) = listener.accept().await {
+                let report = report.clone();
+
+                tokio::spawn(async move {
+                    let mut outbound = tokio::net::TcpStream::connect(target).await.unwrap();
+
+                    if let Ok((_, received)) =
+                        tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await
+                    {
+                        let _ = report.send(received);
+                    }
+                });
+            }
+        });
+
+        (addr, reports)
+    }
+
+    /// Wire size of a typical history page with each codec
+    ///
+    /// Not a regular test, run it with `cargo test --release
+    /// bench_history_page_wire_size -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_history_page_wire_size() {
+        use crate::wal::WalWriter as _;
+
+        // real alonzo blocks, 21 txs each
+        let mut wal = testing::empty_db();
+        wal.roll_forward((0..100).map(testing::test_data_block))
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let service =
+            sync::ChainSyncServiceImpl::new(wal, ledger, DEFAULT_MAX_INTERSECT_POINTS, None);
+
+        let service = ChainSyncServiceServer::new(service)
+            .send_compressed(CompressionEncoding::Gzip)
+            .send_compressed(CompressionEncoding::Zstd);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+
+        let incoming = async_stream::stream! {
+            loop {
+                yield listener.accept().await.map(|(x, _)| x);
+            }
+        };
+
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+
+        let (addr, mut reports) = counting_proxy(target).await;
+
+        let request = u5c::sync::DumpHistoryRequest {
+            max_items: 100,
+            ..Default::default()
+        };
+
+        let codecs = [
+            None,
+            Some(CompressionEncoding::Gzip),
+            Some(CompressionEncoding::Zstd),
+        ];
+
+        let mut sizes = vec![];
+
+        for codec in codecs {
+            let client = ChainSyncServiceClient::connect(addr.clone()).await.unwrap();
+
+            let mut client = match codec {
+                Some(x) => client.accept_compressed(x),
+                None => client,
+            };
+
+            let start = std::time::Instant::now();
+            let page = client.dump_history(request.clone()).await.unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(page.get_ref().block.len(), 100);
+
+            // the connection closes with the last handle of the client
+            drop(client);
+            let bytes = reports.recv().await.unwrap();
+
+            sizes.push((codec, bytes, elapsed));
+        }
+
+        let plain = sizes[0].1;
+
+        for (codec, bytes, elapsed) in sizes.iter() {
+            let name = codec.map_or("none".into(), |x| format!("{x:?}").to_lowercase());
+            let ratio = *bytes as f64 / plain as f64;
+            println!("{name:>5}: {bytes:>9} bytes ({ratio:.2} of plain) in {elapsed:?}");
+        }
+
+        assert!(sizes.iter().skip(1).all(|(_, bytes, _)| *bytes < plain));
+    }
+
+    /// Follow-tip messages per second while catching up, with and without
+    /// batching
+    ///
+    /// Not a regular test, run it with `cargo test --release
+    /// bench_follow_tip_batching -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_follow_tip_batching() {
+        use futures_util::StreamExt as _;
+
+        const BLOCKS: usize = 20_000;
+
+        let wal = testing::db_with_dummy_blocks(BLOCKS);
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let service =
+            sync::ChainSyncServiceImpl::new(wal, ledger, DEFAULT_MAX_INTERSECT_POINTS, None);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+
+        let incoming = async_stream::stream! {
+            loop {
+                yield listener.accept().await.map(|(x, _)| x);
+            }
+        };
+
+        tokio::spawn(
+            Server::builder()
+                .add_service(ChainSyncServiceServer::new(service))
+                .serve_with_incoming(incoming),
+        );
+
+        let mut rates = vec![];
+
+        for batch in [None, Some("10"), Some("100")] {
+            let mut client = ChainSyncServiceClient::connect(addr.clone()).await.unwrap();
+
+            let mut request = tonic::Request::new(u5c::sync::FollowTipRequest::default());
+
+            if let Some(count) = batch {
+                request
+                    .metadata_mut()
+                    .insert("x-dolos-batch-max-count", count.parse().unwrap());
+            }
+
+            let start = std::time::Instant::now();
+            let mut stream = client.follow_tip(request).await.unwrap().into_inner();
+            let mut applied = 0;
+
+            while applied < BLOCKS {
+                let response = stream.next().await.unwrap().unwrap();
+
+                if let Some(u5c::sync::follow_tip_response::Action::Apply(_)) = response.action {
+                    applied += 1;
+                }
+            }
+
+            let elapsed = start.elapsed();
+            let rate = BLOCKS as f64 / elapsed.as_secs_f64();
+            println!("batch {:>4}: {rate:>9.0} msgs/sec", batch.unwrap_or("none"));
+
+            rates.push(rate);
+        }
+
+        assert!(rates.iter().all(|x| *x > 0.0));
+    }
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tonic::{Request, Status};
+
+use crate::prelude::QuotaKey;
+
+/// Request header with the key of the client, see `QuotaKey::ApiKey`
+pub const API_KEY_HEADER: &str = "x-dolos-api-key";
+
+/// Cap on the blocks that a single client can get through `DumpHistory`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct QuotaPolicy {
+    /// Max number of blocks served to a client within a window
+    pub max_blocks: u64,
+
+    /// Seconds after which the count of a client starts over
+    pub window_secs: u64,
+
+    #[serde(default)]
+    pub key: QuotaKey,
+}
+
+/// Identifies the client that sent the request
+///
+/// Requests without a known peer (eg: in-process clients) share a key.
+pub fn client_key<T>(key: QuotaKey, request: &Request<T>) -> String {
+    let api_key = match key {
+        QuotaKey::ApiKey => request
+            .metadata()
+            .get(API_KEY_HEADER)
+            .and_then(|x| x.to_str().ok()),
+        QuotaKey::Peer => None,
+    };
+
+    match (api_key, request.remote_addr()) {
+        (Some(key), _) => format!("key:{key}"),
+        (None, Some(addr)) => format!("peer:{}", addr.ip()),
+        (None, None) => "peer:unknown".into(),
+    }
+}
+
+struct Usage {
+    since: Instant,
+    blocks: u64,
+}
+
+/// Counts the blocks served to each client over fixed windows
+///
+/// Page caps bound the work of a single request, but nothing stops a client
+/// from crawling the whole chain one page after the other. The window of a
+/// client starts with its first request and, once it expires, the count
+/// starts over.
+pub struct Quota {
+    policy: QuotaPolicy,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl Quota {
+    pub fn new(policy: QuotaPolicy) -> Self {
+        Self {
+            policy,
+            usage: Default::default(),
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.policy.window_secs)
+    }
+
+    /// Identifies the client that sent the request
+    pub fn client_key<T>(&self, request: &Request<T>) -> String {
+        client_key(self.policy.key, request)
+    }
+
+    /// Takes up to `wanted` blocks from the quota of the client
+    ///
+    /// Returns how many were taken, which might be less than wanted when the
+    /// quota is about to run out. What isn't served in the end has to be given
+    /// back through `refund`.
+    pub fn reserve(&self, client: &str, wanted: u64, now: Instant) -> Result<u64, Status> {
+        let mut usage = self.usage.lock().unwrap();
+
+        if !usage.contains_key(client) {
+            // forget about clients whose window expired, so the map doesn't grow
+            // with every peer ever seen
+            usage.retain(|_, x| now.duration_since(x.since) < self.window());
+        }
+
+        let entry = usage.entry(client.to_string()).or_insert(Usage {
+            since: now,
+            blocks: 0,
+        });
+
+        if now.duration_since(entry.since) >= self.window() {
+            entry.since = now;
+            entry.blocks = 0;
+        }
+
+        let left = self.policy.max_blocks.saturating_sub(entry.blocks);
+
+        if left == 0 {
+            let reset = self.window() - now.duration_since(entry.since);
+
+            return Err(Status::resource_exhausted(format!(
+                "history quota of {} blocks exhausted, it resets in {} secs",
+                self.policy.max_blocks,
+                reset.as_secs().max(1)
+            )));
+        }
+
+        let taken = wanted.min(left);
+        entry.blocks += taken;
+
+        Ok(taken)
+    }
+
+    /// Gives back blocks that were reserved but not served
+    pub fn refund(&self, client: &str, blocks: u64) {
+        let mut usage = self.usage.lock().unwrap();
+
+        if let Some(entry) = usage.get_mut(client) {
+            entry.blocks = entry.blocks.saturating_sub(blocks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_resets_after_window() {
+        let quota = Quota::new(QuotaPolicy {
+            max_blocks: 10,
+            window_secs: 60,
+            key: QuotaKey::Peer,
+        });
+
+        let start = Instant::now();
+
+        assert_eq!(quota.reserve("a", 6, start).unwrap(), 6);
+        assert_eq!(quota.reserve("a", 6, start).unwrap(), 4);
+        assert!(quota.reserve("a", 1, start).is_err());
+
+        // each client has its own count
+        assert_eq!(quota.reserve("b", 6, start).unwrap(), 6);
+
+        // unserved blocks go back to the quota
+        quota.refund("a", 3);
+        assert_eq!(quota.reserve("a", 6, start).unwrap(), 3);
+
+        let later = start + Duration::from_secs(60);
+        assert_eq!(quota.reserve("a", 6, later).unwrap(), 6);
+    }
+}
@@ -1,23 +1,237 @@
-use crate::submit::{MempoolState, Transaction};
+use crate::ledger::TxoRef;
+use crate::submit::{
+    AdmissionError, FairnessError, FairnessPolicy, FeeError, InputCheck, MempoolState,
+    MinFeeFilter, Monitor, Submission, SubmitterLoad, Transaction, TxStatus,
+};
 use futures_core::Stream;
 use gasket::messaging::{tokio::ChannelSendAdapter, SendAdapter};
 use pallas::crypto::hash::Hash;
 use pallas::interop::utxorpc::spec::submit::{Stage as SubmitStage, WaitForTxResponse, *};
 use pallas::ledger::traverse::MultiEraTx;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
 use std::{pin::Pin, sync::Arc};
+use thiserror::Error;
 use tonic::{Request, Response, Status};
 use tracing::info;
 
+use super::quota::client_key;
+use super::sync::header_flag;
+
+/// Request header to opt-in into partial submissions: the valid txs of the
+/// request are admitted even if others are rejected, and the response has a
+/// ref for each tx of the request, in the same order, empty for the rejected
+/// ones
+const PARTIAL_SUBMIT_HEADER: &str = "x-dolos-partial-submit";
+
+/// Response metadata of a partial submission, a `<index>:<code>:<reason>`
+/// value for each rejected tx
+const REJECTED_TX_HEADER: &str = "x-dolos-rejected-tx";
+
+/// Why a tx of a submission was turned away
+#[derive(Debug, Error)]
+pub enum SubmitError {
+    #[error("tx has no body")]
+    Empty,
+
+    #[error("could not decode tx: {0}")]
+    Decode(#[source] pallas::ledger::traverse::Error),
+
+    #[error(transparent)]
+    Fee(#[from] FeeError),
+
+    #[error("txs interacting with plutus scripts not yet supported")]
+    PlutusUnsupported,
+
+    #[error(transparent)]
+    Admission(#[from] AdmissionError),
+
+    #[error(transparent)]
+    Fairness(#[from] FairnessError),
+}
+
+impl SubmitError {
+    /// Short name of the error, stable for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            SubmitError::Empty => "empty",
+            SubmitError::Decode(_) => "decode",
+            SubmitError::Fee(_) => "fee",
+            SubmitError::PlutusUnsupported => "plutus_unsupported",
+            SubmitError::Admission(_) => "inputs",
+            SubmitError::Fairness(_) => "fairness",
+        }
+    }
+
+    /// Errors of the node rather than of the tx, which fail the whole request
+    fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            SubmitError::Fee(FeeError::Params(_))
+                | SubmitError::Admission(AdmissionError::Ledger(_))
+        )
+    }
+
+    fn into_status(self, idx: usize) -> Status {
+        match self {
+            SubmitError::Empty => {
+                Status::invalid_argument(format!("tx at index {idx} has no body"))
+            }
+            SubmitError::Decode(e) => {
+                Status::invalid_argument(format!("could not decode tx at index {idx}: {e}"))
+            }
+            SubmitError::PlutusUnsupported => Status::invalid_argument(self.to_string()),
+            SubmitError::Fairness(e) => Status::resource_exhausted(format!("txs rejected: {e}")),
+            e if e.is_internal() => Status::internal(e.to_string()),
+            e => Status::failed_precondition(format!("tx at index {idx} rejected: {e}")),
+        }
+    }
+}
+
+/// State of the mempool that the checks of a submission run against
+struct MempoolView {
+    /// Txs tracked by the mempool, resubmitting them takes no extra room
+    tracked: HashSet<Hash<32>>,
+
+    /// Inputs spent by pending txs, empty without an input check
+    spends: HashMap<TxoRef, Hash<32>>,
+
+    load: SubmitterLoad,
+}
+
 pub struct SubmitServiceImpl {
-    channel: ChannelSendAdapter<Vec<Transaction>>,
+    channel: ChannelSendAdapter<Submission>,
     mempool: Arc<MempoolState>,
+    fee_filter: Option<Arc<MinFeeFilter>>,
+    input_check: Option<Arc<InputCheck>>,
+    fairness: Option<FairnessPolicy>,
 }
 
 impl SubmitServiceImpl {
-    pub fn new(channel: ChannelSendAdapter<Vec<Transaction>>, mempool: Arc<MempoolState>) -> Self {
-        Self { channel, mempool }
+    pub fn new(
+        channel: ChannelSendAdapter<Submission>,
+        mempool: Arc<MempoolState>,
+        fee_filter: Option<Arc<MinFeeFilter>>,
+        input_check: Option<Arc<InputCheck>>,
+        fairness: Option<FairnessPolicy>,
+    ) -> Self {
+        Self {
+            channel,
+            mempool,
+            fee_filter,
+            input_check,
+            fairness,
+        }
+    }
+}
+
+impl SubmitServiceImpl {
+    fn check_tx(
+        &self,
+        tx: AnyChainTx,
+        pending: &HashMap<TxoRef, Hash<32>>,
+    ) -> Result<Transaction, SubmitError> {
+        let Some(any_chain_tx::Type::Raw(bytes)) = tx.r#type else {
+            return Err(SubmitError::Empty);
+        };
+
+        let decoded = MultiEraTx::decode(&bytes).map_err(SubmitError::Decode)?;
+
+        if let Some(filter) = &self.fee_filter {
+            filter.check(&decoded, bytes.len())?;
+        }
+
+        // TODO: we don't phase-2 validate txs before propagating so we could
+        // propagate p2 invalid transactions resulting in collateral loss
+        if !decoded.redeemers().is_empty() {
+            return Err(SubmitError::PlutusUnsupported);
+        }
+
+        let tx = Transaction::new(
+            decoded.hash(),
+            u16::from(decoded.era()) - 1, // TODO: pallas Era is 1-indexed so maybe that is the reason this works
+            bytes.into(),
+        );
+
+        if let Some(check) = &self.input_check {
+            check.check(&tx, pending)?;
+        }
+
+        Ok(tx)
+    }
+
+    /// Copies what the checks of a submission need out of the mempool
+    ///
+    /// The checks read the ledger, which blocks, so they can't run while the
+    /// mempool lock is held: the mempool stage would be stuck meanwhile.
+    fn mempool_view(&self, monitor: &Monitor, submitter: &str) -> MempoolView {
+        MempoolView {
+            tracked: monitor.txs.keys().copied().collect(),
+            spends: match &self.input_check {
+                Some(_) => monitor.pending_spends(),
+                None => HashMap::new(),
+            },
+            load: SubmitterLoad::of(monitor, submitter),
+        }
+    }
+
+    /// Checks each tx of a request, in order
+    ///
+    /// Unless the submission is partial, the first tx that is rejected fails
+    /// the whole request. Errors of the node itself (eg: the ledger can't be
+    /// read) always do, since they say nothing about the tx.
+    fn check_txs(
+        &self,
+        txs: Vec<AnyChainTx>,
+        view: MempoolView,
+        partial: bool,
+    ) -> Result<Vec<Result<Transaction, SubmitError>>, Status> {
+        let MempoolView {
+            tracked,
+            spends: mut pending,
+            load,
+        } = view;
+
+        // txs that aren't in the mempool yet, resubmitted txs take no extra room
+        let mut new = HashSet::new();
+
+        let mut results = vec![];
+
+        for (idx, tx) in txs.into_iter().enumerate() {
+            // entries without a body were always ignored, only partial
+            // submissions report them so that refs line up with the request
+            if tx.r#type.is_none() && !partial {
+                continue;
+            }
+
+            let result = self.check_tx(tx, &pending).and_then(|tx| {
+                let is_new = !tracked.contains(&tx.hash) && !new.contains(&tx.hash);
+
+                if let (Some(policy), true) = (&self.fairness, is_new) {
+                    policy.check_load(&load, new.len() + 1)?;
+                }
+
+                if is_new {
+                    new.insert(tx.hash);
+                }
+
+                Ok(tx)
+            });
+
+            match result {
+                Ok(tx) => {
+                    if self.input_check.is_some() {
+                        pending.extend(tx.inputs().iter().map(|x| (x.clone(), tx.hash)));
+                    }
+
+                    results.push(Ok(tx));
+                }
+                Err(err) if partial && !err.is_internal() => results.push(Err(err)),
+                Err(err) => return Err(err.into_status(idx)),
+            }
+        }
+
+        Ok(results)
     }
 }
 
@@ -33,49 +247,68 @@ impl submit_service_server::SubmitService for SubmitServiceImpl {
         &self,
         request: Request<SubmitTxRequest>,
     ) -> Result<Response<SubmitTxResponse>, Status> {
+        let key = self.fairness.as_ref().map(|x| x.key).unwrap_or_default();
+        let submitter = client_key(key, &request);
+        let partial = header_flag(request.metadata(), PARTIAL_SUBMIT_HEADER);
+
         let message = request.into_inner();
 
         info!("received new grpc submit tx request: {:?}", message);
 
-        let mut received = vec![];
+        let view = {
+            let monitor = self.mempool.0.read().await;
+            self.mempool_view(&monitor, &submitter)
+        };
 
-        for (idx, tx_bytes) in message.tx.into_iter().flat_map(|x| x.r#type).enumerate() {
-            match tx_bytes {
-                any_chain_tx::Type::Raw(bytes) => {
-                    let decoded = MultiEraTx::decode(&bytes).map_err(|e| {
-                        Status::invalid_argument(
-                            format! {"could not decode tx at index {idx}: {e}"},
-                        )
-                    })?;
-
-                    let hash = decoded.hash();
-
-                    // TODO: we don't phase-2 validate txs before propagating so we could
-                    // propagate p2 invalid transactions resulting in collateral loss
-                    if !decoded.redeemers().is_empty() {
-                        return Err(Status::invalid_argument(
-                            "txs interacting with plutus scripts not yet supported",
-                        ));
-                    }
+        let results = self.check_txs(message.tx, view, partial)?;
 
-                    received.push(Transaction {
-                        hash,
-                        era: u16::from(decoded.era()) - 1, // TODO: pallas Era is 1-indexed so maybe that is the reason this works
-                        bytes: bytes.into(),
-                    })
-                }
+        let refs = results
+            .iter()
+            .map(|x| match x {
+                Ok(tx) => tx.hash.to_vec().into(),
+                Err(_) => Default::default(),
+            })
+            .collect();
+
+        let mut rejected = vec![];
+        let mut received = vec![];
+
+        for (idx, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(tx) => received.push(tx),
+                Err(err) => rejected.push((idx, err)),
             }
         }
 
-        let hashes = received.iter().map(|x| x.hash.to_vec().into()).collect();
+        if !received.is_empty() {
+            let submission = Submission {
+                submitter,
+                txs: received,
+            };
+
+            self.channel
+                .clone()
+                .send(submission.into())
+                .await
+                .map_err(|_| Status::internal("couldn't add txs to mempool"))?;
+        }
+
+        let mut response = Response::new(SubmitTxResponse { r#ref: refs });
+
+        for (idx, err) in rejected {
+            let value = format!("{idx}:{}:{err}", err.code());
 
-        self.channel
-            .clone()
-            .send(received.into())
-            .await
-            .map_err(|_| Status::internal("couldn't add txs to mempool"))?;
+            // reasons that can't go in a header still report the index and code
+            let value = value
+                .parse()
+                .or_else(|_| format!("{idx}:{}:", err.code()).parse());
 
-        Ok(Response::new(SubmitTxResponse { r#ref: hashes }))
+            if let Ok(value) = value {
+                response.metadata_mut().append(REJECTED_TX_HEADER, value);
+            }
+        }
+
+        Ok(response)
     }
 
     async fn wait_for_tx(
@@ -110,20 +343,22 @@ impl submit_service_server::SubmitService for SubmitServiceImpl {
                 for hash in tx_hashes.iter() {
                     let mempool_view = mempool.0.read().await;
 
-                    let stage = if let Some(maybe_inclusion) = mempool_view.txs.get(&(*hash).into()) {
-                        if let Some(inclusion) = maybe_inclusion {
+                    let stage = match mempool_view.status(&(*hash).into()) {
+                        Some(TxStatus::Included(inclusion)) => {
                             // TODO: spec does not have way to detail number of confirmations
                             let _confirmations = mempool_view.tip_slot - inclusion;
 
                             // tx is included on chain
                             SubmitStage::Confirmed
-                        } else {
-                            // tx has been propagated but not included on chain
-                            SubmitStage::Mempool
                         }
-                    } else {
+                        // tx has been propagated but not included on chain
+                        Some(TxStatus::Pending) => SubmitStage::Mempool,
+                        // TODO: spec does not have a stage for txs that expired
+                        Some(TxStatus::Expired) => SubmitStage::Unspecified,
+                        // TODO: spec does not have a stage for txs that were dropped
+                        Some(TxStatus::Dropped) => SubmitStage::Unspecified,
                         // tx hash provided has not been passed to propagators
-                        SubmitStage::Unspecified
+                        None => SubmitStage::Unspecified,
                     };
 
                     // if stage changed since we last informed user, send user update
@@ -159,3 +394,77 @@ impl submit_service_server::SubmitService for SubmitServiceImpl {
         todo!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use gasket::messaging::InputPort;
+    use submit_service_server::SubmitService as _;
+    use tonic::metadata::MetadataValue;
+
+    use super::*;
+
+    fn load_test_tx() -> Vec<u8> {
+        let cbor = crate::wal::testing::test_data_cbor();
+        let block = pallas::ledger::traverse::MultiEraBlock::decode(&cbor).unwrap();
+
+        let tx = block.txs().into_iter().find(|x| x.redeemers().is_empty());
+
+        tx.unwrap().encode()
+    }
+
+    fn raw_tx(bytes: Vec<u8>) -> AnyChainTx {
+        AnyChainTx {
+            r#type: Some(any_chain_tx::Type::Raw(bytes.into())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_submit_reports_each_tx() {
+        let (send, recv) = gasket::messaging::tokio::mpsc_channel(16);
+        let mut txs_in = InputPort::<Submission>::default();
+        txs_in.connect(recv);
+
+        let mempool = Arc::new(MempoolState::default());
+        let service = SubmitServiceImpl::new(send, mempool, None, None, None);
+
+        let valid = load_test_tx();
+        let hash = MultiEraTx::decode(&valid).unwrap().hash();
+
+        let batch = || SubmitTxRequest {
+            tx: vec![raw_tx(valid.clone()), raw_tx(vec![0xff; 8])],
+        };
+
+        // without the header, the invalid tx fails the whole request
+        let status = service.submit_tx(Request::new(batch())).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("index 1"));
+
+        let mut request = Request::new(batch());
+        request
+            .metadata_mut()
+            .insert(PARTIAL_SUBMIT_HEADER, MetadataValue::from_static("true"));
+
+        let response = service.submit_tx(request).await.unwrap();
+
+        let rejected: Vec<_> = response
+            .metadata()
+            .get_all(REJECTED_TX_HEADER)
+            .iter()
+            .map(|x| x.to_str().unwrap().to_string())
+            .collect();
+
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].starts_with("1:decode:"));
+
+        // a ref for each tx of the request, empty for the rejected one
+        let refs = response.into_inner().r#ref;
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].as_ref(), hash.as_ref());
+        assert!(refs[1].is_empty());
+
+        // only the valid tx made it to the mempool
+        let submission = txs_in.recv().await.unwrap().payload;
+        assert_eq!(submission.txs.len(), 1);
+        assert_eq!(submission.txs[0].hash, hash);
+    }
+}
@@ -1,164 +1,3453 @@
 use futures_core::Stream;
+use futures_util::future::FutureExt as _;
+use futures_util::stream::BoxStream;
 use futures_util::StreamExt;
 use itertools::Itertools;
 use pallas::interop::utxorpc as interop;
 use pallas::interop::utxorpc::{spec as u5c, Mapper};
+use pallas::ledger::traverse::MultiEraBlock;
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio_util::sync::CancellationToken;
+use tonic::metadata::MetadataValue;
 use tonic::{Request, Response, Status};
+use tracing::warn;
 
+use super::latency::{Latency, Timer};
+use super::quota::Quota;
 use crate::ledger;
+use crate::querydb::store::Store as Archive;
+use crate::serve::fetch::BlockFetcher;
 use crate::wal::{self, RawBlock, WalReader as _};
 
+/// Request header to opt-in into the aggregate stats of a history page
+const PAGE_STATS_HEADER: &str = "x-dolos-page-stats";
+const PAGE_TX_COUNT_HEADER: &str = "x-dolos-page-tx-count";
+const PAGE_BYTE_SIZE_HEADER: &str = "x-dolos-page-byte-size";
+
+/// Request header to opt-in into resolving the inputs that the ledger can't
+const RESOLVE_INPUTS_HEADER: &str = "x-dolos-resolve-inputs";
+
+/// Request headers to opt-out of parts of the mapped blocks, see
+/// `MappingOptions`
+const RESOLVE_DATUMS_HEADER: &str = "x-dolos-resolve-datums";
+const INCLUDE_METADATA_HEADER: &str = "x-dolos-include-metadata";
+const INCLUDE_CERTS_HEADER: &str = "x-dolos-include-certs";
+const INCLUDE_BODY_HEADER: &str = "x-dolos-include-body";
+
+/// Request header to opt-in into skipping blocks that can't be decoded when
+/// dumping history, the slots of the skipped blocks are returned as
+/// comma-separated response metadata
+const SKIP_INVALID_HEADER: &str = "x-dolos-skip-invalid";
+const SKIPPED_SLOTS_HEADER: &str = "x-dolos-skipped-slots";
+
+/// Request header (a hex-encoded address) that turns `dump_history` into the
+/// tx history of the address, see `read_address_page`. The direction of each
+/// tx of the page is returned as comma-separated `slot:tx:direction` response
+/// metadata, bounded by the page size.
+const ADDRESS_HEADER: &str = "x-dolos-address";
+const ADDRESS_TXS_HEADER: &str = "x-dolos-address-txs";
+
+/// Request header (a hex-encoded tx hash) that turns `fetch_block` into a tx
+/// lookup, see `read_tx`. The raw cbor of the tx is returned as binary
+/// response metadata.
+const TX_HASH_HEADER: &str = "x-dolos-tx-hash";
+const TX_CBOR_HEADER: &str = "x-dolos-tx-cbor-bin";
+
+// max history entries of an address page, keeps the metadata of the page
+// well below the usual header limits
+const ADDRESS_MAX_ITEMS: usize = 100;
+
+/// Error metadata with points that are known to be valid intersects
+const KNOWN_POINTS_HEADER: &str = "x-dolos-known-points";
+const KNOWN_POINTS_STEP: usize = 100;
+const KNOWN_POINTS_MAX: usize = 20;
+
+/// Metadata of the status that ends a follow_tip stream, telling the client
+/// how to reconnect: wait for the delay, then intersect at the resume point
+/// (the last one it was sent) or, if that's gone, at one of the known points
+const RESUME_POINT_HEADER: &str = "x-dolos-resume-point";
+const RETRY_AFTER_HEADER: &str = "x-dolos-retry-after-ms";
+
+/// Metadata at the start of a follow_tip stream with the heartbeat interval,
+/// only present when heartbeats are enabled. Heartbeats only start once the
+/// client is caught up, so the first response without action is always the
+/// caught-up marker and any later one is a heartbeat.
+const KEEPALIVE_HEADER: &str = "x-dolos-keepalive-ms";
+
+/// Request headers to opt-in into batching follow_tip responses, see
+/// `BatchLimits::from_metadata`
+const BATCH_COUNT_HEADER: &str = "x-dolos-batch-max-count";
+const BATCH_BYTES_HEADER: &str = "x-dolos-batch-max-bytes";
+
+// inputs below this are read in a single go, a thread isn't worth it
+const MIN_RESOLVE_CHUNK: usize = 64;
+
+// same for the blocks of a history page when decoding and mapping them
+const MIN_DECODE_CHUNK: usize = 8;
+
+/// Threads that history pages can be spread over, shared by all requests
+///
+/// Each page asks for as many threads as its parallelism settings allow, but
+/// concurrent pages can't hold more than the pool has in total. Defaults to
+/// one thread per core.
+#[derive(Clone)]
+struct PageWorkers(Arc<Semaphore>);
+
+impl PageWorkers {
+    fn new(size: usize) -> Self {
+        Self(Arc::new(Semaphore::new(size)))
+    }
+
+    /// Takes up to `wanted` threads from the pool
+    ///
+    /// Never waits for them: when other pages hold most of the threads, the
+    /// work is split over the ones left, down to just the calling thread. The
+    /// threads go back to the pool when the permit is dropped.
+    fn reserve(&self, wanted: usize) -> (usize, Option<SemaphorePermit<'_>>) {
+        (2..=wanted)
+            .rev()
+            .find_map(|n| {
+                let permit = self.0.try_acquire_many(n as u32).ok()?;
+                Some((n, Some(permit)))
+            })
+            .unwrap_or((1, None))
+    }
+}
+
+impl Default for PageWorkers {
+    fn default() -> Self {
+        Self::new(std::thread::available_parallelism().map_or(1, |x| x.get()))
+    }
+}
+
+pub(super) fn header_flag(metadata: &tonic::metadata::MetadataMap, key: &str) -> bool {
+    metadata
+        .get(key)
+        .is_some_and(|x| x.to_str().ok() == Some("true"))
+}
+
+/// Same as `header_flag`, but for flags that are on unless set to `false`
+fn header_opt_out(metadata: &tonic::metadata::MetadataMap, key: &str) -> bool {
+    !metadata
+        .get(key)
+        .is_some_and(|x| x.to_str().ok() == Some("false"))
+}
+
+/// Parts of the blocks that a client wants in the responses
+///
+/// Clients that don't need some of the parts (eg: an indexer that only looks
+/// at values) get them dropped before the response is encoded, which keeps the
+/// payload lean. Leaving out the body skips the mapping of the txs altogether.
+/// The mapper in pallas maps each tx as a whole, so the parts within a tx are
+/// still dropped after mapping it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingOptions {
+    /// Plutus datums of the outputs and of the witness sets
+    pub resolve_datums: bool,
+
+    /// Fill inputs that the ledger can't resolve with outputs of the same block
+    pub resolve_inputs: bool,
+
+    /// Auxiliary data of the txs (metadata and scripts)
+    pub include_metadata: bool,
+
+    /// Certificates of the txs
+    pub include_certs: bool,
+
+    /// The txs of the block, without them only the header (slot, hash and
+    /// height) is left
+    pub include_body: bool,
+}
+
+impl Default for MappingOptions {
+    fn default() -> Self {
+        Self {
+            resolve_datums: true,
+            resolve_inputs: false,
+            include_metadata: true,
+            include_certs: true,
+            include_body: true,
+        }
+    }
+}
+
+impl MappingOptions {
+    fn from_metadata(metadata: &tonic::metadata::MetadataMap) -> Self {
+        Self {
+            resolve_datums: header_opt_out(metadata, RESOLVE_DATUMS_HEADER),
+            resolve_inputs: header_flag(metadata, RESOLVE_INPUTS_HEADER),
+            include_metadata: header_opt_out(metadata, INCLUDE_METADATA_HEADER),
+            include_certs: header_opt_out(metadata, INCLUDE_CERTS_HEADER),
+            include_body: header_opt_out(metadata, INCLUDE_BODY_HEADER),
+        }
+    }
+
+    fn apply(&self, block: &mut u5c::cardano::Block) {
+        if !self.include_body {
+            block.body = None;
+            return;
+        }
+
+        if self.resolve_inputs {
+            resolve_missing_inputs(block);
+        }
+
+        let Some(body) = block.body.as_mut() else {
+            return;
+        };
+
+        for tx in body.tx.iter_mut() {
+            if !self.resolve_datums {
+                for output in tx.outputs.iter_mut() {
+                    output.datum = None;
+                }
+
+                if let Some(witnesses) = tx.witnesses.as_mut() {
+                    witnesses.plutus_datums.clear();
+                }
+            }
+
+            if !self.include_metadata {
+                tx.auxiliary = None;
+            }
+
+            if !self.include_certs {
+                tx.certificates.clear();
+            }
+        }
+    }
+}
+
+/// Aggregate stats of the blocks included in a `dump_history` page
+///
+/// The u5c spec doesn't have a place for these values in the response
+/// message, so they travel as response metadata and are only computed for
+/// clients that ask for them through the `x-dolos-page-stats` header.
+#[derive(Debug, Default)]
+struct PageStats {
+    tx_count: usize,
+    byte_size: usize,
+}
+
+impl PageStats {
+    fn add(&mut self, raw: &wal::RawBlock, mapped: &u5c::sync::AnyChainBlock) {
+        self.byte_size += raw.body.len();
+        self.tx_count += count_txs(mapped);
+    }
+
+    fn write_metadata<T>(&self, response: &mut Response<T>) {
+        let metadata = response.metadata_mut();
+        metadata.insert(
+            PAGE_TX_COUNT_HEADER,
+            MetadataValue::from(self.tx_count as u64),
+        );
+        metadata.insert(
+            PAGE_BYTE_SIZE_HEADER,
+            MetadataValue::from(self.byte_size as u64),
+        );
+    }
+}
+
+fn count_txs(block: &u5c::sync::AnyChainBlock) -> usize {
+    match &block.chain {
+        Some(u5c::sync::any_chain_block::Chain::Cardano(x)) => {
+            x.body.as_ref().map(|x| x.tx.len()).unwrap_or_default()
+        }
+        _ => 0,
+    }
+}
+
+/// Error for a follow_tip request where none of the intersect points is known
+///
+/// The u5c spec doesn't have a request to discover valid intersect points, so
+/// the error carries a sample of the chain in the WAL (as comma-separated
+/// `slot:hash` pairs) that the client can use to retry.
+fn intersect_not_found(wal: &wal::redb::WalStore) -> Status {
+    let mut status = Status::not_found("none of the intersect points is in the chain");
+
+    insert_known_points(&mut status, &known_points(wal));
+
+    status
+}
+
+fn known_points(wal: &wal::redb::WalStore) -> Vec<wal::ChainPoint> {
+    wal.known_points(KNOWN_POINTS_STEP, KNOWN_POINTS_MAX)
+        .unwrap_or_default()
+}
+
+fn insert_known_points(status: &mut Status, points: &[wal::ChainPoint]) {
+    let value = points
+        .iter()
+        .filter_map(|x| match x {
+            wal::ChainPoint::Specific(slot, hash) => Some(format!("{slot}:{hash}")),
+            wal::ChainPoint::Origin => None,
+        })
+        .join(",");
+
+    if let Ok(value) = value.parse() {
+        status.metadata_mut().insert(KNOWN_POINTS_HEADER, value);
+    }
+}
+
+/// Adds the reconnection hints to the status that ends a follow_tip stream
+///
+/// Without them, a client that loses its stream (eg: the node restarts) has
+/// to guess where to pick up from. The resume point is left out while the
+/// stream hasn't sent any point yet, the client's own intersect still holds.
+/// Sampling the known points reads the WAL, so it runs off the executor.
+async fn with_reconnect_hints(
+    mut status: Status,
+    resume: Option<&wal::ChainPoint>,
+    wal: &wal::redb::WalStore,
+    delay: Duration,
+) -> Status {
+    if let Some(wal::ChainPoint::Specific(slot, hash)) = resume {
+        if let Ok(value) = format!("{slot}:{hash}").parse() {
+            status.metadata_mut().insert(RESUME_POINT_HEADER, value);
+        }
+    }
+
+    status.metadata_mut().insert(
+        RETRY_AFTER_HEADER,
+        MetadataValue::from(delay.as_millis() as u64),
+    );
+
+    let wal = wal.clone();
+    let points = tokio::task::spawn_blocking(move || known_points(&wal))
+        .await
+        .unwrap_or_default();
+
+    insert_known_points(&mut status, &points);
+
+    status
+}
+
+/// Ends a follow_tip stream with the reconnection hints
+///
+/// Each item comes with the last chain point it moves the client to, and the
+/// response for it (if any). The stream stops at the first error or when the
+/// server shuts down, the status that ends it carries the hints.
+fn with_stream_end<T: Send + 'static>(
+    mut items: BoxStream<'static, (Option<wal::ChainPoint>, Option<Result<T, Status>>)>,
+    wal: wal::redb::WalStore,
+    delay: Duration,
+    shutdown: CancellationToken,
+) -> BoxStream<'static, Result<T, Status>> {
+    let stream = async_stream::stream! {
+        let mut resume = None;
+
+        loop {
+            let item = tokio::select! {
+                x = items.next() => Some(x),
+                _ = shutdown.cancelled() => None,
+            };
+
+            let Some(item) = item else {
+                let status = Status::unavailable("server is shutting down");
+                yield Err(with_reconnect_hints(status, resume.as_ref(), &wal, delay).await);
+                break;
+            };
+
+            let Some((point, response)) = item else {
+                break;
+            };
+
+            match response {
+                Some(Err(status)) => {
+                    yield Err(with_reconnect_hints(status, resume.as_ref(), &wal, delay).await);
+                    break;
+                }
+                Some(Ok(response)) => yield Ok(response),
+                None => (),
+            }
+
+            if point.is_some() {
+                resume = point;
+            }
+        }
+    };
+
+    stream.boxed()
+}
+
+/// Turns the refs of a `fetch_block` request into chain points
+///
+/// A ref without a hash stands for the block of the live chain at its slot,
+/// for clients that only know the slot (eg: after losing their cursor hash).
+/// Combined with the `x-dolos-include-body` header, it resolves a slot into a
+/// full ref without sending the block.
+fn resolve_refs(
+    fetcher: &BlockFetcher,
+    refs: Vec<u5c::sync::BlockRef>,
+) -> Result<Vec<wal::ChainPoint>, Status> {
+    refs.into_iter()
+        .map(|x| {
+            if !x.hash.is_empty() {
+                return Ok(u5c_to_chain_point(x));
+            }
+
+            fetcher
+                .resolve_slot(x.index)
+                .map_err(|_| Status::internal("can't query block"))?
+                .ok_or_else(|| Status::not_found(format!("no block at slot {}", x.index)))
+        })
+        .try_collect()
+}
+
 fn u5c_to_chain_point(block_ref: u5c::sync::BlockRef) -> wal::ChainPoint {
     wal::ChainPoint::Specific(block_ref.index, block_ref.hash.as_ref().into())
 }
 
+fn chain_point_to_u5c(point: wal::ChainPoint) -> u5c::sync::BlockRef {
+    match point {
+        wal::ChainPoint::Origin => u5c::sync::BlockRef {
+            index: 0,
+            hash: vec![].into(),
+        },
+        wal::ChainPoint::Specific(slot, hash) => u5c::sync::BlockRef {
+            index: slot,
+            hash: hash.to_vec().into(),
+        },
+    }
+}
+
 // fn raw_to_anychain2(raw: &[u8]) -> AnyChainBlock {
 //     let block = any_chain_block::Chain::Raw(Bytes::copy_from_slice(raw));
 //     AnyChainBlock { chain: Some(block) }
 // }
 
-fn raw_to_anychain(
-    mapper: &Mapper<ledger::store::LedgerStore>,
-    raw: &wal::RawBlock,
-) -> u5c::sync::AnyChainBlock {
-    let wal::RawBlock { body, .. } = raw;
-    let block = mapper.map_block_cbor(body);
+/// Fills the inputs left unresolved by the mapper with outputs of the block
+///
+/// The mapper resolves inputs through the ledger, which can't help with
+/// outputs produced and spent within the same block: while following the tip
+/// the WAL is ahead of the ledger, and once the block is finalized the ledger
+/// drops the spent outputs. Those outputs are still in the block itself, as
+/// part of an earlier tx.
+fn resolve_missing_inputs(block: &mut u5c::cardano::Block) {
+    let Some(body) = block.body.as_mut() else {
+        return;
+    };
+
+    let mut produced: HashMap<(Vec<u8>, u32), u5c::cardano::TxOutput> = HashMap::new();
+
+    for tx in body.tx.iter_mut() {
+        let inputs = tx.inputs.iter_mut().chain(tx.reference_inputs.iter_mut());
+
+        for input in inputs.filter(|x| x.as_output.is_none()) {
+            let key = (input.tx_hash.to_vec(), input.output_index);
+            input.as_output = produced.get(&key).cloned();
+        }
+
+        for (idx, output) in tx.outputs.iter().enumerate() {
+            produced.insert((tx.hash.to_vec(), idx as u32), output.clone());
+        }
+    }
+}
+
+/// Reads the utxos from the ledger with up to `parallelism` concurrent reads
+///
+/// The refs are split in even chunks and each chunk is read on its own thread
+/// (the ledger allows concurrent read transactions), as long as there are
+/// threads left in the pool. Chunks never go below `MIN_RESOLVE_CHUNK` refs, so
+/// small sets take a single read regardless. Like a failed read, a read that
+/// panics leaves its utxos unresolved.
+fn read_utxos<L>(
+    store: &L,
+    refs: &[interop::TxoRef],
+    parallelism: usize,
+    pool: &PageWorkers,
+) -> interop::UtxoMap
+where
+    L: interop::LedgerContext + Sync,
+{
+    let chunks = refs.len().div_ceil(MIN_RESOLVE_CHUNK).min(parallelism);
+    let (workers, _permit) = pool.reserve(chunks);
+    let chunk = refs.len().div_ceil(workers).max(MIN_RESOLVE_CHUNK);
+
+    if chunk >= refs.len() {
+        return store.get_utxos(refs).unwrap_or_default();
+    }
+
+    std::thread::scope(|scope| {
+        let reads: Vec<_> = refs
+            .chunks(chunk)
+            .map(|x| scope.spawn(move || store.get_utxos(x).unwrap_or_default()))
+            .collect();
+
+        reads
+            .into_iter()
+            .flat_map(|x| x.join().unwrap_or_default())
+            .collect()
+    })
+}
+
+/// Runs `f` over the items with up to `parallelism` threads, keeping their order
+///
+/// Same split as `read_utxos`: even chunks of at least `min_chunk` items, each
+/// one on its own thread as long as there are threads left in the pool, so
+/// small sets run on the calling thread. It's meant for CPU work that already
+/// runs in the blocking pool, the caller waits for all of the threads. A
+/// thread that panics fails the whole run.
+fn map_chunked<'a, T, R, F>(
+    items: &'a [T],
+    parallelism: usize,
+    min_chunk: usize,
+    pool: &PageWorkers,
+    f: F,
+) -> Result<Vec<R>, Status>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&'a T) -> R + Sync,
+{
+    let chunks = items.len().div_ceil(min_chunk).min(parallelism);
+    let (workers, _permit) = pool.reserve(chunks);
+    let chunk = items.len().div_ceil(workers).max(min_chunk);
+
+    if chunk >= items.len() {
+        return Ok(items.iter().map(f).collect());
+    }
+
+    std::thread::scope(|scope| {
+        let f = &f;
+
+        let runs: Vec<_> = items
+            .chunks(chunk)
+            .map(|x| scope.spawn(move || x.iter().map(f).collect::<Vec<_>>()))
+            .collect();
+
+        runs.into_iter()
+            .map(|x| {
+                x.join()
+                    .map_err(|_| Status::internal("page worker panicked"))
+            })
+            .flatten_ok()
+            .collect()
+    })
+}
+
+/// Ledger context shared by all the blocks of a history page
+///
+/// The mapper asks the ledger for the inputs of each tx on its own, which
+/// adds up to many ledger reads for a page. Instead, the inputs of the whole
+/// page are gathered up front: the ones produced by a tx of the page are
+/// resolved from the page itself and the rest are read from the ledger at
+/// once, so the page takes a single ledger read (or one per chunk, when reads
+/// are spread over several threads).
+#[derive(Clone)]
+struct PageContext(Arc<interop::UtxoMap>);
+
+impl PageContext {
+    fn load<'a, 'b: 'a, L>(
+        store: &L,
+        blocks: impl IntoIterator<Item = &'a MultiEraBlock<'b>>,
+        parallelism: usize,
+        pool: &PageWorkers,
+    ) -> Self
+    where
+        L: interop::LedgerContext + Sync,
+    {
+        let mut utxos = interop::UtxoMap::new();
+        let mut consumed = HashSet::new();
+
+        for tx in blocks.into_iter().flat_map(|x| x.txs()) {
+            for input in tx.consumes().iter().chain(tx.reference_inputs().iter()) {
+                consumed.insert((*input.hash(), input.index() as u32));
+            }
+
+            for (idx, output) in tx.produces() {
+                let output = ledger::EraCbor::from(output);
+                utxos.insert((tx.hash(), idx as u32), output.into());
+            }
+        }
+
+        let missing: Vec<_> = consumed
+            .into_iter()
+            .filter(|x| !utxos.contains_key(x))
+            .collect();
+
+        if !missing.is_empty() {
+            utxos.extend(read_utxos(store, &missing, parallelism, pool));
+        }
+
+        Self(Arc::new(utxos))
+    }
+}
+
+impl interop::LedgerContext for PageContext {
+    fn get_utxos<'a>(&self, refs: &[interop::TxoRef]) -> Option<interop::UtxoMap> {
+        let some = refs
+            .iter()
+            .filter_map(|x| self.0.get(x).map(|utxo| (*x, utxo.clone())))
+            .collect();
+
+        Some(some)
+    }
+}
+
+fn block_to_anychain<C>(
+    mapper: &Mapper<C>,
+    block: &MultiEraBlock,
+    options: MappingOptions,
+) -> u5c::sync::AnyChainBlock
+where
+    C: interop::LedgerContext,
+{
+    // without the body only the header is left, none of the txs get mapped
+    let mut block = match options.include_body {
+        true => mapper.map_block(block),
+        false => u5c::cardano::Block {
+            header: Some(u5c::cardano::BlockHeader {
+                slot: block.slot(),
+                hash: block.hash().to_vec().into(),
+                height: block.number(),
+            }),
+            body: None,
+        },
+    };
+
+    options.apply(&mut block);
+
+    u5c::sync::AnyChainBlock {
+        chain: u5c::sync::any_chain_block::Chain::Cardano(block).into(),
+    }
+}
+
+/// Undone block whose body was stripped (see `WalStore::strip_undo_bodies`)
+///
+/// Only the slot and hash are left, which is what a client needs to find the
+/// block to undo. The height isn't kept along with them and goes out as zero.
+fn stripped_to_anychain(raw: &wal::RawBlock) -> u5c::sync::AnyChainBlock {
+    let block = u5c::cardano::Block {
+        header: Some(u5c::cardano::BlockHeader {
+            slot: raw.slot,
+            hash: raw.hash.to_vec().into(),
+            height: 0,
+        }),
+        body: None,
+    };
 
     u5c::sync::AnyChainBlock {
         chain: u5c::sync::any_chain_block::Chain::Cardano(block).into(),
     }
 }
 
+/// Refuses to serve blocks with a body bigger than `max_size`
+///
+/// Block bodies are capped by the protocol, one far above that cap can only
+/// come from corrupt storage. It's reported to the client as lost data before
+/// decoding, instead of allocating whatever the body claims to hold.
+fn check_block_size(raw: &wal::RawBlock, max_size: usize) -> Result<(), Status> {
+    if raw.body.len() > max_size {
+        return Err(block_too_large(raw.slot, raw.body.len(), max_size));
+    }
+
+    Ok(())
+}
+
+fn block_too_large(slot: wal::BlockSlot, size: usize, max_size: usize) -> Status {
+    Status::data_loss(format!(
+        "block at slot {slot} has {size} bytes, over the max of {max_size}"
+    ))
+}
+
+fn raw_to_anychain(
+    mapper: &Mapper<ledger::store::LedgerStore>,
+    raw: &wal::RawBlock,
+    options: MappingOptions,
+    max_size: usize,
+) -> Result<u5c::sync::AnyChainBlock, Status> {
+    check_block_size(raw, max_size)?;
+
+    let block = raw.decode()?;
+
+    Ok(block_to_anychain(mapper, &block, options))
+}
+
 fn roll_to_tip_response(
     mapper: &Mapper<ledger::store::LedgerStore>,
     log: &wal::LogValue,
-) -> u5c::sync::FollowTipResponse {
-    u5c::sync::FollowTipResponse {
-        action: match log {
-            wal::LogValue::Apply(x) => {
-                u5c::sync::follow_tip_response::Action::Apply(raw_to_anychain(mapper, x)).into()
-            }
-            wal::LogValue::Undo(x) => {
-                u5c::sync::follow_tip_response::Action::Undo(raw_to_anychain(mapper, x)).into()
+    options: MappingOptions,
+    max_size: usize,
+) -> Result<Option<u5c::sync::FollowTipResponse>, Status> {
+    let action = match log {
+        wal::LogValue::Apply(x) => {
+            let block = raw_to_anychain(mapper, x, options, max_size)?;
+            u5c::sync::follow_tip_response::Action::Apply(block)
+        }
+        wal::LogValue::Undo(x) if x.body.is_empty() => {
+            u5c::sync::follow_tip_response::Action::Undo(stripped_to_anychain(x))
+        }
+        wal::LogValue::Undo(x) => {
+            let block = raw_to_anychain(mapper, x, options, max_size)?;
+            u5c::sync::follow_tip_response::Action::Undo(block)
+        }
+        // TODO: shouldn't we have a u5c event for origin?
+        wal::LogValue::Mark(..) => return Ok(None),
+    };
+
+    Ok(Some(u5c::sync::FollowTipResponse {
+        action: Some(action),
+    }))
+}
+
+/// How long a follow_tip stream waits for new WAL entries before we consider
+/// it caught up with the tip of the chain
+const CATCH_UP_IDLE: Duration = Duration::from_secs(5);
+
+enum TipEvent {
+    Log(wal::LogEntry),
+    CaughtUp,
+    Reset(wal::ChainPoint),
+    Archived(RawBlock),
+    /// Heartbeat on a quiet stream, see `with_keepalive`
+    Keepalive,
+}
+
+/// Decorates a WAL stream with a one-time "caught up" event
+///
+/// The heuristic is based on the ingestion rate of the WAL: while the node is
+/// catching up, blocks arrive in bulk and the stream always has a new entry
+/// ready, much faster than real time. Once at the tip, new blocks only show up
+/// every few seconds. So, the first time that the stream drains the WAL and
+/// needs to wait longer than `idle` for the next entry, we emit the marker.
+fn with_catch_up<S>(inner: S, idle: Duration) -> impl Stream<Item = TipEvent>
+where
+    S: Stream<Item = wal::LogEntry> + Send,
+{
+    async_stream::stream! {
+        let mut inner = Box::pin(inner);
+        let mut caught_up = false;
+
+        loop {
+            let next = if caught_up {
+                inner.next().await
+            } else {
+                match tokio::time::timeout(idle, inner.next()).await {
+                    Ok(x) => x,
+                    Err(_) => {
+                        caught_up = true;
+                        yield TipEvent::CaughtUp;
+                        continue;
+                    }
+                }
+            };
+
+            match next {
+                Some(x) => yield TipEvent::Log(x),
+                None => break,
             }
-            // TODO: shouldn't we have a u5c event for origin?
-            wal::LogValue::Mark(..) => None,
-        },
+        }
     }
 }
 
-pub struct ChainSyncServiceImpl {
-    wal: wal::redb::WalStore,
-    mapper: interop::Mapper<ledger::store::LedgerStore>,
+/// Point where the client stands once it has processed the event
+///
+/// After an undo the client is at the previous block, which isn't known until
+/// the mark that closes the rollback shows up.
+fn event_point(event: &TipEvent) -> Option<wal::ChainPoint> {
+    match event {
+        TipEvent::Log((_, wal::LogValue::Apply(x))) => Some(x.into()),
+        TipEvent::Log((_, wal::LogValue::Mark(x))) => Some(x.clone()),
+        TipEvent::Archived(x) => Some(x.into()),
+        TipEvent::Reset(x) => Some(x.clone()),
+        _ => None,
+    }
 }
 
-impl ChainSyncServiceImpl {
-    pub fn new(wal: wal::redb::WalStore, ledger: ledger::store::LedgerStore) -> Self {
-        Self {
-            wal,
-            mapper: Mapper::new(ledger),
+/// Emits a heartbeat each time the stream stays idle after catching up
+///
+/// Some proxies close connections that carry no application data for a
+/// while, even with HTTP/2 keepalives in place. The heartbeat doesn't move
+/// the client but keeps data flowing on a quiet chain. It maps to a response
+/// without action, like the caught-up marker, so it's held back until that
+/// marker went out: a stream that is still catching up isn't idle for long,
+/// and the client can tell both apart by their order.
+fn with_keepalive<S>(inner: S, interval: Duration) -> impl Stream<Item = TipEvent>
+where
+    S: Stream<Item = TipEvent> + Send,
+{
+    async_stream::stream! {
+        let mut inner = Box::pin(inner);
+        let mut caught_up = false;
+
+        loop {
+            let next = if caught_up {
+                match tokio::time::timeout(interval, inner.next()).await {
+                    Ok(x) => x,
+                    Err(_) => {
+                        yield TipEvent::Keepalive;
+                        continue;
+                    }
+                }
+            } else {
+                inner.next().await
+            };
+
+            match next {
+                Some(x) => {
+                    caught_up |= matches!(x, TipEvent::CaughtUp);
+                    yield x;
+                }
+                None => break,
+            }
         }
     }
 }
 
-#[async_trait::async_trait]
-impl u5c::sync::chain_sync_service_server::ChainSyncService for ChainSyncServiceImpl {
-    type FollowTipStream =
-        Pin<Box<dyn Stream<Item = Result<u5c::sync::FollowTipResponse, Status>> + Send + 'static>>;
+/// Archived blocks to stream ahead of the WAL
+///
+/// Used when the intersect was trimmed from the WAL but is still held by the
+/// archive. Covers the blocks after the intersect and before the first block
+/// in the WAL (if any).
+struct ArchiveRange {
+    after: wal::BlockSlot,
+    until: Option<wal::BlockSlot>,
+}
 
-    async fn fetch_block(
-        &self,
-        request: Request<u5c::sync::FetchBlockRequest>,
-    ) -> Result<Response<u5c::sync::FetchBlockResponse>, Status> {
-        let message = request.into_inner();
+const ARCHIVE_PAGE_SIZE: usize = 100;
 
-        let points: Vec<_> = message.r#ref.into_iter().map(u5c_to_chain_point).collect();
+/// Streams the blocks of an archive range as apply events
+///
+/// There are no undos for archived blocks: only blocks past the rollback
+/// window are trimmed from the WAL, so nothing in the range can be rolled back
+/// anymore.
+fn archive_stream(fetcher: BlockFetcher, range: ArchiveRange) -> impl Stream<Item = TipEvent> {
+    async_stream::stream! {
+        let mut last = range.after;
 
-        let out = self
-            .wal
-            .read_sparse_blocks(&points)
-            .map_err(|_err| Status::internal("can't query block"))?
-            .into_iter()
-            .map(|x| raw_to_anychain(&self.mapper, &x))
-            .collect();
+        'pages: loop {
+            let page = fetcher.read_archive_page(last, ARCHIVE_PAGE_SIZE);
 
-        let response = u5c::sync::FetchBlockResponse { block: out };
+            if page.is_empty() {
+                break;
+            }
 
-        Ok(Response::new(response))
+            for block in page {
+                if range.until.is_some_and(|x| block.slot >= x) {
+                    break 'pages;
+                }
+
+                last = block.slot;
+                yield TipEvent::Archived(block);
+            }
+        }
     }
+}
 
-    async fn dump_history(
-        &self,
-        request: Request<u5c::sync::DumpHistoryRequest>,
-    ) -> Result<Response<u5c::sync::DumpHistoryResponse>, Status> {
-        let msg = request.into_inner();
+/// Collapses rollbacks deeper than `max_depth` into a single reset event
+///
+/// A rollback is written to the WAL as a run of undos followed by a mark of
+/// the point where the chain went back to, all in the same commit. Undos are
+/// held until the mark shows up, then either streamed as they are or, if
+/// there are too many of them, replaced by a reset to the marked point.
+fn with_reorg_limit<S>(inner: S, max_depth: usize) -> impl Stream<Item = TipEvent>
+where
+    S: Stream<Item = TipEvent> + Send,
+{
+    async_stream::stream! {
+        let mut inner = Box::pin(inner);
+        let mut undos = vec![];
 
-        let from = msg.start_token.map(u5c_to_chain_point);
+        while let Some(event) = inner.next().await {
+            match event {
+                TipEvent::Log((seq, wal::LogValue::Undo(x))) => {
+                    undos.push((seq, wal::LogValue::Undo(x)));
+                }
+                TipEvent::Log((_, wal::LogValue::Mark(point))) if undos.len() > max_depth => {
+                    undos.clear();
+                    yield TipEvent::Reset(point);
+                }
+                other => {
+                    for undo in undos.drain(..) {
+                        yield TipEvent::Log(undo);
+                    }
 
-        let len = msg.max_items as usize + 1;
+                    yield other;
+                }
+            }
+        }
+    }
+}
 
-        let mut page = self
-            .wal
-            .read_block_page(from.as_ref(), len)
-            .map_err(|_err| Status::internal("can't query block"))?
-            .collect_vec();
+/// Caps on the blocks grouped in a single follow_tip batch
+#[derive(Debug, Clone, Copy)]
+pub struct BatchLimits {
+    /// Max number of events in a batch
+    pub max_count: usize,
 
-        let next_token = if page.len() == len {
-            let RawBlock { slot, hash, .. } = page.remove(len - 1);
+    /// Max sum of the (raw) block body sizes in a batch
+    pub max_bytes: usize,
+}
 
-            Some(u5c::sync::BlockRef {
-                index: slot,
-                hash: hash.to_vec().into(),
-            })
-        } else {
-            None
+impl Default for BatchLimits {
+    fn default() -> Self {
+        Self {
+            max_count: 100,
+            max_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl BatchLimits {
+    /// Limits asked for by a gRPC client, `None` if it didn't opt-in
+    ///
+    /// Batching is enabled by a max count greater than one, the max bytes are
+    /// optional and fall back to the default. Values that aren't numbers are
+    /// ignored, same as a missing header.
+    fn from_metadata(metadata: &tonic::metadata::MetadataMap) -> Option<Self> {
+        let read = |key| {
+            metadata
+                .get(key)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.parse::<usize>().ok())
         };
 
-        let blocks = page
-            .into_iter()
-            .map(|x| raw_to_anychain(&self.mapper, &x))
-            .collect();
+        let max_count = read(BATCH_COUNT_HEADER).filter(|x| *x > 1)?;
 
-        let response = u5c::sync::DumpHistoryResponse {
-            block: blocks,
-            next_token,
-        };
+        Some(Self {
+            max_count,
+            max_bytes: read(BATCH_BYTES_HEADER).unwrap_or(Self::default().max_bytes),
+        })
+    }
+}
 
-        Ok(Response::new(response))
+/// Size of the block carried by events that can be batched
+fn batchable_size(event: &TipEvent) -> Option<usize> {
+    match event {
+        TipEvent::Log((_, wal::LogValue::Apply(x))) => Some(x.body.len()),
+        TipEvent::Archived(x) => Some(x.body.len()),
+        _ => None,
     }
+}
 
-    async fn follow_tip(
-        &self,
-        request: Request<u5c::sync::FollowTipRequest>,
-    ) -> Result<Response<Self::FollowTipStream>, tonic::Status> {
-        let request = request.into_inner();
+/// Groups consecutive apply events that are ready to be sent
+///
+/// A batch is never held back waiting for more events: it takes whatever the
+/// inner stream has ready right away, up to the limits. While catching up
+/// there's always a backlog and batches fill up, near the tip blocks trickle
+/// in one at a time and go out as soon as they arrive. Undos, resets and
+/// markers are always sent on their own. A block bigger than `max_bytes` is
+/// sent alone.
+fn with_batching<S>(inner: S, limits: BatchLimits) -> impl Stream<Item = Vec<TipEvent>>
+where
+    S: Stream<Item = TipEvent> + Send,
+{
+    async_stream::stream! {
+        let mut inner = Box::pin(inner);
+        let mut pending = None;
 
-        let from_seq = if request.intersect.is_empty() {
-            self.wal
-                .find_tip()
-                .map_err(|_err| Status::internal("can't read WAL"))?
-                .map(|(x, _)| x)
-                .unwrap_or_default()
-        } else {
+        loop {
+            let first = match pending.take() {
+                Some(x) => x,
+                None => match inner.next().await {
+                    Some(x) => x,
+                    None => break,
+                },
+            };
+
+            let Some(mut bytes) = batchable_size(&first) else {
+                yield vec![first];
+                continue;
+            };
+
+            let mut batch = vec![first];
+
+            while batch.len() < limits.max_count {
+                let Some(next) = inner.next().now_or_never().flatten() else {
+                    break;
+                };
+
+                match batchable_size(&next) {
+                    Some(size) if bytes + size <= limits.max_bytes => {
+                        bytes += size;
+                        batch.push(next);
+                    }
+                    _ => {
+                        pending = Some(next);
+                        break;
+                    }
+                }
+            }
+
+            yield batch;
+        }
+    }
+}
+
+/// Maps a tip event into the response sent to the client, if any
+fn tip_event_response(
+    mapper: &Mapper<ledger::store::LedgerStore>,
+    event: TipEvent,
+    options: MappingOptions,
+    max_size: usize,
+) -> Option<Result<u5c::sync::FollowTipResponse, Status>> {
+    match event {
+        TipEvent::Log((_, log)) => {
+            roll_to_tip_response(mapper, &log, options, max_size).transpose()
+        }
+        // a response without action tells the client that it reached the tip
+        // the first time, and that the stream is still alive after that
+        TipEvent::CaughtUp | TipEvent::Keepalive => {
+            Some(Ok(u5c::sync::FollowTipResponse { action: None }))
+        }
+        TipEvent::Archived(block) => {
+            let log = wal::LogValue::Apply(block);
+            roll_to_tip_response(mapper, &log, options, max_size).transpose()
+        }
+        TipEvent::Reset(point) => Some(Ok(u5c::sync::FollowTipResponse {
+            action: Some(u5c::sync::follow_tip_response::Action::Reset(
+                chain_point_to_u5c(point),
+            )),
+        })),
+    }
+}
+
+fn fetch_blocks(
+    fetcher: &BlockFetcher,
+    mapper: &Mapper<ledger::store::LedgerStore>,
+    points: &[wal::ChainPoint],
+    options: MappingOptions,
+    max_size: usize,
+) -> Result<Vec<u5c::sync::AnyChainBlock>, Status> {
+    points
+        .iter()
+        .map(|point| {
+            let fetched = fetcher.fetch(point).map_err(|err| match err {
+                wal::WalError::CorruptBlock(_) => Status::data_loss(err.to_string()),
+                _ => Status::internal("can't query block"),
+            })?;
+
+            raw_to_anychain(mapper, &fetched.block, options, max_size)
+        })
+        .try_collect()
+}
+
+/// A `dump_history` response, its stats and the slots of the skipped blocks
+type HistoryPage = (
+    u5c::sync::DumpHistoryResponse,
+    PageStats,
+    Vec<wal::BlockSlot>,
+);
+
+/// How `read_history_page` reads, maps and reports the blocks of a page
+#[derive(Clone)]
+struct PageOptions {
+    /// Report the aggregate stats of the page
+    with_stats: bool,
+
+    mapping: MappingOptions,
+
+    /// Leave out the blocks that can't be decoded instead of failing the page
+    skip_invalid: bool,
+
+    /// Max concurrent ledger reads to resolve the inputs, see `read_utxos`
+    resolve_parallelism: usize,
+
+    /// Max threads to decode and map the blocks, see `map_chunked`
+    decode_parallelism: usize,
+
+    /// Max body size of a block, see `check_block_size`
+    max_size: usize,
+
+    /// Threads the parallelism settings take from
+    workers: PageWorkers,
+}
+
+impl Default for PageOptions {
+    fn default() -> Self {
+        Self {
+            with_stats: false,
+            mapping: Default::default(),
+            skip_invalid: false,
+            resolve_parallelism: 1,
+            decode_parallelism: 1,
+            max_size: super::DEFAULT_MAX_BLOCK_SIZE,
+            workers: Default::default(),
+        }
+    }
+}
+
+fn read_history_page<L>(
+    wal: &wal::redb::WalStore,
+    ledger: &L,
+    from: Option<&wal::ChainPoint>,
+    max_items: usize,
+    options: &PageOptions,
+) -> Result<HistoryPage, Status>
+where
+    L: interop::LedgerContext + Sync,
+{
+    let PageOptions {
+        with_stats,
+        mapping,
+        skip_invalid,
+        resolve_parallelism,
+        decode_parallelism,
+        max_size,
+        ref workers,
+    } = *options;
+
+    let len = max_items + 1;
+
+    let mut page = wal
+        .read_block_page_within(from, len, max_size)
+        .map_err(|_err| Status::internal("can't query block"))?;
+
+    let next_token = if page.len() == len {
+        let (slot, hash) = match page.remove(len - 1) {
+            Ok(x) => (x.slot, x.hash),
+            Err(x) => (x.slot, x.hash),
+        };
+
+        Some(u5c::sync::BlockRef {
+            index: slot,
+            hash: hash.to_vec().into(),
+        })
+    } else {
+        None
+    };
+
+    let mut skipped = vec![];
+
+    // oversized bodies were never loaded, they're reported like the blocks
+    // that fail to decode
+    let page: Vec<_> = page
+        .into_iter()
+        .filter_map(|x| match x {
+            Ok(raw) => Some(Ok(raw)),
+            Err(x) if skip_invalid => {
+                warn!(slot = x.slot, size = x.size, "skipping oversized block");
+                skipped.push(x.slot);
+                None
+            }
+            Err(x) => Some(Err(block_too_large(x.slot, x.size, max_size))),
+        })
+        .try_collect()?;
+
+    // decoding and mapping are spread over threads, the ledger context sits in
+    // between since it needs the txs of the whole page
+    let decoded = map_chunked(
+        &page,
+        decode_parallelism,
+        MIN_DECODE_CHUNK,
+        workers,
+        RawBlock::decode,
+    )?;
+
+    let decoded: Vec<_> = page.iter().zip(decoded).collect();
+
+    let blocks = decoded.iter().filter_map(|(_, x)| x.as_ref().ok());
+    let context = PageContext::load(ledger, blocks, resolve_parallelism, workers);
+    let mapper = Mapper::new(context);
+
+    let mapped = map_chunked(
+        &decoded,
+        decode_parallelism,
+        MIN_DECODE_CHUNK,
+        workers,
+        |(_, x)| {
+            x.as_ref()
+                .ok()
+                .map(|x| block_to_anychain(&mapper, x, mapping))
+        },
+    )?;
+
+    let mut stats = PageStats::default();
+    let mut blocks = Vec::with_capacity(page.len());
+
+    // the next token comes from the raw page, so skipped blocks don't shift it
+    for ((raw, decoded), block) in decoded.into_iter().zip(mapped) {
+        let block = match (block, decoded) {
+            (Some(x), _) => x,
+            (None, Err(err)) if skip_invalid => {
+                warn!(slot = raw.slot, %err, "skipping undecodable block");
+                skipped.push(raw.slot);
+                continue;
+            }
+            (None, Err(err)) => return Err(err.into()),
+            (None, Ok(_)) => unreachable!("every decoded block is mapped"),
+        };
+
+        if with_stats {
+            stats.add(raw, &block);
+        }
+
+        blocks.push(block);
+    }
+
+    // oversized and undecodable blocks were set aside at different steps
+    skipped.sort_unstable();
+
+    let response = u5c::sync::DumpHistoryResponse {
+        block: blocks,
+        next_token,
+    };
+
+    Ok((response, stats, skipped))
+}
+
+fn tx_hash_from_metadata(
+    metadata: &tonic::metadata::MetadataMap,
+) -> Result<Option<wal::TxHash>, Status> {
+    let Some(value) = metadata.get(TX_HASH_HEADER) else {
+        return Ok(None);
+    };
+
+    let hash = value
+        .to_str()
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| Status::invalid_argument("tx hash header isn't a valid hash"))?;
+
+    Ok(Some(hash))
+}
+
+/// Reads a tx of the chain by its hash, along with the block that has it
+///
+/// Builds on the tx index of the WAL, so rolled-back txs aren't found. The
+/// block only holds the requested tx in its body. The raw cbor comes from
+/// `WalStore::get_tx`, re-encoded but with the same hash.
+fn read_tx(
+    wal: &wal::redb::WalStore,
+    fetcher: &BlockFetcher,
+    mapper: &Mapper<ledger::store::LedgerStore>,
+    hash: &wal::TxHash,
+    options: MappingOptions,
+    max_size: usize,
+) -> Result<(Vec<u5c::sync::AnyChainBlock>, Vec<u8>), Status> {
+    let not_found = || Status::not_found("tx isn't in the chain");
+
+    let point = wal
+        .locate_tx(hash)
+        .map_err(|_| Status::internal("can't query tx"))?
+        .ok_or_else(not_found)?;
+
+    let cbor = wal
+        .get_tx(hash)
+        .map_err(|_| Status::internal("can't query tx"))?
+        .ok_or_else(not_found)?;
+
+    let mut blocks = fetch_blocks(fetcher, mapper, &[point], options, max_size)?;
+    let keep = HashSet::from([&hash[..]]);
+
+    for block in blocks.iter_mut() {
+        retain_txs(block, &keep);
+    }
+
+    Ok((blocks, cbor))
+}
+
+/// A `dump_history` page of an address and the txs that touched it
+type AddressPage = (u5c::sync::DumpHistoryResponse, Vec<ledger::AddressTx>);
+
+fn address_from_metadata(
+    metadata: &tonic::metadata::MetadataMap,
+) -> Result<Option<Vec<u8>>, Status> {
+    let Some(value) = metadata.get(ADDRESS_HEADER) else {
+        return Ok(None);
+    };
+
+    let address = value
+        .to_str()
+        .ok()
+        .and_then(|x| hex::decode(x).ok())
+        .ok_or_else(|| Status::invalid_argument("address header isn't valid hex"))?;
+
+    Ok(Some(address))
+}
+
+/// Token pointing at the history entry where the next address page starts
+///
+/// The slot goes in the index, the hash holds the tx hash followed by the
+/// direction, so that pages can end halfway through a block.
+fn address_tx_to_token(entry: &ledger::AddressTx) -> u5c::sync::BlockRef {
+    let mut hash = entry.tx.to_vec();
+    hash.push(entry.direction.into());
+
+    u5c::sync::BlockRef {
+        index: entry.slot,
+        hash: hash.into(),
+    }
+}
+
+fn token_to_address_tx(token: u5c::sync::BlockRef) -> Result<ledger::AddressTx, Status> {
+    let invalid = || Status::invalid_argument("start token isn't an address history cursor");
+
+    let (tx, direction) = match token.hash.as_ref() {
+        // only the slot, the whole slot is included
+        [] => ([u8::MAX; 32], ledger::TxDirection::Spent),
+        [tx @ .., direction] if tx.len() == 32 => {
+            let direction = ledger::TxDirection::try_from(*direction).map_err(|_| invalid())?;
+            (tx.try_into().map_err(|_| invalid())?, direction)
+        }
+        _ => return Err(invalid()),
+    };
+
+    Ok(ledger::AddressTx {
+        slot: token.index,
+        tx: tx.into(),
+        direction,
+    })
+}
+
+/// Keeps only the txs of the page in the body of a mapped block
+fn retain_txs(block: &mut u5c::sync::AnyChainBlock, txs: &HashSet<&[u8]>) {
+    let Some(u5c::sync::any_chain_block::Chain::Cardano(block)) = block.chain.as_mut() else {
+        return;
+    };
+
+    if let Some(body) = block.body.as_mut() {
+        body.tx.retain(|tx| txs.contains(tx.hash.as_ref()));
+    }
+}
+
+fn format_address_txs(txs: &[ledger::AddressTx]) -> String {
+    txs.iter()
+        .map(|x| {
+            let direction = match x.direction {
+                ledger::TxDirection::Received => "received",
+                ledger::TxDirection::Spent => "spent",
+            };
+
+            format!("{}:{}:{}", x.slot, x.tx, direction)
+        })
+        .join(",")
+}
+
+/// Reads a page of the txs that touched an address, newest first
+///
+/// Builds on the address index of the ledger, so rolled-back txs are gone as
+/// soon as the ledger reverts their block. A page holds up to `max_items`
+/// history entries (capped at `ADDRESS_MAX_ITEMS`) starting at `from`
+/// (inclusive). The body has a block per slot of the page, holding only the
+/// txs of the page, and the next token points at the entry where the next
+/// page starts. Blocks that aren't held anymore (trimmed from the WAL, without
+/// an archive) are left out of the body, their txs are still listed.
+fn read_address_page(
+    fetcher: &BlockFetcher,
+    ledger: &ledger::store::LedgerStore,
+    address: &[u8],
+    from: Option<ledger::AddressTx>,
+    max_items: usize,
+    options: MappingOptions,
+    max_size: usize,
+) -> Result<AddressPage, Status> {
+    let max_items = max_items.min(ADDRESS_MAX_ITEMS);
+
+    let (txs, next) = ledger
+        .get_address_history(address, from.as_ref(), max_items)
+        .map_err(|_| Status::internal("can't query address history"))?;
+
+    let mapper = Mapper::new(ledger.clone());
+    let mut blocks = vec![];
+
+    for (slot, entries) in &txs.iter().group_by(|x| x.slot) {
+        let hashes: HashSet<&[u8]> = entries.map(|x| x.tx.as_ref()).collect();
+
+        let point = fetcher
+            .resolve_slot(slot)
+            .map_err(|_| Status::internal("can't query block"))?;
+
+        if let Some(point) = point {
+            for mut block in fetch_blocks(fetcher, &mapper, &[point], options, max_size)? {
+                retain_txs(&mut block, &hashes);
+                blocks.push(block);
+            }
+        }
+    }
+
+    let response = u5c::sync::DumpHistoryResponse {
+        block: blocks,
+        next_token: next.as_ref().map(address_tx_to_token),
+    };
+
+    Ok((response, txs))
+}
+
+pub struct ChainSyncServiceImpl {
+    wal: wal::redb::WalStore,
+    fetcher: BlockFetcher,
+    ledger: ledger::store::LedgerStore,
+    mapper: interop::Mapper<ledger::store::LedgerStore>,
+    max_intersect_points: usize,
+    max_reorg_depth: Option<usize>,
+    keepalive: Option<Duration>,
+    latency: Option<Arc<Latency>>,
+    resolve_parallelism: usize,
+    decode_parallelism: usize,
+    max_block_size: usize,
+    history_quota: Option<Arc<Quota>>,
+    reconnect_delay: Duration,
+    shutdown: Option<CancellationToken>,
+    page_workers: PageWorkers,
+}
+
+impl ChainSyncServiceImpl {
+    pub fn new(
+        wal: wal::redb::WalStore,
+        ledger: ledger::store::LedgerStore,
+        max_intersect_points: usize,
+        max_reorg_depth: Option<usize>,
+    ) -> Self {
+        Self {
+            fetcher: BlockFetcher::new(wal.clone()),
+            wal,
+            mapper: Mapper::new(ledger.clone()),
+            ledger,
+            max_intersect_points,
+            max_reorg_depth,
+            keepalive: None,
+            latency: None,
+            resolve_parallelism: 1,
+            decode_parallelism: 1,
+            max_block_size: super::DEFAULT_MAX_BLOCK_SIZE,
+            history_quota: None,
+            reconnect_delay: super::DEFAULT_RECONNECT_DELAY,
+            shutdown: None,
+            page_workers: Default::default(),
+        }
+    }
+
+    /// Serves blocks trimmed from the WAL out of the archive
+    ///
+    /// Blocks requested by `fetch_block` are looked up in the archive when
+    /// they're not in the WAL, and `follow_tip` accepts intersects that are
+    /// only in the archive.
+    pub fn set_archive(&mut self, archive: Arc<Archive>) {
+        self.fetcher.set_archive(archive);
+    }
+
+    /// Finds the first intersect held by the archive, the stream then starts
+    /// with the archived blocks and continues from the start of the WAL
+    ///
+    /// Fails with `OutOfRange` if the archive doesn't reach the start of the
+    /// WAL (eg: it was set up after blocks were already trimmed), streaming
+    /// would leave a hole in the chain.
+    fn archive_start(
+        &self,
+        intersect: &[wal::ChainPoint],
+    ) -> Result<Option<(wal::LogSeq, ArchiveRange)>, Status> {
+        let after = intersect.iter().find_map(|point| match point {
+            wal::ChainPoint::Specific(slot, _) if self.fetcher.archive_has(point) => Some(*slot),
+            _ => None,
+        });
+
+        let Some(after) = after else {
+            return Ok(None);
+        };
+
+        let read_err = |_err: wal::WalError| Status::internal("can't read WAL");
+
+        let first = self
+            .wal
+            .crawl_from(None)
+            .map_err(read_err)?
+            .next()
+            .map(|(x, _)| x);
+
+        let oldest = self
+            .wal
+            .crawl_from(None)
+            .map_err(read_err)?
+            .find_map(|(_, log)| match log {
+                wal::LogValue::Apply(x) => Some(x),
+                _ => None,
+            });
+
+        if let Some(oldest) = &oldest {
+            if !self.fetcher.archive_has_parent(oldest) {
+                return Err(Status::out_of_range(
+                    "archive doesn't reach the start of the WAL, the intersect can't be served",
+                ));
+            }
+        }
+
+        let until = oldest.map(|x| x.slot);
+
+        Ok(Some((
+            first.unwrap_or_default(),
+            ArchiveRange { after, until },
+        )))
+    }
+
+    /// Sends a heartbeat (a response without action) on follow_tip streams
+    /// that have been idle for the given interval after catching up
+    pub fn set_keepalive(&mut self, interval: Duration) {
+        self.keepalive = Some(interval);
+    }
+
+    /// Records the duration of requests and streams into latency histograms
+    pub fn set_latency(&mut self, latency: Arc<Latency>) {
+        self.latency = Some(latency);
+    }
+
+    /// Spreads the ledger reads that resolve the inputs of a history page over
+    /// up to this many threads
+    pub fn set_resolve_parallelism(&mut self, parallelism: usize) {
+        self.resolve_parallelism = parallelism;
+    }
+
+    /// Spreads the decoding and mapping of the blocks of a history page over
+    /// up to this many threads
+    pub fn set_decode_parallelism(&mut self, parallelism: usize) {
+        self.decode_parallelism = parallelism;
+    }
+
+    /// Blocks with a body bigger than this are reported as an error instead
+    /// of being served
+    pub fn set_max_block_size(&mut self, max_size: usize) {
+        self.max_block_size = max_size;
+    }
+
+    /// Caps the blocks that each client can get through `dump_history`
+    pub fn set_history_quota(&mut self, quota: Arc<Quota>) {
+        self.history_quota = Some(quota);
+    }
+
+    /// Delay that follow_tip clients are told to wait before reconnecting
+    pub fn set_reconnect_delay(&mut self, delay: Duration) {
+        self.reconnect_delay = delay;
+    }
+
+    /// Ends the follow_tip streams with an `UNAVAILABLE` status (and the
+    /// reconnection hints) once the token is cancelled
+    pub fn set_shutdown(&mut self, token: CancellationToken) {
+        self.shutdown = Some(token);
+    }
+
+    fn timer(&self, name: &'static str) -> Option<Timer> {
+        self.latency.as_ref().map(|x| x.timer(name))
+    }
+
+    /// Starts the stream of events for a follow_tip request
+    fn tip_events(
+        &self,
+        request: u5c::sync::FollowTipRequest,
+    ) -> Result<BoxStream<'static, TipEvent>, Status> {
+        // each point is an indexed lookup, so bounding the number of points is
+        // enough to bound the work done by a single request
+        if request.intersect.len() > self.max_intersect_points {
+            return Err(Status::invalid_argument(format!(
+                "too many intersect points, max is {}",
+                self.max_intersect_points
+            )));
+        }
+
+        let (from_seq, archived) = if request.intersect.is_empty() {
+            let seq = self
+                .wal
+                .find_tip()
+                .map_err(|_err| Status::internal("can't read WAL"))?
+                .map(|(x, _)| x)
+                .unwrap_or_default();
+
+            (seq, None)
+        } else {
             let intersect: Vec<_> = request
                 .intersect
                 .into_iter()
                 .map(u5c_to_chain_point)
                 .collect();
 
-            self.wal
+            let found = self
+                .wal
                 .find_intersect(&intersect)
-                .map_err(|_err| Status::internal("can't read WAL"))?
-                .map(|(x, _)| x)
-                .ok_or(Status::internal("can't find WAL sequence"))?
+                .map_err(|_err| Status::internal("can't read WAL"))?;
+
+            match found {
+                Some((seq, _)) => (seq, None),
+                None => self
+                    .archive_start(&intersect)?
+                    .map(|(seq, range)| (seq, Some(range)))
+                    .ok_or_else(|| intersect_not_found(&self.wal))?,
+            }
+        };
+
+        // the stream timer is moved into the stream and records when the client
+        // goes away. Catching up is only detected after an idle wait, which
+        // isn't part of the time spent catching up.
+        let stream_timer = self.timer("follow_tip_stream");
+        let mut catch_up = self
+            .latency
+            .as_ref()
+            .map(|x| x.histogram("follow_tip_catch_up"));
+        let start = Instant::now();
+
+        // slow clients keep the entries they haven't read yet from being
+        // compacted away, the pin goes away with the stream
+        let mut pin = self.wal.pin_reader();
+
+        let entries = wal::WalStream::start(self.wal.clone(), from_seq)
+            .inspect(move |(_, log)| pin.advance(log));
+
+        let stream = with_catch_up(entries, CATCH_UP_IDLE);
+
+        let stream = match archived {
+            Some(range) => archive_stream(self.fetcher.clone(), range)
+                .chain(stream)
+                .boxed(),
+            None => stream.boxed(),
+        };
+
+        let stream = with_reorg_limit(stream, self.max_reorg_depth.unwrap_or(usize::MAX));
+
+        let stream = match self.keepalive {
+            Some(interval) => with_keepalive(stream, interval).boxed(),
+            None => stream.boxed(),
         };
 
+        let stream = stream.inspect(move |event| {
+            let _ = &stream_timer;
+
+            if let TipEvent::CaughtUp = event {
+                if let Some(histogram) = catch_up.take() {
+                    histogram.record(start.elapsed().saturating_sub(CATCH_UP_IDLE));
+                }
+            }
+        });
+
+        Ok(stream.boxed())
+    }
+
+    /// Follows the tip with several blocks per item while catching up
+    ///
+    /// Each item carries the responses that `follow_tip` would have sent one
+    /// by one, grouped as described by `with_batching`. The stream ends the
+    /// same way as `follow_tip`, with the reconnection hints. The u5c
+    /// `FollowTipResponse` holds a single action, so gRPC clients that opt-in
+    /// through the batch headers get each batch as consecutive responses, all
+    /// ready at once: tonic encodes them into a single write instead of
+    /// waking up the connection once per block.
+    pub fn follow_tip_batched(
+        &self,
+        request: u5c::sync::FollowTipRequest,
+        limits: BatchLimits,
+        options: MappingOptions,
+    ) -> Result<BoxStream<'static, Result<Vec<u5c::sync::FollowTipResponse>, Status>>, Status> {
+        let events = self.tip_events(request)?;
+
+        let mapper = self.mapper.clone();
+        let max_size = self.max_block_size;
+
+        let items = with_batching(events, limits).map(move |batch| {
+            let point = batch.iter().filter_map(event_point).last();
+
+            let out: Result<Vec<_>, _> = batch
+                .into_iter()
+                .filter_map(|x| tip_event_response(&mapper, x, options, max_size))
+                .collect();
+
+            let out = match out {
+                Ok(x) if x.is_empty() => None,
+                x => Some(x),
+            };
+
+            (point, out)
+        });
+
+        Ok(self.with_stream_end(items.boxed()))
+    }
+
+    /// Announces the heartbeat interval of a follow_tip stream, if enabled
+    fn with_keepalive_header<T>(&self, mut response: Response<T>) -> Response<T> {
+        if let Some(interval) = self.keepalive {
+            response.metadata_mut().insert(
+                KEEPALIVE_HEADER,
+                MetadataValue::from(interval.as_millis() as u64),
+            );
+        }
+
+        response
+    }
+
+    /// Ends the stream of a follow_tip request, see the free `with_stream_end`
+    fn with_stream_end<T: Send + 'static>(
+        &self,
+        items: BoxStream<'static, (Option<wal::ChainPoint>, Option<Result<T, Status>>)>,
+    ) -> BoxStream<'static, Result<T, Status>> {
+        with_stream_end(
+            items,
+            self.wal.clone(),
+            self.reconnect_delay,
+            self.shutdown.clone().unwrap_or_default(),
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl u5c::sync::chain_sync_service_server::ChainSyncService for ChainSyncServiceImpl {
+    type FollowTipStream =
+        Pin<Box<dyn Stream<Item = Result<u5c::sync::FollowTipResponse, Status>> + Send + 'static>>;
+
+    async fn fetch_block(
+        &self,
+        request: Request<u5c::sync::FetchBlockRequest>,
+    ) -> Result<Response<u5c::sync::FetchBlockResponse>, Status> {
+        let _timer = self.timer("fetch_block");
+
+        let options = MappingOptions::from_metadata(request.metadata());
+        let tx_hash = tx_hash_from_metadata(request.metadata())?;
+
+        let message = request.into_inner();
+
+        let fetcher = self.fetcher.clone();
         let mapper = self.mapper.clone();
+        let max_size = self.max_block_size;
+
+        if let Some(hash) = tx_hash {
+            if !message.r#ref.is_empty() {
+                return Err(Status::invalid_argument(
+                    "block refs can't be combined with a tx hash",
+                ));
+            }
+
+            let wal = self.wal.clone();
+
+            let (out, cbor) = super::run_blocking(move || {
+                read_tx(&wal, &fetcher, &mapper, &hash, options, max_size)
+            })
+            .await?;
+
+            let mut response = Response::new(u5c::sync::FetchBlockResponse { block: out });
+
+            response
+                .metadata_mut()
+                .insert_bin(TX_CBOR_HEADER, MetadataValue::from_bytes(&cbor));
+
+            return Ok(response);
+        }
+
+        let out = super::run_blocking(move || {
+            let points = resolve_refs(&fetcher, message.r#ref)?;
+            fetch_blocks(&fetcher, &mapper, &points, options, max_size)
+        })
+        .await?;
+
+        let response = u5c::sync::FetchBlockResponse { block: out };
+
+        Ok(Response::new(response))
+    }
+
+    async fn dump_history(
+        &self,
+        request: Request<u5c::sync::DumpHistoryRequest>,
+    ) -> Result<Response<u5c::sync::DumpHistoryResponse>, Status> {
+        let _timer = self.timer("dump_history");
+
+        let with_stats = header_flag(request.metadata(), PAGE_STATS_HEADER);
+        let options = MappingOptions::from_metadata(request.metadata());
+        let skip_invalid = header_flag(request.metadata(), SKIP_INVALID_HEADER);
+        let address = address_from_metadata(request.metadata())?;
+
+        let quota = self
+            .history_quota
+            .as_ref()
+            .map(|x| (x.clone(), x.client_key(&request)));
+
+        let mut msg = request.into_inner();
+
+        // the page is cut down to what's left of the quota, the next token
+        // lets the client pick up from there once the window resets
+        let reserved = match &quota {
+            Some((quota, client)) => {
+                let reserved = quota.reserve(client, msg.max_items as u64, Instant::now())?;
+                msg.max_items = reserved as u32;
+                Some(reserved)
+            }
+            None => None,
+        };
+
+        let refund = |served: usize| {
+            if let (Some((quota, client)), Some(reserved)) = (&quota, reserved) {
+                quota.refund(client, reserved.saturating_sub(served as u64));
+            }
+        };
+
+        if let Some(address) = address {
+            let from = match msg.start_token {
+                Some(x) => Some(token_to_address_tx(x).inspect_err(|_| refund(0))?),
+                None => None,
+            };
+            let fetcher = self.fetcher.clone();
+            let ledger = self.ledger.clone();
+            let max_size = self.max_block_size;
+
+            let page = super::run_blocking(move || {
+                read_address_page(
+                    &fetcher,
+                    &ledger,
+                    &address,
+                    from,
+                    msg.max_items as usize,
+                    options,
+                    max_size,
+                )
+            })
+            .await;
+
+            refund(page.as_ref().map(|(_, txs)| txs.len()).unwrap_or(0));
+
+            let (response, txs) = page?;
+            let mut response = Response::new(response);
+
+            if let Ok(value) = format_address_txs(&txs).parse() {
+                response.metadata_mut().insert(ADDRESS_TXS_HEADER, value);
+            }
+
+            return Ok(response);
+        }
+
+        let from = msg.start_token.map(u5c_to_chain_point);
+
+        let wal = self.wal.clone();
+        let ledger = self.ledger.clone();
+
+        let page_options = PageOptions {
+            with_stats,
+            mapping: options,
+            skip_invalid,
+            resolve_parallelism: self.resolve_parallelism,
+            decode_parallelism: self.decode_parallelism,
+            max_size: self.max_block_size,
+            workers: self.page_workers.clone(),
+        };
+
+        let page = super::run_blocking(move || {
+            read_history_page(
+                &wal,
+                &ledger,
+                from.as_ref(),
+                msg.max_items as usize,
+                &page_options,
+            )
+        })
+        .await;
+
+        refund(page.as_ref().map(|(x, _, _)| x.block.len()).unwrap_or(0));
+
+        let (response, stats, skipped) = page?;
+
+        let mut response = Response::new(response);
+
+        if with_stats {
+            stats.write_metadata(&mut response);
+        }
+
+        if !skipped.is_empty() {
+            let value = skipped.iter().join(",");
+
+            if let Ok(value) = value.parse() {
+                response.metadata_mut().insert(SKIPPED_SLOTS_HEADER, value);
+            }
+        }
+
+        Ok(response)
+    }
+
+    async fn follow_tip(
+        &self,
+        request: Request<u5c::sync::FollowTipRequest>,
+    ) -> Result<Response<Self::FollowTipStream>, tonic::Status> {
+        let options = MappingOptions::from_metadata(request.metadata());
+
+        if let Some(limits) = BatchLimits::from_metadata(request.metadata()) {
+            let batches = self.follow_tip_batched(request.into_inner(), limits, options)?;
+
+            let stream = batches.flat_map(|batch| {
+                let items: Vec<_> = match batch {
+                    Ok(x) => x.into_iter().map(Ok).collect(),
+                    Err(x) => vec![Err(x)],
+                };
+
+                futures_util::stream::iter(items)
+            });
+
+            return Ok(self.with_keepalive_header(Response::new(stream.boxed())));
+        }
+
+        let events = self.tip_events(request.into_inner())?;
+
+        let mapper = self.mapper.clone();
+        let max_size = self.max_block_size;
+
+        let items = events.map(move |event| {
+            let point = event_point(&event);
+            (point, tip_event_response(&mapper, event, options, max_size))
+        });
+
+        Ok(self.with_keepalive_header(Response::new(self.with_stream_end(items.boxed()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use u5c::sync::chain_sync_service_server::ChainSyncService as _;
+
+    use super::*;
+    use crate::wal::{testing, WalReader as _, WalWriter as _};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn read_header(metadata: &tonic::metadata::MetadataMap, key: &str) -> Option<usize> {
+        metadata
+            .get(key)
+            .map(|x| x.to_str().unwrap().parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_dump_history_page_stats() {
+        // the dummy blocks (667 bytes) have no txs, the test data block (42713
+        // bytes) has 21 of them
+        let mut wal = testing::empty_db();
+
+        wal.roll_forward(
+            [
+                testing::dummy_block_from_slot(0),
+                testing::test_data_block(1),
+                testing::test_data_block(2),
+                testing::dummy_block_from_slot(3),
+                testing::dummy_block_from_slot(4),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let service = ChainSyncServiceImpl::new(wal, ledger, 100, None);
+
+        let message = u5c::sync::DumpHistoryRequest {
+            max_items: 4,
+            ..Default::default()
+        };
+
+        let mut request = Request::new(message.clone());
+        request
+            .metadata_mut()
+            .insert(PAGE_STATS_HEADER, MetadataValue::from_static("true"));
+
+        let response = service.dump_history(request).await.unwrap();
+
+        assert_eq!(response.get_ref().block.len(), 4);
+        assert_eq!(
+            read_header(response.metadata(), PAGE_BYTE_SIZE_HEADER),
+            Some(2 * 667 + 2 * 42713)
+        );
+        assert_eq!(
+            read_header(response.metadata(), PAGE_TX_COUNT_HEADER),
+            Some(2 * 21)
+        );
+
+        // stats are opt-in
+        let response = service.dump_history(Request::new(message)).await.unwrap();
+        assert!(response.metadata().get(PAGE_TX_COUNT_HEADER).is_none());
+        assert!(response.metadata().get(PAGE_BYTE_SIZE_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dump_history_skips_invalid_blocks() {
+        let mut wal = testing::db_with_dummy_blocks(5);
+
+        // a block that passes the WAL checks but isn't valid cbor
+        let mut corrupt = testing::dummy_block_from_slot(5);
+        corrupt.body = vec![0xff; 64];
+
+        wal.roll_forward(std::iter::once(corrupt)).unwrap();
+        wal.roll_forward((6..10).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let service = ChainSyncServiceImpl::new(wal, ledger, 100, None);
+
+        let message = u5c::sync::DumpHistoryRequest {
+            max_items: 8,
+            ..Default::default()
+        };
+
+        // by default, the bad block fails the whole page
+        let status = service
+            .dump_history(Request::new(message.clone()))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert(SKIP_INVALID_HEADER, MetadataValue::from_static("true"));
+
+        let response = service.dump_history(request).await.unwrap();
+
+        let skipped = response
+            .metadata()
+            .get(SKIPPED_SLOTS_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+
+        assert_eq!(skipped, "5");
+
+        // the page covers the same range, minus the skipped block
+        assert_eq!(response.get_ref().block.len(), 7);
+        assert_eq!(response.get_ref().next_token.as_ref().unwrap().index, 8);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_block_is_not_served() {
+        let mut wal = testing::db_with_dummy_blocks(5);
+
+        // way bigger than any of the dummy blocks, the content doesn't matter
+        let mut oversized = testing::dummy_block_from_slot(5);
+        oversized.body = vec![0; 8192];
+
+        wal.roll_forward(std::iter::once(oversized)).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let mut service = ChainSyncServiceImpl::new(wal, ledger, 100, None);
+        service.set_max_block_size(4096);
+
+        let fetch = |slot: u64| {
+            Request::new(u5c::sync::FetchBlockRequest {
+                r#ref: vec![u5c::sync::BlockRef {
+                    index: slot,
+                    hash: testing::slot_to_hash(slot).to_vec().into(),
+                }],
+                ..Default::default()
+            })
+        };
+
+        assert!(service.fetch_block(fetch(4)).await.is_ok());
+
+        let status = service.fetch_block(fetch(5)).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+
+        let request = Request::new(u5c::sync::DumpHistoryRequest {
+            max_items: 10,
+            ..Default::default()
+        });
+
+        let status = service.dump_history(request).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+
+        // unless asked to skip it, like a block that doesn't decode
+        let mut request = Request::new(u5c::sync::DumpHistoryRequest {
+            max_items: 10,
+            ..Default::default()
+        });
+
+        request
+            .metadata_mut()
+            .insert(SKIP_INVALID_HEADER, MetadataValue::from_static("true"));
+
+        let response = service.dump_history(request).await.unwrap();
+        let skipped = response.metadata().get(SKIPPED_SLOTS_HEADER).unwrap();
+        assert_eq!(skipped.to_str().unwrap(), "5");
+        assert_eq!(response.get_ref().block.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_dump_history_of_address() {
+        use pallas::crypto::hash::Hash;
+
+        let chain = testing::TestChainBuilder::new().extend(0..10);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        // the same output received at a few slots
+        let block = MultiEraBlock::decode(&testing::test_data_cbor()).unwrap();
+        let (_, output) = block.txs()[0].produces().into_iter().next().unwrap();
+        let address = output.address().unwrap().to_vec();
+        let body = ledger::EraCbor::from(output);
+
+        let txo = |slot: u64| ledger::TxoRef(Hash::new([slot as u8; 32]), 0);
+
+        let receive = |slot: u64| ledger::LedgerDelta {
+            new_position: Some(ledger::ChainPoint(slot, Hash::new([slot as u8; 32]))),
+            produced_utxo: HashMap::from([(txo(slot), body.clone())]),
+            ..Default::default()
+        };
+
+        // a second tx of the address in the block at slot 5
+        let other = ledger::TxoRef(Hash::new([0x50; 32]), 0);
+        let mut both = receive(5);
+        both.produced_utxo.insert(other.clone(), body.clone());
+
+        ledger.apply(&[receive(3), both, receive(7)]).unwrap();
+
+        let service = ChainSyncServiceImpl::new(wal, ledger.clone(), 100, None);
+
+        let request = |max_items, start: Option<u5c::sync::BlockRef>| {
+            let message = u5c::sync::DumpHistoryRequest {
+                start_token: start,
+                max_items,
+                ..Default::default()
+            };
+
+            let mut request = Request::new(message);
+            let value = hex::encode(&address).parse().unwrap();
+            request.metadata_mut().insert(ADDRESS_HEADER, value);
+
+            request
+        };
+
+        let slots = |response: &Response<u5c::sync::DumpHistoryResponse>| -> Vec<u64> {
+            response
+                .get_ref()
+                .block
+                .iter()
+                .map(|x| match &x.chain {
+                    Some(u5c::sync::any_chain_block::Chain::Cardano(x)) => {
+                        x.header.as_ref().unwrap().slot
+                    }
+                    _ => panic!("expected a cardano block"),
+                })
+                .collect()
+        };
+
+        // pages count txs, so a page can end halfway through a block
+        let response = service.dump_history(request(2, None)).await.unwrap();
+        assert_eq!(slots(&response), vec![7, 5]);
+
+        let txs = response.metadata().get(ADDRESS_TXS_HEADER).unwrap();
+        let expected = format!("7:{}:received,5:{}:received", txo(7).0, other.0);
+        assert_eq!(txs.to_str().unwrap(), expected);
+
+        let token = response.into_inner().next_token.unwrap();
+        assert_eq!(token.index, 5);
+        assert_eq!(token.hash.len(), 33);
+
+        // the next page picks up at the tx of the token, the last one fits
+        // exactly and doesn't point to another
+        let response = service.dump_history(request(2, Some(token))).await.unwrap();
+        assert_eq!(slots(&response), vec![5, 3]);
+        assert!(response.get_ref().next_token.is_none());
+
+        let txs = response.metadata().get(ADDRESS_TXS_HEADER).unwrap();
+        let expected = format!("5:{}:received,3:{}:received", txo(5).0, txo(3).0);
+        assert_eq!(txs.to_str().unwrap(), expected);
+
+        // a token with only the slot starts at the newest tx of that slot
+        let by_slot = u5c::sync::BlockRef {
+            index: 3,
+            hash: vec![].into(),
+        };
+
+        let response = service
+            .dump_history(request(2, Some(by_slot)))
+            .await
+            .unwrap();
+        assert_eq!(slots(&response), vec![3]);
+
+        let bogus = u5c::sync::BlockRef {
+            index: 3,
+            hash: vec![0; 5].into(),
+        };
+
+        let status = service
+            .dump_history(request(2, Some(bogus)))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // a rolled-back tx is gone from subsequent queries
+        let undo = ledger::LedgerDelta {
+            undone_position: Some(ledger::ChainPoint(7, Hash::new([7; 32]))),
+            undone_utxo: HashMap::from([(txo(7), body.clone())]),
+            ..Default::default()
+        };
+
+        ledger.apply(&[undo]).unwrap();
+
+        let response = service.dump_history(request(3, None)).await.unwrap();
+        assert_eq!(slots(&response), vec![5, 3]);
+        assert!(response.get_ref().next_token.is_none());
+
+        let mut invalid = request(2, None);
+        let value = MetadataValue::from_static("not-hex");
+        invalid.metadata_mut().insert(ADDRESS_HEADER, value);
+
+        let status = service.dump_history(invalid).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_block_by_slot() {
+        let chain = testing::TestChainBuilder::new().extend([10, 12, 15]);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let service = ChainSyncServiceImpl::new(wal, ledger, 100, None);
+
+        // a ref with only the slot, asking for the header alone
+        let by_slot = |slot: u64| {
+            let mut request = Request::new(u5c::sync::FetchBlockRequest {
+                r#ref: vec![u5c::sync::BlockRef {
+                    index: slot,
+                    hash: vec![].into(),
+                }],
+                ..Default::default()
+            });
+
+            request
+                .metadata_mut()
+                .insert(INCLUDE_BODY_HEADER, MetadataValue::from_static("false"));
+
+            request
+        };
+
+        let response = service.fetch_block(by_slot(12)).await.unwrap();
+
+        let block = match &response.get_ref().block[0].chain {
+            Some(u5c::sync::any_chain_block::Chain::Cardano(x)) => x.clone(),
+            _ => panic!("expected a cardano block"),
+        };
+
+        let header = block.header.unwrap();
+        assert_eq!(
+            chain.point(12),
+            wal::ChainPoint::Specific(header.slot, header.hash.as_ref().into())
+        );
+        assert!(block.body.is_none());
+
+        // a slot without a block is not found, instead of an empty ref
+        let status = service.fetch_block(by_slot(11)).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_tx_by_hash() {
+        let mut wal = testing::db_with_dummy_blocks(10);
+        let block = testing::test_data_block(10);
+
+        let expected: Vec<_> = block
+            .decode()
+            .unwrap()
+            .txs()
+            .iter()
+            .map(|x| (x.hash(), x.encode()))
+            .collect();
+
+        wal.roll_forward(std::iter::once(block.clone())).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let service = ChainSyncServiceImpl::new(wal.clone(), ledger, 100, None);
+
+        let by_hash = |hash: &str| {
+            let mut request = Request::new(u5c::sync::FetchBlockRequest::default());
+            let value = MetadataValue::try_from(hash).unwrap();
+            request.metadata_mut().insert(TX_HASH_HEADER, value);
+            request
+        };
+
+        let (hash, cbor) = expected.last().unwrap();
+
+        let response = service
+            .fetch_block(by_hash(&hash.to_string()))
+            .await
+            .unwrap();
+
+        let raw = response.metadata().get_bin(TX_CBOR_HEADER).unwrap();
+        assert_eq!(raw.to_bytes().unwrap().as_ref(), cbor.as_slice());
+
+        // the block that has the tx, holding only that tx
+        let block = match &response.get_ref().block[..] {
+            [u5c::sync::AnyChainBlock {
+                chain: Some(u5c::sync::any_chain_block::Chain::Cardano(x)),
+            }] => x.clone(),
+            _ => panic!("expected a single cardano block"),
+        };
+
+        assert_eq!(block.header.unwrap().slot, 10);
+
+        let txs = block.body.unwrap().tx;
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].hash.as_ref(), &hash[..]);
+
+        // a hash that was never applied
+        let unknown = testing::slot_to_hash(1000).to_string();
+        let status = service.fetch_block(by_hash(&unknown)).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        // a hash that isn't one
+        let status = service.fetch_block(by_hash("zz")).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // once the block is rolled back, its txs aren't in the chain anymore
+        wal.roll_back(&wal::ChainPoint::Specific(9, testing::slot_to_hash(9)))
+            .unwrap();
+
+        let status = service
+            .fetch_block(by_hash(&hash.to_string()))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_history_quota_is_exhausted_across_requests() {
+        use crate::prelude::QuotaKey;
+        use crate::serve::grpc::quota::{QuotaPolicy, API_KEY_HEADER};
+
+        let wal = testing::db_with_dummy_blocks(30);
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let mut service = ChainSyncServiceImpl::new(wal, ledger, 100, None);
+
+        service.set_history_quota(Arc::new(Quota::new(QuotaPolicy {
+            max_blocks: 10,
+            window_secs: 3600,
+            key: QuotaKey::ApiKey,
+        })));
+
+        let request = |key: &'static str, start_token| {
+            let mut request = Request::new(u5c::sync::DumpHistoryRequest {
+                start_token,
+                max_items: 4,
+                ..Default::default()
+            });
+
+            request
+                .metadata_mut()
+                .insert(API_KEY_HEADER, MetadataValue::from_static(key));
+
+            request
+        };
+
+        let mut token = None;
+        let mut served = vec![];
+
+        // 4 + 4 + what's left of the quota
+        for _ in 0..3 {
+            let response = service
+                .dump_history(request("a", token.take()))
+                .await
+                .unwrap()
+                .into_inner();
+
+            served.push(response.block.len());
+            token = response.next_token;
+        }
+
+        assert_eq!(served, vec![4, 4, 2]);
+
+        // the cut page still points to the next block
+        assert_eq!(token.as_ref().unwrap().index, 10);
+
+        let status = service
+            .dump_history(request("a", token))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        // other clients are not affected
+        let response = service.dump_history(request("b", None)).await.unwrap();
+        assert_eq!(response.get_ref().block.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_latency_histograms_record_samples() {
+        let wal = testing::db_with_dummy_blocks(10);
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let mut service = ChainSyncServiceImpl::new(wal, ledger, 100, None);
+
+        let latency = Arc::new(Latency::default());
+        service.set_latency(latency.clone());
+
+        for _ in 0..5 {
+            let request = Request::new(u5c::sync::DumpHistoryRequest {
+                max_items: 4,
+                ..Default::default()
+            });
+
+            service.dump_history(request).await.unwrap();
+        }
+
+        let request = Request::new(u5c::sync::FetchBlockRequest {
+            r#ref: vec![u5c::sync::BlockRef {
+                index: 3,
+                hash: testing::slot_to_hash(3).to_vec().into(),
+            }],
+            ..Default::default()
+        });
+
+        service.fetch_block(request).await.unwrap();
+
+        let request = Request::new(u5c::sync::FollowTipRequest::default());
+        let mut stream = service.follow_tip(request).await.unwrap().into_inner();
+        stream.next().await.unwrap().unwrap();
+
+        // the stream is only timed once the client goes away
+        assert_eq!(latency.histogram("follow_tip_stream").count(), 0);
+        drop(stream);
+
+        assert_eq!(latency.histogram("dump_history").count(), 5);
+        assert_eq!(latency.histogram("fetch_block").count(), 1);
+        assert_eq!(latency.histogram("follow_tip_stream").count(), 1);
+        assert!(latency.histogram("dump_history").mean().unwrap() > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_from_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let mut service = ChainSyncServiceImpl::new(testing::empty_db(), ledger, 100, None);
+
+        // the wal is empty, all of the chain lives in the archive
+        let archive = Archive::open(dir.path().join("archive")).unwrap();
+
+        let mut archived: Vec<_> = [
+            testing::dummy_block_from_slot(0),
+            testing::test_data_block(0),
+        ]
+        .iter()
+        .map(|block| {
+            archive.apply_block(&block.body).unwrap();
+            let decoded = block.decode().unwrap();
+            (decoded.slot(), decoded.hash(), decoded.txs().len())
+        })
+        .collect();
+
+        archived.sort_by_key(|(slot, ..)| *slot);
+        let (from_slot, from_hash, _) = archived[0];
+        let (_, _, next_txs) = archived[1];
+
+        let request = || {
+            Request::new(u5c::sync::FollowTipRequest {
+                intersect: vec![u5c::sync::BlockRef {
+                    index: from_slot,
+                    hash: from_hash.to_vec().into(),
+                }],
+                ..Default::default()
+            })
+        };
+
+        let status = service.follow_tip(request()).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        service.set_archive(Arc::new(archive));
+
+        let mut stream = service.follow_tip(request()).await.unwrap().into_inner();
+        let response = stream.next().await.unwrap().unwrap();
+
+        match response.action {
+            Some(u5c::sync::follow_tip_response::Action::Apply(block)) => {
+                assert_eq!(count_txs(&block), next_txs);
+            }
+            _ => panic!("expected apply"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_from_compacted_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = testing::TestChainBuilder::new().extend(0..30);
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        // compaction moves slots 0 to 19 into the archive
+        let policy = wal::redb::CompactionPolicy {
+            high_water: 0,
+            low_water: 0,
+            undo_body_retention: None,
+        };
+
+        let archive = Archive::open(dir.path().join("archive")).unwrap();
+        wal.compact_into(&policy, 19, u64::MAX, Some(&archive))
+            .unwrap();
+
+        let request = || {
+            let wal::ChainPoint::Specific(slot, hash) = chain.point(5) else {
+                unreachable!()
+            };
+
+            Request::new(u5c::sync::FollowTipRequest {
+                intersect: vec![u5c::sync::BlockRef {
+                    index: slot,
+                    hash: hash.to_vec().into(),
+                }],
+                ..Default::default()
+            })
+        };
+
+        let service = |name: &str, archive: Archive| {
+            let ledger = dir.path().join(format!("ledger-{name}"));
+            let ledger = ledger::store::LedgerStore::open(ledger).unwrap();
+            let mut service = ChainSyncServiceImpl::new(wal.clone(), ledger, 100, None);
+            service.set_archive(Arc::new(archive));
+            service
+        };
+
+        // the archived blocks after the intersect come first, then the wal
+        let mut stream = service("full", archive)
+            .follow_tip(request())
+            .await
+            .unwrap()
+            .into_inner();
+
+        for _ in 6..30 {
+            let response = stream.next().await.unwrap().unwrap();
+
+            assert!(matches!(
+                response.action,
+                Some(u5c::sync::follow_tip_response::Action::Apply(_))
+            ));
+        }
+
+        // an archive with a hole before the start of the wal can't serve it
+        let partial = Archive::open(dir.path().join("partial")).unwrap();
+
+        for block in chain.blocks().iter().filter(|x| x.slot < 19) {
+            partial.apply_block(&block.body).unwrap();
+        }
+
+        let status = service("partial", partial)
+            .follow_tip(request())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_rejects_oversized_intersect() {
+        let wal = testing::db_with_dummy_blocks(10);
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let service = ChainSyncServiceImpl::new(wal, ledger, 3, None);
+
+        let intersect = |count: u64| -> Vec<_> {
+            (0..count)
+                .map(|x| u5c::sync::BlockRef {
+                    index: x,
+                    hash: testing::slot_to_hash(x).to_vec().into(),
+                })
+                .collect()
+        };
+
+        let request = Request::new(u5c::sync::FollowTipRequest {
+            intersect: intersect(10),
+            ..Default::default()
+        });
+
+        let status = service.follow_tip(request).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let request = Request::new(u5c::sync::FollowTipRequest {
+            intersect: intersect(3),
+            ..Default::default()
+        });
+
+        assert!(service.follow_tip(request).await.is_ok());
+    }
+
+    #[test]
+    fn test_resolve_inputs_from_same_block() {
+        let output = u5c::cardano::TxOutput {
+            address: vec![1, 2, 3].into(),
+            coin: 1000,
+            ..Default::default()
+        };
+
+        let input = |hash: u8, output_index: u32| u5c::cardano::TxInput {
+            tx_hash: vec![hash; 32].into(),
+            output_index,
+            ..Default::default()
+        };
+
+        let first = u5c::cardano::Tx {
+            hash: vec![1; 32].into(),
+            outputs: vec![Default::default(), output.clone()],
+            ..Default::default()
+        };
+
+        let second = u5c::cardano::Tx {
+            hash: vec![2; 32].into(),
+            inputs: vec![input(1, 1), input(9, 0)],
+            reference_inputs: vec![input(1, 0)],
+            ..Default::default()
+        };
+
+        let mut block = u5c::cardano::Block {
+            body: Some(u5c::cardano::BlockBody {
+                tx: vec![first, second],
+            }),
+            ..Default::default()
+        };
+
+        resolve_missing_inputs(&mut block);
+
+        let tx = &block.body.unwrap().tx[1];
+
+        assert_eq!(tx.inputs[0].as_output, Some(output));
+        assert_eq!(tx.reference_inputs[0].as_output, Some(Default::default()));
+
+        // inputs from other blocks are left for the ledger to resolve
+        assert_eq!(tx.inputs[1].as_output, None);
+    }
+
+    fn enriched_block() -> u5c::cardano::Block {
+        let output = u5c::cardano::TxOutput {
+            datum: Some(Default::default()),
+            datum_hash: vec![3; 32].into(),
+            ..Default::default()
+        };
+
+        let first = u5c::cardano::Tx {
+            hash: vec![1; 32].into(),
+            outputs: vec![output],
+            certificates: vec![Default::default()],
+            auxiliary: Some(Default::default()),
+            witnesses: Some(u5c::cardano::WitnessSet {
+                plutus_datums: vec![Default::default()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let second = u5c::cardano::Tx {
+            hash: vec![2; 32].into(),
+            inputs: vec![u5c::cardano::TxInput {
+                tx_hash: vec![1; 32].into(),
+                output_index: 0,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        u5c::cardano::Block {
+            body: Some(u5c::cardano::BlockBody {
+                tx: vec![first, second],
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn mapped_txs(options: MappingOptions) -> Vec<u5c::cardano::Tx> {
+        let mut block = enriched_block();
+        options.apply(&mut block);
+        block.body.unwrap().tx
+    }
+
+    #[test]
+    fn test_mapping_options() {
+        let full = mapped_txs(MappingOptions::default());
+        assert!(full[0].outputs[0].datum.is_some());
+        assert_eq!(full[0].witnesses.as_ref().unwrap().plutus_datums.len(), 1);
+        assert!(full[0].auxiliary.is_some());
+        assert_eq!(full[0].certificates.len(), 1);
+        assert_eq!(full[1].inputs[0].as_output, None);
+
+        let options = MappingOptions {
+            resolve_datums: false,
+            ..Default::default()
+        };
+
+        let txs = mapped_txs(options);
+        assert!(txs[0].outputs[0].datum.is_none());
+        assert_eq!(txs[0].outputs[0].datum_hash, full[0].outputs[0].datum_hash);
+        assert!(txs[0].witnesses.as_ref().unwrap().plutus_datums.is_empty());
+        assert!(txs[0].auxiliary.is_some());
+
+        let options = MappingOptions {
+            resolve_inputs: true,
+            ..Default::default()
+        };
+
+        let txs = mapped_txs(options);
+        assert_eq!(txs[1].inputs[0].as_output, Some(full[0].outputs[0].clone()));
+
+        let options = MappingOptions {
+            include_metadata: false,
+            ..Default::default()
+        };
+
+        let txs = mapped_txs(options);
+        assert!(txs[0].auxiliary.is_none());
+        assert_eq!(txs[0].certificates.len(), 1);
+
+        let options = MappingOptions {
+            include_certs: false,
+            ..Default::default()
+        };
+
+        let txs = mapped_txs(options);
+        assert!(txs[0].certificates.is_empty());
+        assert!(txs[0].outputs[0].datum.is_some());
+
+        let options = MappingOptions {
+            include_body: false,
+            ..Default::default()
+        };
+
+        let mut block = enriched_block();
+        options.apply(&mut block);
+        assert!(block.body.is_none());
+    }
+
+    #[test]
+    fn test_mapping_options_on_mapped_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let mapper = Mapper::new(ledger);
+
+        let cbor = wal::testing::test_data_cbor();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let cardano = |x: u5c::sync::AnyChainBlock| match x.chain {
+            Some(u5c::sync::any_chain_block::Chain::Cardano(x)) => x,
+            _ => panic!("expected a cardano block"),
+        };
+
+        let full = cardano(block_to_anychain(&mapper, &block, Default::default()));
+
+        // the header alone matches the one of the full mapping
+        let options = MappingOptions {
+            include_body: false,
+            ..Default::default()
+        };
+
+        let bare = cardano(block_to_anychain(&mapper, &block, options));
+        assert_eq!(bare.header, full.header);
+        assert!(bare.body.is_none());
+
+        // datum hashes stay when the datums are left out
+        let options = MappingOptions {
+            resolve_datums: false,
+            ..Default::default()
+        };
+
+        let lean = cardano(block_to_anychain(&mapper, &block, options));
+
+        let hashes = |block: &u5c::cardano::Block| -> Vec<_> {
+            let body = block.body.as_ref().unwrap();
+
+            body.tx
+                .iter()
+                .flat_map(|tx| tx.outputs.iter().map(|x| x.datum_hash.clone()))
+                .collect()
+        };
+
+        assert_eq!(hashes(&lean), hashes(&full));
+    }
+
+    #[test]
+    fn test_stripped_undo_maps_to_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let mapper = Mapper::new(ledger);
+
+        let stripped = wal::RawBlock {
+            body: vec![],
+            ..wal::testing::dummy_block_from_slot(7)
+        };
+
+        let log = wal::LogValue::Undo(stripped.clone());
+        let response = roll_to_tip_response(&mapper, &log, Default::default(), 0).unwrap();
+
+        let Some(u5c::sync::follow_tip_response::Action::Undo(block)) = response.unwrap().action
+        else {
+            panic!("expected undo");
+        };
+
+        let Some(u5c::sync::any_chain_block::Chain::Cardano(block)) = block.chain else {
+            panic!("expected a cardano block");
+        };
+
+        let header = block.header.unwrap();
+        assert_eq!(header.slot, 7);
+        assert_eq!(header.hash.to_vec(), stripped.hash.to_vec());
+        assert!(block.body.is_none());
+    }
+
+    #[test]
+    fn test_mapping_options_from_headers() {
+        let mut metadata = tonic::metadata::MetadataMap::new();
+        assert_eq!(
+            MappingOptions::from_metadata(&metadata),
+            MappingOptions::default()
+        );
+
+        metadata.insert(RESOLVE_DATUMS_HEADER, "false".parse().unwrap());
+        metadata.insert(RESOLVE_INPUTS_HEADER, "true".parse().unwrap());
+        metadata.insert(INCLUDE_METADATA_HEADER, "false".parse().unwrap());
+        metadata.insert(INCLUDE_CERTS_HEADER, "true".parse().unwrap());
+
+        let expected = MappingOptions {
+            resolve_datums: false,
+            resolve_inputs: true,
+            include_metadata: false,
+            include_certs: true,
+            include_body: true,
+        };
+
+        assert_eq!(MappingOptions::from_metadata(&metadata), expected);
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_hints_known_points() {
+        let wal = testing::db_with_dummy_blocks(300);
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let service = ChainSyncServiceImpl::new(wal.clone(), ledger, 100, None);
+
+        let request = Request::new(u5c::sync::FollowTipRequest {
+            intersect: vec![u5c::sync::BlockRef {
+                index: 1000,
+                hash: testing::slot_to_hash(1000).to_vec().into(),
+            }],
+            ..Default::default()
+        });
+
+        let status = service.follow_tip(request).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let hint = status
+            .metadata()
+            .get(KNOWN_POINTS_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+
+        let points: Vec<_> = hint
+            .split(',')
+            .map(|x| {
+                let (slot, hash) = x.split_once(':').unwrap();
+                wal::ChainPoint::Specific(slot.parse().unwrap(), hash.parse().unwrap())
+            })
+            .collect();
+
+        assert_eq!(points.len(), 3);
+
+        for point in points {
+            assert!(wal.find_intersect(&[point]).unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_error_carries_reconnect_hints() {
+        let mut wal = testing::db_with_dummy_blocks(5);
+
+        let mut oversized = testing::dummy_block_from_slot(5);
+        oversized.body = vec![0; 8192];
+
+        wal.roll_forward(std::iter::once(oversized)).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let mut service = ChainSyncServiceImpl::new(wal, ledger, 100, None);
+        service.set_max_block_size(4096);
+        service.set_reconnect_delay(Duration::from_millis(2500));
+
+        let shutdown = CancellationToken::new();
+        service.set_shutdown(shutdown.clone());
+
+        let request = |slot: u64| {
+            Request::new(u5c::sync::FollowTipRequest {
+                intersect: vec![u5c::sync::BlockRef {
+                    index: slot,
+                    hash: testing::slot_to_hash(slot).to_vec().into(),
+                }],
+                ..Default::default()
+            })
+        };
+
+        let mut stream = service.follow_tip(request(2)).await.unwrap().into_inner();
+
+        let status = loop {
+            match stream.next().await.unwrap() {
+                Ok(_) => continue,
+                Err(status) => break status,
+            }
+        };
+
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+
+        let header = |status: &Status, name: &str| {
+            status
+                .metadata()
+                .get(name)
+                .map(|x| x.to_str().unwrap().to_string())
+        };
+
+        // the client resumes from the last block it got, the one before the
+        // oversized block
+        let resume = format!("4:{}", testing::slot_to_hash(4));
+        assert_eq!(header(&status, RESUME_POINT_HEADER), Some(resume));
+        assert_eq!(header(&status, RETRY_AFTER_HEADER).unwrap(), "2500");
+        assert!(header(&status, KNOWN_POINTS_HEADER).is_some());
+
+        // the stream ends after the error
+        assert!(stream.next().await.is_none());
+
+        // batched streams end the same way
+        let limits = BatchLimits {
+            max_count: 1,
+            ..Default::default()
+        };
+
+        let mut batches = service
+            .follow_tip_batched(request(2).into_inner(), limits, MappingOptions::default())
+            .unwrap();
+
+        let status = loop {
+            match batches.next().await.unwrap() {
+                Ok(_) => continue,
+                Err(status) => break status,
+            }
+        };
+
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+        let resume = format!("4:{}", testing::slot_to_hash(4));
+        assert_eq!(header(&status, RESUME_POINT_HEADER), Some(resume));
+        assert!(batches.next().await.is_none());
+
+        // a shutdown ends the streams that are waiting for new blocks
+        let mut stream = service.follow_tip(request(5)).await.unwrap().into_inner();
+        shutdown.cancel();
+
+        let status = loop {
+            match stream.next().await.unwrap() {
+                Ok(_) => continue,
+                Err(status) => break status,
+            }
+        };
+
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(header(&status, RETRY_AFTER_HEADER).unwrap(), "2500");
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_catch_up() {
+        let mut wal = testing::db_with_dummy_blocks(20);
+
+        let stream = with_catch_up(
+            wal::WalStream::start(wal.clone(), 0),
+            Duration::from_millis(100),
+        );
+
+        let mut stream = Box::pin(stream);
+
+        // origin mark plus all of the blocks already in the wal
+        for _ in 0..21 {
+            let event = stream.next().await.unwrap();
+            assert!(matches!(event, TipEvent::Log(..)));
+        }
+
+        let event = stream.next().await.unwrap();
+        assert!(matches!(event, TipEvent::CaughtUp));
+
+        // new blocks after catching up are streamed without any other marker
+        wal.roll_forward((20..25).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        for slot in 20..25 {
+            match stream.next().await.unwrap() {
+                TipEvent::Log((_, wal::LogValue::Apply(x))) => assert_eq!(x.slot, slot),
+                _ => panic!("expected apply"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_keepalive() {
+        let mut wal = testing::db_with_dummy_blocks(3);
+
+        let stream = with_catch_up(
+            wal::WalStream::start(wal.clone(), 0),
+            Duration::from_millis(200),
+        );
+
+        let mut stream = Box::pin(with_keepalive(stream, Duration::from_millis(50)));
+
+        // origin mark plus the blocks already in the wal
+        for _ in 0..4 {
+            let event = stream.next().await.unwrap();
+            assert!(matches!(event, TipEvent::Log(..)));
+        }
+
+        // no heartbeats while catching up, the caught-up marker comes first
+        let event = stream.next().await.unwrap();
+        assert!(matches!(event, TipEvent::CaughtUp));
+
+        // the chain is quiet, heartbeats keep coming
+        for _ in 0..2 {
+            let event = stream.next().await.unwrap();
+            assert!(matches!(event, TipEvent::Keepalive));
+        }
+
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(3)))
+            .unwrap();
+
+        match stream.next().await.unwrap() {
+            TipEvent::Log((_, wal::LogValue::Apply(x))) => assert_eq!(x.slot, 3),
+            _ => panic!("expected apply"),
+        }
+
+        let event = stream.next().await.unwrap();
+        assert!(matches!(event, TipEvent::Keepalive));
+
+        // heartbeats map to responses without action, not to resets
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let mapper = Mapper::new(ledger.clone());
+        let response = tip_event_response(&mapper, TipEvent::Keepalive, Default::default(), 0);
+        assert_eq!(response.unwrap().unwrap().action, None);
+
+        // clients learn that heartbeats are on from the stream metadata
+        let mut service = ChainSyncServiceImpl::new(wal, ledger, 100, None);
+        service.set_keepalive(Duration::from_secs(30));
+
+        let request = Request::new(u5c::sync::FollowTipRequest::default());
+        let response = service.follow_tip(request).await.unwrap();
+        assert_eq!(
+            read_header(response.metadata(), KEEPALIVE_HEADER),
+            Some(30000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_reorg_limit() {
+        let mut wal = testing::db_with_dummy_blocks(20);
+
+        let stream = wal::WalStream::start(wal.clone(), 0).map(TipEvent::Log);
+        let mut stream = Box::pin(with_reorg_limit(stream, 3));
+
+        // a shallow rollback is streamed as individual undos
+        let point = wal::ChainPoint::Specific(17, testing::slot_to_hash(17));
+        wal.roll_back(&point).unwrap();
+        wal.roll_forward((18..20).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        // origin mark plus all of the blocks already in the wal
+        for _ in 0..21 {
+            let event = stream.next().await.unwrap();
+            assert!(matches!(event, TipEvent::Log(..)));
+        }
+
+        for slot in [19, 18] {
+            match stream.next().await.unwrap() {
+                TipEvent::Log((_, wal::LogValue::Undo(x))) => assert_eq!(x.slot, slot),
+                _ => panic!("expected undo"),
+            }
+        }
+
+        match stream.next().await.unwrap() {
+            TipEvent::Log((_, wal::LogValue::Mark(x))) => assert_eq!(x, point),
+            _ => panic!("expected mark"),
+        }
+
+        for slot in [18, 19] {
+            match stream.next().await.unwrap() {
+                TipEvent::Log((_, wal::LogValue::Apply(x))) => assert_eq!(x.slot, slot),
+                _ => panic!("expected apply"),
+            }
+        }
+
+        // wait at the tip so that the next rollback is picked up as a change
+        let idle = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+        assert!(idle.is_err());
+
+        // a deep one is collapsed into a single reset
+        let point = wal::ChainPoint::Specific(5, testing::slot_to_hash(5));
+        wal.roll_back(&point).unwrap();
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(6)))
+            .unwrap();
+
+        match stream.next().await.unwrap() {
+            TipEvent::Reset(x) => assert_eq!(x, point),
+            _ => panic!("expected reset"),
+        }
+
+        match stream.next().await.unwrap() {
+            TipEvent::Log((_, wal::LogValue::Apply(x))) => assert_eq!(x.slot, 6),
+            _ => panic!("expected apply"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_batching_byte_cap() {
+        let block = |slot: u64, size: usize| RawBlock {
+            slot,
+            hash: testing::slot_to_hash(slot),
+            era: pallas::ledger::traverse::Era::Byron,
+            body: vec![0; size],
+        };
+
+        let apply =
+            |seq: u64, size: usize| TipEvent::Log((seq, wal::LogValue::Apply(block(seq, size))));
+
+        let mut events: Vec<_> = (0..5).map(|x| apply(x, 40)).collect();
+        events.push(apply(5, 250));
+        events.push(apply(6, 40));
+        events.push(TipEvent::Log((7, wal::LogValue::Undo(block(6, 40)))));
+        events.extend((8..10).map(|x| apply(x, 40)));
+
+        let limits = BatchLimits {
+            max_count: 100,
+            max_bytes: 100,
+        };
+
+        let batches: Vec<_> = with_batching(futures_util::stream::iter(events), limits)
+            .collect()
+            .await;
+
+        let shape: Vec<_> = batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .iter()
+                    .map(|x| batchable_size(x).unwrap_or(0))
+                    .sum::<usize>()
+            })
+            .collect();
+
+        // pairs of 40 bytes fit, a third one would go over the cap
+        assert_eq!(shape, vec![80, 80, 40, 250, 40, 0, 80]);
+
+        for batch in batches.iter() {
+            let bytes: usize = batch.iter().filter_map(batchable_size).sum();
+            assert!(bytes <= limits.max_bytes || batch.len() == 1);
+        }
+
+        // nothing is lost or reordered along the way
+        let slots: Vec<_> = batches
+            .into_iter()
+            .flatten()
+            .map(|x| match x {
+                TipEvent::Log((seq, _)) => seq,
+                _ => panic!("unexpected event"),
+            })
+            .collect();
+
+        assert_eq!(slots, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_follow_tip_batching_opt_in() {
+        let limits = |headers: &[(&'static str, &'static str)]| {
+            let mut metadata = tonic::metadata::MetadataMap::new();
+
+            for (key, value) in headers {
+                metadata.insert(*key, MetadataValue::from_static(value));
+            }
+
+            BatchLimits::from_metadata(&metadata).map(|x| (x.max_count, x.max_bytes))
+        };
+
+        let default_bytes = BatchLimits::default().max_bytes;
+
+        assert_eq!(limits(&[]), None);
+        assert_eq!(limits(&[(BATCH_COUNT_HEADER, "1")]), None);
+        assert_eq!(limits(&[(BATCH_COUNT_HEADER, "many")]), None);
+        assert_eq!(limits(&[(BATCH_BYTES_HEADER, "500")]), None);
+        assert_eq!(
+            limits(&[(BATCH_COUNT_HEADER, "10")]),
+            Some((10, default_bytes))
+        );
+        assert_eq!(
+            limits(&[(BATCH_COUNT_HEADER, "10"), (BATCH_BYTES_HEADER, "500")]),
+            Some((10, 500))
+        );
+
+        // opting in doesn't change what the client gets, only how it's sent
+        let wal = testing::db_with_dummy_blocks(20);
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let service = ChainSyncServiceImpl::new(wal, ledger, 100, None);
+
+        let plain = Request::new(u5c::sync::FollowTipRequest::default());
+        let plain: Vec<_> = service
+            .follow_tip(plain)
+            .await
+            .unwrap()
+            .into_inner()
+            .take(21)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        let mut batched = Request::new(u5c::sync::FollowTipRequest::default());
+        batched
+            .metadata_mut()
+            .insert(BATCH_COUNT_HEADER, MetadataValue::from_static("8"));
+
+        let batched: Vec<_> = service
+            .follow_tip(batched)
+            .await
+            .unwrap()
+            .into_inner()
+            .take(21)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(batched, plain);
+    }
+
+    /// Ledger context that counts the reads that reach the ledger
+    #[derive(Clone)]
+    struct CountingLedger(ledger::store::LedgerStore, Arc<AtomicUsize>);
+
+    impl interop::LedgerContext for CountingLedger {
+        fn get_utxos<'a>(&self, refs: &[interop::TxoRef]) -> Option<interop::UtxoMap> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            interop::LedgerContext::get_utxos(&self.0, refs)
+        }
+    }
+
+    /// Splits the test block in two, so that one of the outputs produced in
+    /// the first block is spent in the second one
+    ///
+    /// The first block holds the tx that produces an output spent by another
+    /// tx of the original block, the second block holds the rest of the txs.
+    /// Returns both blocks and the reference to the chained output.
+    fn split_chained_block() -> (RawBlock, RawBlock, interop::TxoRef) {
+        use pallas::codec::minicbor;
+        use pallas::codec::utils::{KeyValuePairs, MaybeIndefArray};
+        use pallas::ledger::primitives::alonzo;
+
+        let raw = testing::test_data_block(0);
+        let decoded = raw.decode().unwrap();
+        let hashes: Vec<_> = decoded.txs().iter().map(|x| x.hash()).collect();
+
+        let (producer, chained) = decoded
+            .txs()
+            .iter()
+            .flat_map(|tx| tx.consumes())
+            .find_map(|input| {
+                let idx = hashes.iter().position(|x| x == input.hash())?;
+                Some((idx, (*input.hash(), input.index() as u32)))
+            })
+            .unwrap();
+
+        let (era, block): (u16, alonzo::MintedBlock) = minicbor::decode(&raw.body).unwrap();
+
+        let pick = |slot: u64, keep: &dyn Fn(usize) -> bool| {
+            let mut block = block.clone();
+
+            let bodies = block.transaction_bodies.iter().cloned().enumerate();
+            block.transaction_bodies =
+                MaybeIndefArray::Def(bodies.filter(|(i, _)| keep(*i)).map(|(_, x)| x).collect());
+
+            let witnesses = block.transaction_witness_sets.iter().cloned().enumerate();
+            block.transaction_witness_sets = MaybeIndefArray::Def(
+                witnesses
+                    .filter(|(i, _)| keep(*i))
+                    .map(|(_, x)| x)
+                    .collect(),
+            );
+
+            // aux data is keyed by tx index, it's not relevant here
+            block.auxiliary_data_set = KeyValuePairs::Def(vec![]);
+            block.invalid_transactions = None;
+
+            RawBlock {
+                slot,
+                hash: testing::slot_to_hash(slot),
+                era: raw.era,
+                body: minicbor::to_vec((era, block)).unwrap(),
+            }
+        };
+
+        let first = pick(1, &|i| i == producer);
+        let second = pick(2, &|i| i != producer);
+
+        (first, second, chained)
+    }
+
+    fn cardano_txs(block: &u5c::sync::AnyChainBlock) -> Vec<u5c::cardano::Tx> {
+        match &block.chain {
+            Some(u5c::sync::any_chain_block::Chain::Cardano(x)) => {
+                x.body.clone().map(|x| x.tx).unwrap_or_default()
+            }
+            _ => vec![],
+        }
+    }
+
+    fn find_input(txs: &[u5c::cardano::Tx], txo: &interop::TxoRef) -> u5c::cardano::TxInput {
+        txs.iter()
+            .flat_map(|x| x.inputs.iter())
+            .find(|x| x.tx_hash.as_ref() == txo.0.as_slice() && x.output_index == txo.1)
+            .cloned()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parallel_decode_matches_sequential() {
+        let mut wal = testing::empty_db();
+
+        // real blocks with txs, mixed with undecodable ones to check that the
+        // skipped slots line up too
+        let blocks = (0..50).map(|slot| match slot % 10 {
+            7 => {
+                let mut block = testing::dummy_block_from_slot(slot);
+                block.body = vec![0xff; 64];
+                block
+            }
+            _ => testing::test_data_block(slot),
+        });
+
+        wal.roll_forward(blocks).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let read = |decode_parallelism: usize| {
+            let options = PageOptions {
+                with_stats: true,
+                skip_invalid: true,
+                decode_parallelism,
+                workers: PageWorkers::new(4),
+                ..Default::default()
+            };
+
+            read_history_page(&wal, &ledger, None, 40, &options).unwrap()
+        };
+
+        let (sequential, seq_stats, seq_skipped) = read(1);
+        assert_eq!(sequential.block.len(), 36);
+        assert_eq!(seq_skipped, vec![7, 17, 27, 37]);
+
+        let (parallel, stats, skipped) = read(4);
+        assert_eq!(parallel, sequential);
+        assert_eq!(stats.tx_count, seq_stats.tx_count);
+        assert_eq!(stats.byte_size, seq_stats.byte_size);
+        assert_eq!(skipped, seq_skipped);
+    }
+
+    #[test]
+    fn test_history_page_resolves_inputs_across_blocks() {
+        let (first, second, chained) = split_chained_block();
+
+        let mut wal = testing::empty_db();
+        wal.roll_forward([first, second].into_iter()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let ledger = CountingLedger(store, reads.clone());
+
+        // block by block, each tx goes to the ledger and the output spent in
+        // the second block can't be resolved since it's not in the ledger yet
+        let mapper = Mapper::new(ledger.clone());
+
+        let blocks: Vec<_> = wal
+            .read_block_page(None, 10)
+            .unwrap()
+            .map(|raw| block_to_anychain(&mapper, &raw.decode().unwrap(), Default::default()))
+            .collect();
+
+        let per_block_reads = reads.swap(0, Ordering::SeqCst);
+        assert!(per_block_reads > 1);
+        assert_eq!(
+            find_input(&cardano_txs(&blocks[1]), &chained).as_output,
+            None
+        );
+
+        // the page resolves it from the first block, with a single ledger read
+        let (page, _, _) = read_history_page(&wal, &ledger, None, 10, &Default::default()).unwrap();
+
+        assert_eq!(page.block.len(), 2);
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        let produced = cardano_txs(&page.block[0]);
+        let expected = produced[0].outputs[chained.1 as usize].clone();
+
+        let input = find_input(&cardano_txs(&page.block[1]), &chained);
+        assert_eq!(input.as_output, Some(expected));
+    }
+
+    #[test]
+    fn test_concurrent_resolution_matches_sequential() {
+        let raw = testing::test_data_block(0);
+        let decoded = raw.decode().unwrap();
+        let (_, output) = decoded.txs()[0].produces().into_iter().next().unwrap();
+        let body = ledger::EraCbor::from(output);
+
+        let refs: Vec<_> = (0..500u32)
+            .map(|x| ledger::TxoRef(testing::slot_to_hash(x as u64 / 10), x % 10))
+            .collect();
+
+        // every other ref is in the ledger, the rest can't be resolved
+        let delta = ledger::LedgerDelta {
+            new_position: Some(ledger::ChainPoint(1, testing::slot_to_hash(1))),
+            produced_utxo: refs
+                .iter()
+                .step_by(2)
+                .map(|x| (x.clone(), body.clone()))
+                .collect(),
+            ..Default::default()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ledger::store::LedgerStore::open(dir.path().join("ledger")).unwrap();
+        store.apply(&[delta]).unwrap();
+
+        let reads = Arc::new(AtomicUsize::new(0));
+        let ledger = CountingLedger(store, reads.clone());
+
+        let refs: Vec<interop::TxoRef> = refs.into_iter().map(|x| x.into()).collect();
+
+        let pool = PageWorkers::new(4);
+
+        let sequential = read_utxos(&ledger, &refs, 1, &pool);
+        assert_eq!(reads.swap(0, Ordering::SeqCst), 1);
+        assert_eq!(sequential.len(), 250);
+
+        let concurrent = read_utxos(&ledger, &refs, 4, &pool);
+        assert_eq!(reads.swap(0, Ordering::SeqCst), 4);
+        assert_eq!(concurrent, sequential);
+
+        // reads are capped by the threads left in the pool
+        let (_, held) = pool.reserve(3);
+        let capped = read_utxos(&ledger, &refs, 4, &pool);
+        assert_eq!(reads.swap(0, Ordering::SeqCst), 1);
+        assert_eq!(capped, sequential);
+        drop(held);
+
+        // chunks don't go below the minimum size
+        let small = read_utxos(&ledger, &refs[..MIN_RESOLVE_CHUNK], 4, &pool);
+        assert_eq!(reads.swap(0, Ordering::SeqCst), 1);
+        assert_eq!(small.len(), MIN_RESOLVE_CHUNK / 2);
+    }
+
+    #[test]
+    fn test_map_chunked_reports_panics() {
+        let items: Vec<usize> = (0..100).collect();
+        let pool = PageWorkers::new(4);
+
+        let doubled = map_chunked(&items, 4, 10, &pool, |x| x * 2).unwrap();
+        assert_eq!(doubled, items.iter().map(|x| x * 2).collect::<Vec<_>>());
 
-        let stream = wal::WalStream::start(self.wal.clone(), from_seq)
-            .map(move |(_, log)| Ok(roll_to_tip_response(&mapper, &log)));
+        let status = map_chunked(&items, 4, 10, &pool, |x| {
+            assert_ne!(*x, 60);
+            *x
+        })
+        .unwrap_err();
 
-        Ok(Response::new(Box::pin(stream)))
+        assert_eq!(status.code(), tonic::Code::Internal);
     }
 }
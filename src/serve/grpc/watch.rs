@@ -6,46 +6,57 @@ use futures_core::Stream;
 use futures_util::StreamExt;
 use pallas::interop::utxorpc as interop;
 use pallas::interop::utxorpc::spec as u5c;
-use pallas::ledger::traverse::MultiEraBlock;
 use std::pin::Pin;
 use tonic::{Request, Response, Status};
 
 fn block_to_txs(
     block: &wal::RawBlock,
     mapper: &interop::Mapper<ledger::store::LedgerStore>,
-) -> Vec<u5c::watch::AnyChainTx> {
-    let wal::RawBlock { body, .. } = block;
-    let block = MultiEraBlock::decode(body).unwrap();
+) -> Result<Vec<u5c::watch::AnyChainTx>, Status> {
+    let block = block.decode()?;
     let txs = block.txs();
 
-    txs.iter()
+    let out = txs
+        .iter()
         .map(|x| mapper.map_tx(x))
         .map(|x| u5c::watch::AnyChainTx {
             chain: Some(u5c::watch::any_chain_tx::Chain::Cardano(x)),
         })
-        .collect()
+        .collect();
+
+    Ok(out)
 }
 
 fn roll_to_watch_response(
     mapper: &interop::Mapper<ledger::store::LedgerStore>,
     log: &wal::LogValue,
-) -> impl Stream<Item = u5c::watch::WatchTxResponse> {
-    let txs: Vec<_> = match log {
-        wal::LogValue::Apply(block) => block_to_txs(block, mapper)
-            .into_iter()
-            .map(u5c::watch::watch_tx_response::Action::Apply)
-            .map(|x| u5c::watch::WatchTxResponse { action: Some(x) })
-            .collect(),
-        wal::LogValue::Undo(block) => block_to_txs(block, mapper)
-            .into_iter()
-            .map(u5c::watch::watch_tx_response::Action::Undo)
-            .map(|x| u5c::watch::WatchTxResponse { action: Some(x) })
-            .collect(),
+) -> impl Stream<Item = Result<u5c::watch::WatchTxResponse, Status>> {
+    let txs: Result<Vec<_>, Status> = match log {
+        wal::LogValue::Apply(block) => block_to_txs(block, mapper).map(|txs| {
+            txs.into_iter()
+                .map(u5c::watch::watch_tx_response::Action::Apply)
+                .map(|x| u5c::watch::WatchTxResponse { action: Some(x) })
+                .collect()
+        }),
+        // a stripped undo doesn't tell which txs were undone
+        wal::LogValue::Undo(block) if block.body.is_empty() => Ok(vec![]),
+        wal::LogValue::Undo(block) => block_to_txs(block, mapper).map(|txs| {
+            txs.into_iter()
+                .map(u5c::watch::watch_tx_response::Action::Undo)
+                .map(|x| u5c::watch::WatchTxResponse { action: Some(x) })
+                .collect()
+        }),
         // TODO: shouldn't we have a u5c event for origin?
-        wal::LogValue::Mark(..) => vec![],
+        wal::LogValue::Mark(..) => Ok(vec![]),
+    };
+
+    // a block that can't be decoded terminates the stream with the error
+    let items: Vec<_> = match txs {
+        Ok(txs) => txs.into_iter().map(Ok).collect(),
+        Err(err) => vec![Err(err)],
     };
 
-    tokio_stream::iter(txs)
+    tokio_stream::iter(items)
 }
 
 pub struct WatchServiceImpl {
@@ -84,8 +95,7 @@ impl u5c::watch::watch_service_server::WatchService for WatchServiceImpl {
         let mapper = self.mapper.clone();
 
         let stream = wal::WalStream::start(self.wal.clone(), from_seq)
-            .flat_map(move |(_, log)| roll_to_watch_response(&mapper, &log))
-            .map(Ok);
+            .flat_map(move |(_, log)| roll_to_watch_response(&mapper, &log));
 
         Ok(Response::new(Box::pin(stream)))
     }
@@ -6,8 +6,10 @@ use tokio_util::sync::CancellationToken;
 use tracing::info;
 
 use crate::ledger::store::LedgerStore;
+use crate::querydb::store::Store as Archive;
 use crate::wal::redb::WalStore;
 
+pub mod fetch;
 pub mod grpc;
 
 #[cfg(unix)]
@@ -28,23 +30,59 @@ pub struct Config {
     pub ouroboros: Option<o7s::Config>,
 }
 
+impl Config {
+    /// Checks the settings of every enabled endpoint
+    pub fn validate(&self) -> Result<(), crate::prelude::Error> {
+        if let Some(grpc) = &self.grpc {
+            grpc.validate()?;
+        }
+
+        if let Some(o7s) = &self.ouroboros {
+            let parent = o7s
+                .listen_path
+                .parent()
+                .filter(|x| !x.as_os_str().is_empty());
+
+            if parent.is_some_and(|x| !x.is_dir()) {
+                return Err(crate::prelude::Error::config(format!(
+                    "directory of the Ouroboros socket '{}' doesn't exist",
+                    o7s.listen_path.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Stores and submit plumbing shared by the endpoints
+pub struct ServeContext {
+    pub wal: WalStore,
+    pub ledger: LedgerStore,
+    pub mempool: Arc<crate::submit::MempoolState>,
+    pub txs_out: gasket::messaging::tokio::ChannelSendAdapter<crate::submit::Submission>,
+    pub fee_filter: Option<Arc<crate::submit::MinFeeFilter>>,
+    pub input_check: Option<Arc<crate::submit::InputCheck>>,
+    pub fairness: Option<crate::submit::FairnessPolicy>,
+    pub archive: Option<Arc<Archive>>,
+}
+
 /// Serve remote requests
 ///
 /// Uses specified config to start listening for network connections on either
 /// gRPC, Ouroboros or both protocols.
 pub async fn serve(
     config: Config,
-    wal: WalStore,
-    ledger: LedgerStore,
-    mempool: Arc<crate::submit::MempoolState>,
-    txs_out: gasket::messaging::tokio::ChannelSendAdapter<Vec<crate::submit::Transaction>>,
+    context: ServeContext,
     exit: CancellationToken,
 ) -> miette::Result<()> {
+    let wal = context.wal.clone();
+
     let grpc = async {
         if let Some(cfg) = config.grpc {
             info!("found gRPC config");
 
-            grpc::serve(cfg, wal.clone(), ledger, mempool, txs_out, exit.clone())
+            grpc::serve(cfg, context, exit.clone())
                 .await
                 .into_diagnostic()
                 .context("serving gRPC")
@@ -0,0 +1,181 @@
+use pallas::crypto::hash::Hash;
+use std::collections::HashMap;
+use thiserror::Error;
+
+use super::Transaction;
+use crate::ledger::{store::LedgerStore, TxoRef};
+
+#[derive(Debug, Error)]
+pub enum AdmissionError {
+    #[error("input {0:?} doesn't exist or is already spent")]
+    InputNotFound(TxoRef),
+
+    #[error("input {0:?} is already spent by pending tx {1}")]
+    DoubleSpend(TxoRef, Hash<32>),
+
+    #[error("can't read ledger")]
+    Ledger(#[source] redb::Error),
+}
+
+/// Rejects txs whose inputs can't be spent
+///
+/// Each input has to be unspent in the ledger and not consumed by another tx
+/// that is still pending in the mempool. Txs that fail this would never make
+/// it on-chain, so there's no point in propagating them. This is opt-in since
+/// it reads the ledger for every submitted tx.
+pub struct InputCheck {
+    ledger: LedgerStore,
+}
+
+impl InputCheck {
+    pub fn new(ledger: LedgerStore) -> Self {
+        Self { ledger }
+    }
+
+    /// Checks the inputs of the tx against the ledger and the pending spends
+    ///
+    /// `pending` maps the inputs consumed by pending txs to the tx that spends
+    /// them, see `Monitor::pending_spends`.
+    pub fn check(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<TxoRef, Hash<32>>,
+    ) -> Result<(), AdmissionError> {
+        let inputs = tx.inputs();
+
+        for input in inputs {
+            if let Some(spender) = pending.get(input) {
+                if *spender != tx.hash {
+                    return Err(AdmissionError::DoubleSpend(input.clone(), *spender));
+                }
+            }
+        }
+
+        let unspent = self
+            .ledger
+            .get_unspent_utxos(inputs.to_vec())
+            .map_err(AdmissionError::Ledger)?;
+
+        match inputs.iter().find(|x| !unspent.contains_key(x)) {
+            Some(missing) => Err(AdmissionError::InputNotFound(missing.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::traverse::MultiEraBlock;
+
+    use super::*;
+    use crate::ledger::{ChainPoint, EraCbor, LedgerDelta};
+
+    fn load_test_txs() -> Vec<(Transaction, Vec<(TxoRef, EraCbor)>)> {
+        let cbor = crate::wal::testing::test_data_cbor();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        // any output works as the body of the inputs, only their refs matter
+        let (_, body) = block.txs()[0].produces().into_iter().next().unwrap();
+        let body = EraCbor::from(body);
+
+        block
+            .txs()
+            .iter()
+            .map(|tx| {
+                let tx = Transaction::new(tx.hash(), u16::from(tx.era()) - 1, tx.encode());
+                let utxos = tx
+                    .inputs()
+                    .iter()
+                    .map(|x| (x.clone(), body.clone()))
+                    .collect();
+                (tx, utxos)
+            })
+            .collect()
+    }
+
+    fn ledger_with(utxos: Vec<(TxoRef, EraCbor)>) -> (tempfile::TempDir, LedgerStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let delta = LedgerDelta {
+            new_position: Some(ChainPoint(1, Hash::new([1; 32]))),
+            produced_utxo: utxos.into_iter().collect(),
+            ..Default::default()
+        };
+
+        store.apply(&[delta]).unwrap();
+
+        (dir, store)
+    }
+
+    #[test]
+    fn test_phantom_input_is_rejected() {
+        let mut txs = load_test_txs();
+        let (tx, mut utxos) = txs.remove(0);
+
+        let (_dir, ledger) = ledger_with(utxos.clone());
+        let check = InputCheck::new(ledger);
+        assert!(check.check(&tx, &HashMap::new()).is_ok());
+
+        // one of the inputs was never produced
+        let (phantom, _) = utxos.pop().unwrap();
+
+        let (_dir, ledger) = ledger_with(utxos);
+        let check = InputCheck::new(ledger);
+
+        match check.check(&tx, &HashMap::new()) {
+            Err(AdmissionError::InputNotFound(x)) => assert_eq!(x, phantom),
+            x => panic!("expected missing input, got {x:?}"),
+        }
+    }
+
+    #[test]
+    fn test_spent_input_is_rejected() {
+        let mut txs = load_test_txs();
+        let (tx, utxos) = txs.remove(0);
+
+        let (_dir, mut ledger) = ledger_with(utxos.clone());
+
+        let (spent, body) = utxos[0].clone();
+
+        let spend = LedgerDelta {
+            new_position: Some(ChainPoint(2, Hash::new([2; 32]))),
+            consumed_utxo: HashMap::from([(spent.clone(), body)]),
+            ..Default::default()
+        };
+
+        ledger.apply(&[spend]).unwrap();
+
+        let check = InputCheck::new(ledger);
+
+        match check.check(&tx, &HashMap::new()) {
+            Err(AdmissionError::InputNotFound(x)) => assert_eq!(x, spent),
+            x => panic!("expected missing input, got {x:?}"),
+        }
+    }
+
+    #[test]
+    fn test_mempool_double_spend_is_rejected() {
+        let mut txs = load_test_txs();
+        let (tx, utxos) = txs.remove(0);
+
+        let (_dir, ledger) = ledger_with(utxos.clone());
+        let check = InputCheck::new(ledger);
+
+        let other = Hash::new([7; 32]);
+        let (input, _) = utxos[0].clone();
+        let pending = HashMap::from([(input.clone(), other)]);
+
+        match check.check(&tx, &pending) {
+            Err(AdmissionError::DoubleSpend(x, spender)) => {
+                assert_eq!(x, input);
+                assert_eq!(spender, other);
+            }
+            x => panic!("expected double spend, got {x:?}"),
+        }
+
+        // resubmitting the same tx is not a double spend
+        let pending = HashMap::from([(input, tx.hash)]);
+        assert!(check.check(&tx, &pending).is_ok());
+    }
+}
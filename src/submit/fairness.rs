@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::mempool::Monitor;
+use crate::prelude::QuotaKey;
+
+#[derive(Debug, Error)]
+pub enum FairnessError {
+    #[error("mempool is full, it already holds {0} pending txs")]
+    MempoolFull(usize),
+
+    #[error("submitter already holds {0} pending txs, the max for a single submitter")]
+    ShareExceeded(usize),
+}
+
+/// Pending txs in the mempool, as seen by a submitter
+///
+/// Fairness checks only need these counts, so they can be copied out of the
+/// mempool lock instead of holding it while a submission is checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubmitterLoad {
+    /// Pending txs of every submitter
+    pub pending: usize,
+
+    /// Pending txs of the submitter itself
+    pub owned: usize,
+}
+
+impl SubmitterLoad {
+    pub fn of(monitor: &Monitor, submitter: &str) -> Self {
+        Self {
+            pending: monitor.pending_count(),
+            owned: monitor.pending_by(submitter),
+        }
+    }
+}
+
+/// Caps the room that a single submitter can take in the mempool
+///
+/// Without a cap, a submitter flooding txs fills the mempool and everyone
+/// else is turned away. Only pending txs count, room is given back as soon as
+/// a tx is included or expires.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FairnessPolicy {
+    /// Max number of pending txs in the mempool
+    pub capacity: usize,
+
+    /// Fraction of the capacity that a single submitter can take, from 0 to 1
+    pub max_share: f64,
+
+    /// What tells submitters apart
+    #[serde(default)]
+    pub key: QuotaKey,
+}
+
+impl FairnessPolicy {
+    /// Max number of pending txs of a single submitter, never less than one
+    pub fn max_per_submitter(&self) -> usize {
+        let share = (self.capacity as f64 * self.max_share).floor() as usize;
+        share.max(1)
+    }
+
+    /// Checks if the submitter can add `count` new txs to the mempool
+    pub fn check(
+        &self,
+        monitor: &Monitor,
+        submitter: &str,
+        count: usize,
+    ) -> Result<(), FairnessError> {
+        self.check_load(&SubmitterLoad::of(monitor, submitter), count)
+    }
+
+    /// Same as `check`, against counts copied from the mempool
+    pub fn check_load(&self, load: &SubmitterLoad, count: usize) -> Result<(), FairnessError> {
+        let SubmitterLoad { pending, owned } = *load;
+
+        if pending + count > self.capacity {
+            return Err(FairnessError::MempoolFull(pending));
+        }
+
+        if owned + count > self.max_per_submitter() {
+            return Err(FairnessError::ShareExceeded(owned));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::crypto::hash::Hasher;
+
+    use super::*;
+    use crate::submit::{Submission, Transaction};
+
+    fn load_test_txs(count: usize, salt: &str) -> Vec<Transaction> {
+        let cbor = crate::wal::testing::test_data_cbor();
+        let block = pallas::ledger::traverse::MultiEraBlock::decode(&cbor).unwrap();
+
+        let tx = &block.txs()[0];
+        let tx = Transaction::new(tx.hash(), u16::from(tx.era()) - 1, tx.encode());
+
+        // same body, different hashes, so each one is tracked on its own
+        (0..count)
+            .map(|i| Transaction {
+                hash: Hasher::<256>::hash(format!("{salt}{i}").as_bytes()),
+                ..tx.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_greedy_submitter_doesnt_starve_others() {
+        let policy = FairnessPolicy {
+            capacity: 4,
+            max_share: 0.5,
+            key: QuotaKey::Peer,
+        };
+
+        let mut monitor = Monitor::default();
+
+        let greedy = Submission {
+            submitter: "peer:10.0.0.1".into(),
+            txs: load_test_txs(4, "greedy"),
+        };
+
+        let admitted = monitor.add_submission(&greedy, Some(&policy));
+        assert_eq!(admitted, greedy.txs[..2]);
+        assert_eq!(monitor.pending_by(&greedy.submitter), 2);
+
+        // more txs of the greedy submitter don't fit its share
+        assert!(matches!(
+            policy.check(&monitor, &greedy.submitter, 1),
+            Err(FairnessError::ShareExceeded(2))
+        ));
+
+        // resubmitting a tracked tx takes no extra room
+        let admitted = monitor.add_submission(&greedy, Some(&policy));
+        assert_eq!(admitted, greedy.txs[..2]);
+
+        // but the other submitter still has room
+        let other = Submission {
+            submitter: "peer:10.0.0.2".into(),
+            txs: load_test_txs(2, "other"),
+        };
+
+        assert!(policy.check(&monitor, &other.submitter, 2).is_ok());
+
+        let admitted = monitor.add_submission(&other, Some(&policy));
+        assert_eq!(admitted, other.txs);
+        assert_eq!(monitor.pending_count(), 4);
+
+        // once full, nobody gets in
+        assert!(matches!(
+            policy.check(&monitor, "peer:10.0.0.3", 1),
+            Err(FairnessError::MempoolFull(4))
+        ));
+
+        // included txs give their room back to the submitter
+        monitor.txs.insert(greedy.txs[0].hash, Some(100));
+        assert_eq!(monitor.pending_by(&greedy.submitter), 1);
+        assert!(policy.check(&monitor, &greedy.submitter, 1).is_ok());
+    }
+}
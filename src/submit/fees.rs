@@ -0,0 +1,250 @@
+use pallas::{
+    applying::utils::MultiEraProtocolParameters,
+    ledger::{
+        configs::{alonzo, byron, shelley},
+        traverse::MultiEraTx,
+    },
+};
+use std::sync::Mutex;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::ledger::{self, store::LedgerStore, ChainPoint};
+
+#[derive(Debug, Error)]
+pub enum FeeError {
+    #[error("tx fee {fee} is below the protocol minimum of {min}")]
+    FeeTooLow { fee: u64, min: u64 },
+
+    #[error("can't resolve protocol params")]
+    Params(#[source] redb::Error),
+
+    #[error("can't tell the epoch, shelley genesis doesn't define its length")]
+    Genesis,
+}
+
+/// Linear fee params of the Shelley-based eras, `min = a * size + b`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinearFee {
+    pub a: u64,
+    pub b: u64,
+}
+
+impl LinearFee {
+    pub fn from_pparams(pparams: &MultiEraProtocolParameters) -> Option<Self> {
+        match pparams {
+            MultiEraProtocolParameters::Shelley(x) => Some(Self {
+                a: x.minfee_a as u64,
+                b: x.minfee_b as u64,
+            }),
+            MultiEraProtocolParameters::Alonzo(x) => Some(Self {
+                a: x.minfee_a as u64,
+                b: x.minfee_b as u64,
+            }),
+            MultiEraProtocolParameters::Babbage(x) => Some(Self {
+                a: x.minfee_a as u64,
+                b: x.minfee_b as u64,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn min_fee(&self, tx_size: usize) -> u64 {
+        self.a * tx_size as u64 + self.b
+    }
+
+    /// Checks that the fee declared by the tx covers the minimum for its size
+    ///
+    /// Byron txs don't declare a fee, those are always accepted.
+    pub fn check(&self, tx: &MultiEraTx, tx_size: usize) -> Result<(), FeeError> {
+        let Some(fee) = tx.fee() else {
+            return Ok(());
+        };
+
+        let min = self.min_fee(tx_size);
+
+        if fee < min {
+            return Err(FeeError::FeeTooLow { fee, min });
+        }
+
+        Ok(())
+    }
+}
+
+/// Rejects txs that pay less than the protocol minimum fee
+///
+/// The fee params are folded from the genesis files and the updates in the
+/// ledger for the epoch of the ledger tip, and cached until the epoch changes.
+pub struct MinFeeFilter {
+    ledger: LedgerStore,
+    byron: byron::GenesisFile,
+    shelley: shelley::GenesisFile,
+    alonzo: alonzo::GenesisFile,
+    current: Mutex<Option<(u64, Option<LinearFee>)>>,
+}
+
+impl MinFeeFilter {
+    pub fn new(
+        ledger: LedgerStore,
+        byron: byron::GenesisFile,
+        shelley: shelley::GenesisFile,
+        alonzo: alonzo::GenesisFile,
+    ) -> Self {
+        Self {
+            ledger,
+            byron,
+            shelley,
+            alonzo,
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Fee params in effect at the tip of the ledger
+    pub fn current_fee(&self) -> Result<Option<LinearFee>, FeeError> {
+        let Some(ChainPoint(slot, _)) = self.ledger.cursor().map_err(FeeError::Params)? else {
+            return Ok(None);
+        };
+
+        let epoch = ledger::pparams::epoch_at_slot(&self.shelley, slot).ok_or(FeeError::Genesis)?;
+
+        let mut current = self.current.lock().unwrap();
+
+        if let Some((cached, fee)) = current.as_ref() {
+            if *cached == epoch {
+                return Ok(*fee);
+            }
+        }
+
+        let genesis = ledger::pparams::Genesis {
+            byron: &self.byron,
+            shelley: &self.shelley,
+            alonzo: &self.alonzo,
+        };
+
+        let pparams = self
+            .ledger
+            .protocol_params_at(&genesis, epoch)
+            .map_err(FeeError::Params)?;
+
+        let fee = LinearFee::from_pparams(&pparams);
+
+        // byron txs don't declare a fee, any other era should have known params
+        if fee.is_none() && !matches!(pparams, MultiEraProtocolParameters::Byron(_)) {
+            warn!(
+                epoch,
+                "fee params of the era are unknown, min fee isn't checked"
+            );
+        }
+
+        *current = Some((epoch, fee));
+
+        Ok(fee)
+    }
+
+    pub fn check(&self, tx: &MultiEraTx, tx_size: usize) -> Result<(), FeeError> {
+        match self.current_fee()? {
+            Some(fee) => fee.check(tx, tx_size),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::{crypto::hash::Hash, ledger::traverse::MultiEraBlock};
+
+    use super::*;
+    use crate::ledger::{block_pparams_updates, LedgerDelta};
+
+    fn load_test_tx() -> Vec<u8> {
+        let cbor = crate::wal::testing::test_data_cbor();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let tx = block.txs().into_iter().next().unwrap();
+        tx.encode()
+    }
+
+    #[test]
+    fn test_min_fee_check() {
+        let cbor = load_test_tx();
+        let tx = MultiEraTx::decode(&cbor).unwrap();
+        let fee = tx.fee().unwrap();
+
+        // mainnet params, the tx made it on-chain so it pays enough
+        let mainnet = LinearFee { a: 44, b: 155381 };
+        mainnet.check(&tx, cbor.len()).unwrap();
+
+        // the same tx underpays once the constant part goes above its fee
+        let raised = LinearFee { a: 44, b: fee };
+        let result = raised.check(&tx, cbor.len());
+
+        assert!(matches!(
+            result,
+            Err(FeeError::FeeTooLow { fee: x, min }) if x == fee && min == fee + 44 * cbor.len() as u64
+        ));
+    }
+
+    fn load_json<T: serde::de::DeserializeOwned>(path: &str) -> T {
+        let file = std::fs::File::open(path).unwrap();
+        serde_json::from_reader(file).unwrap()
+    }
+
+    #[test]
+    fn test_min_fee_filter_rejects_underpaying_tx() {
+        let test_data = "src/ledger/pparams/test_data/mainnet";
+
+        let shelley: shelley::GenesisFile =
+            load_json(&format!("{test_data}/genesis/shelley_genesis.json"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut ledger = LedgerStore::open(dir.path().join("ledger")).unwrap();
+
+        let deltas: Vec<_> = std::fs::read_dir(format!("{test_data}/update_proposal_blocks"))
+            .unwrap()
+            .map(|x| std::fs::read(x.unwrap().path()).unwrap())
+            .map(|cbor| LedgerDelta {
+                new_pparams: block_pparams_updates(&MultiEraBlock::decode(&cbor).unwrap()),
+                ..Default::default()
+            })
+            .collect();
+
+        ledger.apply(&deltas).unwrap();
+
+        // move the tip into an alonzo epoch, the first slot of epoch 300
+        let slot = 4492800 + (300 - 208) * 432000;
+        assert_eq!(ledger::pparams::epoch_at_slot(&shelley, slot), Some(300));
+
+        let tip = LedgerDelta {
+            new_position: Some(ChainPoint(slot, Hash::new([0; 32]))),
+            ..Default::default()
+        };
+
+        ledger.apply(&[tip]).unwrap();
+
+        let filter = MinFeeFilter::new(
+            ledger,
+            load_json(&format!("{test_data}/genesis/byron_genesis.json")),
+            shelley,
+            load_json(&format!("{test_data}/genesis/alonzo_genesis.json")),
+        );
+
+        assert_eq!(
+            filter.current_fee().unwrap(),
+            Some(LinearFee { a: 44, b: 155381 })
+        );
+
+        let cbor = load_test_tx();
+        let tx = MultiEraTx::decode(&cbor).unwrap();
+        let fee = tx.fee().unwrap();
+
+        filter.check(&tx, cbor.len()).unwrap();
+
+        // the declared fee doesn't cover a tx big enough to push the minimum past it
+        let size = (fee / 44 + 1) as usize;
+
+        assert!(matches!(
+            filter.check(&tx, size),
+            Err(FeeError::FeeTooLow { fee: x, .. }) if x == fee
+        ));
+    }
+}
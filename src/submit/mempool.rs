@@ -1,32 +1,365 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    io::{Read as _, Write as _},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use gasket::framework::*;
 use pallas::crypto::hash::Hash;
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
-use tracing::debug;
+use tracing::{debug, info, warn};
 
-use super::{monitor::BlockMonitorMessage, BlockHeight, BlockSlot, Transaction};
+use super::{
+    fairness::FairnessPolicy, monitor::BlockMonitorMessage, BlockHeight, BlockSlot, ErrorPolicy,
+    OrSkip as _, Transaction,
+};
+use crate::ledger::TxoRef;
 
-pub type SubmitEndpointReceiver = gasket::messaging::InputPort<Vec<Transaction>>;
+pub type SubmitEndpointReceiver = gasket::messaging::InputPort<Submission>;
 pub type BlockMonitorReceiver = gasket::messaging::InputPort<BlockMonitorMessage>;
 
 pub type PropagatorSender = gasket::messaging::OutputPort<Vec<Transaction>>;
 
 type InclusionPoint = BlockHeight;
 
+/// Identifies who submitted a tx, see `FairnessPolicy::key`
+pub type Submitter = String;
+
+/// Txs sent to the mempool in a single request
+#[derive(Debug, Clone)]
+pub struct Submission {
+    pub submitter: Submitter,
+    pub txs: Vec<Transaction>,
+}
+
 #[derive(Debug)]
 pub enum MempoolEvent {
-    AddTxs(Vec<Transaction>),
+    AddTxs(Submission),
     ChainUpdate(BlockMonitorMessage),
 }
 
 #[derive(Default)]
 pub struct MempoolState(pub RwLock<Monitor>, pub tokio::sync::Notify);
 
-#[derive(Default)]
+impl MempoolState {
+    /// Returns a point-in-time copy of the txs tracked by the mempool
+    ///
+    /// The lock is only held while copying, so the result can be inspected
+    /// without blocking the mempool stage.
+    pub async fn snapshot(&self) -> Vec<TxSnapshot> {
+        self.0.read().await.snapshot()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    Pending,
+    Included(InclusionPoint),
+    Expired,
+    /// Never included, evicted after waiting longer than allowed
+    Dropped,
+}
+
+/// Status of a tx tracked by the mempool at the time of the snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxSnapshot {
+    pub hash: Hash<32>,
+    pub status: TxStatus,
+    /// Size in bytes of the tx cbor
+    pub size: usize,
+    /// Number of slots since the tx was added
+    pub age: BlockSlot,
+}
+
+/// Prefix of the files written by `Monitor::persist`
+const MONITOR_MAGIC: &[u8; 4] = b"dmon";
+
+/// Layout of the persisted monitor state, bump it whenever the fields of
+/// `Monitor` change
+const MONITOR_VERSION: u16 = 1;
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct Monitor {
     pub tip_slot: BlockSlot,
     pub txs: HashMap<Hash<32>, Option<InclusionPoint>>,
+    /// The slot after which each pending tx can't be included anymore
+    pub ttls: HashMap<Hash<32>, BlockSlot>,
+    /// Txs dropped because of their ttl, with the slot where they expired
+    pub expired: HashMap<Hash<32>, BlockSlot>,
+    /// Size in bytes of each tracked tx
+    pub sizes: HashMap<Hash<32>, usize>,
+    /// The tip slot at the moment each tracked tx was added
+    pub added: HashMap<Hash<32>, BlockSlot>,
+    /// Inputs consumed by each tracked tx
+    pub spends: HashMap<Hash<32>, Vec<(Hash<32>, u32)>>,
+    /// Who submitted each tracked tx
+    pub submitters: HashMap<Hash<32>, Submitter>,
+    /// Txs dropped for never being included, with the slot where they were
+    /// dropped
+    pub dropped: HashMap<Hash<32>, BlockSlot>,
+}
+
+impl Monitor {
+    pub fn add_txs(&mut self, txs: &[Transaction]) {
+        for tx in txs {
+            // do not overwrite in the tx monitor map
+            if self.txs.contains_key(&tx.hash) {
+                continue;
+            }
+
+            // make note of new txs for monitoring
+            self.txs.insert(tx.hash, None);
+            self.sizes.insert(tx.hash, tx.size());
+            self.added.insert(tx.hash, self.tip_slot);
+
+            if let Some(ttl) = tx.ttl() {
+                self.ttls.insert(tx.hash, ttl);
+            }
+
+            let inputs = tx.inputs().iter().map(|x| (x.0, x.1)).collect();
+            self.spends.insert(tx.hash, inputs);
+        }
+    }
+
+    /// Adds the txs of a submission, dropping the ones that don't fit the
+    /// fairness policy
+    ///
+    /// Returns the txs that made it in, resubmitted txs included since they
+    /// take no extra room.
+    pub fn add_submission(
+        &mut self,
+        submission: &Submission,
+        fairness: Option<&FairnessPolicy>,
+    ) -> Vec<Transaction> {
+        let mut admitted = vec![];
+
+        for tx in submission.txs.iter() {
+            if !self.txs.contains_key(&tx.hash) {
+                if let Some(policy) = fairness {
+                    if let Err(err) = policy.check(self, &submission.submitter, 1) {
+                        warn!(%err, submitter = %submission.submitter, "dropping tx {}", tx.hash);
+                        continue;
+                    }
+                }
+
+                self.submitters
+                    .insert(tx.hash, submission.submitter.clone());
+            }
+
+            self.add_txs(std::slice::from_ref(tx));
+            admitted.push(tx.clone());
+        }
+
+        admitted
+    }
+
+    /// Number of txs waiting to be included
+    pub fn pending_count(&self) -> usize {
+        self.txs.values().filter(|x| x.is_none()).count()
+    }
+
+    /// Number of txs of the submitter waiting to be included
+    pub fn pending_by(&self, submitter: &str) -> usize {
+        self.submitters
+            .iter()
+            .filter(|(_, x)| x.as_str() == submitter)
+            .filter(|(hash, _)| matches!(self.txs.get(*hash), Some(None)))
+            .count()
+    }
+
+    /// Inputs consumed by the pending txs, with the tx that spends each one
+    pub fn pending_spends(&self) -> HashMap<TxoRef, Hash<32>> {
+        self.spends
+            .iter()
+            .filter(|(hash, _)| matches!(self.txs.get(*hash), Some(None)))
+            .flat_map(|(hash, inputs)| inputs.iter().map(|x| (TxoRef(x.0, x.1), *hash)))
+            .collect()
+    }
+
+    /// Drops pending txs that can't make it on-chain anymore
+    ///
+    /// A tx is valid only for slots before its ttl (aka: invalid hereafter),
+    /// once the tip reaches that slot there's no way for the tx to be included
+    /// in a later block.
+    pub fn evict_expired(&mut self, slot: BlockSlot) {
+        let expired: Vec<_> = self
+            .txs
+            .iter()
+            .filter(|(_, inclusion)| inclusion.is_none())
+            .filter(|(hash, _)| self.ttls.get(*hash).is_some_and(|ttl| slot >= *ttl))
+            .map(|(hash, _)| *hash)
+            .collect();
+
+        for hash in expired {
+            debug!("tx {hash} expired at slot {slot}");
+
+            self.txs.remove(&hash);
+            self.ttls.remove(&hash);
+            self.spends.remove(&hash);
+            self.expired.insert(hash, slot);
+        }
+    }
+
+    /// Drops pending txs that have waited too long to be included
+    ///
+    /// A tx that is still valid can stay pending forever if peers never pick
+    /// it up (eg: they refuse it, or it conflicts with another tx). Once
+    /// `max_age` slots have passed since the tx was added, it's given up on.
+    pub fn evict_unconfirmed(&mut self, slot: BlockSlot, max_age: u64) {
+        let dropped: Vec<_> = self
+            .txs
+            .iter()
+            .filter(|(_, inclusion)| inclusion.is_none())
+            .filter(|(hash, _)| {
+                self.added
+                    .get(*hash)
+                    .is_some_and(|added| slot.saturating_sub(*added) >= max_age)
+            })
+            .map(|(hash, _)| *hash)
+            .collect();
+
+        for hash in dropped {
+            debug!("tx {hash} dropped at slot {slot}, never included");
+
+            self.txs.remove(&hash);
+            self.ttls.remove(&hash);
+            self.spends.remove(&hash);
+            self.dropped.insert(hash, slot);
+        }
+    }
+
+    /// Tracks dropped txs found in a new block as included again
+    ///
+    /// Dropping a tx only means that we gave up on waiting for it, a peer
+    /// can still have it and get it into a block later on. Its details are
+    /// kept for as long as it's tracked as dropped, so it's back as if it had
+    /// never been dropped.
+    pub fn promote_dropped(&mut self, slot: BlockSlot, block_txs: &[Hash<32>]) {
+        for hash in block_txs {
+            if self.dropped.remove(hash).is_some() {
+                debug!("dropped tx {hash} included at slot {slot}");
+                self.txs.insert(*hash, Some(slot));
+            }
+        }
+    }
+
+    /// Drops the details of txs that are not tracked anymore
+    pub fn forget_untracked(&mut self) {
+        let Monitor {
+            txs,
+            expired,
+            dropped,
+            sizes,
+            added,
+            spends,
+            submitters,
+            ttls,
+            ..
+        } = self;
+
+        let tracked = |hash: &Hash<32>| {
+            txs.contains_key(hash) || expired.contains_key(hash) || dropped.contains_key(hash)
+        };
+
+        sizes.retain(|hash, _| tracked(hash));
+        added.retain(|hash, _| tracked(hash));
+        spends.retain(|hash, _| txs.contains_key(hash));
+        submitters.retain(|hash, _| txs.contains_key(hash));
+
+        // included txs keep their ttl, a rollback can make them pending again
+        ttls.retain(|hash, _| txs.contains_key(hash));
+    }
+
+    pub fn snapshot(&self) -> Vec<TxSnapshot> {
+        self.txs
+            .keys()
+            .chain(self.expired.keys())
+            .chain(self.dropped.keys())
+            .filter_map(|hash| {
+                let status = self.status(hash)?;
+                let added = self.added.get(hash).copied().unwrap_or(self.tip_slot);
+
+                Some(TxSnapshot {
+                    hash: *hash,
+                    status,
+                    size: self.sizes.get(hash).copied().unwrap_or_default(),
+                    age: self.tip_slot.saturating_sub(added),
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the monitor state to a file
+    ///
+    /// The state is written to a temporary file first, synced to disk and
+    /// then moved into place, so an interrupted write never leaves a
+    /// truncated file behind. A header with the layout version goes first.
+    pub fn persist(&self, path: &Path) -> Result<(), bincode::Error> {
+        let tmp = path.with_extension("tmp");
+
+        let file = std::fs::File::create(&tmp)?;
+        let mut writer = std::io::BufWriter::new(file);
+
+        writer.write_all(MONITOR_MAGIC)?;
+        writer.write_all(&MONITOR_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        std::fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Reads a monitor state previously written by `persist`, `None` if there
+    /// isn't one
+    ///
+    /// Files written with a different layout (including the ones from before
+    /// the header existed) can't be read, those are ignored with a warning
+    /// and the mempool starts empty.
+    pub fn restore(path: &Path) -> Result<Option<Self>, bincode::Error> {
+        let file = match std::fs::File::open(path) {
+            Ok(x) => x,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut reader = std::io::BufReader::new(file);
+
+        let mut header = [0u8; 6];
+
+        let compatible = match reader.read_exact(&mut header) {
+            Ok(()) => {
+                header[..4] == MONITOR_MAGIC[..] && header[4..] == MONITOR_VERSION.to_le_bytes()
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => false,
+            Err(err) => return Err(err.into()),
+        };
+
+        if !compatible {
+            warn!(
+                ?path,
+                "persisted mempool state has an unknown layout, starting empty"
+            );
+            return Ok(None);
+        }
+
+        let monitor = bincode::deserialize_from(reader)?;
+
+        Ok(Some(monitor))
+    }
+
+    pub fn status(&self, hash: &Hash<32>) -> Option<TxStatus> {
+        match self.txs.get(hash) {
+            Some(Some(inclusion)) => Some(TxStatus::Included(*inclusion)),
+            Some(None) => Some(TxStatus::Pending),
+            None if self.expired.contains_key(hash) => Some(TxStatus::Expired),
+            None if self.dropped.contains_key(hash) => Some(TxStatus::Dropped),
+            None => None,
+        }
+    }
 }
 
 #[derive(Stage)]
@@ -35,7 +368,18 @@ pub struct Stage {
     pub state: Arc<MempoolState>,
 
     pub prune_height: u64,
-    // TODO: prune txs even if they never land on chain?
+
+    /// File where the monitor state is kept across restarts
+    pub persist_path: Option<PathBuf>,
+
+    pub error_policy: ErrorPolicy,
+
+    /// Caps the pending txs of each submitter, if set
+    pub fairness: Option<FairnessPolicy>,
+
+    /// Slots that a tx can stay pending before it's dropped, forever if unset
+    pub drop_unconfirmed_after: Option<u64>,
+
     pub upstream_submit_endpoint: SubmitEndpointReceiver,
     pub upstream_block_monitor: BlockMonitorReceiver,
     pub downstream_propagator: PropagatorSender,
@@ -44,10 +388,14 @@ pub struct Stage {
 }
 
 impl Stage {
-    pub fn new(state: Arc<MempoolState>, prune_height: u64) -> Self {
+    pub fn new(state: Arc<MempoolState>, prune_height: u64, persist_path: Option<PathBuf>) -> Self {
         Self {
             state,
             prune_height,
+            persist_path,
+            error_policy: Default::default(),
+            fairness: None,
+            drop_unconfirmed_after: None,
             upstream_submit_endpoint: Default::default(),
             upstream_block_monitor: Default::default(),
             downstream_propagator: Default::default(),
@@ -55,14 +403,27 @@ impl Stage {
     }
 }
 
-pub struct Worker {}
-
-impl Worker {}
+pub struct Worker {
+    state: Arc<MempoolState>,
+    persist_path: Option<PathBuf>,
+}
 
 #[async_trait::async_trait(?Send)]
 impl gasket::framework::Worker<Stage> for Worker {
-    async fn bootstrap(_stage: &Stage) -> Result<Self, WorkerError> {
-        Ok(Self {})
+    async fn bootstrap(stage: &Stage) -> Result<Self, WorkerError> {
+        if let Some(path) = &stage.persist_path {
+            let restored = Monitor::restore(path).or_skip(stage.error_policy, "persisted state")?;
+
+            if let Some(monitor) = restored.flatten() {
+                info!(txs = monitor.txs.len(), "mempool state restored");
+                *stage.state.0.write().await = monitor;
+            }
+        }
+
+        Ok(Self {
+            state: stage.state.clone(),
+            persist_path: stage.persist_path.clone(),
+        })
     }
 
     async fn schedule(
@@ -85,31 +446,28 @@ impl gasket::framework::Worker<Stage> for Worker {
 
     async fn execute(&mut self, unit: &MempoolEvent, stage: &mut Stage) -> Result<(), WorkerError> {
         match unit {
-            MempoolEvent::AddTxs(txs) => {
-                let mut txs = txs.clone();
+            MempoolEvent::AddTxs(submission) => {
+                let admitted = stage
+                    .state
+                    .0
+                    .write()
+                    .await
+                    .add_submission(submission, stage.fairness.as_ref());
 
                 // pass new txs to downstream/propagate txs
                 stage
                     .downstream_propagator
-                    .send(txs.clone().into())
+                    .send(admitted.into())
                     .await
                     .or_panic()?;
-
-                let mut monitor = stage.state.0.write().await;
-
-                // do not overwrite in the tx monitor map
-                txs.retain(|x| !monitor.txs.contains_key(&x.hash));
-
-                // make note of new txs for monitoring
-                monitor
-                    .txs
-                    .extend(txs.clone().into_iter().map(|x| (x.hash, None)));
             }
             MempoolEvent::ChainUpdate(monitor_msg) => {
                 match monitor_msg {
                     BlockMonitorMessage::NewBlock(slot, block_txs) => {
                         let mut monitor = stage.state.0.write().await;
 
+                        monitor.promote_dropped(*slot, block_txs);
+
                         // set inclusion point for txs found in new block
                         for (tx_hash, inclusion) in monitor.txs.iter_mut() {
                             if block_txs.contains(tx_hash) {
@@ -127,6 +485,23 @@ impl gasket::framework::Worker<Stage> for Worker {
                             }
                         });
 
+                        monitor.evict_expired(*slot);
+
+                        if let Some(max_age) = stage.drop_unconfirmed_after {
+                            monitor.evict_unconfirmed(*slot, max_age);
+                        }
+
+                        // forget about expired and dropped txs after the same window
+                        monitor.expired.retain(|_, expired_at| {
+                            slot.saturating_sub(*expired_at) <= stage.prune_height
+                        });
+
+                        monitor.dropped.retain(|_, dropped_at| {
+                            slot.saturating_sub(*dropped_at) <= stage.prune_height
+                        });
+
+                        monitor.forget_untracked();
+
                         monitor.tip_slot = *slot;
                     }
                     BlockMonitorMessage::Rollback(rb_slot) => {
@@ -156,4 +531,370 @@ impl gasket::framework::Worker<Stage> for Worker {
 
         Ok(())
     }
+
+    /// Flushes the monitor state on a clean shutdown
+    ///
+    /// Teardown runs once the worker loop stopped, so there's no unit of work
+    /// in progress and the state is consistent. Holding the write lock keeps
+    /// other readers from observing anything mid-flush.
+    async fn teardown(&mut self) -> Result<(), WorkerError> {
+        let Some(path) = &self.persist_path else {
+            return Ok(());
+        };
+
+        let monitor = self.state.0.write().await;
+
+        match monitor.persist(path) {
+            Ok(()) => info!(txs = monitor.txs.len(), "mempool state persisted"),
+            Err(err) => warn!(%err, "failed to persist mempool state"),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::framework::Worker as _;
+
+    use super::*;
+
+    fn load_test_tx() -> (Transaction, BlockSlot) {
+        let cbor = crate::wal::testing::test_data_cbor();
+        let block = pallas::ledger::traverse::MultiEraBlock::decode(&cbor).unwrap();
+
+        let tx = block.txs().into_iter().find(|x| x.ttl().is_some()).unwrap();
+
+        let ttl = tx.ttl().unwrap();
+
+        let tx = Transaction::new(tx.hash(), u16::from(tx.era()) - 1, tx.encode());
+
+        (tx, ttl)
+    }
+
+    #[test]
+    fn test_decode_is_cached() {
+        let (tx, ttl) = load_test_tx();
+        assert!(tx.decoded.get().is_none());
+
+        let first = tx.decoded().unwrap() as *const _;
+        assert_eq!(tx.ttl(), Some(ttl));
+        assert!(tx.fee().is_some());
+        assert!(!tx.inputs().is_empty());
+
+        // every accessor reads the same decode, clones included
+        let copy = tx.clone();
+        assert!(std::ptr::eq(first, tx.decoded().unwrap()));
+        assert!(std::ptr::eq(first, copy.decoded().unwrap()));
+
+        // invalid bytes are decoded once too, as a miss
+        let invalid = Transaction::new(tx.hash, tx.era, vec![0xff; 8]);
+        assert_eq!(invalid.ttl(), None);
+        assert!(invalid.inputs().is_empty());
+        assert!(matches!(invalid.decoded.get(), Some(None)));
+
+        // the cache doesn't take part in equality
+        assert_eq!(copy, Transaction::new(tx.hash, tx.era, tx.bytes.clone()));
+    }
+
+    #[test]
+    fn test_ttl_expiration() {
+        let (tx, ttl) = load_test_tx();
+
+        let mut monitor = Monitor::default();
+        monitor.add_txs(&[tx.clone()]);
+
+        assert_eq!(monitor.ttls.get(&tx.hash), Some(&ttl));
+        assert_eq!(monitor.status(&tx.hash), Some(TxStatus::Pending));
+
+        // still valid while the tip is before the ttl
+        monitor.evict_expired(ttl - 1);
+        assert_eq!(monitor.status(&tx.hash), Some(TxStatus::Pending));
+
+        monitor.evict_expired(ttl);
+        assert_eq!(monitor.status(&tx.hash), Some(TxStatus::Expired));
+        assert!(!monitor.txs.contains_key(&tx.hash));
+        assert!(!monitor.ttls.contains_key(&tx.hash));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_persists_pending_txs() {
+        let (tx, ttl) = load_test_tx();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mempool");
+
+        let state = Arc::new(MempoolState::default());
+        let stage = Stage::new(state.clone(), 200, Some(path.clone()));
+
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        state.0.write().await.add_txs(&[tx.clone()]);
+        worker.teardown().await.unwrap();
+
+        // a new stage (eg: after a restart) picks up the persisted state
+        let state = Arc::new(MempoolState::default());
+        let stage = Stage::new(state.clone(), 200, Some(path));
+
+        Worker::bootstrap(&stage).await.unwrap();
+
+        let monitor = state.0.read().await;
+        assert_eq!(monitor.status(&tx.hash), Some(TxStatus::Pending));
+        assert_eq!(monitor.ttls.get(&tx.hash), Some(&ttl));
+    }
+
+    #[test]
+    fn test_restore_ignores_unknown_layout() {
+        let (tx, _) = load_test_tx();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mempool");
+
+        let mut monitor = Monitor::default();
+        monitor.add_txs(&[tx]);
+
+        // a file written before the header existed
+        let file = std::fs::File::create(&path).unwrap();
+        bincode::serialize_into(file, &monitor).unwrap();
+
+        assert!(Monitor::restore(&path).unwrap().is_none());
+
+        // same for a file shorter than the header
+        std::fs::write(&path, b"dm").unwrap();
+        assert!(Monitor::restore(&path).unwrap().is_none());
+
+        // and one written by the current version restores fine
+        monitor.persist(&path).unwrap();
+        let restored = Monitor::restore(&path).unwrap().unwrap();
+        assert_eq!(restored.txs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_tracks_adds_inclusions_and_evictions() {
+        let (expiring, ttl) = load_test_tx();
+
+        let (included, _) = load_test_tx();
+        let included = Transaction {
+            hash: pallas::crypto::hash::Hasher::<256>::hash(b"included"),
+            ..included
+        };
+
+        let state = Arc::new(MempoolState::default());
+        let mut stage = Stage::new(state.clone(), 200, None);
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        let snapshot_of = |snapshot: &[TxSnapshot], hash| {
+            snapshot.iter().find(|x| x.hash == hash).cloned().unwrap()
+        };
+
+        {
+            let mut monitor = state.0.write().await;
+            monitor.tip_slot = ttl - 20;
+            monitor.add_txs(&[expiring.clone(), included.clone()]);
+        }
+
+        let snapshot = state.snapshot().await;
+        assert_eq!(snapshot.len(), 2);
+
+        let tx = snapshot_of(&snapshot, expiring.hash);
+        assert_eq!(tx.status, TxStatus::Pending);
+        assert_eq!(tx.size, expiring.bytes.len());
+        assert_eq!(tx.age, 0);
+
+        let block = BlockMonitorMessage::NewBlock(ttl - 10, vec![included.hash]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        let snapshot = state.snapshot().await;
+        let tx = snapshot_of(&snapshot, included.hash);
+        assert_eq!(tx.status, TxStatus::Included(ttl - 10));
+        assert_eq!(tx.age, 10);
+        assert_eq!(
+            snapshot_of(&snapshot, expiring.hash).status,
+            TxStatus::Pending
+        );
+
+        let block = BlockMonitorMessage::NewBlock(ttl, vec![]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        let snapshot = state.snapshot().await;
+        let tx = snapshot_of(&snapshot, expiring.hash);
+        assert_eq!(tx.status, TxStatus::Expired);
+        assert_eq!(tx.size, expiring.bytes.len());
+        assert_eq!(tx.age, 20);
+
+        // once out of the prune window, txs are gone from the snapshot
+        let block = BlockMonitorMessage::NewBlock(ttl + 300, vec![]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        assert!(state.snapshot().await.is_empty());
+        assert!(state.0.read().await.sizes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rolled_back_txs_still_expire() {
+        let (tx, ttl) = load_test_tx();
+
+        let state = Arc::new(MempoolState::default());
+        let mut stage = Stage::new(state.clone(), 200, None);
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        state.0.write().await.add_txs(&[tx.clone()]);
+
+        let block = BlockMonitorMessage::NewBlock(ttl - 10, vec![tx.hash]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        let status = state.0.read().await.status(&tx.hash);
+        assert_eq!(status, Some(TxStatus::Included(ttl - 10)));
+
+        let unit = MempoolEvent::ChainUpdate(BlockMonitorMessage::Rollback(ttl - 20));
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        let status = state.0.read().await.status(&tx.hash);
+        assert_eq!(status, Some(TxStatus::Pending));
+
+        let block = BlockMonitorMessage::NewBlock(ttl, vec![]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        let monitor = state.0.read().await;
+        assert_eq!(monitor.status(&tx.hash), Some(TxStatus::Expired));
+        assert!(!monitor.ttls.contains_key(&tx.hash));
+    }
+
+    #[tokio::test]
+    async fn test_never_confirmed_txs_are_dropped() {
+        let (tx, ttl) = load_test_tx();
+
+        let state = Arc::new(MempoolState::default());
+        let mut stage = Stage::new(state.clone(), 200, None);
+        stage.drop_unconfirmed_after = Some(50);
+
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        // added well before its ttl, so it can't expire within the window
+        let added = ttl - 1000;
+
+        {
+            let mut monitor = state.0.write().await;
+            monitor.tip_slot = added;
+            monitor.add_txs(&[tx.clone()]);
+        }
+
+        let block = BlockMonitorMessage::NewBlock(added + 49, vec![]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        let status = state.0.read().await.status(&tx.hash);
+        assert_eq!(status, Some(TxStatus::Pending));
+
+        let block = BlockMonitorMessage::NewBlock(added + 50, vec![]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        {
+            let monitor = state.0.read().await;
+            assert_eq!(monitor.status(&tx.hash), Some(TxStatus::Dropped));
+            assert!(!monitor.txs.contains_key(&tx.hash));
+            assert!(!monitor.ttls.contains_key(&tx.hash));
+            assert!(monitor.pending_spends().is_empty());
+        }
+
+        let snapshot = state.snapshot().await;
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].status, TxStatus::Dropped);
+        assert_eq!(snapshot[0].age, 50);
+
+        // dropped txs are forgotten after the prune window, like expired ones
+        let block = BlockMonitorMessage::NewBlock(added + 300, vec![]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        assert!(state.snapshot().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_txs_found_on_chain_are_included() {
+        let (tx, ttl) = load_test_tx();
+
+        let state = Arc::new(MempoolState::default());
+        let mut stage = Stage::new(state.clone(), 200, None);
+        stage.drop_unconfirmed_after = Some(50);
+
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        let added = ttl - 1000;
+
+        {
+            let mut monitor = state.0.write().await;
+            monitor.tip_slot = added;
+            monitor.add_txs(&[tx.clone()]);
+        }
+
+        let block = BlockMonitorMessage::NewBlock(added + 50, vec![]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        let status = state.0.read().await.status(&tx.hash);
+        assert_eq!(status, Some(TxStatus::Dropped));
+
+        // a peer got it into a block after all
+        let block = BlockMonitorMessage::NewBlock(added + 60, vec![tx.hash]);
+        let unit = MempoolEvent::ChainUpdate(block);
+        worker.execute(&unit, &mut stage).await.unwrap();
+
+        {
+            let monitor = state.0.read().await;
+            assert_eq!(
+                monitor.status(&tx.hash),
+                Some(TxStatus::Included(added + 60))
+            );
+            assert!(!monitor.dropped.contains_key(&tx.hash));
+        }
+
+        let snapshot = state.snapshot().await;
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].status, TxStatus::Included(added + 60));
+        assert_eq!(snapshot[0].age, 60);
+    }
+
+    #[test]
+    fn test_included_txs_dont_expire() {
+        let (tx, ttl) = load_test_tx();
+
+        let mut monitor = Monitor::default();
+        monitor.add_txs(&[tx.clone()]);
+
+        monitor.txs.insert(tx.hash, Some(ttl - 1));
+        monitor.evict_expired(ttl + 10);
+
+        assert_eq!(monitor.status(&tx.hash), Some(TxStatus::Included(ttl - 1)));
+    }
+
+    #[test]
+    fn test_pending_spends_follow_tx_status() {
+        let (tx, ttl) = load_test_tx();
+
+        let mut monitor = Monitor::default();
+        monitor.add_txs(&[tx.clone()]);
+
+        let spends = monitor.pending_spends();
+        assert_eq!(spends.len(), tx.inputs().len());
+        assert!(tx.inputs().iter().all(|x| spends.get(x) == Some(&tx.hash)));
+
+        // included txs are checked against the ledger instead
+        monitor.txs.insert(tx.hash, Some(ttl - 1));
+        assert!(monitor.pending_spends().is_empty());
+
+        // a rollback makes them pending again
+        monitor.txs.insert(tx.hash, None);
+        assert_eq!(monitor.pending_spends().len(), tx.inputs().len());
+
+        monitor.evict_expired(ttl + 1);
+        assert!(monitor.pending_spends().is_empty());
+        assert!(monitor.spends.is_empty());
+    }
 }
@@ -1,24 +1,117 @@
+use gasket::framework::WorkerError;
 use gasket::messaging::tokio::ChannelRecvAdapter;
 use pallas::{
     crypto::hash::Hash,
     network::miniprotocols::txsubmission::{EraTxBody, EraTxId, TxIdAndSize},
 };
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tracing::{error, warn};
 
-use crate::{prelude::*, wal::redb::WalStore};
+use crate::{ledger::TxoRef, prelude::*, wal::redb::WalStore};
 
+mod admission;
+mod fairness;
+mod fees;
 mod mempool;
 mod monitor;
 mod propagator;
 
-pub use self::mempool::MempoolState;
+pub use self::admission::{AdmissionError, InputCheck};
+pub use self::fairness::{FairnessError, FairnessPolicy, SubmitterLoad};
+pub use self::fees::{FeeError, LinearFee, MinFeeFilter};
+pub use self::mempool::{MempoolState, Monitor, Submission, Submitter, TxSnapshot, TxStatus};
+
+/// The parts of a tx that the mempool looks at, taken from a single decode
+#[derive(Debug)]
+pub struct DecodedTx {
+    pub ttl: Option<BlockSlot>,
+    pub fee: Option<u64>,
+    pub inputs: Vec<TxoRef>,
+}
+
+impl DecodedTx {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let tx = pallas::ledger::traverse::MultiEraTx::decode(bytes).ok()?;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        let inputs = tx
+            .inputs()
+            .iter()
+            .map(|x| TxoRef(*x.hash(), x.index() as u32))
+            .collect();
+
+        Some(Self {
+            ttl: tx.ttl(),
+            fee: tx.fee(),
+            inputs,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Transaction {
     pub hash: Hash<32>,
     pub era: u16,
     pub bytes: Vec<u8>,
+
+    /// Decoded on first use, clones share the same decode
+    decoded: Arc<OnceLock<Option<DecodedTx>>>,
+}
+
+impl Transaction {
+    pub fn new(hash: Hash<32>, era: u16, bytes: Vec<u8>) -> Self {
+        Self {
+            hash,
+            era,
+            bytes,
+            decoded: Default::default(),
+        }
+    }
+
+    /// The decoded tx, `None` if the bytes aren't a valid tx
+    pub fn decoded(&self) -> Option<&DecodedTx> {
+        self.decoded
+            .get_or_init(|| DecodedTx::decode(&self.bytes))
+            .as_ref()
+    }
+
+    pub fn ttl(&self) -> Option<BlockSlot> {
+        self.decoded().and_then(|x| x.ttl)
+    }
+
+    pub fn fee(&self) -> Option<u64> {
+        self.decoded().and_then(|x| x.fee)
+    }
+
+    pub fn inputs(&self) -> &[TxoRef] {
+        self.decoded()
+            .map(|x| x.inputs.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+// the decode cache is derived from the bytes, so it's left out of comparisons
+impl PartialEq for Transaction {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.era == other.era && self.bytes == other.bytes
+    }
+}
+
+impl Eq for Transaction {}
+
+impl std::hash::Hash for Transaction {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.hash.hash(state);
+        self.era.hash(state);
+        self.bytes.hash(state);
+    }
 }
 
 impl From<Transaction> for TxIdAndSize<EraTxId> {
@@ -41,11 +134,119 @@ pub struct Config {
     prune_height: u64,
     //validate_phase_1: bool,
     //validate_phase_2: bool,
+    /// Reject submitted txs paying less than the protocol minimum fee
+    #[serde(default)]
+    pub min_fee_filter: bool,
+
+    /// Reject submitted txs whose inputs are missing from the ledger or already
+    /// spent by a pending tx
+    #[serde(default)]
+    pub input_check: bool,
+
+    /// File where the mempool state is kept across restarts
+    #[serde(default)]
+    pub persist_path: Option<std::path::PathBuf>,
+
+    /// Caps the share of the mempool that a single submitter can take
+    #[serde(default)]
+    pub fairness: Option<FairnessPolicy>,
+
+    /// Slots that a tx can stay pending before it's dropped
+    #[serde(default)]
+    pub drop_unconfirmed_after: Option<u64>,
+
+    /// How each stage reacts to recoverable errors
+    #[serde(default)]
+    pub error_policy: ErrorPolicies,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Self { prune_height: 200 }
+        Self {
+            prune_height: 200,
+            min_fee_filter: false,
+            input_check: false,
+            persist_path: None,
+            fairness: None,
+            drop_unconfirmed_after: None,
+            error_policy: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Checks the settings upfront, so that mistakes are reported at startup
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(fairness) = &self.fairness {
+            if fairness.capacity == 0 {
+                return Err(Error::config(
+                    "submit fairness capacity must be greater than zero",
+                ));
+            }
+
+            if !(fairness.max_share > 0.0 && fairness.max_share <= 1.0) {
+                return Err(Error::config(
+                    "submit fairness max_share must be greater than 0 and at most 1",
+                ));
+            }
+        }
+
+        if self.drop_unconfirmed_after == Some(0) {
+            return Err(Error::config(
+                "submit drop_unconfirmed_after must be greater than zero",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// What a stage does with an error caused by bad input
+///
+/// Only errors that are local to a single message (eg: a block that can't be
+/// decoded) go through the policy. Errors that leave the stage in an unknown
+/// state, like a broken channel, always panic the worker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    /// Panic the worker, the stage is restarted according to its retry policy
+    #[default]
+    Panic,
+
+    /// Log the error and move on to the next message
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorPolicies {
+    #[serde(default)]
+    pub mempool: ErrorPolicy,
+
+    #[serde(default)]
+    pub monitor: ErrorPolicy,
+}
+
+trait OrSkip<T> {
+    /// Applies the error policy, `None` means that the input was skipped
+    fn or_skip(self, policy: ErrorPolicy, what: &str) -> Result<Option<T>, WorkerError>;
+}
+
+impl<T, E> OrSkip<T> for Result<T, E>
+where
+    E: std::fmt::Display,
+{
+    fn or_skip(self, policy: ErrorPolicy, what: &str) -> Result<Option<T>, WorkerError> {
+        match (self, policy) {
+            (Ok(x), _) => Ok(Some(x)),
+            (Err(err), ErrorPolicy::Skip) => {
+                warn!(%err, "skipping {what}");
+                Ok(None)
+            }
+            (Err(err), ErrorPolicy::Panic) => {
+                error!(%err, "can't process {what}");
+                Err(WorkerError::Panic)
+            }
+        }
     }
 }
 
@@ -74,15 +275,21 @@ pub fn pipeline(
     upstream: &UpstreamConfig,
     wal: WalStore,
     mempool: Arc<mempool::MempoolState>,
-    txs_in: ChannelRecvAdapter<Vec<Transaction>>,
+    txs_in: ChannelRecvAdapter<Submission>,
     retries: &Option<gasket::retries::Policy>,
 ) -> Result<Vec<gasket::runtime::Tether>, Error> {
-    let mut mempool = mempool::Stage::new(mempool, config.prune_height);
+    let mut mempool =
+        mempool::Stage::new(mempool, config.prune_height, config.persist_path.clone());
 
     let mut propagator =
         propagator::Stage::new(vec![upstream.peer_address.clone()], upstream.network_magic);
 
+    mempool.error_policy = config.error_policy.mempool;
+    mempool.fairness = config.fairness.clone();
+    mempool.drop_unconfirmed_after = config.drop_unconfirmed_after;
+
     let mut monitor = monitor::Stage::new(wal);
+    monitor.error_policy = config.error_policy.monitor;
 
     // connect mempool stage to gRPC service
     // mempool stage (sc) has a single consumer receiving messages (txs to add
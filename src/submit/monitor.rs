@@ -6,7 +6,7 @@ use gasket::framework::*;
 use pallas::ledger::traverse::MultiEraBlock;
 use tracing::debug;
 
-use super::{BlockSlot, TxHash};
+use super::{BlockSlot, ErrorPolicy, OrSkip as _, TxHash};
 
 use crate::wal::{self, WalReader};
 
@@ -23,6 +23,8 @@ pub enum BlockMonitorMessage {
 pub struct Stage {
     wal: wal::redb::WalStore,
 
+    pub error_policy: ErrorPolicy,
+
     pub downstream_mempool: MempoolSender,
     // #[metric]
     // received_txs: gasket::metrics::Counter,
@@ -32,6 +34,7 @@ impl Stage {
     pub fn new(wal: wal::redb::WalStore) -> Self {
         Self {
             wal,
+            error_policy: Default::default(),
             downstream_mempool: Default::default(),
         }
     }
@@ -66,7 +69,13 @@ impl gasket::framework::Worker<Stage> for Worker {
         for (seq, log) in iter {
             match log {
                 wal::LogValue::Apply(wal::RawBlock { slot, body, .. }) => {
-                    let block = MultiEraBlock::decode(&body).or_panic()?;
+                    let block =
+                        MultiEraBlock::decode(&body).or_skip(stage.error_policy, "block")?;
+
+                    let Some(block) = block else {
+                        self.0 = Some(seq);
+                        continue;
+                    };
 
                     let txs = block.txs().iter().map(|x| x.hash()).collect::<Vec<_>>();
 
@@ -99,3 +108,64 @@ impl gasket::framework::Worker<Stage> for Worker {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use gasket::framework::Worker as _;
+
+    use super::*;
+    use crate::wal::{testing, WalWriter as _};
+
+    fn connected_stage(
+        wal: wal::redb::WalStore,
+    ) -> (Stage, crate::submit::mempool::BlockMonitorReceiver) {
+        let mut stage = Stage::new(wal);
+
+        let (output, input) = gasket::messaging::tokio::mpsc_channel(64);
+        stage.downstream_mempool.connect(output);
+
+        let mut receiver = crate::submit::mempool::BlockMonitorReceiver::default();
+        receiver.connect(input);
+
+        (stage, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_skip_policy_survives_bad_block() {
+        let mut wal = testing::db_with_dummy_blocks(2);
+
+        // a block that passes the WAL checks but isn't valid cbor
+        let mut corrupt = testing::dummy_block_from_slot(2);
+        corrupt.body = vec![0xff; 64];
+        wal.roll_forward(std::iter::once(corrupt)).unwrap();
+
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(3)))
+            .unwrap();
+
+        // the default policy panics the worker
+        let (mut stage, _receiver) = connected_stage(wal.clone());
+        let mut worker = Worker(Some(0));
+
+        let result = worker.execute(&(), &mut stage).await;
+        assert!(matches!(result, Err(WorkerError::Panic)));
+
+        // skipping moves past the bad block and keeps going
+        let (mut stage, mut receiver) = connected_stage(wal);
+        stage.error_policy = ErrorPolicy::Skip;
+        let mut worker = Worker(Some(0));
+
+        worker.execute(&(), &mut stage).await.unwrap();
+        assert_eq!(worker.0, Some(4));
+
+        let mut slots = vec![];
+
+        for _ in 0..3 {
+            match receiver.recv().await.unwrap().payload {
+                BlockMonitorMessage::NewBlock(slot, _) => slots.push(slot),
+                x => panic!("unexpected message {x:?}"),
+            }
+        }
+
+        assert_eq!(slots, vec![0, 1, 3]);
+    }
+}
@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default capacity of the buffer between the pull and roll stages
+pub const DEFAULT_CAPACITY: usize = 50;
+
+/// What the pull stage does when the buffer is full
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFull {
+    /// Waits for the WAL writes to make room, the upstream connection sits
+    /// idle meanwhile
+    #[default]
+    Block,
+
+    /// Drops what's left of the batch and reconnects once the buffer drains.
+    /// Nothing is lost, the new connection intersects at the WAL tip, so the
+    /// dropped blocks are pulled again.
+    Reconnect,
+}
+
+/// Bounded buffer of pulled events waiting to be written to the WAL
+///
+/// Upstream peers deliver blocks in bursts (eg: a whole batch at once while
+/// catching up), at a rate that the WAL writes can't always keep up with. The
+/// buffer absorbs the bursts, so receiving from the network doesn't have to
+/// wait on the disk.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BufferPolicy {
+    /// Max number of events in the buffer
+    pub capacity: usize,
+
+    #[serde(default)]
+    pub on_full: OnFull,
+}
+
+impl Default for BufferPolicy {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CAPACITY,
+            on_full: OnFull::default(),
+        }
+    }
+}
+
+// how often a reconnecting pull stage checks if the buffer drained
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Default)]
+struct Counts {
+    queued: AtomicUsize,
+    writing: AtomicUsize,
+}
+
+/// Number of events in the buffer, shared by the stages at both ends
+///
+/// The pull stage counts an event before sending it and the roll stage takes
+/// it out of the count once it's received, so the count matches what the
+/// buffer holds and never goes over its capacity. Events that were received
+/// but not written to the WAL yet are tracked on their own, they only matter
+/// to tell when the buffer is fully drained.
+#[derive(Clone, Debug, Default)]
+pub struct Occupancy(Arc<Counts>);
+
+fn decrement(counter: &AtomicUsize) {
+    let _ = counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| x.checked_sub(1));
+}
+
+impl Occupancy {
+    pub fn get(&self) -> usize {
+        self.0.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn push(&self) {
+        self.0.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Moves an event from the buffer to the ones being written
+    pub fn take(&self) {
+        decrement(&self.0.queued);
+        self.0.writing.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Marks a taken event as written to the WAL
+    pub fn written(&self) {
+        decrement(&self.0.writing);
+    }
+
+    /// Waits until every event in the buffer is written to the WAL
+    pub async fn drained(&self) {
+        while self.get() > 0 || self.0.writing.load(Ordering::SeqCst) > 0 {
+            tokio::time::sleep(DRAIN_POLL).await;
+        }
+    }
+}
@@ -1,8 +1,9 @@
 use gasket::framework::*;
 use pallas::ledger::configs::{byron, shelley};
-use pallas::ledger::traverse::MultiEraBlock;
-use tracing::{debug, info};
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
 
+use crate::querydb::store::Store as Archive;
 use crate::wal::{self, LogValue, WalReader as _};
 use crate::{ledger, prelude::*};
 
@@ -15,6 +16,8 @@ pub struct Stage {
     ledger: crate::ledger::store::LedgerStore,
     byron: byron::GenesisFile,
     shelley: shelley::GenesisFile,
+    wal_compaction: Option<wal::redb::CompactionPolicy>,
+    archive: Option<Arc<Archive>>,
 
     pub upstream: UpstreamPort,
 
@@ -23,6 +26,9 @@ pub struct Stage {
 
     #[metric]
     wal_count: gasket::metrics::Counter,
+
+    #[metric]
+    compacted_count: gasket::metrics::Counter,
 }
 
 impl Stage {
@@ -31,18 +37,78 @@ impl Stage {
         ledger: crate::ledger::store::LedgerStore,
         byron: byron::GenesisFile,
         shelley: shelley::GenesisFile,
+        wal_compaction: Option<wal::redb::CompactionPolicy>,
     ) -> Self {
         Self {
             wal,
             ledger,
             byron,
             shelley,
+            wal_compaction,
+            archive: None,
             upstream: Default::default(),
             block_count: Default::default(),
             wal_count: Default::default(),
+            compacted_count: Default::default(),
         }
     }
 
+    /// Bridges the ledger with archived blocks when it falls behind the start
+    /// of the WAL, instead of failing to start
+    pub fn set_archive(&mut self, archive: Arc<Archive>) {
+        self.archive = Some(archive);
+    }
+
+    /// Moves a ledger that fell behind the start of the WAL up to it
+    ///
+    /// Without an archive (or one that doesn't hold the missing blocks) the
+    /// gap can't be closed and the ledger has to be rebuilt.
+    fn bridge_gap(&self, err: wal::WalError) -> Result<wal::LogSeq, WorkerError> {
+        let Some(archive) = &self.archive else {
+            error!(%err, "ledger can't catch up with the wal");
+            return Err(WorkerError::Panic);
+        };
+
+        warn!(%err, "bridging the gap with archived blocks");
+
+        let mut store = self.ledger.clone();
+
+        ledger::replay::bridge_gap(archive, &self.wal, &mut store, &self.byron, &self.shelley)
+            .inspect_err(|err| error!(%err, "ledger can't catch up with the wal"))
+            .or_panic()
+    }
+
+    /// Trims the WAL according to the compaction policy, if any
+    ///
+    /// Entries within the security window of the tip are kept so that
+    /// rollbacks can still be served, and so is everything from the ledger
+    /// cursor onwards, which hasn't been applied yet.
+    fn compact_wal(&mut self, cursor: wal::LogSeq) -> Result<(), WorkerError> {
+        let Some(policy) = &self.wal_compaction else {
+            return Ok(());
+        };
+
+        let tip = match self.wal.find_tip().or_panic()? {
+            Some((_, wal::ChainPoint::Specific(slot, _))) => slot,
+            _ => return Ok(()),
+        };
+
+        let max_slot = crate::ledger::lastest_immutable_slot(tip, &self.byron, &self.shelley);
+
+        let archive = self.archive.as_deref();
+
+        if let Some(seq) = self
+            .wal
+            .compact_into(policy, max_slot, cursor, archive)
+            .or_panic()?
+        {
+            info!(seq, "wal compacted");
+            self.compacted_count.inc(1);
+        }
+
+        Ok(())
+    }
+
     fn process_origin(&mut self) -> Result<(), WorkerError> {
         info!("applying origin");
 
@@ -57,7 +123,7 @@ impl Stage {
 
         info!(slot, "undoing block");
 
-        let block = MultiEraBlock::decode(body).or_panic()?;
+        let block = wal::decode_block(body).or_panic()?;
         let context = crate::ledger::load_slice_for_block(&block, &self.ledger, &[]).or_panic()?;
 
         let delta = crate::ledger::compute_undo_delta(&block, context).or_panic()?;
@@ -71,7 +137,7 @@ impl Stage {
 
         info!(slot, "applying block");
 
-        let block = MultiEraBlock::decode(body).or_panic()?;
+        let block = wal::decode_block(body).or_panic()?;
 
         crate::ledger::import_block_batch(&[block], &mut self.ledger, &self.byron, &self.shelley)
             .or_panic()?;
@@ -104,7 +170,16 @@ impl gasket::framework::Worker<Stage> for Worker {
             None => wal::ChainPoint::Origin,
         };
 
-        let seq = stage.wal.assert_point(&point).or_panic()?;
+        let seq = match stage.wal.assert_cursor(&point) {
+            Err(err @ wal::WalError::CursorBehindWal(..)) => stage.bridge_gap(err)?,
+            x => x
+                .inspect_err(|err| {
+                    if let wal::WalError::CursorAheadOfTip(..) = err {
+                        error!(%err, "ledger is out of sync with the wal");
+                    }
+                })
+                .or_panic()?,
+        };
 
         info!(seq, "wal sequence found");
 
@@ -136,6 +211,8 @@ impl gasket::framework::Worker<Stage> for Worker {
             self.0 = seq;
         }
 
+        stage.compact_wal(self.0)?;
+
         Ok(())
     }
 }
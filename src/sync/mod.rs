@@ -1,23 +1,40 @@
 use crate::ledger::store::LedgerStore;
 use crate::prelude::*;
-use crate::wal::redb::WalStore;
+use crate::querydb::store::Store as Archive;
+use crate::wal::redb::{CompactionPolicy, WalStore};
 use pallas::ledger::configs::{byron, shelley};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use std::time::Duration;
 
+pub mod buffer;
 pub mod ledger;
+pub mod progress;
 pub mod pull;
 pub mod roll;
+pub mod watchdog;
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub pull_batch_size: Option<usize>,
+
+    /// Trims the WAL once it grows past a number of entries
+    pub wal_compaction: Option<CompactionPolicy>,
+
+    /// Watches for a WAL tip that stops moving
+    pub stall_detection: Option<watchdog::StallPolicy>,
+
+    /// Buffer between the upstream peer and the WAL writes
+    pub ingest_buffer: Option<buffer::BufferPolicy>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             pull_batch_size: Some(100),
+            wal_compaction: None,
+            stall_detection: None,
+            ingest_buffer: None,
         }
     }
 }
@@ -50,6 +67,7 @@ pub fn pipeline(
     ledger: LedgerStore,
     byron: byron::GenesisFile,
     shelley: shelley::GenesisFile,
+    archive: Option<Arc<Archive>>,
     retries: &Option<gasket::retries::Policy>,
 ) -> Result<Vec<gasket::runtime::Tether>, Error> {
     let mut pull = pull::Stage::new(
@@ -61,9 +79,55 @@ pub fn pipeline(
 
     let mut roll = roll::Stage::new(wal.clone());
 
-    let mut ledger = ledger::Stage::new(wal.clone(), ledger, byron, shelley);
+    let watchdog = config.stall_detection.as_ref().map(|policy| {
+        let reconnect = policy.reconnect.then(|| pull.reconnect_signal());
+        let timeout = Duration::from_secs(policy.timeout_secs);
+
+        let mut stage = watchdog::Stage::new(wal.watch_tip(), timeout, reconnect);
+
+        let network = crate::ledger::time::NetworkConfig::from_magic(upstream.network_magic);
+
+        if let (Some(skew), Some(network)) = (policy.max_clock_skew_secs, network) {
+            let slots = crate::ledger::time::SlotConverter::new(&network);
+            let clock = watchdog::ChainClock::new(slots, Duration::from_secs(skew));
+            stage.set_clock(clock);
+        }
+
+        stage
+    });
+
+    let progress =
+        crate::ledger::time::NetworkConfig::from_magic(upstream.network_magic).map(|network| {
+            let slots = crate::ledger::time::SlotConverter::new(&network);
+            progress::Stage::new(wal.watch_tip(), slots)
+        });
+
+    let mut ledger = ledger::Stage::new(
+        wal.clone(),
+        ledger,
+        byron,
+        shelley,
+        config.wal_compaction.clone(),
+    );
 
-    let (to_roll, from_pull) = gasket::messaging::tokio::mpsc_channel(50);
+    // a ledger that fell behind the start of the WAL is bridged from it
+    if let Some(archive) = archive {
+        ledger.set_archive(archive);
+    }
+
+    let buffer = config.ingest_buffer.clone().unwrap_or_default();
+
+    if buffer.capacity == 0 {
+        return Err(Error::config(
+            "sync ingest_buffer capacity must be greater than zero",
+        ));
+    }
+
+    let occupancy = buffer::Occupancy::default();
+    pull.set_buffer(&buffer, occupancy.clone());
+    roll.set_buffer_occupancy(occupancy);
+
+    let (to_roll, from_pull) = gasket::messaging::tokio::mpsc_channel(buffer.capacity);
     pull.downstream.connect(to_roll);
     roll.upstream.connect(from_pull);
 
@@ -80,5 +144,15 @@ pub fn pipeline(
     let roll = gasket::runtime::spawn_stage(roll, policy.clone());
     let ledger = gasket::runtime::spawn_stage(ledger, policy.clone());
 
-    Ok(vec![pull, roll, ledger])
+    let mut tethers = vec![pull, roll, ledger];
+
+    if let Some(watchdog) = watchdog {
+        tethers.push(gasket::runtime::spawn_stage(watchdog, policy.clone()));
+    }
+
+    if let Some(progress) = progress {
+        tethers.push(gasket::runtime::spawn_stage(progress, policy.clone()));
+    }
+
+    Ok(tethers)
 }
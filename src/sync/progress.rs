@@ -0,0 +1,208 @@
+use gasket::framework::*;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::ledger::time::{SlotConverter, Timestamp};
+use crate::wal::ChainPoint;
+
+/// How often the estimate is refreshed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Span of the samples that the ingestion speed is measured over
+const RATE_WINDOW: Duration = Duration::from_secs(120);
+
+/// Lag below which the node is considered synced, so there's nothing to report
+const SYNCED_LAG: Duration = Duration::from_secs(600);
+
+/// Measures how fast the chain is being ingested, over a sliding window
+///
+/// Block density changes a lot across eras (byron had a block per slot, later
+/// eras one every 20 secs on average), so blocks per second don't say how far
+/// the tip is. Instead, the speed is measured in chain time: the seconds that
+/// the tip moves forward per second of wall time.
+pub struct IngestRate {
+    window: Duration,
+    samples: VecDeque<(Instant, Timestamp)>,
+}
+
+impl IngestRate {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: Default::default(),
+        }
+    }
+
+    /// Records the chain time of the tip at a point in time
+    pub fn record(&mut self, at: Instant, tip_time: Timestamp) {
+        self.samples.push_back((at, tip_time));
+
+        while self
+            .samples
+            .front()
+            .is_some_and(|(x, _)| at.duration_since(*x) > self.window)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Seconds of chain time ingested per second, `None` until there are
+    /// samples far enough apart
+    pub fn speed(&self) -> Option<f64> {
+        let (first_at, first_time) = self.samples.front()?;
+        let (last_at, last_time) = self.samples.back()?;
+
+        let elapsed = last_at.duration_since(*first_at).as_secs_f64();
+
+        if elapsed == 0.0 {
+            return None;
+        }
+
+        Some(last_time.saturating_sub(*first_time) as f64 / elapsed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// How far behind the tip of the network the local tip is
+    pub lag: Duration,
+
+    /// Time left to reach the tip, `None` if the node isn't catching up
+    pub eta: Option<Duration>,
+}
+
+/// Estimates the time to reach the tip of the network
+///
+/// The tip of the network keeps moving at one second per second, so the lag
+/// only shrinks by whatever the ingestion speed has above that. A node that
+/// ingests slower than that never catches up and gets no ETA.
+pub fn estimate(tip_time: Timestamp, now: Timestamp, speed: Option<f64>) -> Estimate {
+    let lag = now.saturating_sub(tip_time);
+
+    let eta = match speed {
+        _ if lag == 0 => Some(Duration::ZERO),
+        Some(speed) if speed > 1.0 => Some(Duration::from_secs_f64(lag as f64 / (speed - 1.0))),
+        _ => None,
+    };
+
+    Estimate {
+        lag: Duration::from_secs(lag),
+        eta,
+    }
+}
+
+/// Reports how long until the WAL reaches the tip of the network
+#[derive(Stage)]
+#[stage(name = "progress", unit = "()", worker = "Worker")]
+pub struct Stage {
+    tip: watch::Receiver<ChainPoint>,
+    slots: SlotConverter,
+    rate: IngestRate,
+
+    /// Current unix time, the system clock outside of tests
+    now: fn() -> Timestamp,
+
+    /// Seconds of chain time between the WAL tip and the network tip
+    #[metric]
+    lag_secs: gasket::metrics::Gauge,
+
+    /// Estimated seconds to reach the network tip, -1 while unknown
+    #[metric]
+    eta_secs: gasket::metrics::Gauge,
+}
+
+impl Stage {
+    pub fn new(tip: watch::Receiver<ChainPoint>, slots: SlotConverter) -> Self {
+        Self {
+            tip,
+            slots,
+            rate: IngestRate::new(RATE_WINDOW),
+            now: super::watchdog::system_time,
+            lag_secs: Default::default(),
+            eta_secs: Default::default(),
+        }
+    }
+}
+
+pub struct Worker;
+
+#[async_trait::async_trait(?Send)]
+impl gasket::framework::Worker<Stage> for Worker {
+    async fn bootstrap(_stage: &Stage) -> Result<Self, WorkerError> {
+        Ok(Self)
+    }
+
+    async fn schedule(&mut self, _stage: &mut Stage) -> Result<WorkSchedule<()>, WorkerError> {
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+
+        Ok(WorkSchedule::Unit(()))
+    }
+
+    async fn execute(&mut self, _unit: &(), stage: &mut Stage) -> Result<(), WorkerError> {
+        let ChainPoint::Specific(slot, _) = *stage.tip.borrow() else {
+            return Ok(());
+        };
+
+        let Some(tip_time) = stage.slots.slot_to_time(slot) else {
+            return Ok(());
+        };
+
+        stage.rate.record(Instant::now(), tip_time);
+
+        let estimate = estimate(tip_time, (stage.now)(), stage.rate.speed());
+
+        stage.lag_secs.set(estimate.lag.as_secs() as i64);
+
+        let eta = estimate.eta.map(|x| x.as_secs() as i64).unwrap_or(-1);
+        stage.eta_secs.set(eta);
+
+        if estimate.lag > SYNCED_LAG {
+            info!(
+                lag = estimate.lag.as_secs(),
+                eta = estimate.eta.map(|x| x.as_secs()),
+                "syncing, estimated secs to tip"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_from_synthetic_rate() {
+        let start = Instant::now();
+        let mut rate = IngestRate::new(Duration::from_secs(60));
+
+        assert_eq!(rate.speed(), None);
+
+        // a day of chain time every 10 secs, while catching up
+        rate.record(start, 1_000_000);
+        rate.record(start + Duration::from_secs(10), 1_086_400);
+        assert_eq!(rate.speed(), Some(8640.0));
+
+        // it slowed down, the samples out of the window don't count
+        rate.record(start + Duration::from_secs(70), 1_087_000);
+        assert_eq!(rate.speed(), Some(10.0));
+
+        // 30 days behind at 8641 secs per sec, gaining 8640 secs per sec
+        let now = 10_000_000;
+        let tip = now - 30 * 86_400;
+
+        let estimate = estimate(tip, now, Some(8641.0));
+        assert_eq!(estimate.lag, Duration::from_secs(30 * 86_400));
+        assert_eq!(estimate.eta, Some(Duration::from_secs(300)));
+
+        // not faster than the network, it never gets there
+        assert_eq!(super::estimate(tip, now, Some(1.0)).eta, None);
+        assert_eq!(super::estimate(tip, now, None).eta, None);
+
+        // already at the tip
+        assert_eq!(super::estimate(now, now, None).eta, Some(Duration::ZERO));
+    }
+}
@@ -6,8 +6,11 @@ use pallas::network::miniprotocols::chainsync::{
     HeaderContent, NextResponse, RollbackBuffer, RollbackEffect, Tip,
 };
 use pallas::network::miniprotocols::Point;
-use tracing::{debug, info};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
 
+use super::buffer::{BufferPolicy, Occupancy, OnFull};
 use crate::prelude::*;
 use crate::wal::redb::WalStore;
 use crate::wal::WalReader;
@@ -69,68 +72,12 @@ impl Worker {
 
         Ok(range)
     }
-}
-
-#[async_trait::async_trait(?Send)]
-impl gasket::framework::Worker<Stage> for Worker {
-    async fn bootstrap(stage: &Stage) -> Result<Self, WorkerError> {
-        debug!("finding intersection candidates");
-
-        let candidates = stage
-            .wal
-            .intersect_candidates(5)
-            .or_panic()?
-            .into_iter()
-            .map(From::from)
-            .collect_vec();
-
-        debug!("connecting to peer");
-
-        let mut peer_session = PeerClient::connect(&stage.peer_address, stage.network_magic)
-            .await
-            .or_retry()?;
-
-        info!(
-            address = stage.peer_address,
-            magic = stage.network_magic,
-            "connected to peer"
-        );
-
-        debug!("finding intersect");
-
-        let (point, _) = peer_session
-            .chainsync()
-            .find_intersect(candidates)
-            .await
-            .or_restart()?;
-
-        let intersection = point
-            .ok_or(Error::message("couldn't find intersect"))
-            .or_panic()?;
-
-        info!(?intersection, "found intersection");
-
-        let worker = Self { peer_session };
-
-        Ok(worker)
-    }
 
-    async fn schedule(
+    async fn execute_unit(
         &mut self,
-        _stage: &mut Stage,
-    ) -> Result<WorkSchedule<WorkUnit>, WorkerError> {
-        let client = self.peer_session.chainsync();
-
-        if client.has_agency() {
-            debug!("should request next batch of blocks");
-            Ok(WorkSchedule::Unit(WorkUnit::Pull))
-        } else {
-            debug!("should await next block");
-            Ok(WorkSchedule::Unit(WorkUnit::Await))
-        }
-    }
-
-    async fn execute(&mut self, unit: &WorkUnit, stage: &mut Stage) -> Result<(), WorkerError> {
+        unit: &WorkUnit,
+        stage: &mut Stage,
+    ) -> Result<(), WorkerError> {
         match unit {
             WorkUnit::Pull => {
                 info!("pulling block batch from upstream peer");
@@ -197,6 +144,87 @@ impl gasket::framework::Worker<Stage> for Worker {
     }
 }
 
+#[async_trait::async_trait(?Send)]
+impl gasket::framework::Worker<Stage> for Worker {
+    async fn bootstrap(stage: &Stage) -> Result<Self, WorkerError> {
+        // events still in the buffer are past the WAL tip, intersecting before
+        // they're written would pull them twice
+        if stage.occupancy.get() > 0 {
+            debug!("waiting for the ingestion buffer to drain");
+            stage.occupancy.drained().await;
+        }
+
+        debug!("finding intersection candidates");
+
+        let candidates = stage
+            .wal
+            .intersect_candidates(5)
+            .or_panic()?
+            .into_iter()
+            .map(From::from)
+            .collect_vec();
+
+        debug!("connecting to peer");
+
+        let mut peer_session = PeerClient::connect(&stage.peer_address, stage.network_magic)
+            .await
+            .or_retry()?;
+
+        info!(
+            address = stage.peer_address,
+            magic = stage.network_magic,
+            "connected to peer"
+        );
+
+        debug!("finding intersect");
+
+        let (point, _) = peer_session
+            .chainsync()
+            .find_intersect(candidates)
+            .await
+            .or_restart()?;
+
+        let intersection = point
+            .ok_or(Error::message("couldn't find intersect"))
+            .or_panic()?;
+
+        info!(?intersection, "found intersection");
+
+        let worker = Self { peer_session };
+
+        Ok(worker)
+    }
+
+    async fn schedule(
+        &mut self,
+        _stage: &mut Stage,
+    ) -> Result<WorkSchedule<WorkUnit>, WorkerError> {
+        let client = self.peer_session.chainsync();
+
+        if client.has_agency() {
+            debug!("should request next batch of blocks");
+            Ok(WorkSchedule::Unit(WorkUnit::Pull))
+        } else {
+            debug!("should await next block");
+            Ok(WorkSchedule::Unit(WorkUnit::Await))
+        }
+    }
+
+    async fn execute(&mut self, unit: &WorkUnit, stage: &mut Stage) -> Result<(), WorkerError> {
+        let reconnect = stage.reconnect.clone();
+
+        // a silently dead connection blocks forever waiting on the peer, the
+        // watchdog signals when that looks to be the case
+        tokio::select! {
+            result = self.execute_unit(unit, stage) => result,
+            _ = reconnect.notified() => {
+                warn!("ingestion stalled, reconnecting to upstream peer");
+                Err(WorkerError::Restart)
+            }
+        }
+    }
+}
+
 #[derive(Stage)]
 #[stage(name = "pull", unit = "WorkUnit", worker = "Worker")]
 pub struct Stage {
@@ -212,6 +240,15 @@ pub struct Stage {
 
     #[metric]
     chain_tip: gasket::metrics::Gauge,
+
+    /// Times that the buffer to the roll stage was found full
+    #[metric]
+    buffer_full_count: gasket::metrics::Counter,
+
+    reconnect: Arc<Notify>,
+    buffer_capacity: usize,
+    on_full: OnFull,
+    occupancy: Occupancy,
 }
 
 impl Stage {
@@ -229,9 +266,29 @@ impl Stage {
             downstream: Default::default(),
             block_count: Default::default(),
             chain_tip: Default::default(),
+            buffer_full_count: Default::default(),
+            reconnect: Default::default(),
+            buffer_capacity: super::buffer::DEFAULT_CAPACITY,
+            on_full: OnFull::default(),
+            occupancy: Default::default(),
         }
     }
 
+    /// Sets how to behave once the buffer to the roll stage is full
+    ///
+    /// The channel connected downstream has to be created with the same
+    /// capacity, and the roll stage has to share the occupancy.
+    pub fn set_buffer(&mut self, policy: &BufferPolicy, occupancy: Occupancy) {
+        self.buffer_capacity = policy.capacity;
+        self.on_full = policy.on_full;
+        self.occupancy = occupancy;
+    }
+
+    /// Signal that makes the worker drop the connection and start over
+    pub fn reconnect_signal(&self) -> Arc<Notify> {
+        self.reconnect.clone()
+    }
+
     async fn flush_blocks(&mut self, blocks: Vec<BlockBody>) -> Result<(), WorkerError> {
         for cbor in blocks {
             // TODO: can we avoid decoding in this stage?
@@ -243,14 +300,12 @@ impl Stage {
                     slot: decoded.slot(),
                     hash: decoded.hash(),
                     era: decoded.era(),
+                    txs: decoded.txs().iter().map(|x| x.hash()).collect(),
                     body: cbor,
                 }
             };
 
-            self.downstream
-                .send(PullEvent::RollForward(payload).into())
-                .await
-                .or_panic()?;
+            self.send(PullEvent::RollForward(payload)).await?;
         }
 
         Ok(())
@@ -262,10 +317,24 @@ impl Stage {
             Point::Specific(slot, _) => debug!(slot, "rollback"),
         };
 
-        self.downstream
-            .send(PullEvent::Rollback(point.clone()).into())
-            .await
-            .or_panic()?;
+        self.send(PullEvent::Rollback(point.clone())).await?;
+
+        Ok(())
+    }
+
+    async fn send(&mut self, event: PullEvent) -> Result<(), WorkerError> {
+        if self.occupancy.get() >= self.buffer_capacity {
+            self.buffer_full_count.inc(1);
+
+            if self.on_full == OnFull::Reconnect {
+                warn!("ingestion buffer is full, reconnecting once it drains");
+                return Err(WorkerError::Restart);
+            }
+        }
+
+        self.occupancy.push();
+
+        self.downstream.send(event.into()).await.or_panic()?;
 
         Ok(())
     }
@@ -274,3 +343,82 @@ impl Stage {
         self.chain_tip.set(tip.0.slot_or_default() as i64);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use gasket::framework::{WorkSchedule, Worker as _, WorkerError};
+
+    use super::*;
+    use crate::sync::roll;
+    use crate::wal::testing;
+
+    #[tokio::test]
+    async fn test_burst_is_not_lost_when_blocking() {
+        let chain = testing::TestChainBuilder::new().extend(0..100);
+        let wal = testing::empty_db();
+
+        let policy = BufferPolicy {
+            capacity: 4,
+            on_full: OnFull::Block,
+        };
+
+        let occupancy = Occupancy::default();
+
+        let mut pull = Stage::new("localhost:3001".into(), 2, 50, wal.clone());
+        pull.set_buffer(&policy, occupancy.clone());
+
+        let mut roll = roll::Stage::new(wal.clone());
+        roll.set_buffer_occupancy(occupancy.clone());
+
+        let (to_roll, from_pull) = gasket::messaging::tokio::mpsc_channel(policy.capacity);
+        pull.downstream.connect(to_roll);
+        roll.upstream.connect(from_pull);
+
+        // nobody listens to the tip events, they only need room
+        let (to_ledger, _from_roll) = gasket::messaging::tokio::mpsc_channel(200);
+        roll.downstream.connect(to_ledger);
+
+        let burst = chain.blocks().iter().map(|x| x.body.clone()).collect();
+
+        let write = async {
+            let mut worker = roll::Worker::bootstrap(&roll).await.unwrap();
+
+            let mut written = 0;
+
+            while written < 100 {
+                let WorkSchedule::Unit(unit) = worker.schedule(&mut roll).await.unwrap() else {
+                    panic!("roll should always schedule an event");
+                };
+
+                worker.execute(&unit, &mut roll).await.unwrap();
+                written += unit.len();
+            }
+        };
+
+        let (pulled, _) = tokio::join!(pull.flush_blocks(burst), write);
+        pulled.unwrap();
+
+        let written: Vec<_> = wal.crawl_from(None).unwrap().collect();
+        assert_eq!(written.len(), 100);
+        assert_eq!(wal.find_tip().unwrap().unwrap().1, chain.tip());
+        assert_eq!(occupancy.get(), 0);
+
+        // the burst didn't fit, so the pull stage had to wait for room
+        assert!(pull.buffer_full_count.get() > 0);
+
+        // reconnecting instead drops the batch while the buffer is full
+        pull.set_buffer(
+            &BufferPolicy {
+                on_full: OnFull::Reconnect,
+                ..policy
+            },
+            occupancy.clone(),
+        );
+
+        (0..4).for_each(|_| occupancy.push());
+
+        let more = testing::TestChainBuilder::new().extend([100]);
+        let result = pull.flush_blocks(vec![more.blocks()[0].body.clone()]).await;
+        assert!(matches!(result, Err(WorkerError::Restart)));
+    }
+}
@@ -1,6 +1,8 @@
+use futures_util::FutureExt as _;
 use gasket::framework::*;
-use tracing::info;
+use tracing::{info, warn};
 
+use super::buffer::Occupancy;
 use crate::{
     prelude::*,
     wal::{self, redb::WalStore, WalWriter},
@@ -10,8 +12,14 @@ pub type Cursor = (BlockSlot, BlockHash);
 pub type UpstreamPort = gasket::messaging::InputPort<PullEvent>;
 pub type DownstreamPort = gasket::messaging::OutputPort<RollEvent>;
 
+/// Max number of buffered events taken in a single go
+const MAX_BATCH: usize = 100;
+
+/// Events taken from the buffer in a single go, in the order they were pulled
+pub type Batch = Vec<PullEvent>;
+
 #[derive(Stage)]
-#[stage(name = "roll", unit = "PullEvent", worker = "Worker")]
+#[stage(name = "roll", unit = "Batch", worker = "Worker")]
 pub struct Stage {
     store: WalStore,
 
@@ -23,6 +31,15 @@ pub struct Stage {
 
     #[metric]
     roll_count: gasket::metrics::Counter,
+
+    #[metric]
+    invalid_block_count: gasket::metrics::Counter,
+
+    /// Events left in the buffer from the pull stage, as of the last batch
+    #[metric]
+    buffer_occupancy: gasket::metrics::Gauge,
+
+    occupancy: Occupancy,
 }
 
 impl Stage {
@@ -33,38 +50,87 @@ impl Stage {
             downstream: Default::default(),
             block_count: Default::default(),
             roll_count: Default::default(),
+            invalid_block_count: Default::default(),
+            buffer_occupancy: Default::default(),
+            occupancy: Default::default(),
         }
     }
 
-    fn process_pull_event(&mut self, unit: &PullEvent) -> Result<(), WorkerError> {
-        match unit {
-            PullEvent::RollForward(block) => {
-                let block = wal::RawBlock {
-                    slot: block.slot,
-                    hash: block.hash,
-                    era: block.era,
-                    body: block.body.clone(),
-                };
+    /// Shares the count of buffered events with the pull stage
+    pub fn set_buffer_occupancy(&mut self, occupancy: Occupancy) {
+        self.occupancy = occupancy;
+    }
 
-                info!(block.slot, %block.hash, "extending wal");
+    /// Appends consecutive blocks to the WAL in a single write
+    ///
+    /// The WAL commits (and syncs to disk) once per write, so writing a batch
+    /// at once is much cheaper than writing its blocks one at a time, which
+    /// matters while catching up. Sequences are assigned in order, same as if
+    /// each block was written on its own. The WAL write batch limits, if set,
+    /// split the batch into several commits, see `WalStore::roll_forward_batch`.
+    fn roll_forward(
+        &mut self,
+        blocks: Vec<(wal::RawBlock, Vec<TxHash>)>,
+    ) -> Result<(), WorkerError> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.store.roll_forward_decoded(blocks.into_iter());
 
-                self.store.roll_forward(std::iter::once(block)).or_panic()?;
+        if let Err(err) = &result {
+            // the blocks before the failed chunk stay in the wal, pulling
+            // resumes from them once the stage restarts
+            warn!(
+                committed = err.committed,
+                "wal batch only partially written"
+            );
+
+            if let wal::WalError::InvalidBlockBody(slot, size) = &err.error {
+                warn!(slot, size, "upstream sent a block with an invalid body");
+                self.invalid_block_count.inc(1);
             }
-            PullEvent::Rollback(point) => {
-                let point = match point {
-                    pallas::network::miniprotocols::Point::Origin => wal::ChainPoint::Origin,
-                    pallas::network::miniprotocols::Point::Specific(s, h) => {
-                        wal::ChainPoint::Specific(*s, h.as_slice().into())
-                    }
-                };
+        }
+
+        result.or_panic()
+    }
+
+    fn process_batch(&mut self, batch: &[PullEvent]) -> Result<(), WorkerError> {
+        let mut blocks = vec![];
+
+        for event in batch {
+            match event {
+                PullEvent::RollForward(block) => {
+                    info!(block.slot, %block.hash, "extending wal");
 
-                info!(?point, "rolling back wal");
+                    let raw = wal::RawBlock {
+                        slot: block.slot,
+                        hash: block.hash,
+                        era: block.era,
+                        body: block.body.clone(),
+                    };
 
-                self.store.roll_back(&point).or_panic()?;
+                    blocks.push((raw, block.txs.clone()));
+                }
+                PullEvent::Rollback(point) => {
+                    // blocks before the rollback have to be in place first
+                    self.roll_forward(std::mem::take(&mut blocks))?;
+
+                    let point = match point {
+                        pallas::network::miniprotocols::Point::Origin => wal::ChainPoint::Origin,
+                        pallas::network::miniprotocols::Point::Specific(s, h) => {
+                            wal::ChainPoint::Specific(*s, h.as_slice().into())
+                        }
+                    };
+
+                    info!(?point, "rolling back wal");
+
+                    self.store.roll_back(&point).or_panic()?;
+                }
             }
         }
 
-        Ok(())
+        self.roll_forward(blocks)
     }
 }
 
@@ -78,19 +144,33 @@ impl gasket::framework::Worker<Stage> for Worker {
         Ok(Worker)
     }
 
-    async fn schedule(
-        &mut self,
-        stage: &mut Stage,
-    ) -> Result<WorkSchedule<PullEvent>, WorkerError> {
+    async fn schedule(&mut self, stage: &mut Stage) -> Result<WorkSchedule<Batch>, WorkerError> {
         // TODO: define a pruning strategy for the WAL here
 
         let msg = stage.upstream.recv().await.or_panic()?;
+        stage.occupancy.take();
+
+        let mut batch = vec![msg.payload];
+
+        // take whatever else is already buffered, without waiting for more
+        while batch.len() < MAX_BATCH {
+            match stage.upstream.recv().now_or_never() {
+                Some(msg) => batch.push(msg.or_panic()?.payload),
+                None => break,
+            }
 
-        Ok(WorkSchedule::Unit(msg.payload))
+            stage.occupancy.take();
+        }
+
+        stage.buffer_occupancy.set(stage.occupancy.get() as i64);
+
+        Ok(WorkSchedule::Unit(batch))
     }
 
-    async fn execute(&mut self, unit: &PullEvent, stage: &mut Stage) -> Result<(), WorkerError> {
-        stage.process_pull_event(unit)?;
+    async fn execute(&mut self, unit: &Batch, stage: &mut Stage) -> Result<(), WorkerError> {
+        stage.process_batch(unit)?;
+
+        unit.iter().for_each(|_| stage.occupancy.written());
 
         stage
             .downstream
@@ -101,3 +181,141 @@ impl gasket::framework::Worker<Stage> for Worker {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use gasket::framework::Worker as _;
+
+    use super::*;
+    use crate::wal::{testing, WalReader as _};
+
+    fn connected_stage(
+        store: WalStore,
+    ) -> (
+        Stage,
+        gasket::messaging::tokio::ChannelRecvAdapter<RollEvent>,
+    ) {
+        let mut stage = Stage::new(store);
+
+        let (output, input) = gasket::messaging::tokio::mpsc_channel(200);
+        stage.downstream.connect(output);
+
+        (stage, input)
+    }
+
+    fn forward(block: &wal::RawBlock) -> PullEvent {
+        PullEvent::RollForward(RawBlock {
+            slot: block.slot,
+            hash: block.hash,
+            era: block.era,
+            body: block.body.clone(),
+            txs: block
+                .decode()
+                .unwrap()
+                .txs()
+                .iter()
+                .map(|x| x.hash())
+                .collect(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_batch_matches_event_by_event() {
+        let main = testing::TestChainBuilder::new().extend(0..20);
+        let fork = main.fork_at(15).extend([17, 19, 21]);
+
+        let wal::ChainPoint::Specific(slot, hash) = main.point(15) else {
+            unreachable!()
+        };
+
+        let events: Vec<_> = main
+            .blocks()
+            .iter()
+            .map(forward)
+            .chain(std::iter::once(PullEvent::Rollback(
+                pallas::network::miniprotocols::Point::Specific(slot, hash.to_vec()),
+            )))
+            .chain(fork.blocks_after(15).iter().map(forward))
+            .collect();
+
+        let single = testing::empty_db();
+        let (mut stage, _tips) = connected_stage(single.clone());
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        for event in events.iter() {
+            worker
+                .execute(&vec![event.clone()], &mut stage)
+                .await
+                .unwrap();
+        }
+
+        let batched = testing::empty_db();
+        let (mut stage, _tips) = connected_stage(batched.clone());
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        worker.execute(&events, &mut stage).await.unwrap();
+
+        let entries = |wal: &WalStore| {
+            wal.crawl_from(None)
+                .unwrap()
+                .map(|(seq, log)| (seq, format!("{log:?}")))
+                .collect::<Vec<_>>()
+        };
+
+        // same entries, with the same sequences
+        assert_eq!(entries(&batched), entries(&single));
+        assert_eq!(batched.find_tip().unwrap(), single.find_tip().unwrap());
+        assert_eq!(batched.find_tip().unwrap().unwrap().1, fork.tip());
+
+        for block in fork.blocks() {
+            let point = wal::ChainPoint::from(block);
+            assert_eq!(
+                batched.locate_point(&point).unwrap(),
+                single.locate_point(&point).unwrap()
+            );
+            assert_eq!(batched.read_block(&point).unwrap(), *block);
+        }
+
+        // 20 applies, 4 undos and a mark, then the 3 blocks of the fork
+        assert_eq!(entries(&batched).len(), 28);
+    }
+
+    #[tokio::test]
+    async fn test_occupancy_counts_events_until_received() {
+        let chain = testing::TestChainBuilder::new().extend(0..3);
+
+        let (mut stage, _tips) = connected_stage(testing::empty_db());
+        let occupancy = Occupancy::default();
+        stage.set_buffer_occupancy(occupancy.clone());
+
+        let (to_roll, from_pull) = gasket::messaging::tokio::mpsc_channel(4);
+        stage.upstream.connect(from_pull);
+
+        let mut port = gasket::messaging::OutputPort::<PullEvent>::default();
+        port.connect(to_roll);
+
+        for block in chain.blocks() {
+            occupancy.push();
+            port.send(forward(block).into()).await.unwrap();
+        }
+
+        assert_eq!(occupancy.get(), 3);
+
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        let WorkSchedule::Unit(unit) = worker.schedule(&mut stage).await.unwrap() else {
+            panic!("roll should schedule the buffered events");
+        };
+
+        // the batch left the buffer, but it isn't written yet
+        assert_eq!(unit.len(), 3);
+        assert_eq!(occupancy.get(), 0);
+
+        let drained = occupancy.drained();
+        let pending = tokio::time::timeout(std::time::Duration::from_millis(200), drained);
+        assert!(pending.await.is_err());
+
+        worker.execute(&unit, &mut stage).await.unwrap();
+        occupancy.drained().await;
+    }
+}
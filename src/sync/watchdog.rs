@@ -0,0 +1,287 @@
+use gasket::framework::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tracing::{debug, info, warn};
+
+use crate::ledger::time::{SlotConverter, Timestamp};
+use crate::wal::ChainPoint;
+
+/// Flags ingestion as stalled when the WAL tip doesn't move for a while
+///
+/// A dead upstream connection doesn't always surface as an error, the node
+/// just stops receiving blocks while it keeps serving the last known tip.
+/// The timeout runs on monotonic time, so changes to the system clock don't
+/// affect it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StallPolicy {
+    /// Seconds without a change of the WAL tip before ingestion is stalled
+    pub timeout_secs: u64,
+
+    /// Restarts the upstream connection each time the timeout expires
+    #[serde(default)]
+    pub reconnect: bool,
+
+    /// Seconds that the system clock may be off from the chain
+    ///
+    /// When set, an expired timeout is cross-checked against the time of the
+    /// tip slot: if the tip is still recent by the chain clock (give or take
+    /// the skew), no block was due yet and it's not flagged as a stall.
+    #[serde(default)]
+    pub max_clock_skew_secs: Option<u64>,
+}
+
+/// Chain time of the tip, used to cross-check an expired timeout
+pub struct ChainClock {
+    pub slots: SlotConverter,
+    pub max_skew: Duration,
+
+    /// Current unix time, the system clock outside of tests
+    pub now: fn() -> Timestamp,
+}
+
+impl ChainClock {
+    pub fn new(slots: SlotConverter, max_skew: Duration) -> Self {
+        Self {
+            slots,
+            max_skew,
+            now: system_time,
+        }
+    }
+
+    /// Tells if the tip is older than `timeout` by the chain clock
+    ///
+    /// The skew is taken in favor of the tip, a clock running ahead of the
+    /// chain can't make a recent tip look late.
+    fn is_late(&self, tip: &ChainPoint, timeout: Duration) -> bool {
+        let ChainPoint::Specific(slot, _) = tip else {
+            return true;
+        };
+
+        let Some(tip_time) = self.slots.slot_to_time(*slot) else {
+            return true;
+        };
+
+        let lag = Duration::from_secs((self.now)().saturating_sub(tip_time));
+
+        lag.saturating_sub(self.max_skew) >= timeout
+    }
+}
+
+pub(crate) fn system_time() -> Timestamp {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+pub enum Check {
+    TipMoved,
+    Stalled,
+}
+
+#[derive(Stage)]
+#[stage(name = "watchdog", unit = "Check", worker = "Worker")]
+pub struct Stage {
+    tip: watch::Receiver<ChainPoint>,
+    timeout: Duration,
+    reconnect: Option<Arc<Notify>>,
+    clock: Option<ChainClock>,
+
+    /// 1 while ingestion is stalled, 0 otherwise
+    #[metric]
+    stalled: gasket::metrics::Gauge,
+
+    #[metric]
+    stall_count: gasket::metrics::Counter,
+}
+
+impl Stage {
+    /// Watches the tip, `reconnect` is signaled on every expired timeout
+    pub fn new(
+        tip: watch::Receiver<ChainPoint>,
+        timeout: Duration,
+        reconnect: Option<Arc<Notify>>,
+    ) -> Self {
+        Self {
+            tip,
+            timeout,
+            reconnect,
+            clock: None,
+            stalled: Default::default(),
+            stall_count: Default::default(),
+        }
+    }
+
+    /// Cross-checks expired timeouts against the chain time of the tip
+    pub fn set_clock(&mut self, clock: ChainClock) {
+        self.clock = Some(clock);
+    }
+}
+
+pub struct Worker {
+    stalled: bool,
+}
+
+#[async_trait::async_trait(?Send)]
+impl gasket::framework::Worker<Stage> for Worker {
+    async fn bootstrap(_stage: &Stage) -> Result<Self, WorkerError> {
+        Ok(Self { stalled: false })
+    }
+
+    async fn schedule(&mut self, stage: &mut Stage) -> Result<WorkSchedule<Check>, WorkerError> {
+        match tokio::time::timeout(stage.timeout, stage.tip.changed()).await {
+            Ok(changed) => {
+                changed.or_panic()?;
+                Ok(WorkSchedule::Unit(Check::TipMoved))
+            }
+            Err(_) => Ok(WorkSchedule::Unit(Check::Stalled)),
+        }
+    }
+
+    async fn execute(&mut self, unit: &Check, stage: &mut Stage) -> Result<(), WorkerError> {
+        match unit {
+            Check::TipMoved => {
+                if self.stalled {
+                    info!("wal tip moved, ingestion resumed");
+                    stage.stalled.set(0);
+                }
+
+                self.stalled = false;
+            }
+            Check::Stalled => {
+                let tip = stage.tip.borrow().clone();
+
+                if stage
+                    .clock
+                    .as_ref()
+                    .is_some_and(|x| !x.is_late(&tip, stage.timeout))
+                {
+                    debug!(?tip, "timeout expired but the tip is recent by chain time");
+                    return Ok(());
+                }
+
+                warn!(
+                    timeout = stage.timeout.as_secs(),
+                    ?tip,
+                    "wal tip hasn't moved, ingestion looks stalled"
+                );
+
+                if !self.stalled {
+                    stage.stall_count.inc(1);
+                    stage.stalled.set(1);
+                }
+
+                self.stalled = true;
+
+                if let Some(reconnect) = &stage.reconnect {
+                    reconnect.notify_one();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::framework::Worker as _;
+
+    use super::*;
+    use crate::wal::{testing, WalWriter as _};
+
+    async fn next_check(worker: &mut Worker, stage: &mut Stage) -> Check {
+        match worker.schedule(stage).await.unwrap() {
+            WorkSchedule::Unit(x) => x,
+            _ => panic!("watchdog should always schedule a check"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stall_flips_health_metric() {
+        let mut wal = testing::db_with_dummy_blocks(2);
+        let reconnect = Arc::new(Notify::new());
+
+        let mut stage = Stage::new(
+            wal.watch_tip(),
+            Duration::from_millis(50),
+            Some(reconnect.clone()),
+        );
+
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        // nothing is written to the wal, so the timeout expires
+        let check = next_check(&mut worker, &mut stage).await;
+        assert!(matches!(check, Check::Stalled));
+
+        worker.execute(&check, &mut stage).await.unwrap();
+        assert_eq!(stage.stalled.get(), 1);
+
+        let signaled = tokio::time::timeout(Duration::from_millis(10), reconnect.notified());
+        assert!(signaled.await.is_ok());
+
+        // still stalled, counted once
+        let check = next_check(&mut worker, &mut stage).await;
+        worker.execute(&check, &mut stage).await.unwrap();
+        assert_eq!(stage.stall_count.get(), 1);
+
+        // a new block clears the stall
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(2)))
+            .unwrap();
+
+        let check = next_check(&mut worker, &mut stage).await;
+        assert!(matches!(check, Check::TipMoved));
+
+        worker.execute(&check, &mut stage).await.unwrap();
+        assert_eq!(stage.stalled.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_recent_tip_by_chain_time_is_not_a_stall() {
+        use crate::ledger::time::{EraStart, NetworkConfig};
+
+        // slot 1 (the tip) starts at 1_000_001
+        let network = NetworkConfig {
+            eras: vec![EraStart {
+                slot: 0,
+                time: 1_000_000,
+                slot_length: 1_000,
+                epoch_length: 432_000,
+            }],
+        };
+
+        let wal = testing::db_with_dummy_blocks(2);
+
+        let stage_with = |now: fn() -> Timestamp| {
+            let mut stage = Stage::new(wal.watch_tip(), Duration::from_millis(50), None);
+
+            let mut clock = ChainClock::new(SlotConverter::new(&network), Duration::from_secs(30));
+
+            clock.now = now;
+            stage.set_clock(clock);
+
+            stage
+        };
+
+        // the system clock runs 20 secs ahead of the chain, within the skew
+        let mut stage = stage_with(|| 1_000_021);
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        let check = next_check(&mut worker, &mut stage).await;
+        assert!(matches!(check, Check::Stalled));
+
+        worker.execute(&check, &mut stage).await.unwrap();
+        assert_eq!(stage.stalled.get(), 0);
+        assert_eq!(stage.stall_count.get(), 0);
+
+        // a tip an hour old is late no matter the skew
+        let mut stage = stage_with(|| 1_003_601);
+        let mut worker = Worker::bootstrap(&stage).await.unwrap();
+
+        let check = next_check(&mut worker, &mut stage).await;
+        worker.execute(&check, &mut stage).await.unwrap();
+        assert_eq!(stage.stalled.get(), 1);
+    }
+}
@@ -0,0 +1,58 @@
+//! In-memory bloom filter over the block hashes present in the WAL
+//!
+//! Used to short-circuit lookups of points that aren't in the WAL without
+//! touching the db. Block hashes are already uniformly distributed, so the
+//! bit positions are derived directly from the hash bytes.
+
+use super::BlockHash;
+
+const MIN_CAPACITY: usize = 1024;
+const MAX_HASHES: u32 = 30;
+
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for `capacity` keys
+    ///
+    /// More bits per key lower the false positive rate (10 bits gives ~1%)
+    /// at the cost of memory.
+    pub fn new(capacity: usize, bits_per_key: usize) -> Self {
+        let bits_per_key = bits_per_key.max(1);
+        let num_bits = (capacity.max(MIN_CAPACITY) * bits_per_key) as u64;
+        let words = num_bits.div_ceil(64) as usize;
+
+        let num_hashes = ((bits_per_key as f64) * std::f64::consts::LN_2).round() as u32;
+
+        Self {
+            bits: vec![0; words],
+            num_bits: words as u64 * 64,
+            num_hashes: num_hashes.clamp(1, MAX_HASHES),
+        }
+    }
+
+    fn positions(&self, hash: &BlockHash) -> impl Iterator<Item = u64> + '_ {
+        let h1 = u64::from_le_bytes(hash[0..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(hash[8..16].try_into().unwrap()) | 1;
+
+        (0..self.num_hashes as u64)
+            .map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits)
+    }
+
+    pub fn insert(&mut self, hash: &BlockHash) {
+        let positions: Vec<_> = self.positions(hash).collect();
+
+        for bit in positions {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False means the hash was never inserted, true means it might have been
+    pub fn may_contain(&self, hash: &BlockHash) -> bool {
+        self.positions(hash)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+}
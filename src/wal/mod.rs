@@ -1,4 +1,5 @@
 use itertools::Itertools;
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
 use pallas::network::miniprotocols::Point as PallasPoint;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -10,6 +11,17 @@ mod writer;
 // A concrete implementation of the WAL using Redb
 pub mod redb;
 
+// Forwarding of committed entries to secondary sinks
+pub mod tee;
+
+// Async facade over the Redb reads, for use from async handlers
+pub mod nonblocking;
+
+// Raw WAL records for replicas that don't decode blocks
+pub mod mirror;
+
+mod bloom;
+
 #[cfg(test)]
 pub mod testing;
 
@@ -20,6 +32,15 @@ pub type ChainTip = pallas::network::miniprotocols::chainsync::Tip;
 pub type BlockBody = Vec<u8>;
 pub type BlockHeader = Vec<u8>;
 pub type LogSeq = u64;
+pub type BlockHeight = u64;
+pub type TxHash = pallas::crypto::hash::Hash<32>;
+
+/// Smallest body size accepted for a block
+///
+/// Even the smallest Byron boundary block takes a few dozen bytes of CBOR, so
+/// anything below this can't be a valid block and would only blow up later
+/// when decoded.
+pub const MIN_BLOCK_BODY_SIZE: usize = 32;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChainPoint {
@@ -80,6 +101,149 @@ pub struct RawBlock {
     pub body: BlockBody,
 }
 
+impl RawBlock {
+    pub fn decode(&self) -> Result<MultiEraBlock<'_>, DecodeError> {
+        decode_block(&self.body)
+    }
+
+    /// Checks that the body decodes into the block described by the rest of
+    /// the fields
+    ///
+    /// Much more expensive than trusting the fields, meant for blocks that come
+    /// from untrusted sources (eg: a dump to import).
+    pub fn validate(&self) -> Result<(), InvalidBlock> {
+        let block = self.decode().map_err(InvalidBlock::Decode)?;
+
+        if block.slot() != self.slot {
+            return Err(InvalidBlock::SlotMismatch(block.slot()));
+        }
+
+        if block.hash() != self.hash {
+            return Err(InvalidBlock::HashMismatch(block.hash()));
+        }
+
+        if block.era() != self.era {
+            return Err(InvalidBlock::EraMismatch(block.era()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Why a block doesn't hold up to `RawBlock::validate`
+#[derive(Debug, Error)]
+pub enum InvalidBlock {
+    #[error("body can't be decoded")]
+    Decode(#[source] DecodeError),
+
+    #[error("body is a block for slot {0}")]
+    SlotMismatch(BlockSlot),
+
+    #[error("body hashes to {0}")]
+    HashMismatch(BlockHash),
+
+    #[error("body is a block of era {0:?}")]
+    EraMismatch(BlockEra),
+}
+
+/// Decodes each block of a page once, up front
+///
+/// Pages from `read_block_page` hold raw bodies and nothing is decoded until a
+/// consumer needs it. Consumers that need the decoded blocks, and that need
+/// them in more than one place, use this to decode once and share the result.
+/// Decoded blocks borrow from the page, so the page must outlive them.
+pub fn decode_page(page: &[RawBlock]) -> Vec<(&RawBlock, Result<MultiEraBlock<'_>, DecodeError>)> {
+    page.iter().map(|raw| (raw, raw.decode())).collect()
+}
+
+/// The gist of a block, for humans looking into a specific block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockSummary {
+    pub era: BlockEra,
+    pub hash: BlockHash,
+    pub prev_hash: Option<BlockHash>,
+    pub slot: BlockSlot,
+    pub number: BlockHeight,
+    pub tx_count: usize,
+    pub invalid_tx_count: usize,
+
+    /// Lovelace produced by the block, collateral returns of invalid txs included
+    pub output_lovelace: u64,
+
+    /// Txs that carry scripts in their witnesses
+    pub script_tx_count: usize,
+
+    /// Txs with metadata in their auxiliary data
+    pub metadata_tx_count: usize,
+}
+
+fn has_scripts(tx: &MultiEraTx) -> bool {
+    !tx.native_scripts().is_empty()
+        || !tx.plutus_v1_scripts().is_empty()
+        || !tx.plutus_v2_scripts().is_empty()
+        || !tx.plutus_v3_scripts().is_empty()
+}
+
+fn has_metadata(tx: &MultiEraTx) -> bool {
+    !tx.metadata().collect::<Vec<_>>().is_empty()
+}
+
+impl From<&MultiEraBlock<'_>> for BlockSummary {
+    fn from(block: &MultiEraBlock<'_>) -> Self {
+        let txs = block.txs();
+
+        Self {
+            era: block.era(),
+            hash: block.hash(),
+            prev_hash: block.header().previous_hash(),
+            slot: block.slot(),
+            number: block.number(),
+            tx_count: txs.len(),
+            invalid_tx_count: txs.iter().filter(|x| !x.is_valid()).count(),
+            output_lovelace: txs
+                .iter()
+                .flat_map(|x| x.produces())
+                .map(|(_, x)| x.lovelace_amount())
+                .sum(),
+            script_tx_count: txs.iter().filter(|x| has_scripts(x)).count(),
+            metadata_tx_count: txs.iter().filter(|x| has_metadata(x)).count(),
+        }
+    }
+}
+
+/// A rollback recorded in the WAL
+///
+/// The undone blocks only carry their slot, the chain time of the rollback
+/// can be derived from it (see `ledger::time::SlotConverter`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rollback {
+    /// Sequence of the mark that closes the rollback
+    pub seq: LogSeq,
+
+    /// Point where the chain went back to
+    pub point: ChainPoint,
+
+    /// Number of undone blocks
+    pub depth: usize,
+
+    /// Slots of the lowest and highest undone blocks, the latter being the
+    /// tip before the rollback
+    pub undone: std::ops::RangeInclusive<BlockSlot>,
+}
+
+/// How deep a tx is buried in the chain, see `WalStore::tx_finality`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxFinality {
+    /// Block that contains the tx
+    pub block: ChainPoint,
+
+    /// Number of blocks on top of the one with the tx
+    pub depth: BlockHeight,
+
+    /// The block has at least `k` blocks on top, it can't be rolled back
+    pub immutable: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum LogValue {
     Apply(RawBlock),
@@ -100,16 +264,124 @@ impl PartialEq for LogValue {
 
 pub type LogEntry = (LogSeq, LogValue);
 
+/// Height of the chain tip once an entry has been applied
+///
+/// The height recorded for an undo is the one of the undone block, so the tip
+/// goes back one block. Applies and marks leave the tip at their own height.
+pub(crate) fn tip_height_after(log: &LogValue, height: Option<BlockHeight>) -> Option<BlockHeight> {
+    match log {
+        LogValue::Undo(_) => height.and_then(|x| x.checked_sub(1)),
+        _ => height,
+    }
+}
+
+/// Secondary indexes of the WAL, derived from the log entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Chain point (slot) to sequence of the latest entry for that point
+    Position,
+
+    /// Sequence to block height
+    Height,
+
+    /// Block hash to sequence of the entry that applied it
+    Hash,
+
+    /// Tx hash to the entry that applied its block
+    Tx,
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("block era {0} is not supported by this version, an upgrade is required")]
+    UnsupportedEra(u16),
+
+    #[error("invalid block cbor")]
+    InvalidCbor(#[source] pallas::ledger::traverse::Error),
+}
+
+fn probe_era_tag(cbor: &[u8]) -> Option<u16> {
+    let mut decoder = pallas::codec::minicbor::Decoder::new(cbor);
+    decoder.array().ok()?;
+    decoder.u16().ok()
+}
+
+/// Decodes a block body, telling apart blocks from unknown eras
+///
+/// Pallas reports blocks from eras it doesn't know about as generic cbor
+/// errors. We peek at the era tag of the block envelope so that those cases
+/// surface as `UnsupportedEra`, which usually means that a hard fork happened
+/// and the node needs to be upgraded, instead of looking like corrupted data.
+pub fn decode_block(cbor: &[u8]) -> Result<MultiEraBlock<'_>, DecodeError> {
+    MultiEraBlock::decode(cbor).map_err(|err| match probe_era_tag(cbor) {
+        // tag 0 is used by Byron epoch boundary blocks
+        Some(tag) if tag > 0 && BlockEra::try_from(tag).is_err() => {
+            DecodeError::UnsupportedEra(tag)
+        }
+        _ => DecodeError::InvalidCbor(err),
+    })
+}
+
 #[derive(Debug, Error)]
 pub enum WalError {
     #[error("point not found in chain {0:?}")]
     PointNotFound(ChainPoint),
 
+    #[error("sequence gap, expected {0} but found {1}")]
+    SequenceGap(LogSeq, LogSeq),
+
+    #[error("entry {0} conflicts with existing wal data")]
+    SequenceConflict(LogSeq),
+
+    #[error("block {0} already applied at slot {1}, can't apply it at slot {2}")]
+    DuplicateBlockHash(BlockHash, BlockSlot, BlockSlot),
+
+    #[error("invariant violated at entry {0}: {1}")]
+    InvariantViolation(LogSeq, &'static str),
+
+    #[error("block at slot {0} has an invalid body of {1} bytes")]
+    InvalidBlockBody(BlockSlot, usize),
+
+    #[error("block {0} at slot {1} doesn't build on top of the tip {2}")]
+    NonContiguousBlock(BlockHash, BlockSlot, BlockHash),
+
+    #[error("block {0} body doesn't match its checksum")]
+    CorruptBlock(BlockHash),
+
+    #[error("block {0} body can't be decoded")]
+    UndecodableBlock(BlockHash, #[source] DecodeError),
+
+    #[error("ledger cursor {0:?} is ahead of the wal tip {1:?}, the wal needs to be rebuilt")]
+    CursorAheadOfTip(ChainPoint, Option<ChainPoint>),
+
+    #[error(
+        "ledger cursor {0:?} is behind the start of the wal {1:?}, the ledger needs to be rebuilt"
+    )]
+    CursorBehindWal(ChainPoint, ChainPoint),
+
+    #[error("storage is in use by other handles, it needs the node to be stopped")]
+    StorageInUse,
+
+    #[error("compacted blocks can't be archived")]
+    Archive(#[source] crate::querydb::store::Error),
+
     #[error("IO error")]
     IO(#[source] Box<dyn std::error::Error + Send + Sync>),
 }
 
-pub use reader::{ReadUtils, WalReader};
+/// A chunked write that failed after committing the start of the batch, see
+/// `WalStore::roll_forward_batch`
+#[derive(Debug, Error)]
+#[error("batch write failed after committing {committed} blocks")]
+pub struct BatchError {
+    /// Number of blocks at the start of the batch that are in the WAL
+    pub committed: usize,
+
+    #[source]
+    pub error: WalError,
+}
+
+pub use reader::{ChainDensity, ForkReport, ForkWeight, ReadUtils, WalReader};
 pub use stream::WalStream;
 pub use writer::WalWriter;
 
@@ -147,4 +419,79 @@ mod tests {
             ChainPoint::Specific(50, slot_to_hash(50)),
         );
     }
+
+    #[test]
+    fn decode_unsupported_era() {
+        // a block envelope tagged with an era that doesn't exist (yet)
+        let cbor = hex::decode("820980").unwrap();
+        let result = decode_block(&cbor);
+        assert!(matches!(result, Err(DecodeError::UnsupportedEra(9))));
+
+        // a known era with garbage content is reported as invalid cbor
+        let cbor = hex::decode("820680").unwrap();
+        let result = decode_block(&cbor);
+        assert!(matches!(result, Err(DecodeError::InvalidCbor(_))));
+    }
+
+    #[test]
+    fn decode_page_matches_fresh_decode() {
+        let page = vec![
+            testing::dummy_block_from_slot(1),
+            testing::test_data_block(2),
+        ];
+
+        let decoded = decode_page(&page);
+        assert_eq!(decoded.len(), page.len());
+
+        for (raw, block) in decoded {
+            let block = block.unwrap();
+            let fresh = MultiEraBlock::decode(&raw.body).unwrap();
+
+            assert_eq!(block.hash(), fresh.hash());
+            assert_eq!(block.slot(), fresh.slot());
+            assert_eq!(block.era(), fresh.era());
+            assert_eq!(block.txs().len(), fresh.txs().len());
+            assert_eq!(block.header().cbor(), fresh.header().cbor());
+        }
+
+        // invalid bodies are reported per block, not for the whole page
+        let mut corrupt = testing::dummy_block_from_slot(3);
+        corrupt.body = vec![0xff; 64];
+
+        let page = vec![testing::dummy_block_from_slot(1), corrupt];
+        let decoded = decode_page(&page);
+
+        assert!(decoded[0].1.is_ok());
+        assert!(matches!(decoded[1].1, Err(DecodeError::InvalidCbor(_))));
+    }
+
+    #[test]
+    fn block_summary_of_fixture() {
+        let raw = testing::test_data_block(10);
+        let block = raw.decode().unwrap();
+
+        let summary = BlockSummary::from(&block);
+
+        let expected = BlockSummary {
+            era: BlockEra::Alonzo,
+            hash: "b045c7766e16ef570d6f0a9f2ac32ab1add2d4ee92d4ec34fd3d5302f8ee84ef"
+                .parse()
+                .unwrap(),
+            prev_hash: Some(
+                "1e9d6513a8bbfbdded9ef9046f768796d1d4f45be6827dacac9a04591b991d97"
+                    .parse()
+                    .unwrap(),
+            ),
+            // the summary reads the header, not the slot the wal stores it at
+            slot: 48844559,
+            number: 3268852,
+            tx_count: 21,
+            invalid_tx_count: 0,
+            output_lovelace: 29453174350453,
+            script_tx_count: 3,
+            metadata_tx_count: 1,
+        };
+
+        assert_eq!(summary, expected);
+    }
 }
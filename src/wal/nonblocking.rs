@@ -0,0 +1,162 @@
+use super::{
+    redb::WalStore, ChainPoint, LogEntry, LogSeq, RawBlock, ReadUtils, TxFinality, TxHash,
+    WalError, WalReader,
+};
+
+/// Async facade over the WAL reads
+///
+/// Reads from the WAL hit the disk synchronously. Each method here runs the
+/// equivalent read from `WalReader` in the blocking thread pool so that
+/// async handlers don't stall the executor while waiting for storage.
+#[derive(Clone)]
+pub struct AsyncWalStore(WalStore);
+
+impl AsyncWalStore {
+    pub fn new(wal: WalStore) -> Self {
+        Self(wal)
+    }
+
+    pub fn inner(&self) -> &WalStore {
+        &self.0
+    }
+
+    async fn offload<T, F>(&self, op: F) -> Result<T, WalError>
+    where
+        F: FnOnce(WalStore) -> Result<T, WalError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let wal = self.0.clone();
+
+        tokio::task::spawn_blocking(move || op(wal))
+            .await
+            .map_err(|x| WalError::IO(x.into()))?
+    }
+
+    pub async fn find_tip(&self) -> Result<Option<(LogSeq, ChainPoint)>, WalError> {
+        self.offload(|wal| wal.find_tip()).await
+    }
+
+    pub async fn read_block(&self, point: ChainPoint) -> Result<RawBlock, WalError> {
+        self.offload(move |wal| wal.read_block(&point)).await
+    }
+
+    pub async fn get_tx(&self, hash: TxHash) -> Result<Option<Vec<u8>>, WalError> {
+        self.offload(move |wal| wal.get_tx(&hash)).await
+    }
+
+    pub async fn tx_finality(&self, hash: TxHash, k: u64) -> Result<Option<TxFinality>, WalError> {
+        self.offload(move |wal| wal.tx_finality(&hash, k)).await
+    }
+
+    pub async fn read_sparse_blocks(
+        &self,
+        points: Vec<ChainPoint>,
+    ) -> Result<Vec<RawBlock>, WalError> {
+        self.offload(move |wal| wal.read_sparse_blocks(&points))
+            .await
+    }
+
+    pub async fn read_block_range(
+        &self,
+        from: ChainPoint,
+        to: ChainPoint,
+    ) -> Result<Vec<RawBlock>, WalError> {
+        self.offload(move |wal| Ok(wal.read_block_range(&from, &to)?.collect()))
+            .await
+    }
+
+    pub async fn read_block_page(
+        &self,
+        from: Option<ChainPoint>,
+        limit: usize,
+    ) -> Result<Vec<RawBlock>, WalError> {
+        self.offload(move |wal| Ok(wal.read_block_page(from.as_ref(), limit)?.collect()))
+            .await
+    }
+
+    /// Entries between `start` and `end` (both inclusive)
+    pub async fn crawl_range(&self, start: LogSeq, end: LogSeq) -> Result<Vec<LogEntry>, WalError> {
+        self.offload(move |wal| Ok(wal.crawl_range(start, end)?.collect()))
+            .await
+    }
+
+    /// Applied blocks starting at `start` (inclusive), up to `limit` items
+    pub async fn crawl_blocks_from(
+        &self,
+        start: Option<LogSeq>,
+        limit: usize,
+    ) -> Result<Vec<RawBlock>, WalError> {
+        self.offload(move |wal| {
+            let blocks = wal
+                .crawl_from(start)?
+                .filter_apply()
+                .into_blocks()
+                .flatten()
+                .take(limit)
+                .collect();
+
+            Ok(blocks)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::wal::testing;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_concurrent_reads_keep_executor_responsive() {
+        let wal = AsyncWalStore::new(testing::db_with_dummy_blocks(500));
+
+        // a task that only makes progress while the executor is free
+        let ticks = Arc::new(AtomicUsize::new(0));
+
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+
+            async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        let reads = (0..50u64).map(|i| {
+            let wal = wal.clone();
+
+            async move {
+                let from = ChainPoint::Specific(i, testing::slot_to_hash(i));
+                let to = ChainPoint::Specific(i + 400, testing::slot_to_hash(i + 400));
+
+                let range = wal.read_block_range(from, to).await.unwrap();
+                let block = wal.read_block(ChainPoint::from(&range[10])).await.unwrap();
+
+                (range.len(), block.slot)
+            }
+        });
+
+        let results = futures_util::future::join_all(reads).await;
+
+        for (i, (len, slot)) in results.into_iter().enumerate() {
+            assert_eq!(len, 401);
+            assert_eq!(slot, i as u64 + 10);
+        }
+
+        // the single executor thread kept running other tasks while the reads
+        // were waiting on the blocking pool
+        assert!(ticks.load(Ordering::Relaxed) > 0);
+
+        let (_, tip) = wal.find_tip().await.unwrap().unwrap();
+        assert_eq!(tip, ChainPoint::Specific(499, testing::slot_to_hash(499)));
+
+        ticker.abort();
+    }
+}
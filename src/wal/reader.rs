@@ -1,3 +1,5 @@
+use std::collections::{HashMap, HashSet};
+
 use super::*;
 
 pub trait ReadUtils<'a> {
@@ -27,6 +29,90 @@ where
     }
 }
 
+/// Turns a reversed crawl of the WAL into the blocks of the live chain
+///
+/// Walking backwards, an undo entry means that the matching apply (found
+/// further back) isn't part of the chain anymore, so we keep track of undone
+/// blocks and skip their applies.
+fn rev_live_blocks(iter: impl Iterator<Item = LogEntry>) -> impl Iterator<Item = RawBlock> {
+    let mut undone = HashSet::new();
+
+    iter.filter_map(move |(_, log)| match log {
+        LogValue::Undo(x) => {
+            undone.insert((x.slot, x.hash));
+            None
+        }
+        LogValue::Apply(x) if undone.remove(&(x.slot, x.hash)) => None,
+        LogValue::Apply(x) => Some(x),
+        LogValue::Mark(..) => None,
+    })
+}
+
+/// Same as `rev_live_blocks`, but only the points
+fn rev_live_points(iter: impl Iterator<Item = LogEntry>) -> impl Iterator<Item = ChainPoint> {
+    rev_live_blocks(iter).map(|x| ChainPoint::from(&x))
+}
+
+/// Where a set of reference points stops agreeing with the local chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForkReport {
+    /// Highest reference point that is also part of the local chain
+    pub agreement: Option<ChainPoint>,
+
+    /// First reference point after the agreement that isn't part of the local
+    /// chain, if any
+    pub reference: Option<ChainPoint>,
+
+    /// First local block after the agreement, the other side of the fork
+    pub local: Option<ChainPoint>,
+}
+
+/// Number of blocks over a window of slots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainDensity {
+    pub blocks: u64,
+    pub slots: u64,
+}
+
+impl ChainDensity {
+    /// Density of a set of points over the slots after `from` up to `to`
+    ///
+    /// Used for chains that aren't in the WAL, such as the reference chain of
+    /// a fork report.
+    pub fn of_points(points: &[ChainPoint], from: BlockSlot, to: BlockSlot) -> Self {
+        let blocks = points
+            .iter()
+            .filter(|x| matches!(x, ChainPoint::Specific(slot, _) if *slot > from && *slot <= to))
+            .count();
+
+        Self {
+            blocks: blocks as u64,
+            slots: to.saturating_sub(from),
+        }
+    }
+
+    /// Blocks per slot
+    pub fn density(&self) -> f64 {
+        if self.slots == 0 {
+            return 0.0;
+        }
+
+        self.blocks as f64 / self.slots as f64
+    }
+}
+
+/// A fork report along with the weight of each side of the fork
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForkWeight {
+    pub fork: ForkReport,
+
+    /// Density of the local chain after the agreement point
+    pub local: ChainDensity,
+
+    /// Density of the reference points after the agreement point
+    pub reference: ChainDensity,
+}
+
 #[trait_variant::make(Send)]
 pub trait WalReader: Clone {
     type LogIterator<'a>: DoubleEndedIterator<Item = LogEntry> + Sized + Sync + Send;
@@ -44,6 +130,32 @@ pub trait WalReader: Clone {
     /// Tries to find the WAL sequence for a chain point
     fn locate_point(&self, point: &ChainPoint) -> Result<Option<LogSeq>, WalError>;
 
+    /// Returns the block height recorded for a WAL entry
+    ///
+    /// Applies and undos carry the height of their block, marks carry the
+    /// height of the tip they point to. Entries written before heights were
+    /// tracked have none.
+    fn entry_height(&self, seq: LogSeq) -> Result<Option<BlockHeight>, WalError>;
+
+    /// Returns the block height of a chain point, if known
+    fn read_block_height(&self, point: &ChainPoint) -> Result<Option<BlockHeight>, WalError> {
+        match self.locate_point(point)? {
+            Some(seq) => self.entry_height(seq),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the block height of the tip of the chain, if known
+    fn find_tip_height(&self) -> Result<Option<BlockHeight>, WalError> {
+        let Some((seq, log)) = self.crawl_from(None)?.next_back() else {
+            return Ok(None);
+        };
+
+        let height = self.entry_height(seq)?;
+
+        Ok(tip_height_after(&log, height))
+    }
+
     /// Asserts that a chain point exists in the WAL and returns the sequence
     ///
     /// Similar to `locate_point` but it expects a point to be found or
@@ -53,6 +165,44 @@ pub trait WalReader: Clone {
             .ok_or(WalError::PointNotFound(point.clone()))
     }
 
+    /// Locates the ledger cursor in the WAL and returns the sequence
+    ///
+    /// Same as `assert_point`, but tells apart a cursor that is past the tip
+    /// of the WAL. The ledger is only ever fed from the WAL, so that state
+    /// means that the WAL lost entries (corruption, or trimmed too far) and
+    /// can't be used to move the ledger forward. A cursor before the start of
+    /// the WAL is told apart as well: the entries that the ledger still needs
+    /// were trimmed.
+    fn assert_cursor(&self, cursor: &ChainPoint) -> Result<LogSeq, WalError> {
+        if let Some(seq) = self.locate_point(cursor)? {
+            return Ok(seq);
+        }
+
+        let ChainPoint::Specific(slot, _) = cursor else {
+            return Err(WalError::PointNotFound(cursor.clone()));
+        };
+
+        let tip = self.find_tip()?.map(|(_, x)| x);
+
+        match &tip {
+            Some(ChainPoint::Specific(tip_slot, _)) if tip_slot >= slot => {
+                let oldest = self
+                    .crawl_from(None)?
+                    .filter_forward()
+                    .map(|(_, x)| ChainPoint::from(&x))
+                    .next();
+
+                match oldest {
+                    Some(x @ ChainPoint::Specific(oldest_slot, _)) if oldest_slot > *slot => {
+                        Err(WalError::CursorBehindWal(cursor.clone(), x))
+                    }
+                    _ => Err(WalError::PointNotFound(cursor.clone())),
+                }
+            }
+            _ => Err(WalError::CursorAheadOfTip(cursor.clone(), tip)),
+        }
+    }
+
     fn find_tip(&self) -> Result<Option<(LogSeq, ChainPoint)>, WalError> {
         let tip = self
             .crawl_from(None)?
@@ -87,6 +237,33 @@ pub trait WalReader: Clone {
         Ok(out)
     }
 
+    /// Samples points of the live chain that are still present in the WAL
+    ///
+    /// Walking back from the tip, every `step`-th block is returned, up to
+    /// `max_items` points. Since these are taken from the WAL itself, they're
+    /// valid intersect candidates for clients that don't know where to start
+    /// (unlike points older than the start of the WAL).
+    fn known_points(&self, step: usize, max_items: usize) -> Result<Vec<ChainPoint>, WalError> {
+        let points = rev_live_points(self.crawl_from(None)?.rev())
+            .step_by(step.max(1))
+            .take(max_items)
+            .collect();
+
+        Ok(points)
+    }
+
+    /// Returns up to `n` blocks of the live chain, walking back from the tip
+    ///
+    /// Blocks come newest-first. The walk stops at the start of the WAL, so
+    /// fewer blocks are returned if it doesn't hold that many.
+    fn recent_live_blocks(&self, n: usize) -> Result<Vec<RawBlock>, WalError> {
+        let blocks = rev_live_blocks(self.crawl_from(None)?.rev())
+            .take(n)
+            .collect();
+
+        Ok(blocks)
+    }
+
     fn find_intersect(
         &self,
         intersect: &[ChainPoint],
@@ -100,6 +277,155 @@ pub trait WalReader: Clone {
         Ok(None)
     }
 
+    /// Compares reference points (eg: from another node) against the chain
+    ///
+    /// Finds the highest reference point where both chains agree and the
+    /// first one where they diverge. Reference points above the local tip
+    /// can't be checked and are ignored. This is a read-only walk of the WAL.
+    fn find_fork(&self, reference: &[ChainPoint]) -> Result<ForkReport, WalError> {
+        let mut reference: Vec<_> = reference
+            .iter()
+            .filter_map(|x| match x {
+                ChainPoint::Specific(slot, hash) => Some((*slot, *hash)),
+                ChainPoint::Origin => None,
+            })
+            .collect();
+
+        reference.sort_by_key(|(slot, _)| *slot);
+
+        let Some(min_slot) = reference.first().map(|(slot, _)| *slot) else {
+            return Ok(ForkReport {
+                agreement: None,
+                reference: None,
+                local: None,
+            });
+        };
+
+        let wanted: HashSet<_> = reference.iter().map(|(slot, _)| *slot).collect();
+
+        let mut local_tip = None;
+        let mut local = HashMap::new();
+
+        for point in rev_live_points(self.crawl_from(None)?.rev()) {
+            let ChainPoint::Specific(slot, hash) = point else {
+                continue;
+            };
+
+            local_tip = local_tip.or(Some(slot));
+
+            if slot < min_slot {
+                break;
+            }
+
+            if wanted.contains(&slot) {
+                local.insert(slot, hash);
+            }
+        }
+
+        let mut agreement = None;
+        let mut diverging = None;
+
+        let checkable = reference
+            .into_iter()
+            .filter(|(x, _)| local_tip.is_some_and(|tip| *x <= tip));
+
+        for (slot, hash) in checkable {
+            if local.get(&slot) == Some(&hash) {
+                agreement = Some((slot, hash));
+            } else {
+                diverging = Some((slot, hash));
+                break;
+            }
+        }
+
+        // the other side of the fork is the first local block after the agreement
+        let local = match diverging {
+            Some(_) => {
+                let after = agreement.map(|(slot, _)| slot);
+
+                rev_live_points(self.crawl_from(None)?.rev())
+                    .take_while(|x| match (x, after) {
+                        (ChainPoint::Specific(slot, _), Some(after)) => *slot > after,
+                        (ChainPoint::Specific(..), None) => true,
+                        (ChainPoint::Origin, _) => false,
+                    })
+                    .last()
+            }
+            None => None,
+        };
+
+        Ok(ForkReport {
+            agreement: agreement.map(|(slot, hash)| ChainPoint::Specific(slot, hash)),
+            reference: diverging.map(|(slot, hash)| ChainPoint::Specific(slot, hash)),
+            local,
+        })
+    }
+
+    /// Counts the blocks of the live chain over the slots after `from` up to
+    /// `to`
+    ///
+    /// Undone blocks aren't part of the chain, so they don't add up to the
+    /// count. Blocks trimmed from the WAL can't be counted either.
+    fn chain_density(&self, from: BlockSlot, to: BlockSlot) -> Result<ChainDensity, WalError> {
+        let mut blocks = 0;
+
+        for point in rev_live_points(self.crawl_from(None)?.rev()) {
+            let ChainPoint::Specific(slot, _) = point else {
+                continue;
+            };
+
+            if slot <= from {
+                break;
+            }
+
+            if slot <= to {
+                blocks += 1;
+            }
+        }
+
+        Ok(ChainDensity {
+            blocks,
+            slots: to.saturating_sub(from),
+        })
+    }
+
+    /// Finds where the reference points fork from the local chain and
+    /// compares the density of both sides after the fork
+    ///
+    /// Both sides are measured over the same window, from the agreement point
+    /// (or slot zero, if there's none) up to the highest slot of either side.
+    /// The denser side is the one that the chain selection would favor.
+    fn fork_weight(&self, reference: &[ChainPoint]) -> Result<ForkWeight, WalError> {
+        let fork = self.find_fork(reference)?;
+
+        let from = match &fork.agreement {
+            Some(ChainPoint::Specific(slot, _)) => *slot,
+            _ => 0,
+        };
+
+        let local_tip = match self.find_tip()? {
+            Some((_, ChainPoint::Specific(slot, _))) => slot,
+            _ => 0,
+        };
+
+        let reference_tip = reference
+            .iter()
+            .filter_map(|x| match x {
+                ChainPoint::Specific(slot, _) => Some(*slot),
+                ChainPoint::Origin => None,
+            })
+            .max()
+            .unwrap_or_default();
+
+        let to = local_tip.max(reference_tip);
+
+        Ok(ForkWeight {
+            local: self.chain_density(from, to)?,
+            reference: ChainDensity::of_points(reference, from, to),
+            fork,
+        })
+    }
+
     fn read_block_range<'a>(
         &'a self,
         from: &ChainPoint,
@@ -152,3 +478,230 @@ pub trait WalReader: Clone {
         points.iter().map(|p| self.read_block(p)).try_collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::{testing, WalWriter as _};
+
+    fn forked_block(slot: u64) -> RawBlock {
+        RawBlock {
+            hash: testing::slot_to_hash(slot + 1000),
+            ..testing::dummy_block_from_slot(slot)
+        }
+    }
+
+    #[test]
+    fn test_find_fork_shared_prefix() {
+        let mut db = testing::db_with_dummy_blocks(5);
+
+        // undo a few blocks to check that the walk follows the live chain
+        let rollback_to = ChainPoint::Specific(2, testing::slot_to_hash(2));
+        db.roll_back(&rollback_to).unwrap();
+
+        db.roll_forward((3..10).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        // the reference chain shares slots 0..=5 and then forks
+        let reference: Vec<_> = (0..=5)
+            .map(testing::dummy_block_from_slot)
+            .chain((6..=12).map(forked_block))
+            .map(|x| ChainPoint::from(&x))
+            .collect();
+
+        let report = db.find_fork(&reference).unwrap();
+
+        assert_eq!(
+            report.agreement,
+            Some(ChainPoint::Specific(5, testing::slot_to_hash(5)))
+        );
+
+        assert_eq!(
+            report.reference,
+            Some(ChainPoint::Specific(6, testing::slot_to_hash(1006)))
+        );
+
+        assert_eq!(
+            report.local,
+            Some(ChainPoint::Specific(6, testing::slot_to_hash(6)))
+        );
+    }
+
+    #[test]
+    fn test_known_points_intersect() {
+        let mut db = testing::db_with_dummy_blocks(100);
+
+        let rollback_to = ChainPoint::Specific(80, testing::slot_to_hash(80));
+        db.roll_back(&rollback_to).unwrap();
+
+        db.roll_forward((81..120).map(forked_block)).unwrap();
+
+        // the start of the wal is trimmed, those points can't be intersected
+        db.remove_range(None, Some(30)).unwrap();
+
+        let points = db.known_points(10, 100).unwrap();
+
+        let slots: Vec<_> = points
+            .iter()
+            .map(|x| match x {
+                ChainPoint::Specific(slot, _) => *slot,
+                ChainPoint::Origin => panic!("origin isn't a block"),
+            })
+            .collect();
+
+        let expected: Vec<_> = (30..120).rev().step_by(10).collect();
+        assert_eq!(slots, expected);
+
+        for point in points {
+            let found = db.find_intersect(std::slice::from_ref(&point)).unwrap();
+            assert_eq!(found.map(|(_, x)| x), Some(point));
+        }
+
+        // undone blocks aren't part of the sample
+        assert_eq!(
+            db.known_points(1, 1).unwrap(),
+            vec![ChainPoint::Specific(119, testing::slot_to_hash(1119))]
+        );
+    }
+
+    #[test]
+    fn test_find_fork_no_divergence() {
+        let db = testing::db_with_dummy_blocks(10);
+
+        // points beyond the local tip can't be checked
+        let reference: Vec<_> = (5..15)
+            .map(testing::dummy_block_from_slot)
+            .map(|x| ChainPoint::from(&x))
+            .collect();
+
+        let report = db.find_fork(&reference).unwrap();
+
+        assert_eq!(
+            report.agreement,
+            Some(ChainPoint::Specific(9, testing::slot_to_hash(9)))
+        );
+        assert_eq!(report.reference, None);
+        assert_eq!(report.local, None);
+    }
+
+    #[test]
+    fn test_cursor_ahead_of_tip() {
+        let mut db = testing::db_with_dummy_blocks(10);
+
+        let cursor = ChainPoint::Specific(5, testing::slot_to_hash(5));
+        assert_eq!(db.assert_cursor(&cursor).unwrap(), 6);
+
+        // a point missing from within the wal range is just not found
+        db.remove_range(Some(8), Some(8)).unwrap();
+
+        let missing = ChainPoint::Specific(7, testing::slot_to_hash(7));
+        assert!(matches!(
+            db.assert_cursor(&missing),
+            Err(WalError::PointNotFound(_))
+        ));
+
+        // a ledger that applied blocks trimmed from the end of the wal
+        db.remove_range(Some(10), None).unwrap();
+
+        let ahead = ChainPoint::Specific(9, testing::slot_to_hash(9));
+        let tip = ChainPoint::Specific(8, testing::slot_to_hash(8));
+
+        match db.assert_cursor(&ahead) {
+            Err(WalError::CursorAheadOfTip(x, y)) => {
+                assert_eq!(x, ahead);
+                assert_eq!(y, Some(tip));
+            }
+            x => panic!("expected cursor ahead of tip, got {x:?}"),
+        }
+
+        // a ledger that fell behind the start of the wal
+        db.remove_range(None, Some(3)).unwrap();
+
+        let behind = ChainPoint::Specific(1, testing::slot_to_hash(1));
+
+        assert!(matches!(
+            db.assert_cursor(&behind),
+            Err(WalError::CursorBehindWal(_, ChainPoint::Specific(3, _)))
+        ));
+
+        // an empty wal is behind any block
+        let empty = testing::empty_db();
+
+        assert!(matches!(
+            empty.assert_cursor(&cursor),
+            Err(WalError::CursorAheadOfTip(_, Some(ChainPoint::Origin)))
+        ));
+    }
+
+    #[test]
+    fn test_chain_density() {
+        let mut dense = testing::db_with_dummy_blocks(20);
+
+        let mut sparse = testing::empty_db();
+        sparse
+            .roll_forward((0..20).step_by(4).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        let density = dense.chain_density(0, 20).unwrap();
+        assert_eq!(
+            density,
+            ChainDensity {
+                blocks: 19,
+                slots: 20
+            }
+        );
+
+        let density = sparse.chain_density(0, 20).unwrap();
+        assert_eq!(
+            density,
+            ChainDensity {
+                blocks: 4,
+                slots: 20
+            }
+        );
+        assert_eq!(density.density(), 0.2);
+
+        // undone blocks don't count
+        dense
+            .roll_back(&ChainPoint::Specific(9, testing::slot_to_hash(9)))
+            .unwrap();
+
+        let density = dense.chain_density(0, 20).unwrap();
+        assert_eq!(density.blocks, 9);
+    }
+
+    #[test]
+    fn test_fork_weight() {
+        let db = testing::db_with_dummy_blocks(20);
+
+        // a reference chain that forks after slot 9 and is sparser than ours
+        let reference: Vec<_> = (0..10)
+            .map(testing::dummy_block_from_slot)
+            .chain([12, 15, 18].into_iter().map(forked_block))
+            .map(|x| ChainPoint::from(&x))
+            .collect();
+
+        let weight = db.fork_weight(&reference).unwrap();
+
+        assert_eq!(
+            weight.fork.agreement,
+            Some(ChainPoint::Specific(9, testing::slot_to_hash(9)))
+        );
+
+        assert_eq!(
+            weight.local,
+            ChainDensity {
+                blocks: 10,
+                slots: 10
+            }
+        );
+        assert_eq!(
+            weight.reference,
+            ChainDensity {
+                blocks: 3,
+                slots: 10
+            }
+        );
+        assert!(weight.local.density() > weight.reference.density());
+    }
+}
@@ -1,11 +1,27 @@
 use bincode;
 use itertools::Itertools;
 use log::info;
-use redb::{Range, ReadableTable, TableDefinition};
-use std::{path::Path, sync::Arc};
+use redb::{
+    Range, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle, WriteTransaction,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Write},
+    ops::Bound,
+    path::Path,
+    sync::{Arc, RwLock},
+};
 use tracing::warn;
 
-use super::{ChainPoint, LogEntry, LogSeq, LogValue, RawBlock, WalError, WalReader, WalWriter};
+use super::bloom::BloomFilter;
+use super::tee::Tee;
+use super::{
+    tip_height_after, BatchError, BlockHash, BlockHeight, BlockSlot, ChainPoint, IndexKind,
+    InvalidBlock, LogEntry, LogSeq, LogValue, RawBlock, ReadUtils, Rollback, TxFinality, TxHash,
+    WalError, WalReader, WalWriter,
+};
+use crate::querydb::store::Store as Archive;
 
 impl redb::Value for LogValue {
     type SelfType<'a> = Self;
@@ -35,10 +51,296 @@ impl redb::Value for LogValue {
     }
 }
 
+/// Borrowed view of a WAL entry, to look at a block without copying its body
+///
+/// Mirrors the serialized layout of `LogValue`, so entries can be read either
+/// way. Reads through `WAL_VIEW` get these instead of full entries.
+#[derive(Debug, Serialize, Deserialize)]
+enum LogView<'a> {
+    Apply(#[serde(borrow)] BlockView<'a>),
+    Undo(#[serde(borrow)] BlockView<'a>),
+    Mark(ChainPoint),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockView<'a> {
+    slot: BlockSlot,
+    hash: BlockHash,
+    era: super::BlockEra,
+    body: &'a [u8],
+}
+
+impl BlockView<'_> {
+    fn to_raw(&self) -> RawBlock {
+        RawBlock {
+            slot: self.slot,
+            hash: self.hash,
+            era: self.era,
+            body: self.body.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LogViewValue;
+
+impl redb::Value for LogViewValue {
+    type SelfType<'a> = LogView<'a>;
+    type AsBytes<'a> = Vec<u8> where Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> LogView<'a>
+    where
+        Self: 'a,
+    {
+        bincode::deserialize(data).unwrap()
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a LogView<'b>) -> Vec<u8>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        bincode::serialize(value).unwrap()
+    }
+
+    // same as `LogValue`, it's another way to read the same table
+    fn type_name() -> redb::TypeName {
+        redb::TypeName::new("logvalue")
+    }
+}
+
+/// A block left out of a page for having a body over the size limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OversizedBlock {
+    pub slot: BlockSlot,
+    pub hash: BlockHash,
+    pub size: usize,
+}
+
 pub type AugmentedBlockSlot = i128;
 
 const WAL: TableDefinition<LogSeq, LogValue> = TableDefinition::new("wal");
+const WAL_VIEW: TableDefinition<LogSeq, LogViewValue> = TableDefinition::new("wal");
 const POS: TableDefinition<AugmentedBlockSlot, LogSeq> = TableDefinition::new("pos");
+const HEIGHT: TableDefinition<LogSeq, BlockHeight> = TableDefinition::new("height");
+const HASH: TableDefinition<&[u8], LogSeq> = TableDefinition::new("hash");
+
+// tx hash -> (sequence of the apply entry, index of the tx in the block)
+const TX: TableDefinition<&[u8], (LogSeq, u32)> = TableDefinition::new("tx");
+
+// sequence of the apply entry -> blake2b-256 of the block body
+const CHECKSUM: TableDefinition<LogSeq, &[u8]> = TableDefinition::new("checksum");
+
+// sparse reads use a single scan when the WAL entries between the requested
+// points are at most this many times the number of points
+const SPARSE_SCAN_MAX_SPREAD: u64 = 4;
+
+/// Entries that a sparse read scans to reach all of the sequences
+///
+/// `None` when they're spread too far apart (or there are none), then each
+/// block is read on its own instead.
+fn sparse_scan_range(seqs: &[LogSeq]) -> Option<(LogSeq, LogSeq)> {
+    let first = *seqs.iter().min()?;
+    let last = *seqs.iter().max()?;
+
+    let spread = last - first + 1;
+
+    (spread <= seqs.len() as u64 * SPARSE_SCAN_MAX_SPREAD).then_some((first, last))
+}
+
+fn body_checksum(block: &RawBlock) -> super::BlockHash {
+    pallas::crypto::hash::Hasher::<256>::hash(&block.body)
+}
+
+fn checksum_block(
+    checksums: &mut redb::Table<LogSeq, &'static [u8]>,
+    seq: LogSeq,
+    log: &LogValue,
+) -> Result<(), WalError> {
+    if let LogValue::Apply(block) = log {
+        checksums.insert(seq, &body_checksum(block)[..])?;
+    }
+
+    Ok(())
+}
+
+fn decode_height(block: &RawBlock) -> Option<BlockHeight> {
+    block.decode().ok().map(|x| x.number())
+}
+
+/// Computes the height to record for a new entry, given the tip height
+///
+/// Heights are tracked by counting blocks on top of the previous tip. When
+/// the tip height isn't known (eg: right after origin or a bootstrap) we
+/// anchor the count using the block number from the block header.
+fn next_entry_height(log: &LogValue, tip: Option<BlockHeight>) -> Option<BlockHeight> {
+    match log {
+        LogValue::Apply(block) => tip.map(|x| x + 1).or_else(|| decode_height(block)),
+        LogValue::Undo(block) => tip.or_else(|| decode_height(block)),
+        LogValue::Mark(_) => tip,
+    }
+}
+
+fn last_tip_height(
+    wal: &impl ReadableTable<LogSeq, LogValue>,
+    heights: &impl ReadableTable<LogSeq, BlockHeight>,
+) -> Result<Option<BlockHeight>, WalError> {
+    let Some((seq, log)) = wal.last()? else {
+        return Ok(None);
+    };
+
+    let height = heights.get(seq.value())?.map(|x| x.value());
+
+    Ok(tip_height_after(&log.value(), height))
+}
+
+/// Keeps the hash index in sync with the blocks applied to the chain
+///
+/// A block hash can only be applied at a single slot. Applying a hash that
+/// is still indexed (ie: not undone) at a different slot is rejected, since
+/// it means the upstream is sending inconsistent data.
+fn index_block_hash(
+    wal: &impl ReadableTable<LogSeq, LogValue>,
+    hashes: &mut redb::Table<&'static [u8], LogSeq>,
+    seq: LogSeq,
+    log: &LogValue,
+) -> Result<(), WalError> {
+    match log {
+        LogValue::Apply(block) => {
+            let existing = match hashes.get(&block.hash[..])? {
+                Some(x) => wal.get(x.value())?.map(|x| x.value()),
+                None => None,
+            };
+
+            if let Some(LogValue::Apply(existing)) = existing {
+                if existing.slot != block.slot {
+                    return Err(WalError::DuplicateBlockHash(
+                        block.hash,
+                        existing.slot,
+                        block.slot,
+                    ));
+                }
+            }
+
+            hashes.insert(&block.hash[..], seq)?;
+        }
+        LogValue::Undo(block) => {
+            hashes.remove(&block.hash[..])?;
+        }
+        LogValue::Mark(_) => (),
+    }
+
+    Ok(())
+}
+
+/// Hashes of the txs in a block, empty if the block can't be decoded
+fn block_tx_hashes(block: &RawBlock) -> Vec<TxHash> {
+    match block.decode() {
+        Ok(x) => x.txs().iter().map(|tx| tx.hash()).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Keeps the tx index in sync with the blocks applied to the chain
+///
+/// Txs of an undone block are removed, so only txs that are part of the
+/// current chain can be looked up. The hashes of an applied block are taken
+/// from `known` when the writer already has them, otherwise the block is
+/// decoded to find them.
+///
+/// Has to run before `index_block_hash`, stripped undos (see
+/// `WalStore::strip_undo_bodies`) don't carry the txs anymore and they're
+/// taken from the entry that applied the block, found through the hash index.
+fn index_block_txs(
+    wal: &impl ReadableTable<LogSeq, LogValue>,
+    hashes: &impl ReadableTable<&'static [u8], LogSeq>,
+    txs: &mut redb::Table<&'static [u8], (LogSeq, u32)>,
+    seq: LogSeq,
+    log: &LogValue,
+    known: Option<&[TxHash]>,
+) -> Result<(), WalError> {
+    match log {
+        LogValue::Apply(block) => {
+            let decoded;
+
+            let hashes = match known {
+                Some(x) => x,
+                None => {
+                    decoded = block_tx_hashes(block);
+                    &decoded
+                }
+            };
+
+            for (idx, hash) in hashes.iter().enumerate() {
+                txs.insert(&hash[..], (seq, idx as u32))?;
+            }
+        }
+        LogValue::Undo(block) if block.body.is_empty() => {
+            let applied = match hashes.get(&block.hash[..])? {
+                Some(x) => wal.get(x.value())?.map(|x| x.value()),
+                None => None,
+            };
+
+            if let Some(LogValue::Apply(applied)) = applied {
+                for hash in block_tx_hashes(&applied) {
+                    txs.remove(&hash[..])?;
+                }
+            }
+        }
+        LogValue::Undo(block) => {
+            for hash in block_tx_hashes(block) {
+                txs.remove(&hash[..])?;
+            }
+        }
+        LogValue::Mark(_) => (),
+    }
+
+    Ok(())
+}
+
+fn log_to_hash(log: &LogValue) -> Option<&super::BlockHash> {
+    match log {
+        LogValue::Apply(RawBlock { hash, .. }) => Some(hash),
+        LogValue::Undo(RawBlock { hash, .. }) => Some(hash),
+        LogValue::Mark(ChainPoint::Specific(_, hash)) => Some(hash),
+        LogValue::Mark(ChainPoint::Origin) => None,
+    }
+}
+
+/// Point that becomes the tip after the entry, undos don't define one
+fn log_to_tip(log: &LogValue) -> Option<ChainPoint> {
+    match log {
+        LogValue::Apply(_) | LogValue::Mark(_) => Some(log.into()),
+        LogValue::Undo(_) => None,
+    }
+}
+
+/// Hash that the next applied block has to point to, if known
+fn log_to_linked_hash(log: &LogValue) -> Option<BlockHash> {
+    match log_to_tip(log) {
+        Some(ChainPoint::Specific(_, hash)) => Some(hash),
+        _ => None,
+    }
+}
+
+fn check_continuity(log: &LogValue, tip: Option<&BlockHash>) -> Result<(), WalError> {
+    let (LogValue::Apply(block), Some(tip)) = (log, tip) else {
+        return Ok(());
+    };
+
+    let prev = block.decode().ok().and_then(|x| x.header().previous_hash());
+
+    match prev {
+        Some(prev) if prev != *tip => {
+            Err(WalError::NonContiguousBlock(block.hash, block.slot, *tip))
+        }
+        _ => Ok(()),
+    }
+}
 
 fn point_to_augmented_slot(point: &ChainPoint) -> AugmentedBlockSlot {
     match point {
@@ -47,6 +349,68 @@ fn point_to_augmented_slot(point: &ChainPoint) -> AugmentedBlockSlot {
     }
 }
 
+// Since we need to track Origin as part of the wal, we turn slots into signed
+// integers and treat -1 as the reference for Origin. This is not ideal from
+// disk space perspective, but good enough for this stage.
+fn log_to_augmented_slot(log: &LogValue) -> AugmentedBlockSlot {
+    match log {
+        LogValue::Apply(RawBlock { slot, .. }) => *slot as i128,
+        LogValue::Undo(RawBlock { slot, .. }) => *slot as i128,
+        LogValue::Mark(x) => point_to_augmented_slot(x),
+    }
+}
+
+/// Copies every row of a table into the same table of another db, returns the
+/// number of rows
+///
+/// Tables that were never created in the source (eg: checksums, if they
+/// weren't enabled) are skipped.
+fn copy_table<K: redb::Key + 'static, V: redb::Value + 'static>(
+    rx: &redb::ReadTransaction,
+    wx: &WriteTransaction,
+    table: TableDefinition<K, V>,
+) -> Result<u64, WalError> {
+    let source = match rx.open_table(table) {
+        Ok(x) => x,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut target = wx.open_table(table)?;
+    let mut count = 0;
+
+    for entry in source.iter()? {
+        let (key, value) = entry?;
+        target.insert(key.value(), value.value())?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+fn is_eof(err: &bincode::Error) -> bool {
+    matches!(
+        err.as_ref(),
+        bincode::ErrorKind::Io(x) if x.kind() == std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Appends an entry to a dump in the format of `WalStore::export_since`
+pub fn write_dump_entry(out: impl Write, entry: &LogEntry) -> Result<(), WalError> {
+    bincode::serialize_into(out, entry).map_err(WalError::IO)
+}
+
+/// Reads the next entry of a dump written by `WalStore::export_since`
+///
+/// `None` once the input is exhausted.
+pub fn read_dump_entry(input: impl Read) -> Result<Option<LogEntry>, WalError> {
+    match bincode::deserialize_from(input) {
+        Ok(x) => Ok(Some(x)),
+        Err(err) if is_eof(&err) => Ok(None),
+        Err(err) => Err(WalError::IO(err)),
+    }
+}
+
 pub struct WalIter<'a>(Range<'a, LogSeq, LogValue>);
 
 impl<'a> Iterator for WalIter<'a> {
@@ -78,14 +442,160 @@ where
     }
 }
 
+/// Durability of the commits performed by the WAL
+///
+/// `Eventual` skips the fsync on each commit, which speeds up ingestion
+/// considerably (eg: during initial sync) at the cost of losing the latest
+/// writes if the process crashes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    #[default]
+    Immediate,
+    Eventual,
+}
+
+impl From<Durability> for redb::Durability {
+    fn from(value: Durability) -> Self {
+        match value {
+            Durability::Immediate => redb::Durability::Immediate,
+            Durability::Eventual => redb::Durability::Eventual,
+        }
+    }
+}
+
+/// Size thresholds (in number of entries) that trigger a compaction
+///
+/// Once the WAL grows past `high_water` entries, the oldest ones are removed
+/// until `low_water` are left, bounding the WAL size directly instead of by
+/// slot distance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionPolicy {
+    pub high_water: u64,
+    pub low_water: u64,
+
+    /// Slots behind the tip for which undo entries keep the undone body
+    ///
+    /// Older bodies are stripped when the WAL gets trimmed. `None` keeps the
+    /// bodies until the entry itself is compacted away.
+    #[serde(default)]
+    pub undo_body_retention: Option<u64>,
+}
+
+/// Caps on the blocks committed by a single write of `roll_forward_batch`
+///
+/// A write transaction holds all of its pages in memory until the commit, so
+/// a huge batch spikes memory. A chunk is committed as soon as it reaches
+/// either of the limits (a single block larger than `max_bytes` still goes in
+/// a chunk of its own).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatchLimits {
+    #[serde(default)]
+    pub max_entries: Option<usize>,
+
+    #[serde(default)]
+    pub max_bytes: Option<usize>,
+}
+
+impl WriteBatchLimits {
+    fn is_full(&self, entries: usize, bytes: usize) -> bool {
+        self.max_entries.is_some_and(|x| entries >= x) || self.max_bytes.is_some_and(|x| bytes >= x)
+    }
+}
+
+/// Keeps the entries a reader hasn't reached yet from being compacted
+///
+/// Holds a single pin at the slot of the last entry seen by the reader,
+/// moving it forward with `advance` and releasing it on drop.
+pub struct SlotPin {
+    wal: WalStore,
+    slot: Option<BlockSlot>,
+}
+
+impl SlotPin {
+    /// Moves the pin to the slot of an entry the reader just went through
+    pub fn advance(&mut self, log: &LogValue) {
+        let slot = match log {
+            LogValue::Apply(RawBlock { slot, .. }) => *slot,
+            LogValue::Undo(RawBlock { slot, .. }) => *slot,
+            LogValue::Mark(ChainPoint::Specific(slot, _)) => *slot,
+            LogValue::Mark(ChainPoint::Origin) => return,
+        };
+
+        if self.slot == Some(slot) {
+            return;
+        }
+
+        self.wal.pin(slot);
+
+        if let Some(previous) = self.slot.replace(slot) {
+            self.wal.unpin(previous);
+        }
+    }
+}
+
+impl Drop for SlotPin {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            self.wal.unpin(slot);
+        }
+    }
+}
+
+/// Outcome of checking the blocks of a dump, see `WalStore::check_dump`
+#[derive(Debug, Default)]
+pub struct DumpCheck {
+    pub passed: usize,
+
+    /// Sequence and slot of each block that failed, along with the reason
+    pub failed: Vec<(LogSeq, BlockSlot, InvalidBlock)>,
+}
+
+/// Space used by one of the tables of the db
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableSize {
+    pub name: String,
+    pub entries: u64,
+
+    /// Bytes of the keys and values
+    pub stored_bytes: u64,
+
+    /// Bytes of the tree structure around the keys and values
+    pub metadata_bytes: u64,
+
+    /// Bytes allocated to the table but not in use
+    pub fragmented_bytes: u64,
+}
+
+impl TableSize {
+    pub fn total_bytes(&self) -> u64 {
+        self.stored_bytes + self.metadata_bytes + self.fragmented_bytes
+    }
+}
+
 /// Concrete implementation of WalStore using Redb
 #[derive(Clone)]
 pub struct WalStore {
     db: Arc<redb::Database>,
     tip_change: Arc<tokio::sync::Notify>,
+    tip: Arc<tokio::sync::watch::Sender<ChainPoint>>,
+    durability: Durability,
+    tee: Option<Tee>,
+    bloom: Option<Arc<RwLock<BloomFilter>>>,
+    pins: Arc<RwLock<BTreeMap<BlockSlot, usize>>>,
+    checksums: bool,
+    continuity: bool,
+    write_batch: Option<WriteBatchLimits>,
 }
 
 impl WalStore {
+    fn begin_write(&self) -> Result<WriteTransaction, WalError> {
+        let mut wx = self.db.begin_write()?;
+        wx.set_durability(self.durability.into());
+
+        Ok(wx)
+    }
+
     pub fn is_empty(&self) -> Result<bool, WalError> {
         let wr = self.db.begin_read()?;
 
@@ -100,7 +610,13 @@ impl WalStore {
         Ok(false)
     }
 
-    fn initialize(&mut self) -> Result<(), WalError> {
+    /// Writes the origin mark if the WAL doesn't have any entries yet
+    ///
+    /// The mark makes origin a regular point of the chain: it's the tip of a
+    /// brand-new WAL and a valid intersect, so readers don't need to special
+    /// case an empty db. It's a no-op on a WAL that already has entries. All of
+    /// the constructors take care of calling this.
+    pub fn initialize_origin(&mut self) -> Result<(), WalError> {
         if self.is_empty()? {
             info!("initializing wal");
             self.append_entries(std::iter::once(LogValue::Mark(ChainPoint::Origin)))?;
@@ -109,13 +625,54 @@ impl WalStore {
         Ok(())
     }
 
+    fn initialize(&mut self) -> Result<(), WalError> {
+        self.initialize_origin()?;
+
+        if let Some((_, tip)) = self.find_tip()? {
+            self.tip.send_replace(tip);
+        }
+
+        Ok(())
+    }
+
+    /// Subscribes to the tip of the chain
+    ///
+    /// The value is replaced after each committed write that moves the tip
+    /// (roll forward, roll back or import). Being a watch, a slow subscriber
+    /// only gets to see the latest tip, not every intermediate one.
+    pub fn watch_tip(&self) -> tokio::sync::watch::Receiver<ChainPoint> {
+        self.tip.subscribe()
+    }
+
     pub fn memory() -> Result<Self, WalError> {
-        let db =
-            redb::Database::builder().create_with_backend(redb::backends::InMemoryBackend::new())?;
+        Self::with_backend(
+            redb::backends::InMemoryBackend::new(),
+            Durability::default(),
+        )
+    }
+
+    /// Creates a WAL on top of a custom storage backend
+    ///
+    /// Redb abstracts the underlying storage through `StorageBackend`, so the
+    /// WAL can live anywhere a backend is implemented for (eg: memory for tests
+    /// and embedded use). The file backend is the one used by `open`.
+    pub fn with_backend(
+        backend: impl redb::StorageBackend,
+        durability: Durability,
+    ) -> Result<Self, WalError> {
+        let db = redb::Database::builder().create_with_backend(backend)?;
 
         let mut out = Self {
             db: Arc::new(db),
             tip_change: Arc::new(tokio::sync::Notify::new()),
+            tip: Arc::new(tokio::sync::watch::channel(ChainPoint::Origin).0),
+            durability,
+            tee: None,
+            bloom: None,
+            pins: Default::default(),
+            checksums: false,
+            continuity: false,
+            write_batch: None,
         };
 
         out.initialize()?;
@@ -123,14 +680,35 @@ impl WalStore {
         Ok(out)
     }
 
-    pub fn open(path: impl AsRef<Path>) -> Result<Self, WalError> {
-        let inner = redb::Database::builder()
+    /// Opens (or creates) a WAL at the given path
+    ///
+    /// The cache size is expressed in bytes, `None` uses the redb default.
+    pub fn open(
+        path: impl AsRef<Path>,
+        cache_size: Option<usize>,
+        durability: Durability,
+    ) -> Result<Self, WalError> {
+        let mut builder = redb::Database::builder();
+
+        if let Some(size) = cache_size {
+            builder.set_cache_size(size);
+        }
+
+        let inner = builder
             .set_repair_callback(|x| warn!(progress = x.progress() * 100f64, "wal db is repairing"))
             .create(path)?;
 
         let mut out = Self {
             db: Arc::new(inner),
             tip_change: Arc::new(tokio::sync::Notify::new()),
+            tip: Arc::new(tokio::sync::watch::channel(ChainPoint::Origin).0),
+            durability,
+            tee: None,
+            bloom: None,
+            pins: Default::default(),
+            checksums: false,
+            continuity: false,
+            write_batch: None,
         };
 
         out.initialize()?;
@@ -138,123 +716,3001 @@ impl WalStore {
         Ok(out)
     }
 
-    // TODO: see how to expose this method through the official write interface
-    // TODO: improve performance, this approach is immensely inefficient
-    pub fn remove_range(
-        &mut self,
-        from: Option<LogSeq>,
-        to: Option<LogSeq>,
-    ) -> Result<(), WalError> {
-        let wx = self.db.begin_write()?;
-        {
-            let mut wal = wx.open_table(WAL)?;
-
-            wal.extract_if(|seq, _| match (from, to) {
-                (None, None) => true,
-                (Some(a), Some(b)) => seq >= a && seq <= b,
-                (None, Some(x)) => seq <= x,
-                (Some(x), None) => seq >= x,
-            })?
-            .collect_vec();
-        }
-
-        {
-            let mut pos = wx.open_table(POS)?;
-
-            pos.extract_if(|_, seq| match (from, to) {
-                (None, None) => true,
-                (Some(a), Some(b)) => seq >= a && seq <= b,
-                (None, Some(x)) => seq <= x,
-                (Some(x), None) => seq >= x,
-            })?
-            .collect_vec();
+    /// Forwards every entry committed from now on to the given tee
+    ///
+    /// If `resume_from` is set, existing entries starting at that sequence
+    /// (inclusive) are replayed into the tee first, which allows a sink to
+    /// pick up where it left off after a restart. Only writes performed by
+    /// this handle (and clones created after this call) are teed.
+    pub fn set_tee(&mut self, tee: Tee, resume_from: Option<LogSeq>) -> Result<(), WalError> {
+        if let Some(seq) = resume_from {
+            for entry in self.crawl_from(Some(seq))? {
+                tee.send(entry);
+            }
         }
 
-        wx.commit()?;
+        self.tee = Some(tee);
 
         Ok(())
     }
-}
 
-impl super::WalReader for WalStore {
-    type LogIterator<'a> = WalIter<'a>;
+    /// Protects the entries of a slot (and everything after it) from compaction
+    ///
+    /// Meant for long-running reads of old blocks that would otherwise race
+    /// with a compaction. Pins are kept in memory, shared by all the clones of
+    /// this handle, and counted, so the same slot can be pinned by several
+    /// readers. Each pin must be released with `unpin`, a leaked pin blocks
+    /// the trimming of the WAL indefinitely (until the next restart).
+    pub fn pin(&self, slot: BlockSlot) {
+        *self.pins.write().unwrap().entry(slot).or_default() += 1;
+    }
 
-    async fn tip_change(&self) -> Result<(), WalError> {
-        self.tip_change.notified().await;
+    /// Releases a pin taken with `pin`
+    pub fn unpin(&self, slot: BlockSlot) {
+        let mut pins = self.pins.write().unwrap();
 
-        Ok(())
+        if let Some(count) = pins.get_mut(&slot) {
+            *count -= 1;
+
+            if *count == 0 {
+                pins.remove(&slot);
+            }
+        }
     }
 
-    fn crawl_range<'a>(
-        &self,
-        start: LogSeq,
-        end: LogSeq,
-    ) -> Result<Self::LogIterator<'a>, WalError> {
-        let rx = self.db.begin_read()?;
-        let table = rx.open_table(WAL)?;
+    /// Pin that follows a reader as it moves forward, see `SlotPin`
+    pub fn pin_reader(&self) -> SlotPin {
+        SlotPin {
+            wal: self.clone(),
+            slot: None,
+        }
+    }
 
-        let range = table.range(start..=end)?;
+    /// Stores a checksum of each block body and verifies it on block reads
+    ///
+    /// Catches bodies that got corrupted on disk before they're served, reads
+    /// of a body that doesn't match its checksum fail with `CorruptBlock`.
+    /// Only blocks written through this handle (and its clones created after
+    /// this call) get a checksum, older blocks are read without verification.
+    pub fn enable_checksums(&mut self) {
+        self.checksums = true;
+    }
 
-        Ok(WalIter(range))
+    /// Rejects applied blocks that don't build on top of the current tip
+    ///
+    /// The previous hash in the header of each new block has to match the
+    /// hash of the tip, otherwise the write fails with `NonContiguousBlock`
+    /// and nothing from the batch is written. There's nothing to check right
+    /// after origin or after an undo without its closing mark, and blocks
+    /// without a previous hash (or that don't decode) are let through.
+    pub fn enable_continuity_check(&mut self) {
+        self.continuity = true;
     }
 
-    fn crawl_from<'a>(&self, start: Option<LogSeq>) -> Result<Self::LogIterator<'a>, WalError> {
-        let rx = self.db.begin_read()?;
-        let table = rx.open_table(WAL)?;
+    /// Caps the size of each write of `roll_forward_batch`
+    pub fn set_write_batch_limits(&mut self, limits: WriteBatchLimits) {
+        self.write_batch = Some(limits);
+    }
 
-        let range = match start {
-            Some(start) => table.range(start..)?,
-            None => table.range(0..)?,
-        };
+    /// Appends a large number of blocks, committing them in chunks
+    ///
+    /// Chunks are sized by the write batch limits, without limits all of the
+    /// blocks go in a single write like `roll_forward`. Each chunk is a write
+    /// of its own, so if one fails (eg: an invalid body) the previous chunks
+    /// stay committed. Since these are only applies, whatever got committed is
+    /// a consistent WAL with a tip at the last block of the last chunk. The
+    /// error tells how many blocks that is, so that the caller can resume
+    /// right after them.
+    pub fn roll_forward_batch(
+        &mut self,
+        blocks: impl Iterator<Item = RawBlock>,
+    ) -> Result<(), BatchError> {
+        self.write_chunked(blocks.map(|x| (x, None)))
+    }
 
-        Ok(WalIter(range))
+    /// Same as `roll_forward_batch`, for blocks that the caller already decoded
+    ///
+    /// Indexing the txs of a block needs their hashes, which would mean
+    /// decoding each block a second time. The sync pipeline decodes blocks as
+    /// they arrive, so it passes the hashes of the txs along with each block.
+    pub fn roll_forward_decoded(
+        &mut self,
+        blocks: impl Iterator<Item = (RawBlock, Vec<TxHash>)>,
+    ) -> Result<(), BatchError> {
+        self.write_chunked(blocks.map(|(x, txs)| (x, Some(txs))))
     }
 
-    fn locate_point(&self, point: &super::ChainPoint) -> Result<Option<LogSeq>, WalError> {
-        let rx = self.db.begin_read()?;
-        let table = rx.open_table(POS)?;
+    fn write_chunked(
+        &mut self,
+        blocks: impl Iterator<Item = (RawBlock, Option<Vec<TxHash>>)>,
+    ) -> Result<(), BatchError> {
+        let limits = self.write_batch;
+        let mut blocks = blocks.peekable();
+        let mut committed = 0;
 
-        let pos_key = point_to_augmented_slot(point);
-        let pos = table.get(pos_key)?.map(|x| x.value());
+        while blocks.peek().is_some() {
+            let mut chunk = vec![];
+            let mut bytes = 0;
 
-        Ok(pos)
+            for block in blocks.by_ref() {
+                bytes += block.0.body.len();
+                chunk.push(block);
+
+                if limits.is_some_and(|x| x.is_full(chunk.len(), bytes)) {
+                    break;
+                }
+            }
+
+            let len = chunk.len();
+
+            self.write_blocks(chunk)
+                .map_err(|error| BatchError { committed, error })?;
+
+            committed += len;
+        }
+
+        Ok(())
     }
-}
 
-impl super::WalWriter for WalStore {
-    fn append_entries(
+    /// Same as `roll_forward`, with the tx hashes of the blocks if known
+    fn write_blocks(
         &mut self,
-        logs: impl Iterator<Item = super::LogValue>,
-    ) -> Result<(), super::WalError> {
-        let wx = self.db.begin_write()?;
+        blocks: Vec<(RawBlock, Option<Vec<TxHash>>)>,
+    ) -> Result<(), WalError> {
+        if let Some((x, _)) = blocks
+            .iter()
+            .find(|(x, _)| x.body.len() < super::MIN_BLOCK_BODY_SIZE)
+        {
+            return Err(WalError::InvalidBlockBody(x.slot, x.body.len()));
+        }
+
+        self.append_logs(blocks.into_iter().map(|(x, txs)| (LogValue::Apply(x), txs)))
+    }
+
+    fn append_logs(
+        &mut self,
+        logs: impl Iterator<Item = (LogValue, Option<Vec<TxHash>>)>,
+    ) -> Result<(), WalError> {
+        let wx = self.begin_write()?;
+        let mut committed = vec![];
+        let mut new_tip = None;
 
         {
             let mut wal = wx.open_table(WAL)?;
             let mut pos = wx.open_table(POS)?;
+            let mut heights = wx.open_table(HEIGHT)?;
+            let mut hashes = wx.open_table(HASH)?;
+            let mut txs = wx.open_table(TX)?;
+            let mut checksums = wx.open_table(CHECKSUM)?;
+
+            let mut tip_height = last_tip_height(&wal, &heights)?;
 
             let mut next_seq = wal.last()?.map(|(x, _)| x.value() + 1).unwrap_or_default();
 
-            for log in logs {
-                // Since we need to track Origin as part of the wal, we turn slots into signed
-                // integers and treat -1 as the reference for Origin. This is not ideal from
-                // disk space perspective, but good enough for this stage.
-                let pos_key = match &log {
-                    LogValue::Apply(RawBlock { slot, .. }) => *slot as i128,
-                    LogValue::Undo(RawBlock { slot, .. }) => *slot as i128,
-                    LogValue::Mark(x) => point_to_augmented_slot(x),
-                };
+            let mut linked = wal
+                .last()?
+                .and_then(|(_, x)| log_to_linked_hash(&x.value()));
 
-                pos.insert(pos_key, next_seq)?;
-                wal.insert(next_seq, log)?;
+            for (log, known) in logs {
+                if self.continuity {
+                    check_continuity(&log, linked.as_ref())?;
+                    linked = log_to_linked_hash(&log);
+                }
 
-                next_seq += 1;
-            }
-        }
+                let height = next_entry_height(&log, tip_height);
+
+                if let Some(height) = height {
+                    heights.insert(next_seq, height)?;
+                }
+
+                tip_height = tip_height_after(&log, height);
+
+                index_block_txs(&wal, &hashes, &mut txs, next_seq, &log, known.as_deref())?;
+                index_block_hash(&wal, &mut hashes, next_seq, &log)?;
+
+                if self.checksums {
+                    checksum_block(&mut checksums, next_seq, &log)?;
+                }
+
+                // the filter is updated ahead of the commit so that readers never
+                // miss a committed entry, an aborted write only adds false positives
+                self.add_to_bloom(std::iter::once(&log));
+
+                if let Some(tip) = log_to_tip(&log) {
+                    new_tip = Some(tip);
+                }
+
+                let pos_key = log_to_augmented_slot(&log);
+
+                pos.insert(pos_key, next_seq)?;
+                wal.insert(next_seq, &log)?;
+
+                if self.tee.is_some() {
+                    committed.push((next_seq, log));
+                }
+
+                next_seq += 1;
+            }
+        }
 
         wx.commit()?;
 
+        self.send_to_tee(committed);
+
+        if let Some(tip) = new_tip {
+            self.tip.send_replace(tip);
+        }
+
         self.tip_change.notify_waiters();
 
         Ok(())
     }
+
+    fn verify_checksum(&self, seq: LogSeq, block: &RawBlock) -> Result<(), WalError> {
+        if !self.checksums {
+            return Ok(());
+        }
+
+        let rx = self.db.begin_read()?;
+
+        let table = match rx.open_table(CHECKSUM) {
+            Ok(x) => x,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(expected) = table.get(seq)? {
+            if expected.value() != &body_checksum(block)[..] {
+                return Err(WalError::CorruptBlock(block.hash));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keeps an in-memory bloom filter of the hashes in the WAL
+    ///
+    /// Point lookups (block reads, intersections) check the filter first and
+    /// skip the db for hashes that were never written, which helps when
+    /// clients request many unknown blocks. The filter is built by scanning
+    /// every entry and sized for twice the current entries, so it degrades
+    /// (more false positives, never false negatives) as the WAL grows until
+    /// the next restart. Only writes through this handle and its clones
+    /// created after this call update the filter.
+    pub fn enable_bloom(&mut self, bits_per_key: usize) -> Result<(), WalError> {
+        let hashes: Vec<_> = self
+            .crawl_from(None)?
+            .filter_map(|(_, log)| log_to_hash(&log).cloned())
+            .collect();
+
+        let mut bloom = BloomFilter::new(hashes.len() * 2, bits_per_key);
+
+        for hash in hashes.iter() {
+            bloom.insert(hash);
+        }
+
+        info!("wal bloom filter ready, {} hashes loaded", hashes.len());
+
+        self.bloom = Some(Arc::new(RwLock::new(bloom)));
+
+        Ok(())
+    }
+
+    fn add_to_bloom<'a>(&self, logs: impl Iterator<Item = &'a LogValue>) {
+        if let Some(bloom) = &self.bloom {
+            let mut bloom = bloom.write().unwrap();
+
+            for hash in logs.filter_map(log_to_hash) {
+                bloom.insert(hash);
+            }
+        }
+    }
+
+    fn send_to_tee(&self, entries: Vec<LogEntry>) {
+        if let Some(tee) = &self.tee {
+            for entry in entries {
+                tee.send(entry);
+            }
+        }
+    }
+
+    /// Reads the latest `blocks` blocks so that they end up in the db cache
+    ///
+    /// Returns the number of blocks that were read.
+    pub fn warmup(&self, blocks: usize) -> Result<usize, WalError> {
+        let count = self
+            .crawl_from(None)?
+            .rev()
+            .filter_apply()
+            .take(blocks)
+            .count();
+
+        Ok(count)
+    }
+
+    /// Runs the `warmup` procedure in a background thread
+    ///
+    /// Meant to be called right after opening the WAL, so that the first
+    /// requests after a restart don't need to cold-read recent blocks from
+    /// disk, without blocking the startup of the node.
+    pub fn spawn_warmup(&self, blocks: usize) -> std::thread::JoinHandle<Result<usize, WalError>> {
+        let wal = self.clone();
+
+        std::thread::spawn(move || {
+            let result = wal.warmup(blocks);
+
+            match &result {
+                Ok(count) => info!("wal warmup finished, {count} blocks read"),
+                Err(err) => warn!(%err, "wal warmup failed"),
+            }
+
+            result
+        })
+    }
+
+    /// Removes the entries between `from` and `to` (both inclusive)
+    ///
+    /// Entries are keyed by sequence, so only the requested range is visited
+    /// instead of scanning the whole WAL. Positions are keyed by slot, those
+    /// are removed individually, and only while they still point at one of
+    /// the removed entries (a later entry for the same slot stays indexed).
+    // TODO: see how to expose this method through the official write interface
+    pub fn remove_range(
+        &mut self,
+        from: Option<LogSeq>,
+        to: Option<LogSeq>,
+    ) -> Result<(), WalError> {
+        let range = (
+            from.map_or(Bound::Unbounded, Bound::Included),
+            to.map_or(Bound::Unbounded, Bound::Included),
+        );
+
+        let wx = self.begin_write()?;
+
+        {
+            let mut wal = wx.open_table(WAL)?;
+            let mut pos = wx.open_table(POS)?;
+            let mut heights = wx.open_table(HEIGHT)?;
+            let mut hashes = wx.open_table(HASH)?;
+            let mut txs = wx.open_table(TX)?;
+            let mut checksums = wx.open_table(CHECKSUM)?;
+
+            let removed: Vec<(LogSeq, LogValue)> = wal
+                .extract_from_if(range, |_, _| true)?
+                .map_ok(|(seq, log)| (seq.value(), log.value()))
+                .collect::<Result<_, _>>()?;
+
+            for (seq, log) in removed {
+                let pos_key = log_to_augmented_slot(&log);
+                let current = pos.get(pos_key)?.map(|x| x.value());
+
+                if current == Some(seq) {
+                    pos.remove(pos_key)?;
+                }
+
+                if let LogValue::Apply(block) = &log {
+                    let current = hashes.get(&block.hash[..])?.map(|x| x.value());
+
+                    if current == Some(seq) {
+                        hashes.remove(&block.hash[..])?;
+                    }
+
+                    for hash in block_tx_hashes(block) {
+                        let current = txs.get(&hash[..])?.map(|x| x.value().0);
+
+                        if current == Some(seq) {
+                            txs.remove(&hash[..])?;
+                        }
+                    }
+                }
+            }
+
+            heights.retain_in(range, |_, _| false)?;
+            checksums.retain_in(range, |_, _| false)?;
+        }
+
+        wx.commit()?;
+
+        Ok(())
+    }
+
+    /// Trims the start of the WAL if it's larger than the policy allows
+    ///
+    /// Entries are removed from the start until `low_water` are left, but the
+    /// trim stops at the first entry for a slot after `max_slot` (the rollback
+    /// safety window), at the lowest pinned slot or at the tip, and never
+    /// reaches `before` (eg: the cursor of a consumer). When the policy has an
+    /// `undo_body_retention`, undo bodies past it are stripped after a trim.
+    /// The file doesn't shrink, see `compact_storage`. Returns the last removed
+    /// sequence, if any.
+    pub fn compact(
+        &mut self,
+        policy: &CompactionPolicy,
+        max_slot: BlockSlot,
+        before: LogSeq,
+    ) -> Result<Option<LogSeq>, WalError> {
+        self.compact_into(policy, max_slot, before, None)
+    }
+
+    /// Same as `compact`, but the trimmed blocks are handed to an archive
+    ///
+    /// The blocks still applied at the end of the trimmed range (the ones not
+    /// undone within it) are written to the archive before they're removed
+    /// from the WAL. If the trim fails after that, the next one writes them
+    /// again, which the archive tolerates.
+    pub fn compact_into(
+        &mut self,
+        policy: &CompactionPolicy,
+        max_slot: BlockSlot,
+        before: LogSeq,
+        archive: Option<&Archive>,
+    ) -> Result<Option<LogSeq>, WalError> {
+        let (first, last) = {
+            let mut iter = self.crawl_from(None)?;
+
+            match (iter.next(), iter.next_back()) {
+                (Some((first, _)), Some((last, _))) => (first, last),
+                _ => return Ok(None),
+            }
+        };
+
+        let len = last - first + 1;
+
+        if len <= policy.high_water {
+            return Ok(None);
+        }
+
+        // first entry to keep
+        let end = (first + len - policy.low_water.min(len)).min(before);
+
+        if end <= first {
+            return Ok(None);
+        }
+
+        let mut max_slot = max_slot as i128;
+
+        if let Some(pinned) = self.pins.read().unwrap().keys().next() {
+            max_slot = max_slot.min(*pinned as i128 - 1);
+        }
+
+        // right after a rollback, the undos sit above the new tip and a window
+        // computed from the old tip reaches past it, the tip bounds the trim
+        let tip = match self.find_tip()? {
+            Some((_, ChainPoint::Specific(slot, _))) => Some(slot),
+            _ => None,
+        };
+
+        if let Some(tip) = tip {
+            max_slot = max_slot.min(tip as i128 - 1);
+        }
+
+        let end = self
+            .crawl_range(first, end - 1)?
+            .find(|(_, log)| log_to_augmented_slot(log) > max_slot)
+            .map_or(end, |(seq, _)| seq);
+
+        if end <= first {
+            return Ok(None);
+        }
+
+        if let Some(archive) = archive {
+            self.archive_range(archive, None, Some(end - 1))?;
+        }
+
+        self.remove_range(None, Some(end - 1))?;
+
+        // stripping goes over every entry left, so it runs along with the trim
+        // instead of after every block
+        if let (Some(retention), Some(tip)) = (policy.undo_body_retention, tip) {
+            self.strip_undo_bodies(tip.saturating_sub(retention), before)?;
+        }
+
+        Ok(Some(end - 1))
+    }
+
+    /// Hands the blocks of a range over to the archive, ahead of trimming it
+    ///
+    /// The blocks that remain applied at the end of the range are written to
+    /// the archive, the ones undone within it are removed from the archive
+    /// (in case an earlier trim archived them). Meant to be called right
+    /// before `remove_range` over the same range.
+    pub fn archive_range(
+        &self,
+        archive: &Archive,
+        from: Option<LogSeq>,
+        to: Option<LogSeq>,
+    ) -> Result<(), WalError> {
+        let mut live = HashMap::new();
+        let mut undone = HashMap::new();
+
+        let entries = self
+            .crawl_from(from)?
+            .take_while(|(seq, _)| to.map_or(true, |to| *seq <= to));
+
+        for (_, log) in entries {
+            match log {
+                LogValue::Apply(block) => {
+                    undone.remove(&block.hash);
+                    live.insert(block.hash, block);
+                }
+                LogValue::Undo(block) => {
+                    live.remove(&block.hash);
+                    undone.insert(block.hash, block.slot);
+                }
+                LogValue::Mark(_) => (),
+            }
+        }
+
+        if live.is_empty() && undone.is_empty() {
+            return Ok(());
+        }
+
+        let bodies: Vec<_> = live.values().map(|block| block.body.as_slice()).collect();
+
+        let undone: Vec<_> = undone
+            .into_iter()
+            .map(|(hash, slot)| (slot, *hash))
+            .collect();
+
+        archive
+            .archive_blocks(&bodies, &undone)
+            .map_err(WalError::Archive)
+    }
+
+    /// Shrinks the db file, giving the space of removed entries back to the
+    /// file system
+    ///
+    /// Removing entries (eg: through `compact`) only frees pages for later
+    /// writes, the file keeps its size. Rewriting the file is I/O heavy and
+    /// needs exclusive access, so it fails with `StorageInUse` while other
+    /// clones of the store are alive. That's always the case in a running
+    /// node, where each service holds a clone, so the space is only given back
+    /// offline, through `dolos doctor trim-wal --compact`. Returns whether the
+    /// file was compacted.
+    pub fn compact_storage(&mut self) -> Result<bool, WalError> {
+        let db = Arc::get_mut(&mut self.db).ok_or(WalError::StorageInUse)?;
+
+        Ok(db.compact()?)
+    }
+
+    /// Copies the WAL, as of the latest commit, into a new db at `target`
+    ///
+    /// Everything is read from a single read transaction, so the copy is
+    /// consistent even if writes keep coming meanwhile (eg: to back up a
+    /// running node). The copy is a regular WAL that can be opened with
+    /// `open`. The target can't exist already.
+    pub fn snapshot(&self, target: impl AsRef<Path>) -> Result<(), WalError> {
+        let target = target.as_ref();
+
+        if target.exists() {
+            let err = std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("snapshot target {} already exists", target.display()),
+            );
+
+            return Err(WalError::IO(err.into()));
+        }
+
+        let rx = self.db.begin_read()?;
+        let db = redb::Database::create(target)?;
+        let wx = db.begin_write()?;
+
+        copy_table(&rx, &wx, WAL)?;
+        copy_table(&rx, &wx, POS)?;
+        copy_table(&rx, &wx, HEIGHT)?;
+        copy_table(&rx, &wx, HASH)?;
+        copy_table(&rx, &wx, TX)?;
+        copy_table(&rx, &wx, CHECKSUM)?;
+
+        wx.commit()?;
+
+        Ok(())
+    }
+
+    /// Drops the bodies of undone blocks at slots before `max_slot`
+    ///
+    /// Undo entries carry a copy of the undone block, the body is only needed
+    /// to serve the undo downstream or to revert it in the ledger. Stripped
+    /// entries keep the slot, hash and era of the block, the txs can still be
+    /// found through the entry that applied the block. Entries from `before`
+    /// onwards (eg: the ones the ledger hasn't reverted yet) are left as they
+    /// are. Returns the number of stripped bodies.
+    pub fn strip_undo_bodies(
+        &mut self,
+        max_slot: BlockSlot,
+        before: LogSeq,
+    ) -> Result<usize, WalError> {
+        let wx = self.begin_write()?;
+
+        let stripped = {
+            let mut wal = wx.open_table(WAL)?;
+
+            let undos: Vec<(LogSeq, RawBlock)> = wal
+                .range(..before)?
+                .map_ok(|(seq, log)| (seq.value(), log.value()))
+                .filter_map_ok(|(seq, log)| match log {
+                    LogValue::Undo(block) if block.slot < max_slot && !block.body.is_empty() => {
+                        Some((seq, block))
+                    }
+                    _ => None,
+                })
+                .collect::<Result<_, _>>()?;
+
+            let count = undos.len();
+
+            for (seq, block) in undos {
+                let block = RawBlock {
+                    body: vec![],
+                    ..block
+                };
+
+                wal.insert(seq, LogValue::Undo(block))?;
+            }
+
+            count
+        };
+
+        wx.commit()?;
+
+        Ok(stripped)
+    }
+
+    /// Drops a secondary index and re-populates it from the log entries
+    ///
+    /// Meant to recover a corrupted index without rebuilding the whole WAL.
+    pub fn rebuild_index(&mut self, which: IndexKind) -> Result<(), WalError> {
+        let wx = self.begin_write()?;
+
+        {
+            let wal = wx.open_table(WAL)?;
+
+            match which {
+                IndexKind::Position => {
+                    wx.delete_table(POS)?;
+                    let mut pos = wx.open_table(POS)?;
+
+                    for entry in wal.iter()? {
+                        let (seq, log) = entry?;
+                        pos.insert(log_to_augmented_slot(&log.value()), seq.value())?;
+                    }
+                }
+                IndexKind::Height => {
+                    wx.delete_table(HEIGHT)?;
+                    let mut heights = wx.open_table(HEIGHT)?;
+
+                    let mut tip_height = None;
+
+                    for entry in wal.iter()? {
+                        let (seq, log) = entry?;
+                        let log = log.value();
+
+                        let height = next_entry_height(&log, tip_height);
+
+                        if let Some(height) = height {
+                            heights.insert(seq.value(), height)?;
+                        }
+
+                        tip_height = tip_height_after(&log, height);
+                    }
+                }
+                IndexKind::Hash => {
+                    wx.delete_table(HASH)?;
+                    let mut hashes = wx.open_table(HASH)?;
+
+                    for entry in wal.iter()? {
+                        let (seq, log) = entry?;
+
+                        match log.value() {
+                            LogValue::Apply(block) => {
+                                hashes.insert(&block.hash[..], seq.value())?;
+                            }
+                            LogValue::Undo(block) => {
+                                hashes.remove(&block.hash[..])?;
+                            }
+                            LogValue::Mark(_) => (),
+                        }
+                    }
+                }
+                IndexKind::Tx => {
+                    wx.delete_table(TX)?;
+                    let mut txs = wx.open_table(TX)?;
+
+                    // undos can have their body stripped, so instead of replaying
+                    // them only the blocks still part of the chain are indexed
+                    let mut live = HashMap::new();
+
+                    for entry in wal.iter()? {
+                        let (seq, log) = entry?;
+
+                        match log.value() {
+                            LogValue::Apply(block) => live.insert(block.hash, seq.value()),
+                            LogValue::Undo(block) => live.remove(&block.hash),
+                            LogValue::Mark(_) => None,
+                        };
+                    }
+
+                    for seq in live.into_values() {
+                        if let Some(LogValue::Apply(block)) = wal.get(seq)?.map(|x| x.value()) {
+                            for (idx, hash) in block_tx_hashes(&block).iter().enumerate() {
+                                txs.insert(&hash[..], (seq, idx as u32))?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        wx.commit()?;
+
+        Ok(())
+    }
+
+    /// Raw cbor of a tx of the chain, looked up by its hash
+    ///
+    /// The tx is decoded from the block that contains it and re-encoded. The
+    /// body, witnesses and metadata keep their original bytes, so the hash of
+    /// the result matches, but the outer array isn't a slice of the block
+    /// (blocks store those parts in separate lists). Txs of rolled back
+    /// blocks aren't found. WALs created before the tx index
+    /// existed need a rebuild of the index to find older txs.
+    pub fn get_tx(&self, hash: &TxHash) -> Result<Option<Vec<u8>>, WalError> {
+        let rx = self.db.begin_read()?;
+
+        let txs = match rx.open_table(TX) {
+            Ok(x) => x,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let Some((seq, idx)) = txs.get(&hash[..])?.map(|x| x.value()) else {
+            return Ok(None);
+        };
+
+        let wal = rx.open_table(WAL)?;
+
+        let Some(LogValue::Apply(block)) = wal.get(seq)?.map(|x| x.value()) else {
+            return Ok(None);
+        };
+
+        let block = block.decode().map_err(|x| WalError::IO(x.into()))?;
+
+        let tx = block.txs().get(idx as usize).map(|x| x.encode());
+
+        Ok(tx)
+    }
+
+    /// Point of the block that contains a tx, looked up by the tx hash
+    ///
+    /// Unlike `get_tx`, the block isn't decoded. Txs of rolled back blocks
+    /// aren't found.
+    pub fn locate_tx(&self, hash: &TxHash) -> Result<Option<ChainPoint>, WalError> {
+        let rx = self.db.begin_read()?;
+
+        let txs = match rx.open_table(TX) {
+            Ok(x) => x,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let Some((seq, _)) = txs.get(&hash[..])?.map(|x| x.value()) else {
+            return Ok(None);
+        };
+
+        let wal = rx.open_table(WAL)?;
+
+        let Some(LogValue::Apply(block)) = wal.get(seq)?.map(|x| x.value()) else {
+            return Ok(None);
+        };
+
+        Ok(Some(ChainPoint::Specific(block.slot, block.hash)))
+    }
+
+    /// Tells how deep the block with the tx is buried under the tip
+    ///
+    /// Per the protocol, a block with `k` (the security param) or more blocks
+    /// on top can't be rolled back anymore. Unlike the confirmations tracked
+    /// by the mempool, that's final. `None` if the tx isn't in the chain or
+    /// the heights of the blocks aren't known. The block and the tip are read
+    /// from the same snapshot, so a concurrent write can't skew the depth.
+    pub fn tx_finality(&self, hash: &TxHash, k: u64) -> Result<Option<TxFinality>, WalError> {
+        let rx = self.db.begin_read()?;
+
+        let txs = match rx.open_table(TX) {
+            Ok(x) => x,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let Some((seq, _)) = txs.get(&hash[..])?.map(|x| x.value()) else {
+            return Ok(None);
+        };
+
+        let wal = rx.open_table(WAL)?;
+
+        let Some(LogValue::Apply(block)) = wal.get(seq)?.map(|x| x.value()) else {
+            return Ok(None);
+        };
+
+        // wals created before heights were tracked don't have the table
+        let heights = match rx.open_table(HEIGHT) {
+            Ok(x) => x,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let Some((tip_seq, tip_log)) = wal.last()?.map(|(k, v)| (k.value(), v.value())) else {
+            return Ok(None);
+        };
+
+        let height = heights.get(seq)?.map(|x| x.value());
+        let tip = tip_height_after(&tip_log, heights.get(tip_seq)?.map(|x| x.value()));
+
+        let (Some(height), Some(tip)) = (height, tip) else {
+            return Ok(None);
+        };
+
+        let depth = tip.saturating_sub(height);
+
+        Ok(Some(TxFinality {
+            block: ChainPoint::Specific(block.slot, block.hash),
+            depth,
+            immutable: depth >= k,
+        }))
+    }
+
+    /// Block of the chain looked up by its hash, decoded once for `f`
+    ///
+    /// The body goes through the same checksum verification as `read_block`.
+    /// `MultiEraBlock` borrows from the body, so instead of handing it out,
+    /// `f` gets to extract what it needs while the body is alive. Bodies that
+    /// don't decode surface as `UndecodableBlock`. Blocks that were rolled
+    /// back aren't found.
+    pub fn read_decoded_block<T>(
+        &self,
+        hash: &BlockHash,
+        f: impl FnOnce(&MultiEraBlock) -> T,
+    ) -> Result<Option<(RawBlock, T)>, WalError> {
+        let rx = self.db.begin_read()?;
+
+        let hashes = match rx.open_table(HASH) {
+            Ok(x) => x,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let Some(seq) = hashes.get(&hash[..])?.map(|x| x.value()) else {
+            return Ok(None);
+        };
+
+        drop(rx);
+
+        let Some(block) = self.read_block_from(seq)? else {
+            return Ok(None);
+        };
+
+        let out = match block.decode() {
+            Ok(decoded) => f(&decoded),
+            Err(err) => return Err(WalError::UndecodableBlock(block.hash, err)),
+        };
+
+        Ok(Some((block, out)))
+    }
+
+    /// Same blocks as `read_block_page`, minus the bodies over `max_size`
+    ///
+    /// Sizes are checked on the stored entries before anything gets copied out,
+    /// so a corrupt entry that claims a huge body is never loaded. Blocks over
+    /// the limit take their place in the page as an `OversizedBlock`.
+    pub fn read_block_page_within(
+        &self,
+        from: Option<&ChainPoint>,
+        limit: usize,
+        max_size: usize,
+    ) -> Result<Vec<Result<RawBlock, OversizedBlock>>, WalError> {
+        let from = from.map(|p| self.assert_point(p)).transpose()?;
+
+        let rx = self.db.begin_read()?;
+        let wal = rx.open_table(WAL_VIEW)?;
+
+        let mut page = Vec::with_capacity(limit);
+
+        for entry in wal.range(from.unwrap_or_default()..)? {
+            if page.len() >= limit {
+                break;
+            }
+
+            let (_, log) = entry?;
+
+            let LogView::Apply(block) = log.value() else {
+                continue;
+            };
+
+            if block.body.len() > max_size {
+                page.push(Err(OversizedBlock {
+                    slot: block.slot,
+                    hash: block.hash,
+                    size: block.body.len(),
+                }));
+            } else {
+                page.push(Ok(block.to_raw()));
+            }
+        }
+
+        Ok(page)
+    }
+
+    /// Tells which of the blocks are part of the chain in the WAL
+    ///
+    /// Only the hash index is read, block bodies are never loaded. With the
+    /// bloom filter enabled, hashes that were never in the WAL are ruled out
+    /// without touching the db at all. Blocks that were rolled back aren't
+    /// part of the chain anymore. Answers follow the order of `hashes`.
+    pub fn contains_blocks(&self, hashes: &[BlockHash]) -> Result<Vec<bool>, WalError> {
+        let may_exist: Vec<_> = match &self.bloom {
+            Some(bloom) => {
+                let bloom = bloom.read().unwrap();
+                hashes.iter().map(|x| bloom.may_contain(x)).collect()
+            }
+            None => vec![true; hashes.len()],
+        };
+
+        if !may_exist.contains(&true) {
+            return Ok(may_exist);
+        }
+
+        let rx = self.db.begin_read()?;
+
+        let table = match rx.open_table(HASH) {
+            Ok(x) => x,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(vec![false; hashes.len()]),
+            Err(err) => return Err(err.into()),
+        };
+
+        hashes
+            .iter()
+            .zip(may_exist)
+            .map(|(hash, may_exist)| {
+                if !may_exist {
+                    return Ok(false);
+                }
+
+                Ok(table.get(&hash[..])?.is_some())
+            })
+            .collect()
+    }
+
+    /// Oldest point that the WAL can still serve, aka: the compaction horizon
+    ///
+    /// Intersects proposed before this point can't be found anymore, clients
+    /// holding older points are better off starting from origin. Undo entries
+    /// at the start of the WAL don't count since their block isn't part of the
+    /// chain. `None` only if the WAL has no entries at all.
+    pub fn oldest_point(&self) -> Result<Option<ChainPoint>, WalError> {
+        let oldest = self
+            .crawl_from(None)?
+            .filter_forward()
+            .map(|(_, log)| ChainPoint::from(&log))
+            .next();
+
+        Ok(oldest)
+    }
+
+    /// Finds the block of the live chain at a slot
+    ///
+    /// The position index keeps the latest entry of each slot: an apply (or a
+    /// mark, once the chain went back to it) means that the block is on the
+    /// chain, an undo means that it was rolled back and nothing took its place.
+    /// `None` also for slots without a block or trimmed from the WAL.
+    pub fn point_at_slot(&self, slot: BlockSlot) -> Result<Option<ChainPoint>, WalError> {
+        let rx = self.db.begin_read()?;
+
+        let Some(seq) = rx.open_table(POS)?.get(slot as i128)?.map(|x| x.value()) else {
+            return Ok(None);
+        };
+
+        let log = rx.open_table(WAL)?.get(seq)?.map(|x| x.value());
+
+        let point = match log {
+            Some(LogValue::Apply(block)) => Some(ChainPoint::Specific(block.slot, block.hash)),
+            Some(LogValue::Mark(point @ ChainPoint::Specific(..))) => Some(point),
+            _ => None,
+        };
+
+        Ok(point)
+    }
+
+    /// Finds the highest sequence of an entry with a slot at or before `slot`
+    ///
+    /// Seeks the position index backwards from the slot. Undone slots are
+    /// skipped, the rollback that undid them wrote a mark with a lower slot and
+    /// a higher sequence. Slots past the tip give the sequence of the tip, and
+    /// the origin mark counts as a slot before any other. `None` for slots
+    /// before the first entry (eg: trimmed from the WAL).
+    pub fn seq_at_or_before_slot(&self, slot: BlockSlot) -> Result<Option<LogSeq>, WalError> {
+        let rx = self.db.begin_read()?;
+        let pos = rx.open_table(POS)?;
+        let wal = rx.open_table(WAL)?;
+
+        for entry in pos.range(..=slot as i128)?.rev() {
+            let (_, seq) = entry?;
+            let seq = seq.value();
+
+            match wal.get(seq)?.map(|x| x.value()) {
+                Some(LogValue::Undo(_)) | None => continue,
+                Some(_) => return Ok(Some(seq)),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Lists up to `limit` of the rollbacks in the WAL, the latest first
+    ///
+    /// A rollback is written as a run of undos closed by a mark of the point
+    /// where the chain went back to, marks without undos (eg: origin) aren't
+    /// rollbacks. Only what's still in the WAL is seen: rollbacks trimmed away
+    /// are gone, and one cut in half by a trim reports the undos that are left.
+    pub fn recent_rollbacks(&self, limit: usize) -> Result<Vec<Rollback>, WalError> {
+        let mut found = vec![];
+        let mut entries = self.crawl_from(None)?.rev().peekable();
+
+        while found.len() < limit {
+            let Some((seq, log)) = entries.next() else {
+                break;
+            };
+
+            let LogValue::Mark(point) = log else {
+                continue;
+            };
+
+            let mut undone = vec![];
+
+            while let Some((_, LogValue::Undo(block))) = entries.peek() {
+                undone.push(block.slot);
+                entries.next();
+            }
+
+            let (Some(lowest), Some(highest)) = (undone.iter().min(), undone.iter().max()) else {
+                continue;
+            };
+
+            found.push(Rollback {
+                seq,
+                point,
+                depth: undone.len(),
+                undone: *lowest..=*highest,
+            });
+        }
+
+        Ok(found)
+    }
+
+    /// Reports the size of each table, as of the latest commit
+    ///
+    /// Tells which of the block bodies (the `wal` table) or the indexes take
+    /// up most of the disk.
+    pub fn table_sizes(&self) -> Result<Vec<TableSize>, WalError> {
+        let rx = self.db.begin_read()?;
+
+        rx.list_tables()?
+            .map(|handle| {
+                let name = handle.name().to_string();
+                let table = rx.open_untyped_table(handle)?;
+                let stats = table.stats()?;
+
+                Ok(TableSize {
+                    name,
+                    entries: table.len()?,
+                    stored_bytes: stats.stored_bytes(),
+                    metadata_bytes: stats.metadata_bytes(),
+                    fragmented_bytes: stats.fragmented_bytes(),
+                })
+            })
+            .collect()
+    }
+
+    /// Walks the whole WAL verifying the invariants of the log structure
+    ///
+    /// Checks that sequences are contiguous, that every undo reverts the block
+    /// at the tip of the chain, that marks only point at the tip (ie: the
+    /// target of a rollback) and that the last entry defines the tip. Block
+    /// bodies aren't decoded, so it's cheap enough to run after each operation
+    /// in tests. When the start of the WAL has been trimmed, undos and marks
+    /// that reach past the first entry can't be verified and are accepted.
+    pub fn check_invariants(&self) -> Result<(), WalError> {
+        let mut chain: Vec<ChainPoint> = vec![];
+        let mut trimmed = false;
+        let mut last = None;
+
+        for (seq, log) in self.crawl_from(None)? {
+            match last {
+                None => trimmed = seq != 0,
+                Some((prev, _)) if seq != prev + 1 => {
+                    return Err(WalError::InvariantViolation(seq, "sequence gap"));
+                }
+                _ => (),
+            }
+
+            let point = ChainPoint::from(&log);
+
+            match &log {
+                LogValue::Apply(_) => chain.push(point),
+                LogValue::Undo(_) => match chain.last() {
+                    Some(tip) if *tip == point => {
+                        chain.pop();
+                    }
+                    None if trimmed => (),
+                    _ => {
+                        return Err(WalError::InvariantViolation(
+                            seq,
+                            "undo doesn't match the tip",
+                        ))
+                    }
+                },
+                LogValue::Mark(_) => match chain.last() {
+                    Some(tip) if *tip == point => (),
+                    None if trimmed || last.is_none() => chain.push(point),
+                    _ => return Err(WalError::InvariantViolation(seq, "mark isn't at the tip")),
+                },
+            }
+
+            last = Some((seq, log));
+        }
+
+        if let Some((seq, LogValue::Undo(_))) = last {
+            return Err(WalError::InvariantViolation(seq, "wal ends with an undo"));
+        }
+
+        Ok(())
+    }
+
+    /// Passes the entries between `from` and `to` (both inclusive) to the
+    /// visitor, in order
+    ///
+    /// Nothing is written to the WAL, so the same range can be replayed as
+    /// many times as needed (eg: to reprocess it with a derived index while
+    /// debugging). The whole range is read from a single snapshot, writes
+    /// that happen meanwhile aren't seen. Returns the number of visited
+    /// entries.
+    pub fn replay_range(
+        &self,
+        from: LogSeq,
+        to: LogSeq,
+        mut visitor: impl FnMut(LogSeq, &LogValue),
+    ) -> Result<usize, WalError> {
+        if from > to {
+            return Ok(0);
+        }
+
+        let mut count = 0;
+
+        for (seq, log) in self.crawl_range(from, to)? {
+            visitor(seq, &log);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Writes every WAL entry starting at `seq` (inclusive) into `out`
+    ///
+    /// Entries are written as a stream of bincode-encoded `(LogSeq, LogValue)`
+    /// tuples. Sequence numbers are preserved so that a standby WAL can
+    /// replicate them verbatim through `import`, including undos and marks.
+    /// Returns the number of exported entries.
+    pub fn export_since(&self, seq: LogSeq, mut out: impl Write) -> Result<usize, WalError> {
+        let mut count = 0;
+
+        for entry in self.crawl_from(Some(seq))? {
+            write_dump_entry(&mut out, &entry)?;
+            count += 1;
+        }
+
+        out.flush().map_err(|x| WalError::IO(x.into()))?;
+
+        Ok(count)
+    }
+
+    /// Validates each block of a dump produced by `export_since`
+    ///
+    /// Nothing is written, it's meant to run before `import` on dumps that
+    /// come from untrusted sources, so that a corrupt block can't make it into
+    /// the WAL. Undos with a stripped body (see `strip_undo_bodies`) have
+    /// nothing to check and aren't counted.
+    pub fn check_dump(mut input: impl Read) -> Result<DumpCheck, WalError> {
+        let mut check = DumpCheck::default();
+
+        while let Some((seq, log)) = read_dump_entry(&mut input)? {
+            let block = match log {
+                LogValue::Apply(x) => x,
+                LogValue::Undo(x) if !x.body.is_empty() => x,
+                _ => continue,
+            };
+
+            match block.validate() {
+                Ok(()) => check.passed += 1,
+                Err(err) => check.failed.push((seq, block.slot, err)),
+            }
+        }
+
+        Ok(check)
+    }
+
+    /// Appends entries produced by `export_since` into this WAL
+    ///
+    /// Entries that already exist with identical content are skipped, which
+    /// makes it safe to re-import overlapping segments. An entry that differs
+    /// from the one stored under the same sequence, or one that would leave a
+    /// hole in the sequence, aborts the whole import without writing anything.
+    /// Returns the number of entries appended.
+    pub fn import(&mut self, mut input: impl Read) -> Result<usize, WalError> {
+        let wx = self.begin_write()?;
+        let mut count = 0;
+        let mut committed = vec![];
+        let mut new_tip = None;
+
+        {
+            let mut wal = wx.open_table(WAL)?;
+            let mut pos = wx.open_table(POS)?;
+            let mut heights = wx.open_table(HEIGHT)?;
+            let mut hashes = wx.open_table(HASH)?;
+            let mut txs = wx.open_table(TX)?;
+            let mut checksums = wx.open_table(CHECKSUM)?;
+
+            let mut tip_height = last_tip_height(&wal, &heights)?;
+
+            let last_seq = wal.last()?.map(|(x, _)| x.value());
+            let mut next_seq = last_seq.map(|x| x + 1).unwrap_or_default();
+
+            while let Some((seq, log)) = read_dump_entry(&mut input)? {
+                if seq < next_seq {
+                    let existing = wal.get(seq)?.map(|x| x.value());
+
+                    match existing {
+                        Some(existing) if existing == log => continue,
+                        _ => return Err(WalError::SequenceConflict(seq)),
+                    }
+                }
+
+                if seq > next_seq {
+                    return Err(WalError::SequenceGap(next_seq, seq));
+                }
+
+                let height = next_entry_height(&log, tip_height);
+
+                if let Some(height) = height {
+                    heights.insert(seq, height)?;
+                }
+
+                tip_height = tip_height_after(&log, height);
+
+                index_block_txs(&wal, &hashes, &mut txs, seq, &log, None)?;
+                index_block_hash(&wal, &mut hashes, seq, &log)?;
+
+                if self.checksums {
+                    checksum_block(&mut checksums, seq, &log)?;
+                }
+
+                self.add_to_bloom(std::iter::once(&log));
+
+                if let Some(tip) = log_to_tip(&log) {
+                    new_tip = Some(tip);
+                }
+
+                pos.insert(log_to_augmented_slot(&log), seq)?;
+                wal.insert(seq, &log)?;
+
+                if self.tee.is_some() {
+                    committed.push((seq, log));
+                }
+
+                next_seq += 1;
+                count += 1;
+            }
+        }
+
+        wx.commit()?;
+
+        self.send_to_tee(committed);
+
+        if let Some(tip) = new_tip {
+            self.tip.send_replace(tip);
+        }
+
+        if count > 0 {
+            self.tip_change.notify_waiters();
+        }
+
+        Ok(count)
+    }
+}
+
+impl super::WalReader for WalStore {
+    type LogIterator<'a> = WalIter<'a>;
+
+    async fn tip_change(&self) -> Result<(), WalError> {
+        self.tip_change.notified().await;
+
+        Ok(())
+    }
+
+    fn crawl_range<'a>(
+        &self,
+        start: LogSeq,
+        end: LogSeq,
+    ) -> Result<Self::LogIterator<'a>, WalError> {
+        let rx = self.db.begin_read()?;
+        let table = rx.open_table(WAL)?;
+
+        let range = table.range(start..=end)?;
+
+        Ok(WalIter(range))
+    }
+
+    fn crawl_from<'a>(&self, start: Option<LogSeq>) -> Result<Self::LogIterator<'a>, WalError> {
+        let rx = self.db.begin_read()?;
+        let table = rx.open_table(WAL)?;
+
+        let range = match start {
+            Some(start) => table.range(start..)?,
+            None => table.range(0..)?,
+        };
+
+        Ok(WalIter(range))
+    }
+
+    fn locate_point(&self, point: &super::ChainPoint) -> Result<Option<LogSeq>, WalError> {
+        if let (Some(bloom), ChainPoint::Specific(_, hash)) = (&self.bloom, point) {
+            if !bloom.read().unwrap().may_contain(hash) {
+                return Ok(None);
+            }
+        }
+
+        let rx = self.db.begin_read()?;
+        let table = rx.open_table(POS)?;
+
+        let pos_key = point_to_augmented_slot(point);
+        let pos = table.get(pos_key)?.map(|x| x.value());
+
+        Ok(pos)
+    }
+
+    fn entry_height(&self, seq: LogSeq) -> Result<Option<BlockHeight>, WalError> {
+        let rx = self.db.begin_read()?;
+
+        // wals created before heights were tracked don't have the table
+        let table = match rx.open_table(HEIGHT) {
+            Ok(x) => x,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let height = table.get(seq)?.map(|x| x.value());
+
+        Ok(height)
+    }
+
+    fn read_block(&self, point: &ChainPoint) -> Result<RawBlock, WalError> {
+        let seq = self.assert_point(point)?;
+
+        self.read_block_from(seq)?
+            .ok_or(WalError::PointNotFound(point.clone()))
+    }
+
+    /// First block applied at or after `seq`, checked against its checksum
+    fn read_block_from(&self, seq: LogSeq) -> Result<Option<RawBlock>, WalError> {
+        let found = self
+            .crawl_from(Some(seq))?
+            .find_map(|(seq, log)| match log {
+                LogValue::Apply(x) => Some((seq, x)),
+                _ => None,
+            });
+
+        let Some((seq, block)) = found else {
+            return Ok(None);
+        };
+
+        self.verify_checksum(seq, &block)?;
+
+        Ok(Some(block))
+    }
+
+    /// Reads the blocks of the points, in the same order
+    ///
+    /// Points are located through the index first. When they're packed close
+    /// together in the WAL (eg: a client fetching a run of consecutive blocks)
+    /// the blocks come from a single scan over the entries between them,
+    /// otherwise each block is read on its own.
+    fn read_sparse_blocks(&self, points: &[ChainPoint]) -> Result<Vec<RawBlock>, WalError> {
+        let seqs: Vec<_> = points.iter().map(|x| self.assert_point(x)).try_collect()?;
+
+        let Some((first, last)) = sparse_scan_range(&seqs) else {
+            return points.iter().map(|x| self.read_block(x)).try_collect();
+        };
+
+        // same as `read_block`, each point gets the first apply at or after it
+        let mut applies = BTreeMap::new();
+
+        for (seq, log) in self.crawl_from(Some(first))? {
+            if let LogValue::Apply(block) = log {
+                applies.insert(seq, block);
+
+                if seq >= last {
+                    break;
+                }
+            }
+        }
+
+        seqs.iter()
+            .zip(points)
+            .map(|(seq, point)| {
+                let (seq, block) = applies
+                    .range(seq..)
+                    .next()
+                    .ok_or(WalError::PointNotFound(point.clone()))?;
+
+                self.verify_checksum(*seq, block)?;
+
+                Ok(block.clone())
+            })
+            .collect()
+    }
+}
+
+impl super::WalWriter for WalStore {
+    fn append_entries(
+        &mut self,
+        logs: impl Iterator<Item = super::LogValue>,
+    ) -> Result<(), super::WalError> {
+        self.append_logs(logs.map(|x| (x, None)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::testing;
+
+    #[test]
+    fn test_fresh_db_intersects_at_origin() {
+        testing::with_each_backend(|mut db| {
+            assert_eq!(db.find_tip().unwrap(), Some((0, ChainPoint::Origin)));
+
+            let intersect = db.find_intersect(&[ChainPoint::Origin]).unwrap();
+            assert_eq!(intersect, Some((0, ChainPoint::Origin)));
+
+            // origin is only written once
+            db.initialize_origin().unwrap();
+            assert_eq!(db.crawl_from(None).unwrap().count(), 1);
+            assert_eq!(*db.watch_tip().borrow(), ChainPoint::Origin);
+        });
+    }
+
+    #[test]
+    fn test_export_import_roundtrip() {
+        let mut primary = testing::db_with_dummy_blocks(20);
+
+        let rollback_to = ChainPoint::Specific(15, testing::slot_to_hash(15));
+        primary.roll_back(&rollback_to).unwrap();
+
+        let mut standby = testing::empty_db();
+
+        let mut buffer = vec![];
+        primary.export_since(0, &mut buffer).unwrap();
+
+        // the origin mark already exists in the standby, so it's skipped
+        let imported = standby.import(buffer.as_slice()).unwrap();
+        assert_eq!(imported, 25);
+
+        let expected: Vec<_> = primary.crawl_from(None).unwrap().collect();
+        let actual: Vec<_> = standby.crawl_from(None).unwrap().collect();
+        assert_eq!(expected, actual);
+
+        assert_eq!(standby.find_tip().unwrap().unwrap().1, rollback_to);
+        testing::assert_invariants(&standby);
+
+        // importing an overlapping segment is a no-op
+        let mut buffer = vec![];
+        primary.export_since(10, &mut buffer).unwrap();
+        assert_eq!(standby.import(buffer.as_slice()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_check_dump_rejects_malformed_blocks() {
+        let chain = testing::TestChainBuilder::new().extend(0..10);
+        let mut blocks = chain.blocks().to_vec();
+
+        // garbage instead of cbor
+        blocks[4].body = vec![0xff; 64];
+
+        // a valid block, but not the one described by the entry
+        blocks[7].body = chain.blocks()[8].body.clone();
+
+        let mut primary = testing::empty_db();
+        primary.roll_forward(blocks.into_iter()).unwrap();
+
+        let mut buffer = vec![];
+        primary.export_since(0, &mut buffer).unwrap();
+
+        let check = WalStore::check_dump(buffer.as_slice()).unwrap();
+        assert_eq!(check.passed, 8);
+
+        let failed: Vec<_> = check.failed.iter().map(|(_, slot, _)| *slot).collect();
+        assert_eq!(failed, vec![4, 7]);
+
+        assert!(matches!(check.failed[0].2, InvalidBlock::Decode(_)));
+        assert!(matches!(check.failed[1].2, InvalidBlock::SlotMismatch(8)));
+
+        // a clean dump passes in full
+        let mut clean = testing::empty_db();
+        clean.roll_forward(chain.blocks().iter().cloned()).unwrap();
+
+        let mut buffer = vec![];
+        clean.export_since(0, &mut buffer).unwrap();
+
+        let check = WalStore::check_dump(buffer.as_slice()).unwrap();
+        assert_eq!(check.passed, 10);
+        assert!(check.failed.is_empty());
+    }
+
+    #[test]
+    fn test_import_rejects_gaps_and_conflicts() {
+        let primary = testing::db_with_dummy_blocks(20);
+
+        let mut buffer = vec![];
+        primary.export_since(5, &mut buffer).unwrap();
+
+        let mut standby = testing::empty_db();
+        let result = standby.import(buffer.as_slice());
+        assert!(matches!(result, Err(WalError::SequenceGap(1, 5))));
+
+        // nothing should have been written by the failed import
+        assert_eq!(standby.crawl_from(None).unwrap().count(), 1);
+
+        let mut standby = testing::empty_db();
+        standby
+            .roll_forward(std::iter::once(testing::dummy_block_from_slot(99)))
+            .unwrap();
+
+        let mut buffer = vec![];
+        primary.export_since(0, &mut buffer).unwrap();
+
+        let result = standby.import(buffer.as_slice());
+        assert!(matches!(result, Err(WalError::SequenceConflict(1))));
+    }
+
+    #[test]
+    fn test_roll_forward_batch_chunks() {
+        let blocks = || (0..25).map(testing::dummy_block_from_slot);
+
+        let mut expected = testing::empty_db();
+        expected.roll_forward(blocks()).unwrap();
+
+        let mut chunked = testing::empty_db();
+        chunked.set_write_batch_limits(WriteBatchLimits {
+            max_entries: Some(10),
+            max_bytes: None,
+        });
+
+        chunked.roll_forward_batch(blocks()).unwrap();
+
+        let entries = |db: &WalStore| db.crawl_from(None).unwrap().collect::<Vec<_>>();
+        assert_eq!(entries(&chunked), entries(&expected));
+        testing::assert_invariants(&chunked);
+
+        // an invalid block only discards its own chunk, the previous ones are
+        // already committed
+        let mut invalid = testing::dummy_block_from_slot(45);
+        invalid.body.clear();
+
+        let batch = (25..45)
+            .chain(46..50)
+            .map(testing::dummy_block_from_slot)
+            .chain(std::iter::once(invalid));
+
+        let mut chunked = testing::empty_db();
+        chunked.set_write_batch_limits(WriteBatchLimits {
+            max_entries: Some(10),
+            max_bytes: None,
+        });
+
+        let batch: Vec<_> = blocks().chain(batch).collect();
+        let result = chunked.roll_forward_batch(batch.into_iter());
+        assert!(matches!(
+            result,
+            Err(BatchError {
+                committed: 40,
+                error: WalError::InvalidBlockBody(45, 0)
+            })
+        ));
+
+        let (seq, tip) = chunked.find_tip().unwrap().unwrap();
+        assert_eq!(seq, 40);
+        assert_eq!(tip, ChainPoint::Specific(39, testing::slot_to_hash(39)));
+        testing::assert_invariants(&chunked);
+
+        // a byte cap below the size of a block commits them one by one
+        let mut chunked = testing::empty_db();
+        chunked.set_write_batch_limits(WriteBatchLimits {
+            max_entries: None,
+            max_bytes: Some(1),
+        });
+
+        chunked.roll_forward_batch(blocks()).unwrap();
+        assert_eq!(entries(&chunked), entries(&expected));
+    }
+
+    #[test]
+    fn test_durability_modes_survive_reopen() {
+        for durability in [Durability::Immediate, Durability::Eventual] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("wal");
+
+            let expected: Vec<_> = {
+                let mut wal = WalStore::open(&path, Some(1024 * 1024), durability).unwrap();
+
+                let blocks = (0..10).map(testing::dummy_block_from_slot);
+                wal.roll_forward(blocks).unwrap();
+
+                wal.crawl_from(None).unwrap().collect()
+            };
+
+            let wal = WalStore::open(&path, None, Durability::default()).unwrap();
+            let actual: Vec<_> = wal.crawl_from(None).unwrap().collect();
+
+            assert_eq!(expected.len(), 11, "{durability:?}");
+            assert_eq!(expected, actual, "{durability:?}");
+        }
+    }
+
+    #[test]
+    fn test_compact_storage_shrinks_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal");
+
+        let mut wal = WalStore::open(&path, None, Durability::Immediate).unwrap();
+
+        let blocks = (0..2_000).map(testing::dummy_block_from_slot);
+        wal.roll_forward(blocks).unwrap();
+
+        wal.remove_range(None, Some(1_900)).unwrap();
+
+        let size = || std::fs::metadata(&path).unwrap().len();
+        let before = size();
+
+        // clones share the db, it can't be rewritten under them
+        let clone = wal.clone();
+        assert!(matches!(wal.compact_storage(), Err(WalError::StorageInUse)));
+        drop(clone);
+
+        assert!(wal.compact_storage().unwrap());
+        assert!(size() < before);
+
+        // nothing is lost
+        let tip = ChainPoint::Specific(1_999, testing::slot_to_hash(1_999));
+        assert_eq!(wal.find_tip().unwrap().map(|(_, x)| x), Some(tip));
+        assert_eq!(wal.crawl_from(None).unwrap().count(), 100);
+    }
+
+    #[test]
+    fn test_snapshot_keeps_entries_up_to_then() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+
+        let mut wal = testing::db_with_dummy_blocks(30);
+        wal.snapshot(&path).unwrap();
+
+        let expected: Vec<_> = wal.crawl_from(None).unwrap().collect();
+        let expected_height = wal.find_tip_height().unwrap();
+
+        // writes after the snapshot don't make it into the copy
+        wal.roll_forward((30..40).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        assert!(wal.snapshot(&path).is_err());
+
+        let snapshot = WalStore::open(&path, None, Durability::default()).unwrap();
+        testing::assert_invariants(&snapshot);
+
+        let actual: Vec<_> = snapshot.crawl_from(None).unwrap().collect();
+        assert_eq!(actual.len(), 31);
+        assert_eq!(actual, expected);
+
+        // the indexes come along
+        let tip = ChainPoint::Specific(29, testing::slot_to_hash(29));
+        assert_eq!(snapshot.find_tip().unwrap().map(|(_, x)| x), Some(tip));
+        assert_eq!(snapshot.find_tip_height().unwrap(), expected_height);
+
+        let point = ChainPoint::Specific(10, testing::slot_to_hash(10));
+        assert_eq!(snapshot.locate_point(&point).unwrap(), Some(11));
+
+        assert_eq!(wal.crawl_from(None).unwrap().count(), 41);
+    }
+
+    #[test]
+    fn test_crawl_order_across_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal");
+
+        let open = || WalStore::open(&path, None, Durability::default()).unwrap();
+
+        // each session writes its own batch, the sequences cross the one byte
+        // boundary (255 -> 256) so that a wrong key encoding would show up
+        let sessions: [&dyn Fn(&mut WalStore); 3] = [
+            &|wal: &mut WalStore| {
+                wal.roll_forward((0..200).map(testing::dummy_block_from_slot))
+                    .unwrap()
+            },
+            &|wal: &mut WalStore| {
+                let point = ChainPoint::Specific(150, testing::slot_to_hash(150));
+                wal.roll_back(&point).unwrap();
+                wal.roll_forward((151..260).map(testing::dummy_block_from_slot))
+                    .unwrap();
+            },
+            &|wal: &mut WalStore| {
+                wal.roll_forward((260..400).map(testing::dummy_block_from_slot))
+                    .unwrap()
+            },
+        ];
+
+        let mut last = None;
+
+        for session in sessions {
+            let mut wal = open();
+
+            // the reopened db picks up where the previous session left
+            if last.is_some() {
+                assert_eq!(wal.find_tip().unwrap().map(|(seq, _)| seq), last);
+            }
+
+            session(&mut wal);
+
+            // new entries continue right after the ones of the previous session
+            let first_new = wal
+                .crawl_from(None)
+                .unwrap()
+                .map(|(seq, _)| seq)
+                .find(|seq| !last.is_some_and(|x| *seq <= x));
+
+            assert_eq!(first_new, Some(last.map_or(0, |x| x + 1)));
+
+            last = wal.crawl_from(None).unwrap().last().map(|(seq, _)| seq);
+        }
+
+        let wal = open();
+
+        let forward: Vec<_> = wal.crawl_from(None).unwrap().map(|(seq, _)| seq).collect();
+        assert!(forward.len() > 256);
+        assert_eq!(forward, (0..forward.len() as u64).collect::<Vec<_>>());
+
+        let backward: Vec<_> = wal
+            .crawl_from(None)
+            .unwrap()
+            .rev()
+            .map(|(seq, _)| seq)
+            .collect();
+
+        assert!(forward.iter().rev().eq(backward.iter()));
+
+        let tip = wal.find_tip().unwrap().unwrap().1;
+        assert_eq!(tip, ChainPoint::Specific(399, testing::slot_to_hash(399)));
+        testing::assert_invariants(&wal);
+    }
+
+    #[test]
+    fn test_warmup_reads_latest_blocks() {
+        let wal = testing::db_with_dummy_blocks(20);
+
+        let count = wal.spawn_warmup(5).join().unwrap().unwrap();
+        assert_eq!(count, 5);
+
+        // can't read more than what's available
+        let count = wal.spawn_warmup(100).join().unwrap().unwrap();
+        assert_eq!(count, 20);
+    }
+
+    #[test]
+    fn test_tee_receives_committed_entries() {
+        let mut wal = testing::db_with_dummy_blocks(5);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        let sink = move |entry: &LogEntry| {
+            sender.send(entry.clone()).unwrap();
+            Ok(())
+        };
+
+        let tee = Tee::spawn(sink, 10, Default::default());
+
+        // replay the last 2 existing entries before teeing new ones
+        wal.set_tee(tee, Some(4)).unwrap();
+
+        wal.roll_forward((5..8).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        wal.roll_back(&ChainPoint::Specific(6, testing::slot_to_hash(6)))
+            .unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+
+        let teed: Vec<_> = (0..7)
+            .map(|_| receiver.recv_timeout(timeout).unwrap())
+            .collect();
+
+        let seqs: Vec<_> = teed.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, vec![4, 5, 6, 7, 8, 9, 10]);
+
+        let expected: Vec<_> = wal.crawl_from(Some(4)).unwrap().collect();
+        assert_eq!(teed, expected);
+
+        assert!(matches!(
+            teed[5].1,
+            LogValue::Undo(RawBlock { slot: 7, .. })
+        ));
+        assert!(matches!(
+            teed[6].1,
+            LogValue::Mark(ChainPoint::Specific(6, _))
+        ));
+    }
+
+    #[test]
+    fn test_block_height_across_undos() {
+        let mut wal = testing::db_with_dummy_blocks(5);
+
+        let base = wal
+            .read_block_height(&ChainPoint::Specific(0, testing::slot_to_hash(0)))
+            .unwrap()
+            .unwrap();
+
+        for slot in 1..5 {
+            let point = ChainPoint::Specific(slot, testing::slot_to_hash(slot));
+            let height = wal.read_block_height(&point).unwrap();
+            assert_eq!(height, Some(base + slot));
+        }
+
+        assert_eq!(wal.find_tip_height().unwrap(), Some(base + 4));
+
+        wal.roll_back(&ChainPoint::Specific(2, testing::slot_to_hash(2)))
+            .unwrap();
+
+        // each undo takes the tip one block back
+        let undos: Vec<_> = wal
+            .crawl_from(None)
+            .unwrap()
+            .filter(|(_, x)| matches!(x, LogValue::Undo(..)))
+            .map(|(seq, _)| wal.entry_height(seq).unwrap())
+            .collect();
+
+        assert_eq!(undos, vec![Some(base + 4), Some(base + 3)]);
+        assert_eq!(wal.find_tip_height().unwrap(), Some(base + 2));
+
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(5)))
+            .unwrap();
+
+        let point = ChainPoint::Specific(5, testing::slot_to_hash(5));
+        assert_eq!(wal.read_block_height(&point).unwrap(), Some(base + 3));
+        assert_eq!(wal.find_tip_height().unwrap(), Some(base + 3));
+    }
+
+    /// The previous implementation of `remove_range`, scanning every table
+    fn remove_range_by_scan(wal: &mut WalStore, from: Option<LogSeq>, to: Option<LogSeq>) {
+        let in_range = |seq: LogSeq| match (from, to) {
+            (None, None) => true,
+            (Some(a), Some(b)) => seq >= a && seq <= b,
+            (None, Some(x)) => seq <= x,
+            (Some(x), None) => seq >= x,
+        };
+
+        let wx = wal.begin_write().unwrap();
+
+        {
+            let mut table = wx.open_table(WAL).unwrap();
+            table.retain(|seq, _| !in_range(seq)).unwrap();
+
+            let mut table = wx.open_table(POS).unwrap();
+            table.retain(|_, seq| !in_range(seq)).unwrap();
+
+            let mut table = wx.open_table(HEIGHT).unwrap();
+            table.retain(|seq, _| !in_range(seq)).unwrap();
+        }
+
+        wx.commit().unwrap();
+    }
+
+    type TablesDump = (
+        Vec<LogEntry>,
+        Vec<(AugmentedBlockSlot, LogSeq)>,
+        Vec<(LogSeq, BlockHeight)>,
+    );
+
+    fn dump_tables(wal: &WalStore) -> TablesDump {
+        let rx = wal.db.begin_read().unwrap();
+
+        let entries = rx
+            .open_table(WAL)
+            .unwrap()
+            .iter()
+            .unwrap()
+            .map(|x| x.map(|(k, v)| (k.value(), v.value())).unwrap())
+            .collect();
+
+        let pos = rx
+            .open_table(POS)
+            .unwrap()
+            .iter()
+            .unwrap()
+            .map(|x| x.map(|(k, v)| (k.value(), v.value())).unwrap())
+            .collect();
+
+        let heights = rx
+            .open_table(HEIGHT)
+            .unwrap()
+            .iter()
+            .unwrap()
+            .map(|x| x.map(|(k, v)| (k.value(), v.value())).unwrap())
+            .collect();
+
+        (entries, pos, heights)
+    }
+
+    fn wal_with_rollbacks() -> WalStore {
+        let mut wal = testing::db_with_dummy_blocks(20);
+
+        // rolling back and forward again leaves slots indexed by later entries
+        wal.roll_back(&ChainPoint::Specific(14, testing::slot_to_hash(14)))
+            .unwrap();
+
+        wal.roll_forward((15..25).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        wal
+    }
+
+    #[test]
+    fn test_remove_range_matches_scan() {
+        let ranges = [
+            (None, Some(5)),
+            (Some(3), Some(8)),
+            (Some(10), Some(24)),
+            (Some(18), None),
+            (Some(50), None),
+            (None, None),
+        ];
+
+        for (from, to) in ranges {
+            let mut fast = wal_with_rollbacks();
+            fast.remove_range(from, to).unwrap();
+
+            let mut scan = wal_with_rollbacks();
+            remove_range_by_scan(&mut scan, from, to);
+
+            assert_eq!(dump_tables(&fast), dump_tables(&scan), "{from:?}..{to:?}");
+        }
+    }
+
+    #[test]
+    fn test_rebuild_corrupted_indexes() {
+        let mut wal = wal_with_rollbacks();
+        let expected = dump_tables(&wal);
+
+        let point = ChainPoint::Specific(16, testing::slot_to_hash(16));
+        let seq = wal.locate_point(&point).unwrap();
+
+        // corrupt both indexes: drop a position, point another one to the wrong
+        // entry and shift every height
+        let wx = wal.begin_write().unwrap();
+
+        {
+            let mut pos = wx.open_table(POS).unwrap();
+            pos.remove(16).unwrap();
+            pos.insert(3, 0).unwrap();
+
+            let mut heights = wx.open_table(HEIGHT).unwrap();
+            heights.retain(|_, _| false).unwrap();
+            heights.insert(1, 1000).unwrap();
+        }
+
+        wx.commit().unwrap();
+
+        assert_eq!(wal.locate_point(&point).unwrap(), None);
+        assert_ne!(dump_tables(&wal), expected);
+
+        wal.rebuild_index(IndexKind::Position).unwrap();
+        wal.rebuild_index(IndexKind::Height).unwrap();
+
+        assert_eq!(wal.locate_point(&point).unwrap(), seq);
+        assert_eq!(dump_tables(&wal), expected);
+    }
+
+    #[test]
+    fn test_duplicate_block_hash_at_different_slot() {
+        let mut wal = testing::db_with_dummy_blocks(10);
+        let tip = wal.find_tip().unwrap();
+
+        // same hash as the block at slot 3, but sent for slot 20
+        let mut duplicate = testing::dummy_block_from_slot(20);
+        duplicate.hash = testing::slot_to_hash(3);
+
+        let result = wal.roll_forward(std::iter::once(duplicate.clone()));
+
+        assert!(matches!(
+            result,
+            Err(WalError::DuplicateBlockHash(hash, 3, 20)) if hash == duplicate.hash
+        ));
+
+        // nothing from the rejected batch was written
+        assert_eq!(wal.find_tip().unwrap(), tip);
+
+        // the index survives a rebuild from the log entries
+        wal.rebuild_index(IndexKind::Hash).unwrap();
+        let result = wal.roll_forward(std::iter::once(duplicate.clone()));
+        assert!(matches!(
+            result,
+            Err(WalError::DuplicateBlockHash(_, 3, 20))
+        ));
+
+        // once the original block is rolled back, the hash isn't on the chain
+        // anymore
+        let point = ChainPoint::Specific(2, testing::slot_to_hash(2));
+        wal.roll_back(&point).unwrap();
+        wal.roll_forward(std::iter::once(duplicate)).unwrap();
+    }
+
+    #[test]
+    fn test_bloom_skips_missing_points() {
+        let mut wal = testing::db_with_dummy_blocks(500);
+
+        // without the filter, an unknown hash at a known slot still reaches the
+        // position index
+        let unknown = ChainPoint::Specific(10, testing::slot_to_hash(1_000_010));
+        assert_eq!(wal.locate_point(&unknown).unwrap(), Some(11));
+
+        wal.enable_bloom(10).unwrap();
+        assert_eq!(wal.locate_point(&unknown).unwrap(), None);
+
+        // no false negatives for the existing points
+        for slot in 0..500 {
+            let point = ChainPoint::Specific(slot, testing::slot_to_hash(slot));
+            assert_eq!(wal.locate_point(&point).unwrap(), Some(slot + 1));
+        }
+
+        // miss-heavy workload: count the lookups that would still hit the db
+        let bloom = wal.bloom.clone().unwrap();
+        let bloom = bloom.read().unwrap();
+
+        let reaching_db = (0..10_000)
+            .map(|x| testing::slot_to_hash(x + 1_000_000))
+            .filter(|x| bloom.may_contain(x))
+            .count();
+
+        assert!(
+            reaching_db < 100,
+            "{reaching_db} of 10000 misses reached the db"
+        );
+
+        drop(bloom);
+
+        // new writes are added to the filter
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(500)))
+            .unwrap();
+
+        let point = ChainPoint::Specific(500, testing::slot_to_hash(500));
+        assert_eq!(wal.locate_point(&point).unwrap(), Some(501));
+    }
+
+    #[test]
+    fn test_contains_blocks() {
+        let mut wal = testing::db_with_dummy_blocks(20);
+
+        let point = ChainPoint::Specific(15, testing::slot_to_hash(15));
+        wal.roll_back(&point).unwrap();
+
+        // present, absent, rolled back and present again
+        let hashes = [
+            testing::slot_to_hash(3),
+            testing::slot_to_hash(1_000),
+            testing::slot_to_hash(17),
+            testing::slot_to_hash(15),
+        ];
+
+        let expected = vec![true, false, false, true];
+
+        assert_eq!(wal.contains_blocks(&hashes).unwrap(), expected);
+
+        // the filter only rules out hashes, it doesn't change the answers
+        wal.enable_bloom(10).unwrap();
+        assert_eq!(wal.contains_blocks(&hashes).unwrap(), expected);
+
+        assert!(wal.contains_blocks(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_point_at_slot() {
+        let mut wal = testing::empty_db();
+
+        let blocks = [2, 5, 9, 12].map(testing::dummy_block_from_slot);
+        wal.roll_forward(blocks.into_iter()).unwrap();
+
+        let point = |slot| Some(ChainPoint::Specific(slot, testing::slot_to_hash(slot)));
+
+        assert_eq!(wal.point_at_slot(5).unwrap(), point(5));
+        assert_eq!(wal.point_at_slot(12).unwrap(), point(12));
+
+        // slots without a block
+        assert_eq!(wal.point_at_slot(6).unwrap(), None);
+        assert_eq!(wal.point_at_slot(100).unwrap(), None);
+
+        // the rollback target stays, the undone blocks are gone
+        wal.roll_back(&ChainPoint::Specific(5, testing::slot_to_hash(5)))
+            .unwrap();
+
+        assert_eq!(wal.point_at_slot(5).unwrap(), point(5));
+        assert_eq!(wal.point_at_slot(9).unwrap(), None);
+
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(9)))
+            .unwrap();
+
+        assert_eq!(wal.point_at_slot(9).unwrap(), point(9));
+    }
+
+    #[test]
+    fn test_seq_at_or_before_slot() {
+        let mut wal = testing::empty_db();
+
+        // slots 10, 20, 30, 40 and 50 at seqs 0 to 4
+        let blocks = [10, 20, 30, 40, 50].map(testing::dummy_block_from_slot);
+        wal.roll_forward(blocks.into_iter()).unwrap();
+
+        assert_eq!(wal.seq_at_or_before_slot(30).unwrap(), Some(2));
+        assert_eq!(wal.seq_at_or_before_slot(35).unwrap(), Some(2));
+        assert_eq!(wal.seq_at_or_before_slot(10).unwrap(), Some(0));
+
+        // before the first entry and after the tip
+        assert_eq!(wal.seq_at_or_before_slot(9).unwrap(), None);
+        assert_eq!(wal.seq_at_or_before_slot(1000).unwrap(), Some(4));
+
+        // back to 30, the undone slots lead to the mark (seq 7)
+        wal.roll_back(&ChainPoint::Specific(30, testing::slot_to_hash(30)))
+            .unwrap();
+
+        assert_eq!(wal.seq_at_or_before_slot(30).unwrap(), Some(7));
+        assert_eq!(wal.seq_at_or_before_slot(45).unwrap(), Some(7));
+        assert_eq!(wal.seq_at_or_before_slot(1000).unwrap(), Some(7));
+        assert_eq!(wal.seq_at_or_before_slot(25).unwrap(), Some(1));
+
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(35)))
+            .unwrap();
+
+        assert_eq!(wal.seq_at_or_before_slot(45).unwrap(), Some(8));
+        assert_eq!(wal.seq_at_or_before_slot(32).unwrap(), Some(7));
+
+        // trimmed entries are gone from the index
+        wal.remove_range(None, Some(1)).unwrap();
+        assert_eq!(wal.seq_at_or_before_slot(25).unwrap(), None);
+    }
+
+    #[test]
+    fn test_tip_watch_follows_transitions() {
+        let mut wal = testing::empty_db();
+        let mut tip = wal.watch_tip();
+
+        assert_eq!(*tip.borrow(), ChainPoint::Origin);
+
+        let mut seen = vec![];
+
+        for slot in 0..5 {
+            let block = testing::dummy_block_from_slot(slot);
+            wal.roll_forward(std::iter::once(block)).unwrap();
+
+            assert!(tip.has_changed().unwrap());
+            seen.push(tip.borrow_and_update().clone());
+        }
+
+        let point = ChainPoint::Specific(2, testing::slot_to_hash(2));
+        wal.roll_back(&point).unwrap();
+
+        assert!(tip.has_changed().unwrap());
+        seen.push(tip.borrow_and_update().clone());
+
+        let block = testing::dummy_block_from_slot(10);
+        wal.roll_forward(std::iter::once(block)).unwrap();
+
+        assert!(tip.has_changed().unwrap());
+        seen.push(tip.borrow_and_update().clone());
+
+        let expected: Vec<_> = [0, 1, 2, 3, 4, 2, 10]
+            .into_iter()
+            .map(|x| ChainPoint::Specific(x, testing::slot_to_hash(x)))
+            .collect();
+
+        assert_eq!(seen, expected);
+
+        // late subscribers start from the current tip
+        let late = wal.watch_tip();
+        assert_eq!(*late.borrow(), expected[6]);
+
+        testing::assert_invariants(&wal);
+    }
+
+    fn insert_raw_entry(wal: &WalStore, seq: LogSeq, log: LogValue) {
+        let wx = wal.begin_write().unwrap();
+
+        {
+            let mut table = wx.open_table(WAL).unwrap();
+            table.insert(seq, &log).unwrap();
+        }
+
+        wx.commit().unwrap();
+    }
+
+    #[test]
+    fn test_tampered_body_fails_checksum() {
+        let mut wal = testing::empty_db();
+        wal.enable_checksums();
+
+        wal.roll_forward((0..10).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        let point = ChainPoint::Specific(5, testing::slot_to_hash(5));
+        let block = wal.read_block(&point).unwrap();
+
+        let seq = wal.locate_point(&point).unwrap().unwrap();
+        let mut tampered = block.clone();
+        tampered.body[0] ^= 0xff;
+        insert_raw_entry(&wal, seq, LogValue::Apply(tampered));
+
+        let result = wal.read_block(&point);
+        assert!(matches!(result, Err(WalError::CorruptBlock(hash)) if hash == block.hash));
+
+        let result = wal.read_decoded_block(&block.hash, |_| ());
+        assert!(matches!(result, Err(WalError::CorruptBlock(hash)) if hash == block.hash));
+
+        // other blocks are still readable
+        let point = ChainPoint::Specific(6, testing::slot_to_hash(6));
+        assert!(wal.read_block(&point).is_ok());
+    }
+
+    #[test]
+    fn test_get_tx_by_hash() {
+        let mut wal = testing::db_with_dummy_blocks(10);
+        let block = testing::test_data_block(10);
+
+        let expected: Vec<_> = block
+            .decode()
+            .unwrap()
+            .txs()
+            .iter()
+            .map(|x| (x.hash(), x.encode()))
+            .collect();
+
+        wal.roll_forward(std::iter::once(block.clone())).unwrap();
+
+        // every tx of the block can be fetched by hash
+        for (hash, cbor) in expected.iter() {
+            assert_eq!(wal.get_tx(hash).unwrap().as_ref(), Some(cbor));
+        }
+
+        // and located in the block without decoding it
+        let point = ChainPoint::Specific(block.slot, block.hash);
+        assert_eq!(wal.locate_tx(&expected[0].0).unwrap(), Some(point));
+
+        // a hash that was never applied
+        assert_eq!(wal.get_tx(&testing::slot_to_hash(1000)).unwrap(), None);
+        assert_eq!(wal.locate_tx(&testing::slot_to_hash(1000)).unwrap(), None);
+
+        // the index survives a rebuild from the log entries
+        wal.rebuild_index(IndexKind::Tx).unwrap();
+        let (hash, cbor) = &expected[0];
+        assert_eq!(wal.get_tx(hash).unwrap().as_ref(), Some(cbor));
+
+        // once the block is rolled back, its txs aren't in the chain anymore
+        let point = ChainPoint::Specific(9, testing::slot_to_hash(9));
+        wal.roll_back(&point).unwrap();
+
+        for (hash, _) in expected.iter() {
+            assert_eq!(wal.get_tx(hash).unwrap(), None);
+            assert_eq!(wal.locate_tx(hash).unwrap(), None);
+        }
+
+        testing::assert_invariants(&wal);
+    }
+
+    #[test]
+    fn test_decoded_blocks_index_the_given_tx_hashes() {
+        let mut wal = testing::db_with_dummy_blocks(10);
+        let block = testing::test_data_block(10);
+
+        let hashes: Vec<_> = block
+            .decode()
+            .unwrap()
+            .txs()
+            .iter()
+            .map(|x| x.hash())
+            .collect();
+
+        wal.roll_forward_decoded(std::iter::once((block, hashes.clone())))
+            .unwrap();
+
+        for hash in hashes.iter() {
+            assert!(wal.get_tx(hash).unwrap().is_some());
+        }
+
+        // the hashes are taken as they are, the block isn't decoded for them
+        let given = testing::slot_to_hash(1000);
+        let block = testing::test_data_block(11);
+
+        wal.roll_forward_decoded(std::iter::once((block, vec![given])))
+            .unwrap();
+
+        assert!(wal.get_tx(&given).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_tx_finality_at_immutable_boundary() {
+        const K: u64 = 3;
+
+        let mut wal = testing::db_with_dummy_blocks(10);
+        let block = testing::test_data_block(10);
+        let tx = block.decode().unwrap().txs()[0].hash();
+
+        wal.roll_forward(std::iter::once(block)).unwrap();
+
+        // k - 1 blocks on top, still within reach of a rollback
+        wal.roll_forward((11..13).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        let finality = wal.tx_finality(&tx, K).unwrap().unwrap();
+        assert_eq!(
+            finality.block,
+            ChainPoint::Specific(10, testing::slot_to_hash(10))
+        );
+        assert_eq!(finality.depth, K - 1);
+        assert!(!finality.immutable);
+
+        // the k-th block on top makes it final
+        wal.roll_forward(std::iter::once(testing::dummy_block_from_slot(13)))
+            .unwrap();
+
+        let finality = wal.tx_finality(&tx, K).unwrap().unwrap();
+        assert_eq!(finality.depth, K);
+        assert!(finality.immutable);
+
+        assert_eq!(
+            wal.tx_finality(&testing::slot_to_hash(1000), K).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_continuity_check_rejects_gaps() {
+        let chain = testing::TestChainBuilder::new().extend(0..12);
+        let blocks = chain.blocks();
+
+        let mut db = testing::empty_db();
+        db.enable_continuity_check();
+
+        // right after origin, anything goes
+        db.roll_forward(blocks[..10].iter().cloned()).unwrap();
+
+        // slot 10 is skipped
+        let result = db.roll_forward(std::iter::once(blocks[11].clone()));
+
+        assert!(matches!(
+            result,
+            Err(WalError::NonContiguousBlock(hash, 11, tip))
+                if hash == blocks[11].hash && tip == blocks[9].hash
+        ));
+
+        // a gap in the middle of a batch fails the whole batch
+        let batch = [blocks[10].clone(), blocks[10].clone()];
+        assert!(db.roll_forward(batch.into_iter()).is_err());
+
+        let (_, tip) = db.find_tip().unwrap().unwrap();
+        assert_eq!(tip, chain.point(9));
+
+        // a rollback moves the tip, blocks of the fork follow the new one
+        let fork = chain.fork_at(5).extend([7, 8]);
+        db.roll_back(&chain.point(5)).unwrap();
+        db.roll_forward(fork.blocks_after(5).into_iter()).unwrap();
+
+        // the old branch doesn't follow the fork tip
+        let result = db.roll_forward(std::iter::once(blocks[10].clone()));
+        assert!(matches!(result, Err(WalError::NonContiguousBlock(..))));
+
+        testing::assert_invariants(&db);
+
+        // without the check, the gap is accepted
+        let mut db = testing::empty_db();
+        db.roll_forward(blocks[..10].iter().cloned()).unwrap();
+        db.roll_forward(std::iter::once(blocks[11].clone()))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_page_within_size_limit() {
+        let mut db = testing::db_with_dummy_blocks(5);
+
+        let mut oversized = testing::dummy_block_from_slot(5);
+        oversized.body = vec![0; 8192];
+
+        db.roll_forward(std::iter::once(oversized.clone())).unwrap();
+        db.roll_forward((6..10).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        let full: Vec<_> = db.read_block_page(None, 8).unwrap().collect();
+        let page = db.read_block_page_within(None, 8, 4096).unwrap();
+        assert_eq!(page.len(), 8);
+
+        for (block, read) in full.iter().zip(page) {
+            match read {
+                Ok(read) => assert_eq!(&read, block),
+                Err(x) => assert_eq!(
+                    x,
+                    OversizedBlock {
+                        slot: 5,
+                        hash: oversized.hash,
+                        size: 8192
+                    }
+                ),
+            }
+        }
+
+        let from = ChainPoint::Specific(8, testing::slot_to_hash(8));
+        let page = db.read_block_page_within(Some(&from), 8, 4096).unwrap();
+        assert_eq!(page.len(), 2);
+    }
+
+    #[test]
+    fn test_sparse_read_paths_match() {
+        let mut db = testing::db_with_dummy_blocks(200);
+        db.enable_checksums();
+
+        let point = |slot| ChainPoint::Specific(slot, testing::slot_to_hash(slot));
+
+        let one_by_one = |db: &WalStore, points: &[ChainPoint]| -> Vec<RawBlock> {
+            points.iter().map(|x| db.read_block(x).unwrap()).collect()
+        };
+
+        let scan_range = |db: &WalStore, points: &[ChainPoint]| {
+            let seqs: Vec<_> = points.iter().map(|x| db.assert_point(x).unwrap()).collect();
+            sparse_scan_range(&seqs)
+        };
+
+        // a run of consecutive blocks, requested out of order, takes the scan
+        // (block at slot `n` sits at sequence `n + 1`)
+        let mut contiguous: Vec<_> = (40..60).map(point).collect();
+        contiguous.swap(0, 19);
+        contiguous.swap(5, 11);
+        assert_eq!(scan_range(&db, &contiguous), Some((41, 60)));
+
+        let scanned = db.read_sparse_blocks(&contiguous).unwrap();
+        assert_eq!(scanned, one_by_one(&db, &contiguous));
+
+        // points far apart are read on their own
+        let sparse: Vec<_> = [150, 3, 90, 199].into_iter().map(point).collect();
+        assert_eq!(scan_range(&db, &sparse), None);
+
+        let read = db.read_sparse_blocks(&sparse).unwrap();
+        assert_eq!(read, one_by_one(&db, &sparse));
+
+        // two points take the scan while they span up to 2 * SPARSE_SCAN_MAX_SPREAD
+        // entries, one more and they're read on their own
+        let within: Vec<_> = [17, 10].into_iter().map(point).collect();
+        assert_eq!(scan_range(&db, &within), Some((11, 18)));
+        assert_eq!(
+            db.read_sparse_blocks(&within).unwrap(),
+            one_by_one(&db, &within)
+        );
+
+        let beyond: Vec<_> = [18, 10].into_iter().map(point).collect();
+        assert_eq!(scan_range(&db, &beyond), None);
+        assert_eq!(
+            db.read_sparse_blocks(&beyond).unwrap(),
+            one_by_one(&db, &beyond)
+        );
+
+        // a missing point fails either way
+        let missing = ChainPoint::Specific(500, testing::slot_to_hash(500));
+
+        let mut points = contiguous.clone();
+        points.push(missing.clone());
+        assert!(matches!(
+            db.read_sparse_blocks(&points),
+            Err(WalError::PointNotFound(x)) if x == missing
+        ));
+
+        assert!(db.read_sparse_blocks(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recent_rollbacks() {
+        let main = testing::TestChainBuilder::new().extend(0..20);
+        let fork = main.fork_at(15).extend([17, 19, 21, 23]);
+
+        let mut db = testing::empty_db();
+        db.roll_forward(main.blocks().iter().cloned()).unwrap();
+
+        // the origin mark is not a rollback
+        assert!(db.recent_rollbacks(10).unwrap().is_empty());
+
+        // undoes 16 to 19
+        db.roll_back(&main.point(15)).unwrap();
+        db.roll_forward(fork.blocks_after(15).into_iter()).unwrap();
+
+        // undoes 19 to 23
+        db.roll_back(&fork.point(17)).unwrap();
+
+        let rollbacks = db.recent_rollbacks(10).unwrap();
+        assert_eq!(rollbacks.len(), 2);
+
+        assert_eq!(rollbacks[0].point, fork.point(17));
+        assert_eq!(rollbacks[0].depth, 3);
+        assert_eq!(rollbacks[0].undone, 19..=23);
+
+        assert_eq!(rollbacks[1].point, main.point(15));
+        assert_eq!(rollbacks[1].depth, 4);
+        assert_eq!(rollbacks[1].undone, 16..=19);
+        assert!(rollbacks[1].seq < rollbacks[0].seq);
+
+        // the limit keeps the latest ones
+        assert_eq!(db.recent_rollbacks(1).unwrap(), rollbacks[..1]);
+    }
+
+    #[test]
+    fn test_replay_range_is_repeatable() {
+        let mut db = testing::db_with_dummy_blocks(20);
+
+        // the range includes undos and a mark
+        db.roll_back(&ChainPoint::Specific(14, testing::slot_to_hash(14)))
+            .unwrap();
+        db.roll_forward((15..18).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        let replay = |from, to| {
+            let mut visited = vec![];
+
+            let count = db
+                .replay_range(from, to, |seq, log| visited.push((seq, log.clone())))
+                .unwrap();
+
+            assert_eq!(count, visited.len());
+            visited
+        };
+
+        let first = replay(10, 26);
+        let second = replay(10, 26);
+
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 17);
+        assert_eq!(first.first().unwrap().0, 10);
+        assert_eq!(first.last().unwrap().0, 26);
+        assert!(first.iter().any(|(_, x)| matches!(x, LogValue::Undo(_))));
+        assert!(first.iter().any(|(_, x)| matches!(x, LogValue::Mark(_))));
+
+        // the wal is untouched
+        let (tip_seq, _) = db.find_tip().unwrap().unwrap();
+        assert_eq!(tip_seq, 29);
+
+        // ranges past the tip are clipped, inverted ones are empty
+        assert_eq!(replay(27, 100).len(), 3);
+        assert!(replay(25, 10).is_empty());
+    }
+
+    #[test]
+    fn test_read_decoded_block() {
+        let mut wal = testing::db_with_dummy_blocks(10);
+        let block = testing::test_data_block(10);
+
+        wal.roll_forward(std::iter::once(block.clone())).unwrap();
+
+        let (raw, (hash, slot, txs)) = wal
+            .read_decoded_block(&block.hash, |x| (x.hash(), x.slot(), x.txs().len()))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(raw, block);
+        assert_eq!((hash, slot, txs), (block.hash, block.slot, 21));
+
+        // bodies that don't decode are reported as such
+        let point = ChainPoint::Specific(5, testing::slot_to_hash(5));
+        let seq = wal.locate_point(&point).unwrap().unwrap();
+        let mut garbage = wal.read_block(&point).unwrap();
+        garbage.body = vec![0xff; 4];
+        insert_raw_entry(&wal, seq, LogValue::Apply(garbage));
+
+        let result = wal.read_decoded_block(&testing::slot_to_hash(5), |_| ());
+        assert!(matches!(result, Err(WalError::UndecodableBlock(..))));
+
+        // unknown and rolled back blocks aren't found
+        let unknown = testing::slot_to_hash(1000);
+        assert!(wal.read_decoded_block(&unknown, |_| ()).unwrap().is_none());
+
+        let point = ChainPoint::Specific(9, testing::slot_to_hash(9));
+        wal.roll_back(&point).unwrap();
+        assert!(wal
+            .read_decoded_block(&block.hash, |_| ())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_compaction_fills_archive() {
+        let policy = CompactionPolicy {
+            high_water: 10,
+            low_water: 10,
+            undo_body_retention: None,
+        };
+
+        let main = testing::TestChainBuilder::new().extend(0..=20);
+        let fork = main.fork_at(10).extend(11..60);
+
+        let mut db = testing::empty_db();
+        db.roll_forward(main.blocks().iter().cloned()).unwrap();
+        db.roll_back(&main.point(10)).unwrap();
+        db.roll_forward(fork.blocks_after(10).into_iter()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive = Archive::open(dir.path().join("archive")).unwrap();
+
+        assert!(db
+            .compact_into(&policy, 30, u64::MAX, Some(&archive))
+            .unwrap()
+            .is_some());
+
+        // only the blocks of the canonical chain are handed over, the ones
+        // rolled back within the trimmed range are left out
+        let archived: Vec<_> = archive.crawl_chain(None).map(|x| x.unwrap().1).collect();
+
+        let expected: Vec<_> = fork
+            .blocks()
+            .iter()
+            .filter(|x| x.slot <= 30)
+            .map(|x| x.body.clone())
+            .collect();
+
+        assert_eq!(archived, expected);
+
+        // and the WAL carries on right after the archived blocks
+        let (_, first) = db.crawl_from(None).unwrap().next().unwrap();
+        assert_eq!(first, LogValue::Apply(fork.blocks()[31].clone()));
+        testing::assert_invariants(&db);
+    }
+
+    #[test]
+    fn test_compaction_high_low_water() {
+        let policy = CompactionPolicy {
+            high_water: 80,
+            low_water: 50,
+            undo_body_retention: None,
+        };
+
+        let first_seq = |db: &WalStore| db.crawl_from(None).unwrap().next().unwrap().0;
+
+        // below the high-water mark, nothing happens
+        let mut db = testing::db_with_dummy_blocks(70);
+        assert_eq!(db.compact(&policy, 69, 70).unwrap(), None);
+        assert_eq!(first_seq(&db), 0);
+
+        // above it, the WAL is trimmed down to the low-water mark
+        let mut db = testing::db_with_dummy_blocks(100);
+        assert_eq!(db.compact(&policy, 99, 100).unwrap(), Some(50));
+        assert_eq!(first_seq(&db), 51);
+        assert_eq!(db.crawl_from(None).unwrap().count(), 50);
+        assert_eq!(db.compact(&policy, 99, 100).unwrap(), None);
+        testing::assert_invariants(&db);
+
+        // entries after the safety window are kept, even above the low-water mark
+        db.roll_forward((100..140).map(testing::dummy_block_from_slot))
+            .unwrap();
+        assert_eq!(db.compact(&policy, 69, 140).unwrap(), Some(70));
+        assert_eq!(first_seq(&db), 71);
+        assert_eq!(db.crawl_from(None).unwrap().count(), 70);
+
+        // and so are the entries after the consumer cursor
+        db.roll_forward((140..160).map(testing::dummy_block_from_slot))
+            .unwrap();
+        assert_eq!(db.compact(&policy, 159, 80).unwrap(), Some(79));
+        assert_eq!(first_seq(&db), 80);
+        testing::assert_invariants(&db);
+
+        let tip = db.find_tip().unwrap().unwrap().1;
+        assert_eq!(tip, ChainPoint::Specific(159, testing::slot_to_hash(159)));
+    }
+
+    #[test]
+    fn test_undo_bodies_within_retention_are_kept() {
+        let mut db = testing::db_with_dummy_blocks(20);
+
+        // undoes slots 19..=15, then 19..=5 after moving forward again
+        db.roll_back(&ChainPoint::Specific(14, testing::slot_to_hash(14)))
+            .unwrap();
+        db.roll_forward((15..20).map(testing::dummy_block_from_slot))
+            .unwrap();
+        db.roll_back(&ChainPoint::Specific(4, testing::slot_to_hash(4)))
+            .unwrap();
+
+        let undos = |db: &WalStore| -> Vec<RawBlock> {
+            db.crawl_from(None)
+                .unwrap()
+                .filter_map(|(_, log)| match log {
+                    LogValue::Undo(x) => Some(x),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let before = undos(&db);
+        let (_, tip) = db.find_tip().unwrap().unwrap();
+        let cursor = db.locate_point(&tip).unwrap().unwrap() + 1;
+
+        // undos of slots before 12 are past retention
+        let stripped = db.strip_undo_bodies(12, cursor).unwrap();
+        assert_eq!(stripped, 7);
+
+        for (old, new) in before.iter().zip(undos(&db)) {
+            assert_eq!((old.slot, old.hash), (new.slot, new.hash));
+
+            if old.slot < 12 {
+                assert!(new.body.is_empty());
+            } else {
+                // still decodes, so the undo can be fully served
+                assert_eq!(&new, old);
+                assert!(new.decode().is_ok());
+            }
+        }
+
+        // stripping again doesn't find anything left to do
+        assert_eq!(db.strip_undo_bodies(12, cursor).unwrap(), 0);
+        testing::assert_invariants(&db);
+    }
+
+    #[test]
+    fn test_stripped_undos_keep_txs_out_of_the_index() {
+        let mut db = testing::db_with_dummy_blocks(10);
+        let block = testing::test_data_block(10);
+        let tx = block.decode().unwrap().txs()[0].hash();
+
+        db.roll_forward(std::iter::once(block)).unwrap();
+        db.roll_back(&ChainPoint::Specific(9, testing::slot_to_hash(9)))
+            .unwrap();
+        db.roll_forward((11..20).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        let (_, tip) = db.find_tip().unwrap().unwrap();
+        let cursor = db.locate_point(&tip).unwrap().unwrap() + 1;
+        assert_eq!(db.strip_undo_bodies(15, cursor).unwrap(), 1);
+
+        // the undone txs don't come back through a rebuild of the index
+        db.rebuild_index(IndexKind::Tx).unwrap();
+        assert!(db.get_tx(&tx).unwrap().is_none());
+
+        // nor through an import of the stripped entries
+        let mut buffer = vec![];
+        db.export_since(0, &mut buffer).unwrap();
+
+        let mut standby = testing::empty_db();
+        standby.import(buffer.as_slice()).unwrap();
+        assert!(standby.get_tx(&tx).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_compaction_strips_undo_bodies() {
+        let policy = CompactionPolicy {
+            high_water: 30,
+            low_water: 20,
+            undo_body_retention: Some(15),
+        };
+
+        let mut db = testing::db_with_dummy_blocks(30);
+        db.roll_back(&ChainPoint::Specific(19, testing::slot_to_hash(19)))
+            .unwrap();
+        db.roll_forward((30..40).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        let bodies = |db: &WalStore| -> Vec<(BlockSlot, bool)> {
+            db.crawl_from(None)
+                .unwrap()
+                .filter_map(|(_, log)| match log {
+                    LogValue::Undo(x) => Some((x.slot, x.body.is_empty())),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        assert!(bodies(&db).iter().all(|(_, stripped)| !stripped));
+
+        let (_, tip) = db.find_tip().unwrap().unwrap();
+        let cursor = db.locate_point(&tip).unwrap().unwrap() + 1;
+        assert!(db.compact(&policy, 39, cursor).unwrap().is_some());
+
+        // undos of slots more than 15 behind the tip (39) lost their body
+        for (slot, stripped) in bodies(&db) {
+            assert_eq!(stripped, slot < 24, "slot {slot}");
+        }
+
+        testing::assert_invariants(&db);
+    }
+
+    #[test]
+    fn test_oldest_point_follows_compaction() {
+        let policy = CompactionPolicy {
+            high_water: 80,
+            low_water: 50,
+            undo_body_retention: None,
+        };
+
+        let mut db = testing::db_with_dummy_blocks(100);
+        assert_eq!(db.oldest_point().unwrap(), Some(ChainPoint::Origin));
+
+        // slot 50 sits at seq 51, the first one left after the trim
+        assert_eq!(db.compact(&policy, 99, 100).unwrap(), Some(50));
+
+        let oldest = db.oldest_point().unwrap();
+        assert_eq!(
+            oldest,
+            Some(ChainPoint::Specific(50, testing::slot_to_hash(50)))
+        );
+
+        let trimmed = ChainPoint::Specific(49, testing::slot_to_hash(49));
+        assert_eq!(db.find_intersect(&[trimmed]).unwrap(), None);
+
+        let oldest = oldest.unwrap();
+        assert!(db.find_intersect(&[oldest]).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_pinned_slots_survive_compaction() {
+        let policy = CompactionPolicy {
+            high_water: 80,
+            low_water: 50,
+            undo_body_retention: None,
+        };
+
+        let pinned = ChainPoint::Specific(20, testing::slot_to_hash(20));
+        let mut db = testing::db_with_dummy_blocks(100);
+
+        // pins are shared between clones and counted
+        db.pin(20);
+        db.clone().pin(20);
+
+        // slot 20 sits at seq 21, the trim stops right before it
+        assert_eq!(db.compact(&policy, 99, 100).unwrap(), Some(20));
+        assert!(db.locate_point(&pinned).unwrap().is_some());
+
+        db.roll_forward((100..120).map(testing::dummy_block_from_slot))
+            .unwrap();
+
+        db.unpin(20);
+        assert_eq!(db.compact(&policy, 119, 120).unwrap(), None);
+        assert!(db.locate_point(&pinned).unwrap().is_some());
+
+        // once every pin is released the trim goes down to the low-water mark
+        db.unpin(20);
+        assert_eq!(db.compact(&policy, 119, 120).unwrap(), Some(70));
+        assert!(db.locate_point(&pinned).unwrap().is_none());
+        testing::assert_invariants(&db);
+    }
+
+    #[test]
+    fn test_reader_pin_follows_reader() {
+        let policy = CompactionPolicy {
+            high_water: 80,
+            low_water: 50,
+            undo_body_retention: None,
+        };
+
+        let mut db = testing::db_with_dummy_blocks(100);
+        let mut pin = db.pin_reader();
+
+        // a reader that went through slots 0 to 10 keeps slot 10 onwards
+        for (_, log) in db.crawl_from(None).unwrap().take(12) {
+            pin.advance(&log);
+        }
+
+        assert_eq!(db.compact(&policy, 99, 100).unwrap(), Some(10));
+        assert_eq!(db.pins.read().unwrap().len(), 1);
+
+        // dropping the reader releases its pin
+        drop(pin);
+        assert!(db.pins.read().unwrap().is_empty());
+
+        assert_eq!(db.compact(&policy, 99, 100).unwrap(), Some(50));
+        testing::assert_invariants(&db);
+    }
+
+    #[test]
+    fn test_compaction_right_after_rollback() {
+        let policy = CompactionPolicy {
+            high_water: 80,
+            low_water: 50,
+            undo_body_retention: None,
+        };
+
+        let tip = ChainPoint::Specific(60, testing::slot_to_hash(60));
+
+        // the undos of slots 99..=61 sit above the new tip
+        let mut db = testing::db_with_dummy_blocks(100);
+        db.roll_back(&tip).unwrap();
+
+        // a window computed from the old tip reaches past the new one, the trim
+        // stops right before the block at the tip (slot 60, seq 61)
+        assert_eq!(db.compact(&policy, 99, 141).unwrap(), Some(60));
+        testing::assert_invariants(&db);
+
+        let first = db.crawl_from(None).unwrap().next().unwrap();
+        assert!(matches!(first, (61, LogValue::Apply(x)) if x.slot == 60));
+        assert_eq!(db.find_tip().unwrap().unwrap().1, tip);
+
+        assert_eq!(db.compact(&policy, 99, 141).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invariant_violations_are_detected() {
+        let wal = wal_with_rollbacks();
+        testing::assert_invariants(&wal);
+
+        // trimming the start leaves undos and marks that can't be verified
+        let mut trimmed = wal_with_rollbacks();
+        trimmed.remove_range(None, Some(22)).unwrap();
+        testing::assert_invariants(&trimmed);
+
+        // an undo of a block that isn't the tip
+        let wal = testing::db_with_dummy_blocks(10);
+        insert_raw_entry(&wal, 11, LogValue::Undo(testing::dummy_block_from_slot(3)));
+
+        assert!(matches!(
+            wal.check_invariants(),
+            Err(WalError::InvariantViolation(11, _))
+        ));
+
+        // a hole in the sequence
+        let wal = testing::db_with_dummy_blocks(10);
+        insert_raw_entry(
+            &wal,
+            15,
+            LogValue::Apply(testing::dummy_block_from_slot(10)),
+        );
+
+        assert!(matches!(
+            wal.check_invariants(),
+            Err(WalError::InvariantViolation(15, "sequence gap"))
+        ));
+
+        // a mark that isn't preceded by the undos of a rollback
+        let wal = testing::db_with_dummy_blocks(10);
+        let point = ChainPoint::Specific(3, testing::slot_to_hash(3));
+        insert_raw_entry(&wal, 11, LogValue::Mark(point));
+
+        assert!(matches!(
+            wal.check_invariants(),
+            Err(WalError::InvariantViolation(11, "mark isn't at the tip"))
+        ));
+    }
+
+    #[test]
+    fn test_table_sizes_grow_with_blocks() {
+        let mut wal = testing::db_with_dummy_blocks(1);
+
+        let size_of = |wal: &WalStore, name: &str| {
+            wal.table_sizes()
+                .unwrap()
+                .into_iter()
+                .find(|x| x.name == name)
+                .unwrap()
+        };
+
+        let before = size_of(&wal, "wal");
+        assert_eq!(before.entries, 2);
+
+        let blocks = (1..50).map(testing::dummy_block_from_slot);
+        wal.roll_forward(blocks).unwrap();
+
+        let after = size_of(&wal, "wal");
+        assert_eq!(after.entries, 51);
+        assert!(after.stored_bytes > before.stored_bytes);
+        assert!(after.total_bytes() > before.total_bytes());
+
+        // indexes are reported too
+        assert_eq!(size_of(&wal, "pos").entries, 51);
+    }
 }
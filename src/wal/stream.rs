@@ -46,7 +46,8 @@ mod tests {
             slot,
             hash,
             era: pallas::ledger::traverse::Era::Byron,
-            body: slot.to_be_bytes().to_vec(),
+            // repeated to stay above the minimum body size
+            body: slot.to_be_bytes().repeat(4),
         }
     }
 
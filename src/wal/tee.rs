@@ -0,0 +1,164 @@
+//! Forwarding of committed WAL entries to a secondary sink
+//!
+//! A tee receives every `(LogSeq, LogValue)` pair after it has been committed
+//! to the WAL, in sequence order. Entries are handed over to a dedicated
+//! worker thread through a bounded buffer, so that slow sinks don't stall the
+//! primary write path (unless configured to apply backpressure).
+//!
+//! Delivery is at-least-once over the committed stream: a sink never sees an
+//! entry that wasn't committed and a failed delivery is retried until the
+//! sink accepts it, so sinks must tolerate duplicates (the sequence number
+//! can be used to detect them). When the overflow policy is `drop`, entries
+//! that don't fit in the buffer are discarded; sinks can detect the hole
+//! through the sequence number and backfill using `WalStore::export_since`.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use super::{LogEntry, WalError};
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_BUFFER: usize = 1000;
+
+/// A destination for the committed WAL entries
+pub trait TeeSink: Send + 'static {
+    fn deliver(&mut self, entry: &LogEntry) -> Result<(), WalError>;
+}
+
+impl<F> TeeSink for F
+where
+    F: FnMut(&LogEntry) -> Result<(), WalError> + Send + 'static,
+{
+    fn deliver(&mut self, entry: &LogEntry) -> Result<(), WalError> {
+        self(entry)
+    }
+}
+
+/// Appends entries to a file using the same format as `export_since`
+pub struct FileSink(std::fs::File);
+
+impl FileSink {
+    pub fn open(path: &Path) -> Result<Self, WalError> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|x| WalError::IO(x.into()))?;
+
+        Ok(Self(file))
+    }
+}
+
+impl TeeSink for FileSink {
+    fn deliver(&mut self, entry: &LogEntry) -> Result<(), WalError> {
+        let bytes = bincode::serialize(entry).map_err(|x| WalError::IO(x))?;
+
+        self.0
+            .write_all(&bytes)
+            .map_err(|x| WalError::IO(x.into()))?;
+
+        Ok(())
+    }
+}
+
+/// What to do when the tee buffer is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Block the WAL writer until there's room in the buffer
+    #[default]
+    Backpressure,
+
+    /// Discard the entry and keep going
+    Drop,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// File where teed entries are appended
+    pub path: PathBuf,
+
+    /// Max number of entries waiting to be delivered
+    pub buffer: Option<usize>,
+
+    pub overflow: Option<Overflow>,
+}
+
+/// Handle used by the WAL to push committed entries into a tee
+#[derive(Clone)]
+pub struct Tee {
+    sender: SyncSender<LogEntry>,
+    overflow: Overflow,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Tee {
+    /// Starts a worker thread that delivers entries to `sink`
+    ///
+    /// The worker stops once every handle (including the ones held by WAL
+    /// clones) has been dropped and the buffer has been drained.
+    pub fn spawn(mut sink: impl TeeSink, buffer: usize, overflow: Overflow) -> Self {
+        let (sender, receiver) = sync_channel::<LogEntry>(buffer);
+
+        std::thread::spawn(move || {
+            for entry in receiver {
+                while let Err(err) = sink.deliver(&entry) {
+                    warn!(%err, seq = entry.0, "wal tee delivery failed, retrying");
+                    std::thread::sleep(RETRY_DELAY);
+                }
+            }
+
+            info!("wal tee finished");
+        });
+
+        Self {
+            sender,
+            overflow,
+            dropped: Default::default(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self, WalError> {
+        let sink = FileSink::open(&config.path)?;
+
+        Ok(Self::spawn(
+            sink,
+            config.buffer.unwrap_or(DEFAULT_BUFFER),
+            config.overflow.unwrap_or_default(),
+        ))
+    }
+
+    /// Number of entries discarded because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn send(&self, entry: LogEntry) {
+        let result = match self.overflow {
+            Overflow::Backpressure => self.sender.send(entry).map_err(|x| x.0 .0),
+            Overflow::Drop => match self.sender.try_send(entry) {
+                Ok(_) => Ok(()),
+                Err(TrySendError::Full((seq, _))) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    warn!(seq, "wal tee buffer is full, dropping entry");
+                    Ok(())
+                }
+                Err(TrySendError::Disconnected((seq, _))) => Err(seq),
+            },
+        };
+
+        if let Err(seq) = result {
+            warn!(seq, "wal tee worker is gone, entry not delivered");
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::{cell::Cell, rc::Rc};
+
 use super::*;
 
 const DUMMY_BLOCK_BYTES: &str = "820183851a2d964a09582089d9b5a5b8ddc8d7e5a6795e9774d97faf1efea59b2caf7eaf9f8c5b32059df484830058200e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a85820afc0da64183bf2664f3d4eec7238d524ba607faeeab24fc100eb861dba69971b8300582025777aca9e4a73d48fc73b4f961d345b06d4a6f349cb7916570d35537d53479f5820d36a2619a672494604e11bb447cbcf5231e9f2ba25c2169177edc941bd50ad6c5820afc0da64183bf2664f3d4eec7238d524ba607faeeab24fc100eb861dba69971b58204e66280cd94d591072349bec0a3090a53aa945562efb6d08d56e53654b0e40988482000058401bc97a2fe02c297880ce8ecfd997fe4c1ec09ee10feeee9f686760166b05281d6283468ffd93becb0c956ccddd642df9b1244c915911185fa49355f6f22bfab98101820282840058401bc97a2fe02c297880ce8ecfd997fe4c1ec09ee10feeee9f686760166b05281d6283468ffd93becb0c956ccddd642df9b1244c915911185fa49355f6f22bfab9584061261a95b7613ee6bf2067dad77b70349729b0c50d57bc1cf30de0db4a1e73a885d0054af7c23fc6c37919dba41c602a57e2d0f9329a7954b867338d6fb2c9455840e03e62f083df5576360e60a32e22bbb07b3c8df4fcab8079f1d6f61af3954d242ba8a06516c395939f24096f3df14e103a7d9c2b80a68a9363cf1f27c7a4e307584044f18ef23db7d2813415cb1b62e8f3ead497f238edf46bb7a97fd8e9105ed9775e8421d18d47e05a2f602b700d932c181e8007bbfb231d6f1a050da4ebeeba048483000000826a63617264616e6f2d736c00a058204ba92aa320c60acc9ad7b9a64f2eda55c4d2ec28e604faf186708b4f0c4e8edf849fff8300d9010280d90102809fff82809fff81a0";
@@ -20,10 +22,51 @@ pub fn dummy_block_from_slot(slot: u64) -> RawBlock {
     }
 }
 
+/// Cbor of a real (alonzo) block with txs from the test data
+///
+/// Tests that need real txs or outputs pick them from this block.
+pub fn test_data_cbor() -> Vec<u8> {
+    let path = std::path::PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap())
+        .join("test_data")
+        .join("alonzo27.block");
+
+    let content = std::fs::read_to_string(path).unwrap();
+    hex::decode(content.trim()).unwrap()
+}
+
+/// A real (alonzo) block with txs from the test data, placed at the given slot
+pub fn test_data_block(slot: u64) -> RawBlock {
+    let body = test_data_cbor();
+    let block = pallas::ledger::traverse::MultiEraBlock::decode(&body).unwrap();
+
+    RawBlock {
+        slot,
+        hash: slot_to_hash(slot),
+        era: block.era(),
+        body,
+    }
+}
+
 pub fn empty_db() -> redb::WalStore {
     super::redb::WalStore::memory().unwrap()
 }
 
+/// Runs a test scenario against an empty WAL on each storage backend
+pub fn with_each_backend(test: impl Fn(redb::WalStore)) {
+    test(empty_db());
+
+    let file = tempfile::tempfile().unwrap();
+    let backend = ::redb::backends::FileBackend::new(file).unwrap();
+    test(redb::WalStore::with_backend(backend, Default::default()).unwrap());
+}
+
+/// Fails the test if the WAL breaks any of its structural invariants
+pub fn assert_invariants(wal: &redb::WalStore) {
+    if let Err(err) = wal.check_invariants() {
+        panic!("wal invariants don't hold: {err}");
+    }
+}
+
 pub fn db_with_dummy_blocks(quantity: usize) -> redb::WalStore {
     let mut wal = empty_db();
 
@@ -32,3 +75,175 @@ pub fn db_with_dummy_blocks(quantity: usize) -> redb::WalStore {
 
     wal
 }
+
+// byte ranges of the header fields in `DUMMY_BLOCK_BYTES` that get patched
+// when building linked blocks
+const PREV_HASH: std::ops::Range<usize> = 11..43;
+const SLOT_ID: std::ops::Range<usize> = 253..256;
+const DIFFICULTY: std::ops::Range<usize> = 322..324;
+const SOFTWARE_VERSION: std::ops::Range<usize> = 610..611;
+
+/// Builds a Byron block derived from the dummy one with the given header data
+///
+/// The slot goes into epoch 0, the difficulty (which Byron uses as the block
+/// number) is the height, and the software version carries the branch id so
+/// that sibling blocks on different forks end up with different hashes.
+fn linked_block(slot: u64, height: u64, prev: Option<BlockHash>, branch: u64) -> RawBlock {
+    let dummy = hex::decode(DUMMY_BLOCK_BYTES).unwrap();
+
+    let mut body = Vec::with_capacity(dummy.len() + 32);
+
+    body.extend_from_slice(&dummy[..PREV_HASH.start]);
+
+    match prev {
+        Some(prev) => body.extend_from_slice(&prev[..]),
+        None => body.extend_from_slice(&dummy[PREV_HASH]),
+    }
+
+    body.extend_from_slice(&dummy[PREV_HASH.end..SLOT_ID.start]);
+    body.extend(pallas::codec::minicbor::to_vec((0u64, slot)).unwrap());
+    body.extend_from_slice(&dummy[SLOT_ID.end..DIFFICULTY.start]);
+    body.extend(pallas::codec::minicbor::to_vec([height]).unwrap());
+    body.extend_from_slice(&dummy[DIFFICULTY.end..SOFTWARE_VERSION.start]);
+    body.extend(pallas::codec::minicbor::to_vec(branch).unwrap());
+    body.extend_from_slice(&dummy[SOFTWARE_VERSION.end..]);
+
+    let block = pallas::ledger::traverse::MultiEraBlock::decode(&body).unwrap();
+
+    RawBlock {
+        slot: block.slot(),
+        hash: block.hash(),
+        era: block.era(),
+        body,
+    }
+}
+
+/// Builds chains of blocks linked through their previous hash
+///
+/// Meant to express re-org scenarios in tests, eg:
+///
+/// ```ignore
+/// let main = TestChainBuilder::new().extend(0..10);
+/// let fork = main.fork_at(5).extend([7, 9, 11, 13]);
+/// ```
+///
+/// Forks share a branch counter with the builder they come from, so every
+/// branch gets a distinct id and the output is the same on every run.
+pub struct TestChainBuilder {
+    blocks: Vec<RawBlock>,
+    branch: u64,
+    branches: Rc<Cell<u64>>,
+}
+
+impl Default for TestChainBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestChainBuilder {
+    pub fn new() -> Self {
+        Self {
+            blocks: vec![],
+            branch: 0,
+            branches: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Adds one block per slot on top of the current tip
+    pub fn extend(mut self, slots: impl IntoIterator<Item = u64>) -> Self {
+        for slot in slots {
+            let prev = self.blocks.last().map(|x| x.hash);
+            let height = self.blocks.len() as u64;
+
+            let block = linked_block(slot, height, prev, self.branch);
+            self.blocks.push(block);
+        }
+
+        self
+    }
+
+    /// Starts a new branch sharing the blocks up to (and including) `slot`
+    pub fn fork_at(&self, slot: u64) -> Self {
+        let branch = self.branches.get() + 1;
+        self.branches.set(branch);
+
+        Self {
+            blocks: self
+                .blocks
+                .iter()
+                .take_while(|x| x.slot <= slot)
+                .cloned()
+                .collect(),
+            branch,
+            branches: self.branches.clone(),
+        }
+    }
+
+    pub fn blocks(&self) -> &[RawBlock] {
+        &self.blocks
+    }
+
+    /// Blocks of this branch with a slot greater than `slot`
+    pub fn blocks_after(&self, slot: u64) -> Vec<RawBlock> {
+        self.blocks
+            .iter()
+            .filter(|x| x.slot > slot)
+            .cloned()
+            .collect()
+    }
+
+    pub fn point(&self, slot: u64) -> ChainPoint {
+        self.blocks
+            .iter()
+            .find(|x| x.slot == slot)
+            .map(ChainPoint::from)
+            .expect("slot not part of the chain")
+    }
+
+    pub fn tip(&self) -> ChainPoint {
+        self.blocks
+            .last()
+            .map(ChainPoint::from)
+            .unwrap_or(ChainPoint::Origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_builder_reorg() {
+        let main = TestChainBuilder::new().extend(0..10);
+        let fork = main.fork_at(5).extend([7, 9, 11, 13]);
+        let other = main.fork_at(5).extend([7]);
+
+        // every block points to the previous one in its branch
+        for chain in [&main, &fork, &other] {
+            for pair in chain.blocks().windows(2) {
+                let block = pair[1].decode().unwrap();
+                assert_eq!(block.header().previous_hash(), Some(pair[0].hash));
+                assert_eq!(block.slot(), pair[1].slot);
+            }
+        }
+
+        // forks share the prefix but diverge right after the fork point
+        assert_eq!(fork.point(5), main.point(5));
+        assert_ne!(fork.point(7), main.point(7));
+        assert_ne!(fork.point(7), other.point(7));
+
+        // switch the wal to the longer fork
+        let mut wal = empty_db();
+        wal.roll_forward(main.blocks().iter().cloned()).unwrap();
+
+        wal.roll_back(&fork.point(5)).unwrap();
+        wal.roll_forward(fork.blocks_after(5).into_iter()).unwrap();
+
+        let (_, tip) = wal.find_tip().unwrap().unwrap();
+        assert_eq!(tip, fork.tip());
+
+        let block = wal.read_block(&fork.point(7)).unwrap();
+        assert_eq!(block, fork.blocks_after(5)[0]);
+    }
+}
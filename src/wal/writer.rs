@@ -4,11 +4,29 @@ use super::*;
 pub trait WalWriter: WalReader {
     fn append_entries(&mut self, logs: impl Iterator<Item = LogValue>) -> Result<(), WalError>;
 
+    /// Appends the blocks to the WAL
+    ///
+    /// Blocks with a body too small to be valid are rejected, nothing from the
+    /// batch is written in that case.
     fn roll_forward(&mut self, blocks: impl Iterator<Item = RawBlock>) -> Result<(), WalError> {
-        self.append_entries(blocks.map(LogValue::Apply))
+        let blocks: Vec<_> = blocks.collect();
+
+        if let Some(x) = blocks.iter().find(|x| x.body.len() < MIN_BLOCK_BODY_SIZE) {
+            return Err(WalError::InvalidBlockBody(x.slot, x.body.len()));
+        }
+
+        self.append_entries(blocks.into_iter().map(LogValue::Apply))
     }
 
+    /// Undoes the blocks after `until` and marks it as the new tip
+    ///
+    /// Rolling back to the current tip is a no-op, nothing is written (not
+    /// even a mark). Upstream peers usually do that right after an intersect.
     fn roll_back(&mut self, until: &ChainPoint) -> Result<(), WalError> {
+        if self.find_tip()?.is_some_and(|(_, tip)| tip.eq(until)) {
+            return Ok(());
+        }
+
         let seq = self.assert_point(until)?;
 
         // find all of the "apply" event in the wall and gather the contained block
@@ -43,105 +61,154 @@ mod tests {
 
     #[test]
     fn test_origin_event() {
-        let db = testing::empty_db();
-
-        let mut iter = db.crawl_from(None).unwrap();
+        testing::with_each_backend(|db| {
+            let mut iter = db.crawl_from(None).unwrap();
 
-        let origin = iter.next();
-        assert!(origin.is_some());
+            let origin = iter.next();
+            assert!(origin.is_some());
 
-        let (seq, value) = origin.unwrap();
-        assert_eq!(seq, 0);
-        assert!(matches!(value, LogValue::Mark(ChainPoint::Origin)));
+            let (seq, value) = origin.unwrap();
+            assert_eq!(seq, 0);
+            assert!(matches!(value, LogValue::Mark(ChainPoint::Origin)));
 
-        // ensure nothing else
-        let origin = iter.next();
-        assert!(origin.is_none());
+            // ensure nothing else
+            let origin = iter.next();
+            assert!(origin.is_none());
+        });
     }
 
     #[test]
     fn test_basic_append() {
-        let mut db = testing::empty_db();
+        testing::with_each_backend(|mut db| {
+            let expected_block = testing::dummy_block_from_slot(11);
+            let expected_point = ChainPoint::Specific(11, expected_block.hash);
 
-        let expected_block = testing::dummy_block_from_slot(11);
-        let expected_point = ChainPoint::Specific(11, expected_block.hash);
+            db.roll_forward(std::iter::once(expected_block.clone()))
+                .unwrap();
 
-        db.roll_forward(std::iter::once(expected_block.clone()))
-            .unwrap();
+            // ensure tip matches
+            let (seq, point) = db.find_tip().unwrap().unwrap();
+            assert_eq!(seq, 1);
+            assert_eq!(point, expected_point);
 
-        // ensure tip matches
-        let (seq, point) = db.find_tip().unwrap().unwrap();
-        assert_eq!(seq, 1);
-        assert_eq!(point, expected_point);
+            // ensure point can be located
+            let seq = db.locate_point(&expected_point).unwrap().unwrap();
+            assert_eq!(seq, 1);
 
-        // ensure point can be located
-        let seq = db.locate_point(&expected_point).unwrap().unwrap();
-        assert_eq!(seq, 1);
+            // ensure chain has item
+            let mut iter = db.crawl_from(None).unwrap();
 
-        // ensure chain has item
-        let mut iter = db.crawl_from(None).unwrap();
+            iter.next(); // origin
 
-        iter.next(); // origin
+            let (seq, log) = iter.next().unwrap();
+            assert_eq!(seq, 1);
+            assert_eq!(log, LogValue::Apply(expected_block));
 
-        let (seq, log) = iter.next().unwrap();
-        assert_eq!(seq, 1);
-        assert_eq!(log, LogValue::Apply(expected_block));
+            // ensure nothing else
+            let origin = iter.next();
+            assert!(origin.is_none());
 
-        // ensure nothing else
-        let origin = iter.next();
-        assert!(origin.is_none());
+            testing::assert_invariants(&db);
+        });
     }
 
     #[test]
-    fn test_rollback_undos() {
-        let mut db = testing::empty_db();
+    fn test_reject_empty_body() {
+        let mut db = testing::db_with_dummy_blocks(3);
+        let tip = db.find_tip().unwrap();
 
-        let forward = (0..=5).map(|x| testing::dummy_block_from_slot(x * 10));
-        db.roll_forward(forward).unwrap();
+        let mut empty = testing::dummy_block_from_slot(10);
+        empty.body.clear();
 
-        let rollback_to = ChainPoint::Specific(20, testing::slot_to_hash(20));
-        db.roll_back(&rollback_to).unwrap();
+        let batch = [testing::dummy_block_from_slot(5), empty];
+        let result = db.roll_forward(batch.into_iter());
 
-        // ensure tip show rollback point
-        let (_, tip_point) = db.find_tip().unwrap().unwrap();
-        assert_eq!(tip_point, rollback_to);
+        assert!(matches!(result, Err(WalError::InvalidBlockBody(10, 0))));
 
-        // after the previous actions, we should get the following sequence
-        // Origin => Apply(0) => Apply(10) => Apply(20) => Apply(30) => Apply(40) =>
-        // Apply(50) => Undo(50) => Undo(40) => Undo(30) => Mark(20)
+        // the valid block of the batch wasn't written either
+        assert_eq!(db.find_tip().unwrap(), tip);
+    }
 
-        // ensure wal has correct sequence of events
-        let mut wal = db.crawl_from(None).unwrap();
+    #[test]
+    fn test_rollback_undos() {
+        testing::with_each_backend(|mut db| {
+            let forward = (0..=5).map(|x| testing::dummy_block_from_slot(x * 10));
+            db.roll_forward(forward).unwrap();
+
+            let rollback_to = ChainPoint::Specific(20, testing::slot_to_hash(20));
+            db.roll_back(&rollback_to).unwrap();
+
+            // ensure tip show rollback point
+            let (_, tip_point) = db.find_tip().unwrap().unwrap();
+            assert_eq!(tip_point, rollback_to);
 
-        let (seq, log) = wal.next().unwrap();
-        assert_eq!(log, LogValue::Mark(ChainPoint::Origin));
-        println!("{seq}");
+            // after the previous actions, we should get the following sequence
+            // Origin => Apply(0) => Apply(10) => Apply(20) => Apply(30) => Apply(40) =>
+            // Apply(50) => Undo(50) => Undo(40) => Undo(30) => Mark(20)
+
+            // ensure wal has correct sequence of events
+            let mut wal = db.crawl_from(None).unwrap();
 
-        for i in 0..=5 {
             let (seq, log) = wal.next().unwrap();
+            assert_eq!(log, LogValue::Mark(ChainPoint::Origin));
             println!("{seq}");
 
-            match log {
-                LogValue::Apply(RawBlock { slot, .. }) => assert_eq!(slot, i * 10),
-                _ => panic!("expected apply"),
+            for i in 0..=5 {
+                let (seq, log) = wal.next().unwrap();
+                println!("{seq}");
+
+                match log {
+                    LogValue::Apply(RawBlock { slot, .. }) => assert_eq!(slot, i * 10),
+                    _ => panic!("expected apply"),
+                }
+            }
+
+            for i in (3..=5).rev() {
+                let (seq, log) = wal.next().unwrap();
+                println!("{seq}");
+
+                match log {
+                    LogValue::Undo(RawBlock { slot, .. }) => assert_eq!(slot, i * 10),
+                    _ => panic!("expected undo"),
+                }
             }
-        }
 
-        for i in (3..=5).rev() {
             let (seq, log) = wal.next().unwrap();
+            assert_eq!(log, LogValue::Mark(rollback_to));
             println!("{seq}");
 
-            match log {
-                LogValue::Undo(RawBlock { slot, .. }) => assert_eq!(slot, i * 10),
-                _ => panic!("expected undo"),
-            }
-        }
+            // ensure chain stops here
+            assert!(wal.next().is_none());
+
+            testing::assert_invariants(&db);
+        });
+    }
+
+    #[test]
+    fn test_rollback_to_tip_is_noop() {
+        testing::with_each_backend(|mut db| {
+            let forward = (0..=5).map(|x| testing::dummy_block_from_slot(x * 10));
+            db.roll_forward(forward).unwrap();
+
+            let before: Vec<_> = db.crawl_from(None).unwrap().collect();
+            let (_, tip) = db.find_tip().unwrap().unwrap();
+
+            db.roll_back(&tip).unwrap();
+
+            let after: Vec<_> = db.crawl_from(None).unwrap().collect();
+            assert_eq!(before, after);
+
+            // once at a mark, rolling back to it again doesn't add another one
+            let rollback_to = ChainPoint::Specific(20, testing::slot_to_hash(20));
+            db.roll_back(&rollback_to).unwrap();
+
+            let before: Vec<_> = db.crawl_from(None).unwrap().collect();
+            db.roll_back(&rollback_to).unwrap();
 
-        let (seq, log) = wal.next().unwrap();
-        assert_eq!(log, LogValue::Mark(rollback_to));
-        println!("{seq}");
+            let after: Vec<_> = db.crawl_from(None).unwrap().collect();
+            assert_eq!(before, after);
 
-        // ensure chain stops here
-        assert!(wal.next().is_none());
+            testing::assert_invariants(&db);
+        });
     }
 }